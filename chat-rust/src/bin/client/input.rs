@@ -0,0 +1,455 @@
+//! Text input with a cursor. Fields are single-line unless the caller
+//! inserts `'\n'` (the chat box does for Alt+Enter / Shift+Enter).
+
+use std::collections::VecDeque;
+
+use unicode_width::UnicodeWidthStr;
+
+/// Most inputs [`InputHistory`] remembers; older ones are forgotten.
+const HISTORY_MAX: usize = 100;
+
+/// Text plus a cursor kept on a UTF-8 char boundary. Movement and deletion
+/// work one `char` at a time, so a combining mark is its own step.
+#[derive(Default, Clone)]
+pub struct Input {
+    pub value: String,
+    /// Byte offset into `value`.
+    cursor: usize,
+}
+
+impl Input {
+    pub fn insert(&mut self, ch: char) {
+        self.value.insert(self.cursor, ch);
+        self.cursor += ch.len_utf8();
+    }
+
+    /// Inserts a whole string at the cursor (used for pastes).
+    pub fn insert_str(&mut self, text: &str) {
+        self.value.insert_str(self.cursor, text);
+        self.cursor += text.len();
+    }
+
+    pub fn delete_back(&mut self) {
+        if let Some(prev) = self.prev_boundary() {
+            self.value.drain(prev..self.cursor);
+            self.cursor = prev;
+        }
+    }
+
+    pub fn delete_forward(&mut self) {
+        if let Some(next) = self.next_boundary() {
+            self.value.drain(self.cursor..next);
+        }
+    }
+
+    /// Ctrl+W: deletes whitespace before the cursor, then the word before that.
+    pub fn delete_word_back(&mut self) {
+        let before = &self.value[..self.cursor];
+        let trimmed = before.trim_end();
+        let start = trimmed
+            .char_indices()
+            .rev()
+            .find(|(_, c)| c.is_whitespace())
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        self.value.drain(start..self.cursor);
+        self.cursor = start;
+    }
+
+    /// Ctrl+U: deletes from the start of the current line to the cursor.
+    pub fn delete_to_start(&mut self) {
+        let start = self.line_start();
+        self.value.drain(start..self.cursor);
+        self.cursor = start;
+    }
+
+    /// Ctrl+K: deletes from the cursor to the end of the current line.
+    pub fn delete_to_end(&mut self) {
+        let end = self.line_end();
+        self.value.drain(self.cursor..end);
+    }
+
+    pub fn move_left(&mut self) {
+        if let Some(prev) = self.prev_boundary() {
+            self.cursor = prev;
+        }
+    }
+
+    pub fn move_right(&mut self) {
+        if let Some(next) = self.next_boundary() {
+            self.cursor = next;
+        }
+    }
+
+    /// Moves to the start of the current line.
+    pub fn move_home(&mut self) {
+        self.cursor = self.line_start();
+    }
+
+    /// Moves to the end of the current line.
+    pub fn move_end(&mut self) {
+        self.cursor = self.line_end();
+    }
+
+    pub fn clear(&mut self) {
+        self.value.clear();
+        self.cursor = 0;
+    }
+
+    /// Replaces the text, leaving the cursor at the end.
+    pub fn set(&mut self, value: String) {
+        self.cursor = value.len();
+        self.value = value;
+    }
+
+    pub fn cursor_at_start(&self) -> bool {
+        self.cursor == 0
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.value
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.value.split('\n').count()
+    }
+
+    /// Terminal columns taken by the current line before the cursor.
+    pub fn cursor_width(&self) -> u16 {
+        self.value[self.line_start()..self.cursor].width() as u16
+    }
+
+    /// Zero-based line the cursor is on.
+    pub fn cursor_line(&self) -> usize {
+        self.value[..self.cursor].matches('\n').count()
+    }
+
+    /// Number of chars before the cursor (for masked fields).
+    pub fn cursor_chars(&self) -> usize {
+        self.value[..self.cursor].chars().count()
+    }
+
+    fn line_start(&self) -> usize {
+        self.value[..self.cursor].rfind('\n').map_or(0, |i| i + 1)
+    }
+
+    fn line_end(&self) -> usize {
+        self.value[self.cursor..].find('\n').map_or(self.value.len(), |i| self.cursor + i)
+    }
+
+    fn prev_boundary(&self) -> Option<usize> {
+        self.value[..self.cursor].char_indices().next_back().map(|(i, _)| i)
+    }
+
+    fn next_boundary(&self) -> Option<usize> {
+        self.value[self.cursor..].chars().next().map(|c| self.cursor + c.len_utf8())
+    }
+}
+
+/// Recently sent inputs, recalled newest first with Up and back with Down.
+/// The text being typed when recall starts is kept as a draft and comes back
+/// after stepping down past the newest entry.
+#[derive(Default)]
+pub struct InputHistory {
+    /// Oldest first.
+    entries: VecDeque<String>,
+    /// Index of the entry being shown; `None` while editing the draft.
+    pos: Option<usize>,
+    draft: String,
+}
+
+impl InputHistory {
+    /// Records a sent input and stops any recall. Repeating the newest entry
+    /// doesn't add a copy.
+    pub fn push(&mut self, entry: String) {
+        self.pos = None;
+        self.draft.clear();
+        if self.entries.back() == Some(&entry) {
+            return;
+        }
+        if self.entries.len() == HISTORY_MAX {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub fn is_recalling(&self) -> bool {
+        self.pos.is_some()
+    }
+
+    /// The entry before the one shown, saving `current` as the draft when
+    /// recall starts. Stays on the oldest entry once there; `None` if there
+    /// is no history.
+    pub fn older(&mut self, current: &str) -> Option<&str> {
+        let pos = match self.pos {
+            Some(pos) => pos.saturating_sub(1),
+            None => {
+                let newest = self.entries.len().checked_sub(1)?;
+                self.draft = current.to_string();
+                newest
+            }
+        };
+        self.pos = Some(pos);
+        self.entries.get(pos).map(String::as_str)
+    }
+
+    /// The entry after the one shown, or the saved draft after the newest.
+    /// `None` when not recalling.
+    pub fn newer(&mut self) -> Option<String> {
+        let pos = self.pos?;
+        if pos + 1 < self.entries.len() {
+            self.pos = Some(pos + 1);
+            return Some(self.entries[pos + 1].clone());
+        }
+        self.pos = None;
+        Some(std::mem::take(&mut self.draft))
+    }
+}
+
+/// Cleans up pasted text before it goes into a field. Line endings are
+/// normalized to `\n` and kept only when `multiline`, otherwise each line
+/// break becomes a space. Tabs become spaces; other control characters
+/// (escape sequences included) are dropped.
+pub fn sanitize_paste(text: &str, multiline: bool) -> String {
+    let text = text.replace("\r\n", "\n").replace('\r', "\n");
+    let text = if multiline { text } else { text.trim_end_matches('\n').to_string() };
+    text.chars()
+        .filter_map(|c| match c {
+            '\n' if multiline => Some('\n'),
+            '\n' | '\t' => Some(' '),
+            c if c.is_control() => None,
+            c => Some(c),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed(text: &str) -> Input {
+        let mut input = Input::default();
+        input.set(text.to_string());
+        input
+    }
+
+    /// An input holding `text` with the cursor where its `|` is.
+    fn editing(text: &str) -> Input {
+        let cursor = text.find('|').expect("no cursor marker");
+        Input {
+            value: text.replacen('|', "", 1),
+            cursor,
+        }
+    }
+
+    /// `input` with a `|` where the cursor is.
+    fn shown(input: &Input) -> String {
+        let mut s = input.value.clone();
+        s.insert(input.cursor, '|');
+        s
+    }
+
+    #[test]
+    fn cursor_steps_over_whole_chars() {
+        let mut input = typed("aé😀");
+        assert_eq!(shown(&input), "aé😀|");
+        input.move_left();
+        assert_eq!(shown(&input), "aé|😀");
+        assert_eq!(input.cursor_chars(), 2);
+        input.move_left();
+        input.move_left();
+        input.move_left();
+        assert_eq!(shown(&input), "|aé😀");
+        assert!(input.cursor_at_start());
+        input.move_right();
+        input.move_right();
+        assert_eq!(shown(&input), "aé|😀");
+        input.move_end();
+        input.move_right();
+        assert_eq!(shown(&input), "aé😀|");
+    }
+
+    #[test]
+    fn combining_marks_are_their_own_step() {
+        let mut input = typed("ne\u{301}e");
+        input.move_left();
+        input.move_left();
+        assert_eq!(shown(&input), "ne|\u{301}e");
+        input.delete_forward();
+        assert_eq!(input.value, "nee");
+        input.delete_back();
+        assert_eq!(shown(&input), "n|e");
+    }
+
+    #[test]
+    fn editing_mid_string() {
+        let mut input = typed("hi 😀 there");
+        input.move_home();
+        input.move_right();
+        input.insert('é');
+        assert_eq!(shown(&input), "hé|i 😀 there");
+        input.move_right();
+        input.move_right();
+        input.delete_forward();
+        assert_eq!(shown(&input), "héi | there");
+        input.insert_str("👋🏽");
+        assert_eq!(shown(&input), "héi 👋🏽| there");
+
+        input.move_end();
+        input.delete_forward();
+        assert_eq!(input.value, "héi 👋🏽 there");
+        input.move_home();
+        input.delete_back();
+        assert_eq!(shown(&input), "|héi 👋🏽 there");
+    }
+
+    #[test]
+    fn home_and_end_stay_on_the_line() {
+        let mut input = typed("first\nsecond");
+        input.move_home();
+        assert_eq!(shown(&input), "first\n|second");
+        assert_eq!(input.cursor_line(), 1);
+        input.move_left();
+        input.move_home();
+        assert_eq!(shown(&input), "|first\nsecond");
+        input.move_end();
+        assert_eq!(shown(&input), "first|\nsecond");
+    }
+
+    #[test]
+    fn word_delete_takes_the_word_and_the_space_before_the_cursor() {
+        let cases = [
+            ("hello wörld|", "hello |"),
+            ("hello wörld  |", "hello |"),
+            ("hello wö|rld", "hello |rld"),
+            ("hello |wörld", "|wörld"),
+            ("   |x", "|x"),
+            ("|hello", "|hello"),
+            ("say 😀👋|", "say |"),
+            ("one\ntwo|", "one\n|"),
+        ];
+        for (before, after) in cases {
+            let mut input = editing(before);
+            input.delete_word_back();
+            assert_eq!(shown(&input), after, "from {:?}", before);
+        }
+    }
+
+    #[test]
+    fn line_deletes_stop_at_the_cursor_and_the_line() {
+        let cases = [
+            ("|héllo", "|héllo", "|"),
+            ("hé|llo", "|llo", "hé|"),
+            ("héllo|", "|", "héllo|"),
+            ("héllo  |", "|", "héllo  |"),
+            ("one\ntw|o\nthree", "one\n|o\nthree", "one\ntw|\nthree"),
+        ];
+        for (before, to_start, to_end) in cases {
+            let mut input = editing(before);
+            input.delete_to_start();
+            assert_eq!(shown(&input), to_start, "Ctrl+U from {:?}", before);
+            let mut input = editing(before);
+            input.delete_to_end();
+            assert_eq!(shown(&input), to_end, "Ctrl+K from {:?}", before);
+        }
+    }
+
+    #[test]
+    fn newlines_split_the_input_into_lines() {
+        let mut input = Input::default();
+        assert_eq!(input.line_count(), 1);
+        input.insert_str("first");
+        input.insert('\n');
+        assert_eq!((input.line_count(), input.cursor_line()), (2, 1));
+        assert_eq!(input.cursor_width(), 0);
+        input.insert_str("sécond");
+        assert_eq!(input.cursor_width(), 6);
+        input.move_home();
+        input.move_left();
+        assert_eq!(shown(&input), "first|\nsécond");
+        assert_eq!(input.cursor_line(), 0);
+
+        // Deleting the newline joins the lines again.
+        input.delete_forward();
+        assert_eq!((input.as_str(), input.line_count()), ("firstsécond", 1));
+        input.insert('\n');
+        input.delete_back();
+        assert_eq!(input.line_count(), 1);
+        input.set("trailing\n".to_string());
+        assert_eq!((input.line_count(), input.cursor_line()), (2, 1));
+    }
+
+    #[test]
+    fn pastes_keep_newlines_only_where_allowed() {
+        let pasted = "one\r\ntwo\rthree\tfour\n";
+        assert_eq!(sanitize_paste(pasted, true), "one\ntwo\nthree four\n");
+        assert_eq!(sanitize_paste(pasted, false), "one two three four");
+        assert_eq!(sanitize_paste("\u{1b}[31mred\u{7}\u{0}", true), "[31mred");
+        assert_eq!(sanitize_paste("naïve 😀", false), "naïve 😀");
+        assert_eq!(sanitize_paste("", true), "");
+    }
+
+    #[test]
+    fn a_paste_lands_as_one_insert_at_the_cursor() {
+        let mut input = editing("say |!");
+        input.insert_str(&sanitize_paste("hi\nthere", false));
+        assert_eq!(shown(&input), "say hi there|!");
+        input.delete_back();
+        assert_eq!(shown(&input), "say hi ther|!");
+    }
+
+    #[test]
+    fn recall_walks_back_and_returns_to_the_draft() {
+        let mut history = InputHistory::default();
+        assert_eq!(history.older("draft"), None);
+        assert!(!history.is_recalling());
+        assert_eq!(history.newer(), None);
+
+        for sent in ["one", "two", "three"] {
+            history.push(sent.to_string());
+        }
+        assert_eq!(history.older("half-typed"), Some("three"));
+        assert!(history.is_recalling());
+        assert_eq!(history.older("three"), Some("two"));
+        assert_eq!(history.older("two"), Some("one"));
+        // Stays on the oldest.
+        assert_eq!(history.older("one"), Some("one"));
+        assert_eq!(history.newer().as_deref(), Some("two"));
+        assert_eq!(history.newer().as_deref(), Some("three"));
+        assert_eq!(history.newer().as_deref(), Some("half-typed"));
+        assert!(!history.is_recalling());
+        assert_eq!(history.newer(), None);
+    }
+
+    #[test]
+    fn sending_ends_recall_and_repeats_are_kept_once() {
+        let mut history = InputHistory::default();
+        history.push("one".to_string());
+        history.push("one".to_string());
+        history.older("draft");
+        history.push("two".to_string());
+        assert!(!history.is_recalling());
+        assert_eq!(history.older(""), Some("two"));
+        assert_eq!(history.older(""), Some("one"));
+        assert_eq!(history.older(""), Some("one"));
+
+        let mut history = InputHistory::default();
+        for n in 0..HISTORY_MAX + 5 {
+            history.push(n.to_string());
+        }
+        for _ in 0..HISTORY_MAX + 10 {
+            history.older("");
+        }
+        assert_eq!(history.older(""), Some("5"));
+    }
+
+    #[test]
+    fn cursor_width_counts_columns() {
+        let mut input = typed("a😀b");
+        assert_eq!(input.cursor_width(), 4);
+        input.move_left();
+        assert_eq!(input.cursor_width(), 3);
+        assert_eq!(input.cursor_chars(), 2);
+        input.set("one\nwide 😀".to_string());
+        assert_eq!(input.cursor_width(), 7);
+    }
+}
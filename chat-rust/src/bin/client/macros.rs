@@ -0,0 +1,154 @@
+//! Text macros: typing `/name [args]` sends the macro's text in place of
+//! the command. `/shrug` is built in; `--macros-file` adds more, or
+//! replaces it, from a JSON object of names and texts:
+//!
+//! ```json
+//! { "tableflip": "(╯°□°)╯︵ ┻━┻", "slap": "slaps $1 around a bit with $2" }
+//! ```
+//!
+//! In a text, `$1` to `$9` are replaced by the space-separated arguments,
+//! `$*` by all of them as typed, and `$$` by a single `$`. A macro that
+//! uses numbered placeholders needs exactly that many arguments. A macro
+//! with no placeholders puts any arguments in front of its text, so
+//! `/shrug oh well` sends `oh well ¯\_(ツ)_/¯`. Built-in slash commands
+//! take precedence over macros with the same name.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+
+const BUILTIN: &[(&str, &str)] = &[("shrug", r"¯\_(ツ)_/¯")];
+
+pub struct Macros(BTreeMap<String, String>);
+
+impl Default for Macros {
+    fn default() -> Self {
+        Macros(BUILTIN.iter().map(|&(name, text)| (name.to_string(), text.to_string())).collect())
+    }
+}
+
+impl Macros {
+    /// The built-in macros plus those in the file at `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read_to_string(path)
+            .map_err(|e| anyhow!("failed to read macros file {}: {}", path.display(), e))?;
+        let file: BTreeMap<String, String> = serde_json::from_str(&data)
+            .map_err(|e| anyhow!("bad macros file {}: {}", path.display(), e))?;
+        let mut macros = Macros::default();
+        for (name, text) in file {
+            if !is_name(&name) {
+                anyhow::bail!(
+                    "bad macros file {}: {:?} is not a valid macro name",
+                    path.display(),
+                    name
+                );
+            }
+            macros.0.insert(name, text);
+        }
+        Ok(macros)
+    }
+
+    /// The text `/name arg` sends, or `None` if there is no such macro. Fails
+    /// with a usage message if the arguments don't fit the placeholders.
+    pub fn expand(&self, name: &str, arg: &str) -> Option<Result<String, String>> {
+        let text = self.0.get(name)?;
+        Some(expand(text, arg).map_err(|wanted| {
+            let plural = if wanted == 1 { "" } else { "s" };
+            format!("/{} takes {} argument{}", name, wanted, plural)
+        }))
+    }
+}
+
+/// Whether `name` could be typed as `/name`: letters, digits, `_` and `-`.
+pub fn is_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+/// `text` with its placeholders filled from `arg`. On a mismatch, fails
+/// with the number of arguments `text` takes.
+fn expand(text: &str, arg: &str) -> Result<String, usize> {
+    let args: Vec<&str> = arg.split_whitespace().collect();
+    let mut out = String::with_capacity(text.len() + arg.len());
+    let mut wanted = 0;
+    let mut placeholders = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            out.push(c);
+            continue;
+        }
+        match chars.peek() {
+            Some('$') => out.push('$'),
+            Some('*') => {
+                placeholders = true;
+                out.push_str(arg);
+            }
+            Some(&d @ '1'..='9') => {
+                placeholders = true;
+                let n = d as usize - '0' as usize;
+                wanted = wanted.max(n);
+                out.push_str(args.get(n - 1).copied().unwrap_or_default());
+            }
+            _ => {
+                out.push('$');
+                continue;
+            }
+        }
+        chars.next();
+    }
+    if wanted > 0 && args.len() != wanted {
+        return Err(wanted);
+    }
+    if !placeholders && !arg.is_empty() {
+        out = format!("{} {}", arg, out);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with(contents: &str) -> Result<Macros> {
+        let path = std::env::temp_dir().join(format!("chat-macros-{}.json", std::process::id()));
+        fs::write(&path, contents).unwrap();
+        let macros = Macros::load(&path);
+        fs::remove_file(&path).ok();
+        macros
+    }
+
+    #[test]
+    fn placeholders_are_filled_from_the_arguments() {
+        assert_eq!(expand("slaps $1 with $2", "bob  trout"), Ok("slaps bob with trout".into()));
+        assert_eq!(expand("slaps $1 with $2", "bob"), Err(2));
+        assert_eq!(expand("slaps $1 with $2", "bob a trout"), Err(2));
+        assert_eq!(expand("says: $*", "hi  there"), Ok("says: hi  there".into()));
+        assert_eq!(expand("$2 before $1", "one two"), Ok("two before one".into()));
+        assert_eq!(expand("costs $$5 or $x", ""), Ok("costs $5 or $x".into()));
+        assert_eq!(expand("trailing $", ""), Ok("trailing $".into()));
+    }
+
+    #[test]
+    fn a_macro_without_placeholders_puts_arguments_first() {
+        let macros = Macros::default();
+        assert_eq!(macros.expand("shrug", ""), Some(Ok(r"¯\_(ツ)_/¯".to_string())));
+        let shrugged = r"oh well ¯\_(ツ)_/¯".to_string();
+        assert_eq!(macros.expand("shrug", "oh well"), Some(Ok(shrugged)));
+        assert_eq!(macros.expand("nope", ""), None);
+    }
+
+    #[test]
+    fn the_file_adds_and_overrides_macros() {
+        let macros = with(r#"{ "shrug": "meh", "slap": "slaps $1" }"#).unwrap();
+        assert_eq!(macros.expand("shrug", ""), Some(Ok("meh".to_string())));
+        assert_eq!(macros.expand("slap", "bob"), Some(Ok("slaps bob".to_string())));
+        assert_eq!(macros.expand("slap", ""), Some(Err("/slap takes 1 argument".to_string())));
+
+        let err = with(r#"{ "two words": "x" }"#).err().unwrap();
+        assert!(err.to_string().contains("not a valid macro name"), "{}", err);
+        assert!(with("[]").is_err());
+        assert!(is_name("table-flip_2") && !is_name("") && !is_name("usr/bin"));
+    }
+}
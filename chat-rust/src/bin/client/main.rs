@@ -0,0 +1,3375 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Datelike, NaiveDate, TimeDelta, Utc};
+use clap::{Parser, ValueEnum};
+use crossterm::{
+    event::{
+        self, DisableBracketedPaste, EnableBracketedPaste, Event, KeyCode, KeyEvent, KeyModifiers,
+    },
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Alignment, Constraint, Direction, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{
+        Block, Borders, Clear, List, ListItem, Paragraph, Scrollbar, ScrollbarOrientation,
+        ScrollbarState,
+    },
+    Frame, Terminal,
+};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TryRecvError;
+use tracing_subscriber::EnvFilter;
+use unicode_width::UnicodeWidthStr;
+
+use chat::client::{Client, ConnectOptions};
+use chat::{emoji, entities};
+use chat::protocol::*;
+use chat::query::Query;
+use chat::store::normalize_username;
+
+mod input;
+use input::{sanitize_paste, Input, InputHistory};
+mod macros;
+use macros::Macros;
+mod timefmt;
+use timefmt::{DisplayZone, TimeDisplay};
+
+/// Rows the chat input grows to before it starts scrolling.
+const MAX_INPUT_LINES: usize = 6;
+/// Accounts per `/directory` page.
+const DIRECTORY_PAGE: usize = 20;
+/// How often expiry countdowns are redrawn and expired lines dropped.
+const EXPIRY_REDRAW: Duration = Duration::from_secs(1);
+
+// ─── CLI ──────────────────────────────────────────────────────────────────────
+
+#[derive(Parser)]
+#[command(name = "client", about = "RustChat TUI client")]
+struct Args {
+    /// Server address: host:port, or unix:/path for a Unix socket
+    #[arg(long, default_value = "localhost:8080")]
+    addr: String,
+
+    /// Wire framing; must match the server's --framing
+    #[arg(long, default_value_t = Framing::Newline)]
+    framing: Framing,
+
+    /// Ask the server to zlib-compress the connection (falls back if unsupported)
+    #[arg(long)]
+    compress: bool,
+
+    /// Ping the server after this many seconds without sending anything, so
+    /// servers with an idle timeout keep the session open (0 = never)
+    #[arg(long, default_value_t = 30)]
+    keepalive_secs: u64,
+
+    /// Show our own messages when the server echoes them back, rather than
+    /// as soon as they are sent
+    #[arg(long)]
+    server_echo: bool,
+
+    /// Remember the last message seen in this file and, on the next login,
+    /// fetch only what was missed since then
+    #[arg(long)]
+    cursor_file: Option<PathBuf>,
+
+    /// Color theme
+    #[arg(long, value_enum, default_value_t = ThemeName::Dark)]
+    theme: ThemeName,
+
+    /// strftime-style format for message timestamps
+    #[arg(long, default_value = "%H:%M:%S")]
+    time_format: String,
+
+    /// Timezone to show timestamps in: local, or an IANA name such as
+    /// Europe/Berlin or UTC
+    #[arg(long, default_value = "local")]
+    timezone: DisplayZone,
+
+    /// JSON file of text macros ({"name": "text"}), sent by typing /name;
+    /// $1..$9 and $* in a text are replaced by the arguments
+    #[arg(long)]
+    macros_file: Option<PathBuf>,
+
+    /// Write logs to this file (the terminal is owned by the UI)
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+
+    /// Log filter (e.g. "info", "chat=debug"); RUST_LOG takes precedence
+    #[arg(long, default_value = "info")]
+    log_level: String,
+}
+
+// ─── Theme ───────────────────────────────────────────────────────────────────
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum ThemeName {
+    Dark,
+    Light,
+    HighContrast,
+}
+
+/// Semantic colors used by the draw functions.
+#[derive(Debug, Clone, Copy)]
+struct Theme {
+    header_fg: Color,
+    header_bg: Color,
+    title: Color,
+    text: Color,
+    hint: Color,
+    /// Username colors; each user is hashed to a fixed slot. None may match
+    /// the muted or error colors, or a user would read as a system line.
+    user_palette: &'static [Color],
+    timestamp: Color,
+    system: Color,
+    error: Color,
+    border: Color,
+    border_focused: Color,
+    border_muted: Color,
+    /// Background behind search matches; the text on it is black.
+    highlight: Color,
+    /// Code messages, code blocks and `inline code`.
+    code: Color,
+}
+
+impl Theme {
+    fn dark() -> Self {
+        Self {
+            header_fg: Color::White,
+            header_bg: Color::DarkGray,
+            title: Color::Yellow,
+            text: Color::White,
+            hint: Color::DarkGray,
+            user_palette: &[
+                Color::Cyan,
+                Color::Green,
+                Color::Yellow,
+                Color::Magenta,
+                Color::Blue,
+                Color::LightCyan,
+                Color::LightGreen,
+                Color::LightMagenta,
+                Color::LightBlue,
+                Color::LightRed,
+            ],
+            timestamp: Color::DarkGray,
+            system: Color::DarkGray,
+            error: Color::Red,
+            border: Color::Cyan,
+            border_focused: Color::Yellow,
+            border_muted: Color::DarkGray,
+            highlight: Color::Yellow,
+            code: Color::LightGreen,
+        }
+    }
+
+    fn light() -> Self {
+        Self {
+            header_fg: Color::Black,
+            header_bg: Color::Gray,
+            title: Color::Magenta,
+            text: Color::Black,
+            hint: Color::DarkGray,
+            user_palette: &[
+                Color::Blue,
+                Color::Green,
+                Color::Magenta,
+                Color::Rgb(0x00, 0x5f, 0x87),
+                Color::Cyan,
+                Color::Rgb(0x8a, 0x4b, 0x08),
+                Color::Rgb(0x1b, 0x5e, 0x20),
+                Color::Rgb(0x4a, 0x14, 0x8c),
+            ],
+            timestamp: Color::DarkGray,
+            system: Color::DarkGray,
+            error: Color::Red,
+            border: Color::Blue,
+            border_focused: Color::Magenta,
+            border_muted: Color::Gray,
+            highlight: Color::Yellow,
+            code: Color::Green,
+        }
+    }
+
+    fn high_contrast() -> Self {
+        Self {
+            header_fg: Color::Black,
+            header_bg: Color::White,
+            title: Color::LightYellow,
+            text: Color::White,
+            hint: Color::Gray,
+            user_palette: &[
+                Color::LightCyan,
+                Color::LightGreen,
+                Color::LightYellow,
+                Color::LightMagenta,
+                Color::LightBlue,
+                Color::Rgb(0xff, 0xaf, 0x5f),
+            ],
+            timestamp: Color::Gray,
+            system: Color::Gray,
+            error: Color::LightRed,
+            border: Color::White,
+            border_focused: Color::LightYellow,
+            border_muted: Color::Gray,
+            highlight: Color::LightYellow,
+            code: Color::LightGreen,
+        }
+    }
+
+    /// Picks a palette color for `username`. Uses FNV-1a over the lowercased
+    /// name so the mapping is stable across sessions and Rust versions.
+    fn user_color(&self, username: &str) -> Color {
+        let mut hash: u32 = 0x811c_9dc5;
+        for b in username.to_lowercase().bytes() {
+            hash ^= b as u32;
+            hash = hash.wrapping_mul(0x0100_0193);
+        }
+        self.user_palette[hash as usize % self.user_palette.len()]
+    }
+
+    fn from_name(name: ThemeName) -> Self {
+        match name {
+            ThemeName::Dark => Self::dark(),
+            ThemeName::Light => Self::light(),
+            ThemeName::HighContrast => Self::high_contrast(),
+        }
+    }
+}
+
+// ─── Screens ─────────────────────────────────────────────────────────────────
+
+#[derive(Debug, Clone, PartialEq)]
+enum Screen {
+    Login,
+    Chat,
+    Search,
+}
+
+// ─── App state ───────────────────────────────────────────────────────────────
+
+#[derive(Debug, Clone)]
+struct ChatLine {
+    /// Server message id; `None` for local and live system lines, and for
+    /// direct messages (whose ids aren't `sync` cursors).
+    id: Option<String>,
+    /// Sender's user ID; empty for system lines.
+    user_id: String,
+    username: String,
+    content: String,
+    timestamp: Option<DateTime<Utc>>,
+    is_system: bool,
+    direct: Option<DirectInfo>,
+    /// Mentions and links, as extracted by the server.
+    entities: Vec<Entity>,
+    format: MessageFormat,
+    /// When the server deletes the message, for one sent with a TTL.
+    expires_at: Option<DateTime<Utc>>,
+}
+
+/// What makes a [`ChatLine`] a direct message.
+#[derive(Debug, Clone)]
+struct DirectInfo {
+    message_id: String,
+    /// Sent by us, rather than received.
+    outgoing: bool,
+    /// The other party's username.
+    peer: String,
+    /// Latest receipt for an outgoing message.
+    receipt: Option<ReceiptKind>,
+    /// A read receipt has been sent for this incoming message.
+    acked: bool,
+}
+
+impl ChatLine {
+    fn system(content: impl Into<String>) -> Self {
+        Self {
+            id: None,
+            user_id: String::new(),
+            username: String::new(),
+            content: content.into(),
+            timestamp: None,
+            is_system: true,
+            direct: None,
+            entities: Vec::new(),
+            format: MessageFormat::Plain,
+            expires_at: None,
+        }
+    }
+
+    fn from_stored(m: StoredMessage) -> Self {
+        Self {
+            id: Some(m.id),
+            user_id: m.user_id,
+            username: m.username,
+            content: m.content,
+            timestamp: Some(m.timestamp),
+            is_system: m.kind == MessageKind::System,
+            direct: None,
+            entities: m.entities,
+            format: m.format.unwrap_or_default(),
+            expires_at: m.expires_at,
+        }
+    }
+
+    fn from_direct(dm: DirectMessage, outgoing: bool) -> Self {
+        let peer = if outgoing { dm.to } else { dm.username.clone() };
+        Self {
+            id: None,
+            user_id: dm.user_id,
+            username: dm.username,
+            content: dm.content,
+            timestamp: Some(dm.timestamp),
+            is_system: false,
+            entities: dm.entities,
+            format: MessageFormat::Plain,
+            expires_at: None,
+            direct: Some(DirectInfo {
+                message_id: dm.id,
+                outgoing,
+                peer,
+                receipt: None,
+                acked: false,
+            }),
+        }
+    }
+}
+
+/// A row in the rendered message list: either a message or a day divider.
+enum ChatRow<'a> {
+    DateSeparator(NaiveDate),
+    Message(&'a ChatLine),
+}
+
+/// Interleaves a date separator wherever two consecutive timestamped lines
+/// fall on different calendar days. Untimestamped (live system) lines never
+/// trigger a separator and don't reset the comparison.
+/// Days are taken in the display timezone.
+fn chat_rows<'a>(lines: &'a [ChatLine], time: &TimeDisplay) -> Vec<ChatRow<'a>> {
+    let mut rows = Vec::with_capacity(lines.len());
+    let mut last_day: Option<NaiveDate> = None;
+    for line in lines {
+        if let Some(ts) = line.timestamp {
+            let day = time.day(ts);
+            if last_day.is_some_and(|d| d != day) {
+                rows.push(ChatRow::DateSeparator(day));
+            }
+            last_day = Some(day);
+        }
+        rows.push(ChatRow::Message(line));
+    }
+    rows
+}
+
+fn date_separator_label(day: NaiveDate, time: &TimeDisplay) -> String {
+    if day.year() == time.today().year() {
+        format!("── {} ──", day.format("%B %-d"))
+    } else {
+        format!("── {} ──", day.format("%B %-d, %Y"))
+    }
+}
+
+/// The lobby (`room` is `None`) or a joined room, with its own messages
+/// and scroll position.
+struct Tab {
+    room: Option<String>,
+    messages: Vec<ChatLine>,
+    /// While scrolled up, the newest row shown, counted from the oldest
+    /// row (see [`chat_rows`]). New messages land below it, so the view
+    /// stays put. `None` follows the newest messages.
+    anchor: Option<usize>,
+    /// Chat messages that arrived while scrolled away from the bottom or
+    /// while another tab was shown.
+    unread: usize,
+    /// The room's topic, shown in the header.
+    topic: Option<String>,
+}
+
+impl Tab {
+    fn new(room: Option<String>) -> Self {
+        Self {
+            room,
+            messages: Vec::new(),
+            anchor: None,
+            unread: 0,
+            topic: None,
+        }
+    }
+
+    fn label(&self) -> String {
+        match &self.room {
+            Some(room) => format!("#{}", room),
+            None => "lobby".to_string(),
+        }
+    }
+
+    /// Rows in [`chat_rows`] for this tab's messages.
+    fn row_count(&self, time: &TimeDisplay) -> usize {
+        chat_rows(&self.messages, time).len()
+    }
+
+    /// One past the newest row shown.
+    fn view_end(&self, time: &TimeDisplay) -> usize {
+        let rows = self.row_count(time);
+        self.anchor.map_or(rows, |a| (a + 1).min(rows))
+    }
+
+    /// Removes the lines `gone` picks, keeping the view on the same
+    /// messages while scrolled up.
+    fn remove_lines(&mut self, time: &TimeDisplay, gone: impl Fn(&ChatLine) -> bool) {
+        // Messages up to and including the anchored row, and how many stay.
+        let shown = self.anchor.map(|a| {
+            let rows = chat_rows(&self.messages, time);
+            let n = rows.iter().take(a + 1).filter(|r| matches!(r, ChatRow::Message(_))).count();
+            self.messages[..n].iter().filter(|l| !gone(l)).count()
+        });
+        self.messages.retain(|l| !gone(l));
+        if let Some(n) = shown {
+            self.anchor = Some(chat_rows(&self.messages[..n], time).len().saturating_sub(1));
+        }
+    }
+}
+
+struct App {
+    screen: Screen,
+
+    // Login fields
+    login_field: usize, // 0=username, 1=password
+    login_username: Input,
+    login_password: Input,
+    is_register: bool,
+    login_error: String,
+
+    // Chat
+    /// The lobby first, then rooms in the order they were joined.
+    tabs: Vec<Tab>,
+    /// Index into `tabs` of the one shown.
+    active: usize,
+    chat_input: Input,
+    /// What was sent from `chat_input`, for Up/Down recall.
+    input_history: InputHistory,
+    /// Our own identity, as reported by the server on login.
+    me: Option<UserInfo>,
+    /// Our preferences as the server keeps them; `notify` decides which
+    /// messages ring the terminal bell.
+    prefs: Prefs,
+    /// Ring the bell at the next redraw.
+    bell: bool,
+    time: TimeDisplay,
+    /// Online users by ID, seeded from `users` responses and kept current
+    /// from presence notices.
+    online: BTreeMap<String, UserInfo>,
+    /// Print the next `users` response (set by `/users`).
+    show_users: bool,
+    /// `sync` cursor loaded from `--cursor-file`.
+    cursor: Option<String>,
+    /// Server stats shown in a popup until the next key press.
+    stats: Option<ServerStats>,
+    /// Do-not-disturb requested; the server pauses chat broadcasts.
+    dnd: bool,
+    /// Marked away with `/away`. The server clears it when we next send a
+    /// chat or direct message, and so do we.
+    away: bool,
+    /// Pinned messages in every room, oldest pin first.
+    pinned: Vec<StoredMessage>,
+    /// The server leaves us out of our own chat broadcasts; our messages
+    /// are shown as they are sent instead.
+    local_echo: bool,
+    macros: Macros,
+    viewport_height: u16,
+
+    // Search overlay
+    search_field: usize, // 0=query, 1=username, 2=from, 3=to
+    search_query: Input,
+    search_user: Input,
+    search_from: Input,
+    search_to: Input,
+    search_results: Vec<ChatLine>,
+    search_scroll: usize,
+    /// Rows available for results in the search overlay.
+    search_height: u16,
+    /// Whether a search has returned since the overlay was opened.
+    search_done: bool,
+    /// Outcome of the last export, or a note on the results, shown at the
+    /// bottom of the overlay.
+    search_status: Option<String>,
+    /// The content query of the last search sent, for highlighting results.
+    search_highlight: Option<Query>,
+    /// Filter the shown tab's loaded messages as the fields change, instead
+    /// of asking the server on Enter (Ctrl+L toggles).
+    search_local: bool,
+
+    // Quit flag
+    quit: bool,
+    /// Message ids of incoming direct messages drawn in the last frame, for
+    /// read receipts. Filled in by `draw_chat`.
+    directs_in_view: RefCell<Vec<String>>,
+}
+
+impl App {
+    fn new(time: TimeDisplay) -> Self {
+        Self {
+            screen: Screen::Login,
+            login_field: 0,
+            login_username: Input::default(),
+            login_password: Input::default(),
+            is_register: false,
+            login_error: String::new(),
+
+            tabs: vec![Tab::new(None)],
+            active: 0,
+            chat_input: Input::default(),
+            input_history: InputHistory::default(),
+            me: None,
+            prefs: Prefs::default(),
+            bell: false,
+            time,
+            online: BTreeMap::new(),
+            show_users: false,
+            cursor: None,
+            stats: None,
+            dnd: false,
+            away: false,
+            pinned: Vec::new(),
+            local_echo: false,
+            macros: Macros::default(),
+            viewport_height: 20,
+
+            search_field: 0,
+            search_query: Input::default(),
+            search_user: Input::default(),
+            search_from: Input::default(),
+            search_to: Input::default(),
+            search_results: Vec::new(),
+            search_scroll: 0,
+            search_height: 10,
+            search_done: false,
+            search_status: None,
+            search_highlight: None,
+            search_local: false,
+
+            quit: false,
+            directs_in_view: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn tab(&self) -> &Tab {
+        &self.tabs[self.active]
+    }
+
+    fn tab_mut(&mut self) -> &mut Tab {
+        &mut self.tabs[self.active]
+    }
+
+    /// Adds a line to the tab being shown.
+    fn push_message(&mut self, line: ChatLine) {
+        let tab = self.tab_mut();
+        if tab.anchor.is_some() && !line.is_system {
+            tab.unread += 1;
+        }
+        tab.messages.push(line);
+    }
+
+    /// Rings the bell for a message from `user_id` if our notification
+    /// level asks for it. `mention` is true for direct messages and chat
+    /// that mentions us.
+    fn alert(&mut self, user_id: &str, mention: bool) {
+        let mine = self.me.as_ref().is_some_and(|me| me.user_id == user_id);
+        if !mine && self.prefs.notify.notifies(mention) {
+            self.bell = true;
+        }
+    }
+
+    /// Whether one of the mentions in `content` is of us.
+    fn mentions_me(&self, content: &str, entities: &[Entity]) -> bool {
+        let me = match &self.me {
+            Some(me) => normalize_username(&me.username),
+            None => return false,
+        };
+        entities.iter().filter(|e| e.kind == EntityKind::Mention).any(|e| {
+            content.get(e.start + 1..e.end).is_some_and(|name| normalize_username(name) == me)
+        })
+    }
+
+    fn tab_index(&self, room: Option<&str>) -> Option<usize> {
+        self.tabs.iter().position(|t| t.room.as_deref() == room)
+    }
+
+    /// Adds a line to `room`'s tab (the lobby's for `None`). Lines for a
+    /// room we have no tab for (just left) are dropped.
+    fn push_to_room(&mut self, room: Option<&str>, line: ChatLine) {
+        match self.tab_index(room) {
+            Some(i) if i == self.active => self.push_message(line),
+            Some(i) => {
+                let tab = &mut self.tabs[i];
+                if !line.is_system {
+                    tab.unread += 1;
+                }
+                tab.messages.push(line);
+            }
+            None => {}
+        }
+    }
+
+    /// Shows `room`'s tab, opening it with `history` if it is new.
+    fn open_room(&mut self, join: JoinResult) {
+        let i = match self.tab_index(Some(&join.room)) {
+            Some(i) => i,
+            None => {
+                let mut tab = Tab::new(Some(join.room));
+                tab.messages = join.messages.into_iter().map(ChatLine::from_stored).collect();
+                self.tabs.push(tab);
+                self.tabs.len() - 1
+            }
+        };
+        self.tabs[i].topic = join.topic.map(|t| t.topic);
+        self.show_tab(i);
+    }
+
+    /// Closes `room`'s tab, showing its left neighbour if it was shown.
+    fn close_room(&mut self, room: &str) {
+        if let Some(i) = self.tab_index(Some(room)) {
+            self.tabs.remove(i);
+            if self.active >= i {
+                self.show_tab(self.active.saturating_sub(1));
+            }
+        }
+    }
+
+    /// Moves `delta` tabs to the right (left if negative), wrapping around.
+    fn cycle_tab(&mut self, delta: isize) {
+        let n = self.tabs.len() as isize;
+        self.show_tab((self.active as isize + delta).rem_euclid(n) as usize);
+    }
+
+    fn show_tab(&mut self, i: usize) {
+        self.active = i;
+        let tab = self.tab_mut();
+        if tab.anchor.is_none() {
+            tab.unread = 0;
+        }
+    }
+
+    /// Id of the newest lobby message shown, falling back to the cursor we
+    /// started from. Rooms don't take part in `sync`.
+    fn last_seen_id(&self) -> Option<&str> {
+        self.tabs[0]
+            .messages
+            .iter()
+            .rev()
+            .find_map(|l| l.id.as_deref())
+            .or(self.cursor.as_deref())
+    }
+
+    /// Records a receipt on our outgoing direct message. `read` wins over a
+    /// late `delivered`.
+    fn apply_receipt(&mut self, r: ReceiptPayload) {
+        let info = self
+            .tabs
+            .iter_mut()
+            .flat_map(|t| t.messages.iter_mut().rev())
+            .filter_map(|l| l.direct.as_mut())
+            .find(|d| d.outgoing && d.message_id == r.message_id);
+        if let Some(info) = info {
+            if info.receipt != Some(ReceiptKind::Read) {
+                info.receipt = Some(r.kind);
+            }
+        }
+    }
+
+    /// Incoming direct messages that have been on screen but not yet
+    /// acknowledged; marks them acknowledged.
+    fn take_unacked_directs(&mut self) -> Vec<String> {
+        let in_view = self.directs_in_view.take();
+        let mut ids = Vec::new();
+        let lines = self.tabs.iter_mut().flat_map(|t| t.messages.iter_mut());
+        for info in lines.filter_map(|l| l.direct.as_mut()) {
+            if !info.outgoing && !info.acked && in_view.contains(&info.message_id) {
+                info.acked = true;
+                ids.push(info.message_id.clone());
+            }
+        }
+        ids
+    }
+
+    /// Puts fetched lobby history before anything received live, keeping
+    /// the view on the same rows if scrolled up.
+    fn prepend_history(&mut self, msgs: Vec<StoredMessage>) {
+        let lobby = &mut self.tabs[0];
+        let before = lobby.row_count(&self.time);
+        let mut history: Vec<ChatLine> = msgs.into_iter().map(ChatLine::from_stored).collect();
+        history.append(&mut lobby.messages);
+        lobby.messages = history;
+        let added = lobby.row_count(&self.time) - before;
+        if let Some(anchor) = &mut lobby.anchor {
+            *anchor += added;
+        }
+    }
+
+    /// Drops messages the server has deleted.
+    fn remove_messages(&mut self, ids: &[String]) {
+        for tab in &mut self.tabs {
+            tab.remove_lines(&self.time, |l| l.id.as_ref().is_some_and(|id| ids.contains(id)));
+        }
+        self.pinned.retain(|m| !ids.contains(&m.id));
+    }
+
+    /// Drops lines whose expiry has passed, which also covers local echoes
+    /// that no `deleted` packet names. True if any lines went or are still
+    /// counting down, so the view needs redrawing.
+    fn expire_lines(&mut self) -> bool {
+        let now = self.time.now();
+        let mut changed = false;
+        for tab in &mut self.tabs {
+            let before = tab.messages.len();
+            tab.remove_lines(&self.time, |l| l.expires_at.is_some_and(|at| at <= now));
+            changed |= tab.messages.len() != before;
+            changed |= tab.messages.iter().any(|l| l.expires_at.is_some());
+        }
+        changed
+    }
+
+    /// Updates the online list from a join/leave/rename notice.
+    fn apply_presence(&mut self, p: Presence) {
+        match p.event {
+            PresenceEvent::Join | PresenceEvent::Rename | PresenceEvent::Profile => {
+                self.online.insert(p.user.user_id.clone(), p.user);
+            }
+            PresenceEvent::Leave => {
+                self.online.remove(&p.user.user_id);
+            }
+        }
+    }
+
+    /// Updates the pinned list from a pin/unpin notice.
+    fn apply_pin(&mut self, p: PinEvent) {
+        self.pinned.retain(|m| m.id != p.message.id);
+        if p.pinned {
+            self.pinned.push(p.message);
+        }
+    }
+
+    fn apply_topic(&mut self, t: RoomTopic) {
+        if let Some(i) = self.tab_index(Some(&t.room)) {
+            self.tabs[i].topic = Some(t.topic).filter(|topic| !topic.is_empty());
+        }
+    }
+
+    /// The shown tab's pinned messages, most recent pin first.
+    fn tab_pins(&self) -> Vec<&StoredMessage> {
+        let room = self.tab().room.as_deref();
+        self.pinned.iter().rev().filter(|m| m.room.as_deref() == room).collect()
+    }
+
+    /// Rows taken by the pinned-messages bar: one if the shown tab has any.
+    fn pin_bar_height(&self) -> u16 {
+        u16::from(!self.tab_pins().is_empty())
+    }
+
+    fn set_online(&mut self, users: Vec<UserInfo>) {
+        self.online = users.into_iter().map(|u| (u.user_id.clone(), u)).collect();
+    }
+
+    /// How a sender is shown: avatar and display name if they are online
+    /// and have set them, otherwise the name the message was sent under.
+    fn sender_label(&self, line: &ChatLine) -> String {
+        match self.online.get(&line.user_id) {
+            Some(u) => user_label(u),
+            None => line.username.clone(),
+        }
+    }
+
+    /// Shows the rows ending just before `end`, clamped so the viewport
+    /// stays full; reaching the newest row goes back to following it.
+    fn scroll_to(&mut self, end: usize) {
+        let rows = self.tab().row_count(&self.time);
+        let end = end.max((self.viewport_height as usize).min(rows));
+        let tab = self.tab_mut();
+        if end >= rows {
+            tab.anchor = None;
+            tab.unread = 0;
+        } else {
+            tab.anchor = Some(end.saturating_sub(1));
+        }
+    }
+
+    fn scroll_up(&mut self) {
+        let end = self.tab().view_end(&self.time);
+        self.scroll_to(end.saturating_sub(3));
+    }
+
+    fn scroll_down(&mut self) {
+        let end = self.tab().view_end(&self.time);
+        self.scroll_to(end + 3);
+    }
+
+    fn scroll_to_top(&mut self) {
+        self.scroll_to(0);
+    }
+
+    fn scroll_to_bottom(&mut self) {
+        self.scroll_to(usize::MAX);
+    }
+
+    fn search_scroll_up(&mut self) {
+        let max = max_search_scroll(self.search_results.len(), self.search_height as usize);
+        self.search_scroll = (self.search_scroll + 3).min(max);
+    }
+
+    fn search_scroll_down(&mut self) {
+        self.search_scroll = self.search_scroll.saturating_sub(3);
+    }
+
+    /// Refills the search results from the shown tab's loaded messages.
+    fn search_locally(&mut self) {
+        let query = self.search_query.value.trim();
+        let username = self.search_user.value.trim().to_lowercase();
+        let from = parse_datetime(self.search_from.as_str());
+        let to = parse_datetime(self.search_to.as_str());
+        self.search_scroll = 0;
+        if query.is_empty() && username.is_empty() && from.is_none() && to.is_none() {
+            self.search_results.clear();
+            self.search_done = false;
+            self.search_status = None;
+            return;
+        }
+        self.search_highlight = (!query.is_empty()).then(|| Query::parse(query));
+        let query = self.search_highlight.as_ref();
+        self.search_results = local_matches(&self.tab().messages, query, &username, from, to);
+        self.search_done = true;
+        self.search_status = Some(if self.reaches_past_loaded(from, to) {
+            "dates reach past loaded history; Enter searches the server".to_string()
+        } else {
+            format!("{} match(es) in loaded messages", self.search_results.len())
+        });
+    }
+
+    /// Whether a date range asks for messages older than the shown tab has
+    /// loaded, so only the server can answer it.
+    fn reaches_past_loaded(&self, from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>) -> bool {
+        if from.is_none() && to.is_none() {
+            return false;
+        }
+        match self.tab().messages.iter().find_map(|l| l.timestamp) {
+            Some(oldest) => from.is_none_or(|from| from < oldest),
+            None => true,
+        }
+    }
+}
+
+/// The chat lines (not system notices) in `lines` that match every given
+/// criterion, as the server's search would: `query` against the content,
+/// `username` exactly (already lowercased) and the `from`/`to` range.
+fn local_matches(
+    lines: &[ChatLine],
+    query: Option<&Query>,
+    username: &str,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> Vec<ChatLine> {
+    lines
+        .iter()
+        .filter(|l| !l.is_system)
+        .filter(|l| query.is_none_or(|q| q.matches(&l.content)))
+        .filter(|l| username.is_empty() || l.username.to_lowercase() == username)
+        .filter(|l| from.is_none_or(|from| l.timestamp.is_some_and(|t| t >= from)))
+        .filter(|l| to.is_none_or(|to| l.timestamp.is_some_and(|t| t <= to)))
+        .cloned()
+        .collect()
+}
+
+// ─── Network message types (from server → TUI) ───────────────────────────────
+
+enum NetMsg {
+    Packet(Packet),
+    Disconnected,
+}
+
+// ─── Main ────────────────────────────────────────────────────────────────────
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    if StrftimeItems::new(&args.time_format).any(|i| matches!(i, Item::Error)) {
+        anyhow::bail!("invalid --time-format {:?}", args.time_format);
+    }
+    let macros = match &args.macros_file {
+        Some(path) => Macros::load(path)?,
+        None => Macros::default(),
+    };
+
+    if let Some(path) = &args.log_file {
+        tracing_subscriber::fmt()
+            .with_env_filter(
+                EnvFilter::try_from_default_env()
+                    .unwrap_or_else(|_| EnvFilter::new(&args.log_level)),
+            )
+            .with_writer(Mutex::new(File::create(path)?))
+            .with_ansi(false)
+            .init();
+    }
+
+    // Connect to server
+    let opts = ConnectOptions {
+        framing: args.framing,
+        compress: args.compress,
+        keepalive: (args.keepalive_secs > 0).then(|| Duration::from_secs(args.keepalive_secs)),
+    };
+    let client = Client::connect_addr(&args.addr, opts).await?;
+    let mut features = vec![FEATURE_PROFILES, FEATURE_RECEIPTS];
+    if !args.server_echo {
+        features.push(FEATURE_LOCAL_ECHO);
+    }
+    let mut local_echo = false;
+    match client.hello(&features).await {
+        Ok(server) => {
+            tracing::info!(version = %server.version, "client: connected");
+            local_echo = !args.server_echo
+                && server.features.iter().any(|f| f == FEATURE_LOCAL_ECHO);
+        }
+        Err(e) => tracing::info!(error = %e, "client: server did not accept hello"),
+    }
+    let mut net_rx = client.subscribe();
+
+    // Set up terminal
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen, EnableBracketedPaste)?;
+    let backend = CrosstermBackend::new(stdout);
+    let mut terminal = Terminal::new(backend)?;
+
+    let mut app = App::new(TimeDisplay::new(args.time_format, args.timezone));
+    app.local_echo = local_echo;
+    app.macros = macros;
+    // Older servers don't answer `time`; their clock is taken to match ours.
+    match client.clock_offset().await {
+        Ok(offset) => {
+            tracing::debug!(offset_ms = offset.num_milliseconds(), "client: server clock offset");
+            app.time.set_clock_offset(offset);
+        }
+        Err(e) => tracing::debug!(error = %e, "client: server did not report its time"),
+    }
+    if let Some(path) = &args.cursor_file {
+        app.cursor = std::fs::read_to_string(path)
+            .ok()
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty());
+    }
+    let theme = Theme::from_name(args.theme);
+    let result = run_app(&mut terminal, &mut app, &theme, &mut net_rx, &client).await;
+
+    // Restore terminal
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), DisableBracketedPaste, LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+
+    if let (Some(path), Some(id)) = (&args.cursor_file, app.last_seen_id()) {
+        if let Err(e) = std::fs::write(path, id) {
+            tracing::warn!(error = %e, "client: failed to save cursor");
+        }
+    }
+
+    result
+}
+
+async fn run_app(
+    terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
+    app: &mut App,
+    theme: &Theme,
+    net_rx: &mut mpsc::Receiver<Packet>,
+    client: &Client,
+) -> Result<()> {
+    let mut disconnected = false;
+    // Only redraw after input, a resize, network activity or a countdown tick.
+    let mut dirty = true;
+    let mut last_expiry_check = Instant::now();
+    loop {
+        // Draw
+        if dirty {
+            let size = terminal.size()?;
+            let chrome = 3 + app.pin_bar_height() + input_height(&app.chat_input);
+            app.viewport_height = size.height.saturating_sub(chrome);
+            let area = Rect::new(0, 0, size.width, size.height);
+            app.search_height = search_overlay_chunks(area)[4].height;
+            terminal.draw(|f| draw(f, app, theme))?;
+            dirty = false;
+            if app.bell {
+                app.bell = false;
+                terminal.backend_mut().write_all(b"\x07")?;
+                terminal.backend_mut().flush()?;
+            }
+            for id in app.take_unacked_directs() {
+                // A dead connection is reported through NetMsg::Disconnected.
+                client.send_read_receipt(&id).await.ok();
+            }
+        }
+
+        // Poll keyboard (non-blocking, 20ms)
+        if event::poll(Duration::from_millis(20))? {
+            match event::read()? {
+                Event::Key(key) => handle_key(app, key, client).await?,
+                Event::Paste(text) => handle_paste(app, &text),
+                _ => {}
+            }
+            dirty = true;
+        }
+
+        // Drain all pending network messages
+        loop {
+            match net_rx.try_recv() {
+                Ok(pkt) => {
+                    handle_net(app, NetMsg::Packet(pkt), client).await?;
+                    dirty = true;
+                }
+                Err(TryRecvError::Disconnected) if !disconnected => {
+                    disconnected = true;
+                    handle_net(app, NetMsg::Disconnected, client).await?;
+                    dirty = true;
+                }
+                Err(_) => break,
+            }
+        }
+
+        // Expiring messages count down, and go once their time is up.
+        if last_expiry_check.elapsed() >= EXPIRY_REDRAW {
+            last_expiry_check = Instant::now();
+            dirty |= app.expire_lines();
+        }
+
+        if app.quit {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// ─── Key handling ─────────────────────────────────────────────────────────────
+
+async fn handle_key(
+    app: &mut App,
+    key: KeyEvent,
+    client: &Client,
+) -> Result<()> {
+    if app.stats.take().is_some() {
+        return Ok(());
+    }
+    match app.screen {
+        Screen::Login => handle_login_key(app, key, client).await,
+        Screen::Chat => handle_chat_key(app, key, client).await,
+        Screen::Search => handle_search_key(app, key, client).await,
+    }
+}
+
+/// Inserts a bracketed paste into the focused field in one go, so pasted
+/// newlines never reach the Enter handler. Only the chat input keeps them.
+fn handle_paste(app: &mut App, text: &str) {
+    if app.stats.is_some() {
+        return;
+    }
+    let (input, multiline) = match app.screen {
+        Screen::Login if app.login_field == 0 => (&mut app.login_username, false),
+        Screen::Login => (&mut app.login_password, false),
+        Screen::Chat => (&mut app.chat_input, true),
+        Screen::Search => (active_search_field(app), false),
+    };
+    input.insert_str(&sanitize_paste(text, multiline));
+}
+
+async fn handle_login_key(
+    app: &mut App,
+    key: KeyEvent,
+    client: &Client,
+) -> Result<()> {
+    match key.code {
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            app.quit = true;
+        }
+        KeyCode::Char('q') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            app.quit = true;
+        }
+        KeyCode::Char('r') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            app.is_register = !app.is_register;
+            app.login_error.clear();
+        }
+        KeyCode::Char('g') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            send_packet(client, MessageType::Guest, serde_json::json!({})).await?;
+        }
+        KeyCode::Tab => {
+            app.login_field = if app.login_field == 0 { 1 } else { 0 };
+        }
+        KeyCode::BackTab => {
+            app.login_field = if app.login_field == 1 { 0 } else { 1 };
+        }
+        KeyCode::Enter => {
+            let username = app.login_username.value.trim().to_string();
+            let password = app.login_password.value.clone();
+            if username.is_empty() || password.is_empty() {
+                app.login_error = "Username and password are required".into();
+                return Ok(());
+            }
+            let msg_type = if app.is_register {
+                MessageType::Register
+            } else {
+                MessageType::Login
+            };
+            let payload = AuthPayload { username, password };
+            send_packet(client, msg_type, payload).await?;
+        }
+        KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => {
+            if app.login_field == 0 {
+                app.login_username.insert(c);
+            } else {
+                app.login_password.insert(c);
+            }
+        }
+        _ => {
+            let input = if app.login_field == 0 {
+                &mut app.login_username
+            } else {
+                &mut app.login_password
+            };
+            edit_input(input, key);
+        }
+    }
+    Ok(())
+}
+
+async fn handle_chat_key(
+    app: &mut App,
+    key: KeyEvent,
+    client: &Client,
+) -> Result<()> {
+    match key.code {
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            app.quit = true;
+        }
+        KeyCode::Char('q') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            app.quit = true;
+        }
+        KeyCode::Char('f') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            app.screen = Screen::Search;
+            app.search_results.clear();
+            app.search_scroll = 0;
+            app.search_done = false;
+            app.search_status = None;
+            if app.search_local {
+                app.search_locally();
+            }
+        }
+        KeyCode::Char('d') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            app.dnd = !app.dnd;
+            send_packet(client, MessageType::Dnd, DndPayload { enabled: app.dnd }).await?;
+        }
+        KeyCode::F(5) => {
+            send_packet(client, MessageType::Users, serde_json::json!({})).await?;
+        }
+        KeyCode::Left if key.modifiers.contains(KeyModifiers::CONTROL) => app.cycle_tab(-1),
+        KeyCode::Right if key.modifiers.contains(KeyModifiers::CONTROL) => app.cycle_tab(1),
+        KeyCode::PageUp => app.scroll_up(),
+        KeyCode::PageDown => app.scroll_down(),
+        // Home/End edit the input while it has text; Ctrl always scrolls.
+        KeyCode::Home
+            if app.chat_input.as_str().is_empty()
+                || key.modifiers.contains(KeyModifiers::CONTROL) =>
+        {
+            app.scroll_to_top()
+        }
+        KeyCode::End
+            if app.chat_input.as_str().is_empty()
+                || key.modifiers.contains(KeyModifiers::CONTROL) =>
+        {
+            app.scroll_to_bottom()
+        }
+        // Not every terminal reports Shift+Enter; Alt+Enter is the fallback.
+        KeyCode::Enter
+            if key.modifiers.intersects(KeyModifiers::SHIFT | KeyModifiers::ALT) =>
+        {
+            app.chat_input.insert('\n');
+        }
+        KeyCode::Enter => {
+            let mut content = app.chat_input.value.trim().to_string();
+            if content.is_empty() {
+                return Ok(());
+            }
+            app.chat_input.clear();
+            app.input_history.push(content.clone());
+            if let Some(cmd) = content.strip_prefix('/') {
+                let (name, arg) = cmd.split_once(' ').unwrap_or((cmd, ""));
+                if run_command(app, name, arg.trim(), client).await? {
+                    return Ok(());
+                }
+                match app.macros.expand(name, arg.trim()) {
+                    Some(Ok(text)) => content = text,
+                    Some(Err(usage)) => {
+                        app.push_message(ChatLine::system(usage));
+                        return Ok(());
+                    }
+                    // Anything else starting with `/`, such as a path, is
+                    // sent as typed.
+                    None if macros::is_name(name) => {
+                        let error = format!("unknown command or macro /{}", name);
+                        app.push_message(ChatLine::system(error));
+                        return Ok(());
+                    }
+                    None => {}
+                }
+                if content.is_empty() {
+                    return Ok(());
+                }
+            }
+            // Fenced code blocks only render as such in markdown.
+            let format = content.contains("```").then_some(MessageFormat::Markdown);
+            let payload = ChatPayload {
+                content,
+                format,
+                room: app.tab().room.clone(),
+                ttl_secs: None,
+            };
+            send_chat(app, client, payload).await?;
+        }
+        // Recall starts from an empty input or with the cursor at its start,
+        // so Up/Down don't throw away a draft by accident.
+        KeyCode::Up
+            if app.input_history.is_recalling()
+                || app.chat_input.as_str().is_empty()
+                || app.chat_input.cursor_at_start() =>
+        {
+            if let Some(entry) = app.input_history.older(app.chat_input.as_str()) {
+                app.chat_input.set(entry.to_string());
+            }
+        }
+        KeyCode::Down if app.input_history.is_recalling() => {
+            if let Some(entry) = app.input_history.newer() {
+                app.chat_input.set(entry);
+            }
+        }
+        KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => {
+            app.chat_input.insert(c);
+        }
+        _ => edit_input(&mut app.chat_input, key),
+    }
+    Ok(())
+}
+
+async fn handle_search_key(
+    app: &mut App,
+    key: KeyEvent,
+    client: &Client,
+) -> Result<()> {
+    match key.code {
+        KeyCode::Esc => {
+            app.screen = Screen::Chat;
+        }
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            app.quit = true;
+        }
+        KeyCode::Tab => {
+            app.search_field = (app.search_field + 1) % 4;
+        }
+        KeyCode::BackTab => {
+            app.search_field = (app.search_field + 3) % 4;
+        }
+        KeyCode::Char('s') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            app.search_status = Some(if app.search_results.is_empty() {
+                "nothing to export".to_string()
+            } else {
+                match export_search_results(&app.search_results) {
+                    Ok(path) => format!(
+                        "exported {} result(s) to {}",
+                        app.search_results.len(),
+                        path.display()
+                    ),
+                    Err(e) => format!("export failed: {}", e),
+                }
+            });
+        }
+        KeyCode::Char('l') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            app.search_local = !app.search_local;
+            if app.search_local {
+                app.search_locally();
+            } else {
+                app.search_results.clear();
+                app.search_done = false;
+                app.search_status = None;
+            }
+        }
+        KeyCode::PageUp => app.search_scroll_up(),
+        KeyCode::PageDown => app.search_scroll_down(),
+        // Local results are already up to date unless the dates need more.
+        KeyCode::Enter
+            if app.search_local
+                && !app.reaches_past_loaded(
+                    parse_datetime(app.search_from.as_str()),
+                    parse_datetime(app.search_to.as_str()),
+                ) => {}
+        KeyCode::Enter => {
+            let payload = SearchPayload {
+                query: app.search_query.value.trim().to_string(),
+                username: app.search_user.value.trim().to_string(),
+                from: parse_datetime(app.search_from.as_str()),
+                to: parse_datetime(app.search_to.as_str()),
+                include_system: false,
+                regex: false,
+                limit: 0,
+                offset: 0,
+            };
+            if payload.query.is_empty()
+                && payload.username.is_empty()
+                && payload.from.is_none()
+                && payload.to.is_none()
+            {
+                return Ok(());
+            }
+            app.search_highlight = if payload.query.is_empty() {
+                None
+            } else {
+                Some(Query::parse(&payload.query))
+            };
+            send_packet(client, MessageType::Search, payload).await?;
+        }
+        KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => {
+            active_search_field(app).insert(c);
+            if app.search_local {
+                app.search_locally();
+            }
+        }
+        _ => {
+            edit_input(active_search_field(app), key);
+            if app.search_local {
+                app.search_locally();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes `lines` to `search-<timestamp>.txt` in the working directory and
+/// returns the path.
+fn export_search_results(lines: &[ChatLine]) -> io::Result<PathBuf> {
+    let path = PathBuf::from(format!("search-{}.txt", Utc::now().format("%Y%m%d-%H%M%S")));
+    std::fs::write(&path, format_export(lines))?;
+    Ok(path)
+}
+
+/// One `<RFC 3339 timestamp> <username>: <content>` line per result, with
+/// full UTC timestamps regardless of `--time-format`.
+fn format_export(lines: &[ChatLine]) -> String {
+    let mut out = String::new();
+    for line in lines {
+        let ts = line
+            .timestamp
+            .map(|t| t.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+            .unwrap_or_else(|| "-".to_string());
+        if line.is_system {
+            out.push_str(&format!("{} * {}\n", ts, line.content));
+        } else {
+            out.push_str(&format!("{} {}: {}\n", ts, line.username, line.content));
+        }
+    }
+    out
+}
+
+/// Runs a slash command typed into the chat input. Returns `false` for
+/// unrecognised commands so the text can be tried as a macro.
+async fn run_command(
+    app: &mut App,
+    name: &str,
+    arg: &str,
+    client: &Client,
+) -> Result<bool> {
+    match name {
+        "clear" => {
+            app.tab_mut().messages.clear();
+            app.scroll_to_bottom();
+        }
+        "purge" => {
+            let before = parse_datetime(arg);
+            if !arg.is_empty() && before.is_none() {
+                app.push_message(ChatLine::system("usage: /purge [YYYY-MM-DD]"));
+                return Ok(true);
+            }
+            send_packet(client, MessageType::Purge, PurgePayload { before }).await?;
+        }
+        "stats" => {
+            send_packet(client, MessageType::Stats, serde_json::json!({})).await?;
+        }
+        "users" => {
+            app.show_users = true;
+            send_packet(client, MessageType::Users, serde_json::json!({})).await?;
+        }
+        "nick" => {
+            if arg.is_empty() {
+                app.push_message(ChatLine::system("usage: /nick <new name>"));
+                return Ok(true);
+            }
+            let payload = RenamePayload {
+                new_username: arg.to_string(),
+            };
+            send_packet(client, MessageType::Rename, payload).await?;
+        }
+        "recent" => {
+            let hours = match arg {
+                "" => 0,
+                _ => match arg.parse::<u32>() {
+                    Ok(n) => n,
+                    Err(_) => {
+                        app.push_message(ChatLine::system("usage: /recent [hours]"));
+                        return Ok(true);
+                    }
+                },
+            };
+            send_packet(client, MessageType::RecentUsers, RecentUsersPayload { hours }).await?;
+        }
+        "directory" => {
+            let page = match arg {
+                "" => 1,
+                _ => match arg.parse::<usize>() {
+                    Ok(n) if n > 0 => n,
+                    _ => {
+                        app.push_message(ChatLine::system("usage: /directory [page]"));
+                        return Ok(true);
+                    }
+                },
+            };
+            let payload = DirectoryPayload {
+                limit: DIRECTORY_PAGE,
+                offset: (page - 1) * DIRECTORY_PAGE,
+            };
+            send_packet(client, MessageType::Directory, payload).await?;
+        }
+        "msg" => {
+            let (to, text) = arg.split_once(' ').unwrap_or((arg, ""));
+            if to.is_empty() || text.trim().is_empty() {
+                app.push_message(ChatLine::system("usage: /msg <user> <message>"));
+                return Ok(true);
+            }
+            let payload = DirectPayload {
+                to: to.to_string(),
+                content: emoji::expand(text.trim()),
+            };
+            app.away = false;
+            send_packet(client, MessageType::Direct, payload).await?;
+        }
+        "code" | "md" => {
+            if arg.is_empty() {
+                app.push_message(ChatLine::system(format!("usage: /{} <message>", name)));
+                return Ok(true);
+            }
+            let format = match name {
+                "code" => MessageFormat::Code,
+                _ => MessageFormat::Markdown,
+            };
+            let payload = ChatPayload {
+                content: arg.to_string(),
+                format: Some(format),
+                room: app.tab().room.clone(),
+                ttl_secs: None,
+            };
+            send_chat(app, client, payload).await?;
+        }
+        "ttl" => {
+            let (secs, text) = arg.split_once(' ').unwrap_or((arg, ""));
+            let ttl = match secs.parse::<u64>() {
+                Ok(ttl) if ttl > 0 && !text.trim().is_empty() => ttl,
+                _ => {
+                    app.push_message(ChatLine::system("usage: /ttl <seconds> <message>"));
+                    return Ok(true);
+                }
+            };
+            let payload = ChatPayload {
+                content: text.trim().to_string(),
+                format: None,
+                room: app.tab().room.clone(),
+                ttl_secs: Some(ttl),
+            };
+            send_chat(app, client, payload).await?;
+        }
+        "join" => {
+            if arg.is_empty() {
+                app.push_message(ChatLine::system("usage: /join <room>"));
+                return Ok(true);
+            }
+            let payload = RoomPayload {
+                room: arg.to_string(),
+            };
+            send_packet(client, MessageType::Join, payload).await?;
+        }
+        "leave" => {
+            let room = match (arg, &app.tab().room) {
+                ("", Some(room)) => room.clone(),
+                ("", None) => {
+                    let usage = "usage: /leave [room] (the lobby can't be left)";
+                    app.push_message(ChatLine::system(usage));
+                    return Ok(true);
+                }
+                (arg, _) => arg.to_string(),
+            };
+            send_packet(client, MessageType::Leave, RoomPayload { room }).await?;
+        }
+        "pin" => {
+            // Counted back from the newest message in the tab.
+            let n = match arg {
+                "" => 1,
+                _ => match arg.parse::<usize>() {
+                    Ok(n) if n > 0 => n,
+                    _ => {
+                        app.push_message(ChatLine::system("usage: /pin [n] (n-th newest)"));
+                        return Ok(true);
+                    }
+                },
+            };
+            let id = app
+                .tab()
+                .messages
+                .iter()
+                .rev()
+                .filter(|l| !l.is_system)
+                .filter_map(|l| l.id.clone())
+                .nth(n - 1);
+            match id {
+                Some(message_id) => {
+                    send_packet(client, MessageType::Pin, PinPayload { message_id }).await?;
+                }
+                None => app.push_message(ChatLine::system("no such message to pin")),
+            }
+        }
+        "topic" => {
+            let room = match app.tab().room.clone() {
+                Some(room) => room,
+                None => {
+                    app.push_message(ChatLine::system("the lobby has no topic"));
+                    return Ok(true);
+                }
+            };
+            if arg.is_empty() {
+                let line = match &app.tab().topic {
+                    Some(topic) => format!("topic of #{}: {}", room, topic),
+                    None => format!("#{} has no topic (/topic <text> sets one)", room),
+                };
+                app.push_message(ChatLine::system(line));
+                return Ok(true);
+            }
+            let topic = if arg == "-" { String::new() } else { arg.to_string() };
+            send_packet(client, MessageType::Topic, TopicPayload { room, topic }).await?;
+        }
+        "away" => {
+            app.away = !arg.is_empty();
+            let payload = AwayPayload {
+                message: arg.to_string(),
+            };
+            send_packet(client, MessageType::Away, payload).await?;
+        }
+        "notify" => {
+            if arg.is_empty() {
+                app.push_message(ChatLine::system(format!(
+                    "notifications: {} (/notify all|mentions|none changes it)",
+                    app.prefs.notify
+                )));
+                return Ok(true);
+            }
+            match arg.parse::<NotifyLevel>() {
+                Ok(notify) => {
+                    let payload = UpdatePrefsPayload { notify: Some(notify) };
+                    send_packet(client, MessageType::UpdatePrefs, payload).await?;
+                }
+                Err(e) => app.push_message(ChatLine::system(e)),
+            }
+        }
+        "unpin" => match app.tab_pins().first() {
+            Some(m) => {
+                let payload = PinPayload {
+                    message_id: m.id.clone(),
+                };
+                send_packet(client, MessageType::Unpin, payload).await?;
+            }
+            None => app.push_message(ChatLine::system("nothing is pinned here")),
+        },
+        "pinned" => {
+            let lines: Vec<String> = app
+                .tab_pins()
+                .iter()
+                .map(|m| format!("📌 {}: {}", m.username, m.content))
+                .collect();
+            if lines.is_empty() {
+                app.push_message(ChatLine::system("nothing is pinned here"));
+            }
+            for line in lines {
+                app.push_message(ChatLine::system(line));
+            }
+        }
+        "whois" => {
+            if arg.is_empty() {
+                app.push_message(ChatLine::system("usage: /whois <user>"));
+                return Ok(true);
+            }
+            let payload = ProfilePayload {
+                username: arg.to_string(),
+            };
+            send_packet(client, MessageType::Profile, payload).await?;
+        }
+        "profile" => {
+            let (field, value) = arg.split_once(' ').unwrap_or((arg, ""));
+            let value = Some(value.trim().to_string());
+            let payload = match field {
+                "name" => UpdateProfilePayload {
+                    display_name: value,
+                    ..Default::default()
+                },
+                "status" => UpdateProfilePayload {
+                    status_text: value,
+                    ..Default::default()
+                },
+                "avatar" => UpdateProfilePayload {
+                    avatar: value,
+                    ..Default::default()
+                },
+                _ => {
+                    app.push_message(ChatLine::system(
+                        "usage: /profile <name|status|avatar> [value] (no value clears it)",
+                    ));
+                    return Ok(true);
+                }
+            };
+            send_packet(client, MessageType::UpdateProfile, payload).await?;
+        }
+        "block" | "unblock" => {
+            if arg.is_empty() {
+                app.push_message(ChatLine::system(format!("usage: /{} <user>", name)));
+                return Ok(true);
+            }
+            let msg_type = if name == "block" {
+                MessageType::Block
+            } else {
+                MessageType::Unblock
+            };
+            let payload = BlockPayload {
+                username: arg.to_string(),
+            };
+            send_packet(client, msg_type, payload).await?;
+        }
+        "slowmode" => {
+            let seconds = match arg {
+                "off" => 0,
+                _ => match arg.parse::<u64>() {
+                    Ok(n) => n,
+                    Err(_) => {
+                        app.push_message(ChatLine::system("usage: /slowmode <seconds|off>"));
+                        return Ok(true);
+                    }
+                },
+            };
+            send_packet(client, MessageType::Admin, AdminPayload::SlowMode { seconds }).await?;
+        }
+        "readonly" => {
+            let enabled = match arg {
+                "on" => true,
+                "off" => false,
+                _ => {
+                    app.push_message(ChatLine::system("usage: /readonly <on|off>"));
+                    return Ok(true);
+                }
+            };
+            send_packet(client, MessageType::Admin, AdminPayload::ReadOnly { enabled }).await?;
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Cursor movement and deletion keys shared by every text field.
+fn edit_input(input: &mut Input, key: KeyEvent) {
+    let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+    match key.code {
+        KeyCode::Char('w') if ctrl => input.delete_word_back(),
+        KeyCode::Char('u') if ctrl => input.delete_to_start(),
+        KeyCode::Char('k') if ctrl => input.delete_to_end(),
+        KeyCode::Backspace => input.delete_back(),
+        KeyCode::Delete => input.delete_forward(),
+        KeyCode::Left => input.move_left(),
+        KeyCode::Right => input.move_right(),
+        KeyCode::Home => input.move_home(),
+        KeyCode::End => input.move_end(),
+        _ => {}
+    }
+}
+
+fn active_search_field(app: &mut App) -> &mut Input {
+    match app.search_field {
+        0 => &mut app.search_query,
+        1 => &mut app.search_user,
+        2 => &mut app.search_from,
+        _ => &mut app.search_to,
+    }
+}
+
+// ─── Network message handling ─────────────────────────────────────────────────
+
+async fn handle_net(
+    app: &mut App,
+    msg: NetMsg,
+    client: &Client,
+) -> Result<()> {
+    match msg {
+        NetMsg::Disconnected => {
+            app.push_message(ChatLine::system("Disconnected from server."));
+        }
+        NetMsg::Packet(pkt) => match pkt.msg_type {
+            MessageType::Broadcast => {
+                if let Ok(p) = serde_json::from_value::<BroadcastPayload>(pkt.payload) {
+                    let mention = app.mentions_me(&p.content, &p.entities);
+                    app.alert(&p.user_id, mention);
+                    let room = p.room.clone();
+                    app.push_to_room(room.as_deref(), ChatLine {
+                        id: Some(p.id).filter(|id| !id.is_empty()),
+                        user_id: p.user_id,
+                        username: p.username,
+                        content: p.content,
+                        timestamp: Some(p.timestamp),
+                        is_system: false,
+                        direct: None,
+                        entities: p.entities,
+                        format: p.format.unwrap_or_default(),
+                        expires_at: p.expires_at,
+                    });
+                }
+            }
+            MessageType::Direct => {
+                if let Ok(dm) = serde_json::from_value::<DirectMessage>(pkt.payload) {
+                    app.alert(&dm.user_id, true);
+                    app.push_message(ChatLine::from_direct(dm, false));
+                }
+            }
+            MessageType::Deleted => {
+                if let Ok(p) = serde_json::from_value::<DeletedPayload>(pkt.payload) {
+                    app.remove_messages(&p.message_ids);
+                }
+            }
+            MessageType::Receipt => {
+                if let Ok(r) = serde_json::from_value::<ReceiptPayload>(pkt.payload) {
+                    app.apply_receipt(r);
+                }
+            }
+            MessageType::System => {
+                if let Ok(p) = serde_json::from_value::<SystemPayload>(pkt.payload) {
+                    if let Some(presence) = p.presence {
+                        app.apply_presence(presence);
+                    }
+                    if let Some(pin) = p.pin {
+                        app.apply_pin(pin);
+                    }
+                    match p.topic {
+                        // Topic notices only go to the room's members.
+                        Some(topic) => {
+                            let room = topic.room.clone();
+                            app.apply_topic(topic);
+                            app.push_to_room(Some(&room), ChatLine::system(p.message));
+                        }
+                        None => app.push_message(ChatLine::system(p.message)),
+                    }
+                }
+            }
+            MessageType::Response => {
+                if let Ok(p) = serde_json::from_value::<ResponsePayload>(pkt.payload) {
+                    if app.screen == Screen::Login {
+                        if p.success {
+                            // Switch to chat, request history
+                            app.screen = Screen::Chat;
+                            app.login_error.clear();
+                            let login = p.data.and_then(|d| {
+                                serde_json::from_value::<LoginResult>(d).ok()
+                            });
+                            app.prefs = login.as_ref().map(|l| l.prefs.clone()).unwrap_or_default();
+                            app.me = login.map(|l| l.user);
+                            match app.cursor.clone() {
+                                Some(since_id) => {
+                                    let payload = SyncPayload {
+                                        since_id,
+                                        include_system: true,
+                                    };
+                                    send_packet(client, MessageType::Sync, payload).await?;
+                                }
+                                None => {
+                                    let payload = HistoryPayload {
+                                        limit: 50,
+                                        include_system: true,
+                                    };
+                                    send_packet(client, MessageType::History, payload).await?;
+                                }
+                            }
+                            send_packet(client, MessageType::Users, serde_json::json!({}))
+                                .await?;
+                            send_packet(client, MessageType::Pinned, serde_json::json!({}))
+                                .await?;
+                        } else {
+                            app.login_error = p.message;
+                        }
+                    } else if app.screen == Screen::Search {
+                        // Parse search results
+                        app.search_results.clear();
+                        app.search_scroll = 0;
+                        app.search_done = true;
+                        app.search_status = None;
+                        if let Some(data) = p.data {
+                            if let Ok(result) = serde_json::from_value::<SearchResult>(data) {
+                                if result.total > result.messages.len() {
+                                    app.search_status = Some(format!(
+                                        "showing the newest {} of {} matches",
+                                        result.messages.len(),
+                                        result.total
+                                    ));
+                                }
+                                // Newest first on the wire; the list reads top to bottom.
+                                app.search_results.extend(
+                                    result.messages.into_iter().rev().map(ChatLine::from_stored),
+                                );
+                            }
+                        }
+                    } else if !p.success {
+                        app.push_message(ChatLine::system(p.message));
+                    } else {
+                        // History, sync or users response while in chat
+                        if let Some(data) = p.data {
+                            if let Ok(join) = serde_json::from_value::<JoinResult>(data.clone()) {
+                                app.open_room(join);
+                            } else if let Ok(left) =
+                                serde_json::from_value::<RoomPayload>(data.clone())
+                            {
+                                app.close_room(&left.room);
+                                app.push_message(ChatLine::system(p.message));
+                            } else if let Ok(list) =
+                                serde_json::from_value::<PinnedList>(data.clone())
+                            {
+                                app.pinned = list.pinned;
+                            } else if let Ok(stats) =
+                                serde_json::from_value::<ServerStats>(data.clone())
+                            {
+                                app.stats = Some(stats);
+                            } else if let Ok(profile) =
+                                serde_json::from_value::<Profile>(data.clone())
+                            {
+                                app.push_message(ChatLine::system(format_profile(
+                                    &profile,
+                                    &app.time,
+                                )));
+                            } else if let Ok(recent) =
+                                serde_json::from_value::<RecentUsersResult>(data.clone())
+                            {
+                                let entries: Vec<String> = recent
+                                    .users
+                                    .iter()
+                                    .map(|u| {
+                                        let seen = if app.online.contains_key(&u.user.user_id) {
+                                            "online".to_string()
+                                        } else {
+                                            app.time.time(u.last_seen)
+                                        };
+                                        format!("{} ({})", user_label(&u.user), seen)
+                                    })
+                                    .collect();
+                                app.push_message(ChatLine::system(format!(
+                                    "{}: {}",
+                                    p.message,
+                                    entries.join(", ")
+                                )));
+                            } else if let Ok(page) =
+                                serde_json::from_value::<DirectoryResult>(data.clone())
+                            {
+                                let entries: Vec<String> = page
+                                    .users
+                                    .iter()
+                                    .map(|u| {
+                                        let status = if u.online { "online" } else { "offline" };
+                                        let joined = app.time.date(u.created_at);
+                                        let name = user_label(&u.user);
+                                        format!("{} ({}, joined {})", name, status, joined)
+                                    })
+                                    .collect();
+                                app.push_message(ChatLine::system(format!(
+                                    "{}: {}",
+                                    p.message,
+                                    entries.join(", ")
+                                )));
+                            } else if serde_json::from_value::<BlockList>(data.clone()).is_ok() {
+                                app.push_message(ChatLine::system(p.message));
+                            } else if let Ok(dm) =
+                                serde_json::from_value::<DirectMessage>(data.clone())
+                            {
+                                app.push_message(ChatLine::from_direct(dm, true));
+                                // Queued for an offline recipient: say so.
+                                if p.message.contains("offline") {
+                                    app.push_message(ChatLine::system(p.message));
+                                }
+                            } else if let Ok(sync) =
+                                serde_json::from_value::<SyncResult>(data.clone())
+                            {
+                                if !sync.complete {
+                                    app.push_message(ChatLine::system(p.message));
+                                }
+                                app.prepend_history(sync.messages);
+                            } else if let Ok(msgs) =
+                                serde_json::from_value::<Vec<StoredMessage>>(data.clone())
+                            {
+                                if p.truncated {
+                                    app.push_message(ChatLine::system(p.message));
+                                }
+                                app.prepend_history(msgs);
+                            } else if let Ok(users) =
+                                serde_json::from_value::<Vec<UserInfo>>(data.clone())
+                            {
+                                if app.show_users {
+                                    app.show_users = false;
+                                    let names: Vec<&str> =
+                                        users.iter().map(|u| u.username.as_str()).collect();
+                                    app.push_message(ChatLine::system(format!(
+                                        "{} online: {}",
+                                        names.len(),
+                                        names.join(", ")
+                                    )));
+                                }
+                                app.set_online(users);
+                            } else if let Ok(prefs) =
+                                serde_json::from_value::<Prefs>(data.clone())
+                            {
+                                app.prefs = prefs;
+                                app.push_message(ChatLine::system(p.message));
+                            } else if let Ok(me) = serde_json::from_value::<UserInfo>(data) {
+                                // Rename confirmation
+                                app.me = Some(me);
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {}
+        },
+    }
+    Ok(())
+}
+
+// ─── Drawing ─────────────────────────────────────────────────────────────────
+
+fn draw(f: &mut Frame, app: &App, theme: &Theme) {
+    match app.screen {
+        Screen::Login => draw_login(f, app, theme),
+        Screen::Chat => draw_chat(f, app, theme),
+        Screen::Search => {
+            draw_chat(f, app, theme);
+            draw_search_overlay(f, app, theme);
+        }
+    }
+    if let Some(stats) = &app.stats {
+        draw_stats_popup(f, stats, theme);
+    }
+}
+
+fn draw_stats_popup(f: &mut Frame, stats: &ServerStats, theme: &Theme) {
+    let area = f.area();
+    let width = 34.min(area.width);
+    let height = 8.min(area.height);
+    let popup = Rect::new(
+        area.x + (area.width - width) / 2,
+        area.y + (area.height - height) / 2,
+        width,
+        height,
+    );
+    f.render_widget(Clear, popup);
+
+    let secs = stats.uptime_secs;
+    let uptime = format!("{}d {:02}h {:02}m", secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    let rows = [
+        ("Messages", stats.messages.to_string()),
+        ("Registered users", stats.users.to_string()),
+        ("Online now", stats.online.to_string()),
+        ("Uptime", uptime),
+    ];
+    let lines: Vec<Line> = rows
+        .into_iter()
+        .map(|(label, value)| {
+            Line::from(vec![
+                Span::styled(format!(" {:<18}", label), Style::default().fg(theme.hint)),
+                Span::styled(value, Style::default().fg(theme.text)),
+            ])
+        })
+        .collect();
+    let widget = Paragraph::new(lines).block(
+        Block::default()
+            .title(" Server stats ")
+            .title_bottom(" any key to close ")
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(theme.border_focused)),
+    );
+    f.render_widget(widget, popup);
+}
+
+fn draw_login(f: &mut Frame, app: &App, theme: &Theme) {
+    let area = f.area();
+
+    let block = Block::default()
+        .title(" RustChat ")
+        .title_alignment(Alignment::Center)
+        .borders(Borders::ALL)
+        .border_style(Style::default().fg(theme.border));
+
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+
+    let mode = if app.is_register { "Register" } else { "Login" };
+    let hint = if app.is_register {
+        "Ctrl+R to switch to Login"
+    } else {
+        "Ctrl+R to switch to Register"
+    };
+
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(2), // title
+            Constraint::Length(3), // username
+            Constraint::Length(3), // password
+            Constraint::Length(1), // hint
+            Constraint::Length(1), // error
+            Constraint::Min(0),
+        ])
+        .split(inner);
+
+    let title = Paragraph::new(format!("── {} ──", mode))
+        .alignment(Alignment::Center)
+        .style(Style::default().fg(theme.title).add_modifier(Modifier::BOLD));
+    f.render_widget(title, chunks[0]);
+
+    let u_style = if app.login_field == 0 {
+        Style::default().fg(theme.border_focused)
+    } else {
+        Style::default().fg(theme.text)
+    };
+    let username_widget = Paragraph::new(app.login_username.as_str())
+        .block(
+            Block::default()
+                .title(" Username ")
+                .borders(Borders::ALL)
+                .border_style(u_style),
+        )
+        .style(Style::default().fg(theme.text));
+    f.render_widget(username_widget, chunks[1]);
+
+    let p_style = if app.login_field == 1 {
+        Style::default().fg(theme.border_focused)
+    } else {
+        Style::default().fg(theme.text)
+    };
+    let masked: String = "*".repeat(app.login_password.value.chars().count());
+    let password_widget = Paragraph::new(masked)
+        .block(
+            Block::default()
+                .title(" Password ")
+                .borders(Borders::ALL)
+                .border_style(p_style),
+        )
+        .style(Style::default().fg(theme.text));
+    f.render_widget(password_widget, chunks[2]);
+
+    let hint_widget = Paragraph::new(format!(
+        "{} | Ctrl+G to look around as a guest | Tab to switch fields | Enter to submit",
+        hint
+    ))
+        .alignment(Alignment::Center)
+        .style(Style::default().fg(theme.hint));
+    f.render_widget(hint_widget, chunks[3]);
+
+    if !app.login_error.is_empty() {
+        let err = Paragraph::new(app.login_error.as_str())
+            .alignment(Alignment::Center)
+            .style(Style::default().fg(theme.error));
+        f.render_widget(err, chunks[4]);
+    }
+
+    // Place cursor
+    if app.login_field == 0 {
+        f.set_cursor_position((
+            chunks[1].x + 1 + app.login_username.cursor_width(),
+            chunks[1].y + 1,
+        ));
+    } else {
+        f.set_cursor_position((
+            chunks[2].x + 1 + app.login_password.cursor_chars() as u16,
+            chunks[2].y + 1,
+        ));
+    }
+}
+
+fn draw_chat(f: &mut Frame, app: &App, theme: &Theme) {
+    let area = f.area();
+
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(1),  // header
+            Constraint::Length(app.pin_bar_height()), // pinned
+            Constraint::Min(3),     // messages
+            Constraint::Length(input_height(&app.chat_input)), // input
+        ])
+        .split(area);
+
+    // Header
+    let me = app.me.as_ref().map(user_label).unwrap_or_else(|| "?".to_string());
+    let flags = match (app.dnd, app.away) {
+        (true, true) => " (dnd, away)",
+        (true, false) => " (dnd)",
+        (false, true) => " (away)",
+        (false, false) => "",
+    };
+    let unread = if app.tab().unread > 0 {
+        format!("  │  {} new ↓", app.tab().unread)
+    } else {
+        String::new()
+    };
+    let topic = match &app.tab().topic {
+        Some(topic) => format!("  │  {}: {}", app.tab().label(), topic),
+        None => String::new(),
+    };
+    let header = Paragraph::new(format!(
+        " RustChat  │  {}{}  │  {} online{}{}  │  Ctrl+F search  │  PgUp/PgDn scroll  │  Ctrl+Q quit ",
+        me,
+        flags,
+        app.online.len(),
+        unread,
+        topic
+    ))
+    .style(
+        Style::default()
+            .bg(theme.header_bg)
+            .fg(theme.header_fg)
+            .add_modifier(Modifier::BOLD),
+    );
+    f.render_widget(header, chunks[0]);
+
+    if let Some(bar) = pin_bar(app, theme) {
+        f.render_widget(Paragraph::new(bar), chunks[1]);
+    }
+
+    // Messages viewport
+    let mut msg_block = Block::default()
+        .borders(Borders::LEFT | Borders::RIGHT | Borders::TOP)
+        .border_style(Style::default().fg(theme.border_muted));
+    if app.tabs.len() > 1 {
+        msg_block = msg_block.title(tab_bar(app, theme));
+    }
+    let msg_inner = msg_block.inner(chunks[2]);
+    f.render_widget(msg_block, chunks[2]);
+
+    // To pick out mentions of us.
+    let me = app.me.as_ref().map(|u| normalize_username(&u.username));
+    let rows = chat_rows(&app.tab().messages, &app.time);
+    let end = app.tab().anchor.map_or(rows.len(), |a| (a + 1).min(rows.len()));
+    // Walk back from the newest visible row until the viewport is full;
+    // multi-line messages take one terminal row per line.
+    let mut start = end;
+    let mut used = 0;
+    while start > 0 {
+        let h = row_height(&rows[start - 1]);
+        if used + h > msg_inner.height as usize && used > 0 {
+            break;
+        }
+        used += h;
+        start -= 1;
+    }
+    let visible = &rows[start..end];
+
+    let items: Vec<ListItem> = visible
+        .iter()
+        .map(|row| {
+            let line = match row {
+                ChatRow::DateSeparator(day) => {
+                    return ListItem::new(Line::from(Span::styled(
+                        date_separator_label(*day, &app.time),
+                        Style::default().fg(theme.hint),
+                    )));
+                }
+                ChatRow::Message(line) => line,
+            };
+            // Not while the search overlay covers the list.
+            if let Some(info) = line.direct.as_ref().filter(|d| !d.outgoing) {
+                if app.screen == Screen::Chat {
+                    app.directs_in_view.borrow_mut().push(info.message_id.clone());
+                }
+            }
+            if line.is_system {
+                let style = Style::default()
+                    .fg(theme.system)
+                    .add_modifier(Modifier::ITALIC);
+                let mut parts = line.content.split('\n');
+                let first = parts.next().unwrap_or_default();
+                let mut lines = vec![Line::from(Span::styled(format!("  ◆ {}", first), style))];
+                lines.extend(parts.map(|l| Line::from(Span::styled(format!("    {}", l), style))));
+                ListItem::new(lines)
+            } else {
+                let stamp = format!("[{}] ", format_timestamp(line.timestamp, &app.time));
+                let name = match &line.direct {
+                    Some(d) if d.outgoing => format!("{} → {}: ", app.sender_label(line), d.peer),
+                    Some(_) => format!("{} → you: ", app.sender_label(line)),
+                    None => format!("{}: ", app.sender_label(line)),
+                };
+                let indent = " ".repeat(stamp.width() + name.width());
+                let mut prefix = Some(vec![
+                    Span::styled(stamp, Style::default().fg(theme.timestamp)),
+                    Span::styled(
+                        name,
+                        Style::default()
+                            .fg(theme.user_color(&line.username))
+                            .add_modifier(Modifier::BOLD),
+                    ),
+                ]);
+                let mut lines = Vec::new();
+                let mut offset = 0;
+                let mut in_fence = false;
+                for part in line.content.split('\n') {
+                    // Continuation lines line up under the first line's text.
+                    let mut spans =
+                        prefix.take().unwrap_or_else(|| vec![Span::raw(indent.clone())]);
+                    let me = me.as_deref();
+                    spans.extend(content_spans(part, offset, line, &mut in_fence, me, theme));
+                    offset += part.len() + 1;
+                    lines.push(Line::from(spans));
+                }
+                let receipt = line.direct.as_ref().and_then(|d| d.receipt);
+                if let (Some(kind), Some(last)) = (receipt, lines.last_mut()) {
+                    let mark = match kind {
+                        ReceiptKind::Delivered => " ✓",
+                        ReceiptKind::Read => " ✓✓",
+                    };
+                    last.spans.push(Span::styled(mark, Style::default().fg(theme.hint)));
+                }
+                if let (Some(at), Some(last)) = (line.expires_at, lines.last_mut()) {
+                    let note = format!("  (expires in {})", until(at, app.time.now()));
+                    last.spans.push(Span::styled(note, Style::default().fg(theme.hint)));
+                }
+                ListItem::new(lines)
+            }
+        })
+        .collect();
+
+    let list = List::new(items);
+    f.render_widget(list, msg_inner);
+
+    // Scrollbar over the right border, below the top border
+    let track = Rect {
+        x: chunks[1].right().saturating_sub(1),
+        y: msg_inner.y,
+        width: 1,
+        height: msg_inner.height,
+    };
+    draw_scrollbar(f, track, rows.len(), visible.len(), start, theme);
+
+    // Input box
+    let input_block = Block::default()
+        .title(" Message (Enter to send, Alt+Enter for a new line) ")
+        .borders(Borders::ALL)
+        .border_style(Style::default().fg(theme.border));
+    let input_inner = input_block.inner(chunks[2]);
+    f.render_widget(input_block, chunks[2]);
+
+    // Scroll so the cursor's line stays inside the box.
+    let cursor_line = app.chat_input.cursor_line() as u16;
+    let input_scroll = (cursor_line + 1).saturating_sub(input_inner.height);
+    let input_widget = Paragraph::new(app.chat_input.as_str())
+        .style(Style::default().fg(theme.text))
+        .scroll((input_scroll, 0));
+    f.render_widget(input_widget, input_inner);
+
+    // Cursor in input
+    if app.screen == Screen::Chat {
+        f.set_cursor_position((
+            input_inner.x + app.chat_input.cursor_width(),
+            input_inner.y + cursor_line - input_scroll,
+        ));
+    }
+}
+
+/// Height of the chat input box: one row per line up to
+/// `MAX_INPUT_LINES`, plus borders.
+fn input_height(input: &Input) -> u16 {
+    input.line_count().min(MAX_INPUT_LINES) as u16 + 2
+}
+
+fn row_height(row: &ChatRow) -> usize {
+    match row {
+        ChatRow::DateSeparator(_) => 1,
+        ChatRow::Message(line) => line.content.split('\n').count(),
+    }
+}
+
+/// Centered overlay: 70% wide, 80% tall.
+fn search_popup(area: Rect) -> Rect {
+    centered_rect(70, 80, area)
+}
+
+/// The four search fields followed by the results area, inside the popup's
+/// border. Shared with `run_app` so scrolling knows the results height.
+fn search_overlay_chunks(area: Rect) -> std::rc::Rc<[Rect]> {
+    let inner = Block::default().borders(Borders::ALL).inner(search_popup(area));
+    Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(3), // query
+            Constraint::Length(3), // username
+            Constraint::Length(3), // from
+            Constraint::Length(3), // to
+            Constraint::Min(0),    // results
+        ])
+        .split(inner)
+}
+
+/// The tab names for the top border, the shown one highlighted and the
+/// others with their unread counts.
+/// The shown tab's latest pin on one line, with a count of any others.
+fn pin_bar<'a>(app: &App, theme: &Theme) -> Option<Line<'a>> {
+    let pins = app.tab_pins();
+    let latest = pins.first()?;
+    let content = latest.content.lines().next().unwrap_or_default().to_string();
+    let mut spans = vec![
+        Span::styled(" 📌 ", Style::default().fg(theme.highlight)),
+        Span::styled(
+            format!("{}: ", latest.username),
+            Style::default().fg(theme.title).add_modifier(Modifier::BOLD),
+        ),
+        Span::styled(content, Style::default().fg(theme.text)),
+    ];
+    if pins.len() > 1 {
+        let more = format!("  (+{} more, /pinned)", pins.len() - 1);
+        spans.push(Span::styled(more, Style::default().fg(theme.hint)));
+    }
+    Some(Line::from(spans))
+}
+
+fn tab_bar<'a>(app: &App, theme: &Theme) -> Line<'a> {
+    let mut spans = vec![Span::raw(" ")];
+    for (i, tab) in app.tabs.iter().enumerate() {
+        if i > 0 {
+            spans.push(Span::styled(" │ ", Style::default().fg(theme.border_muted)));
+        }
+        if i == app.active {
+            let style = Style::default().fg(theme.title).add_modifier(Modifier::BOLD);
+            spans.push(Span::styled(tab.label(), style));
+        } else {
+            spans.push(Span::styled(tab.label(), Style::default().fg(theme.text)));
+            if tab.unread > 0 {
+                let badge = format!(" ({})", tab.unread);
+                spans.push(Span::styled(badge, Style::default().fg(theme.highlight)));
+            }
+        }
+    }
+    spans.push(Span::raw(" "));
+    Line::from(spans)
+}
+
+/// Spans for one line of `line`'s content, which starts `offset` bytes in,
+/// styled for its format. `in_fence` carries whether a markdown code block
+/// is open from one line to the next; fence lines stay visible (dimmed) so
+/// every message keeps one row per line.
+fn content_spans<'a>(
+    text: &'a str,
+    offset: usize,
+    line: &ChatLine,
+    in_fence: &mut bool,
+    me: Option<&str>,
+    theme: &Theme,
+) -> Vec<Span<'a>> {
+    let code = Style::default().fg(theme.code);
+    match line.format {
+        MessageFormat::Plain => entity_spans(text, offset, &line.entities, me, theme),
+        MessageFormat::Code => vec![Span::styled(text, code)],
+        MessageFormat::Markdown if text.trim_start().starts_with("```") => {
+            *in_fence = !*in_fence;
+            vec![Span::styled(text, Style::default().fg(theme.hint))]
+        }
+        MessageFormat::Markdown if *in_fence => vec![Span::styled(text, code)],
+        MessageFormat::Markdown => {
+            let mut spans = Vec::new();
+            for run in markdown_runs(text) {
+                match run {
+                    MarkdownRun::Code(range) => spans.push(Span::styled(&text[range], code)),
+                    MarkdownRun::Text(range, modifier) => {
+                        let start = offset + range.start;
+                        let text = &text[range];
+                        let style = Style::default().add_modifier(modifier);
+                        spans.extend(
+                            entity_spans(text, start, &line.entities, me, theme)
+                                .into_iter()
+                                .map(|span| span.patch_style(style)),
+                        );
+                    }
+                }
+            }
+            spans
+        }
+    }
+}
+
+/// A visible piece of one line of markdown; the markers around it are
+/// dropped.
+#[derive(Debug, PartialEq)]
+enum MarkdownRun {
+    Text(Range<usize>, Modifier),
+    Code(Range<usize>),
+}
+
+/// Splits a line into runs for `**bold**`, `*italic*` / `_italic_` and
+/// `` `code` ``. Markers only count when closed on the same line around
+/// non-blank text, and `*`/`_` not inside a word, so `snake_case_name` and
+/// `2 * 3 * 4` stay plain. Emphasis doesn't nest.
+fn markdown_runs(line: &str) -> Vec<MarkdownRun> {
+    let mut runs = Vec::new();
+    let mut plain = 0;
+    let mut i = 0;
+    while i < line.len() {
+        let rest = &line[i..];
+        let after_word = line[..i].chars().next_back().is_some_and(char::is_alphanumeric);
+        let found = if let Some(code) = rest.strip_prefix('`') {
+            code.find('`').filter(|&len| len > 0).map(|len| (1, len, None))
+        } else if let Some(bold) = rest.strip_prefix("**") {
+            emphasis_len(bold, "**").map(|len| (2, len, Some(Modifier::BOLD)))
+        } else if !after_word && (rest.starts_with('*') || rest.starts_with('_')) {
+            emphasis_len(&rest[1..], &rest[..1]).map(|len| (1, len, Some(Modifier::ITALIC)))
+        } else {
+            None
+        };
+        match found {
+            Some((marker, len, modifier)) => {
+                if plain < i {
+                    runs.push(MarkdownRun::Text(plain..i, Modifier::empty()));
+                }
+                let inner = i + marker..i + marker + len;
+                runs.push(match modifier {
+                    Some(modifier) => MarkdownRun::Text(inner, modifier),
+                    None => MarkdownRun::Code(inner),
+                });
+                i += 2 * marker + len;
+                plain = i;
+            }
+            // An unclosed `**` is plain as a whole, not an italic marker.
+            None if rest.starts_with("**") => i += 2,
+            None => i += rest.chars().next().map_or(1, char::len_utf8),
+        }
+    }
+    if plain < line.len() {
+        runs.push(MarkdownRun::Text(plain..line.len(), Modifier::empty()));
+    }
+    runs
+}
+
+/// Length of the emphasized text before the closing `marker` in `s`, if it
+/// is closed: not empty, not padded with spaces and not followed by a word
+/// character.
+fn emphasis_len(s: &str, marker: &str) -> Option<usize> {
+    let len = s.find(marker)?;
+    let inner = &s[..len];
+    let next = s[len + marker.len()..].chars().next();
+    let ok = !inner.is_empty()
+        && inner.trim() == inner
+        && !next.is_some_and(char::is_alphanumeric);
+    ok.then_some(len)
+}
+
+/// Spans for one line of a message, which starts `offset` bytes into its
+/// content: links are underlined and mentions bold, highlighted when they
+/// name `me` (a normalized username).
+fn entity_spans<'a>(
+    text: &'a str,
+    offset: usize,
+    entities: &[Entity],
+    me: Option<&str>,
+    theme: &Theme,
+) -> Vec<Span<'a>> {
+    let mut spans = Vec::new();
+    let mut pos = 0;
+    for e in entities {
+        // Entities never cross a newline; skip any that would not slice
+        // cleanly (they come from the server).
+        let (start, end) = match (e.start.checked_sub(offset), e.end.checked_sub(offset)) {
+            (Some(start), Some(end)) if start >= pos && end <= text.len() => (start, end),
+            _ => continue,
+        };
+        let word = match text.get(start..end) {
+            Some(word) => word,
+            None => continue,
+        };
+        let style = match e.kind {
+            EntityKind::Url => Style::default()
+                .fg(theme.border)
+                .add_modifier(Modifier::UNDERLINED),
+            EntityKind::Mention if me.is_some_and(|me| normalize_username(&word[1..]) == me) => {
+                Style::default()
+                    .fg(Color::Black)
+                    .bg(theme.highlight)
+                    .add_modifier(Modifier::BOLD)
+            }
+            EntityKind::Mention => Style::default().add_modifier(Modifier::BOLD),
+        };
+        if start > pos {
+            spans.push(Span::raw(&text[pos..start]));
+        }
+        spans.push(Span::styled(word, style));
+        pos = end;
+    }
+    if pos < text.len() {
+        spans.push(Span::raw(&text[pos..]));
+    }
+    spans
+}
+
+/// Splits `text` into runs, flagging those covered by `ranges` (sorted,
+/// non-overlapping byte ranges on char boundaries).
+fn highlight_segments<'a>(text: &'a str, ranges: &[Range<usize>]) -> Vec<(&'a str, bool)> {
+    let mut segments = Vec::new();
+    let mut pos = 0;
+    for range in ranges {
+        if range.start > pos {
+            segments.push((&text[pos..range.start], false));
+        }
+        segments.push((&text[range.clone()], true));
+        pos = range.end;
+    }
+    if pos < text.len() {
+        segments.push((&text[pos..], false));
+    }
+    segments
+}
+
+fn draw_search_overlay(f: &mut Frame, app: &App, theme: &Theme) {
+    let area = f.area();
+    let popup = search_popup(area);
+
+    f.render_widget(Clear, popup);
+
+    let scope = if app.search_local { "loaded messages" } else { "server" };
+    let mut block = Block::default()
+        .title(format!(
+            " Search Messages in {}  (Esc to close | Tab to move | Enter to search | \
+             PgUp/PgDn scroll | Ctrl+L local/server | Ctrl+S export) ",
+            scope
+        ))
+        .borders(Borders::ALL)
+        .border_style(Style::default().fg(theme.border_focused));
+    if let Some(status) = &app.search_status {
+        block = block.title_bottom(Span::styled(
+            format!(" {} ", status),
+            Style::default().fg(theme.hint),
+        ));
+    }
+    f.render_widget(block, popup);
+
+    let chunks = search_overlay_chunks(area);
+
+    let fields = [
+        ("Content", &app.search_query, 0),
+        ("Username", &app.search_user, 1),
+        ("From (YYYY-MM-DD)", &app.search_from, 2),
+        ("To (YYYY-MM-DD)", &app.search_to, 3),
+    ];
+
+    for (label, input, idx) in &fields {
+        let focused = app.search_field == *idx;
+        let border_style = if focused {
+            Style::default().fg(theme.border_focused)
+        } else {
+            Style::default().fg(theme.text)
+        };
+        let widget = Paragraph::new(input.as_str())
+            .block(
+                Block::default()
+                    .title(format!(" {} ", label))
+                    .borders(Borders::ALL)
+                    .border_style(border_style),
+            )
+            .style(Style::default().fg(theme.text));
+        f.render_widget(widget, chunks[*idx]);
+    }
+
+    // Show cursor in active field
+    let active_input = match app.search_field {
+        0 => &app.search_query,
+        1 => &app.search_user,
+        2 => &app.search_from,
+        _ => &app.search_to,
+    };
+    f.set_cursor_position((
+        chunks[app.search_field].x + 1 + active_input.cursor_width(),
+        chunks[app.search_field].y + 1,
+    ));
+
+    // Results
+    let results_area = chunks[4];
+    let total = app.search_results.len();
+    let window = search_window(total, results_area.height as usize, app.search_scroll);
+    let start = window.start;
+    let visible = &app.search_results[window];
+
+    let match_style = Style::default()
+        .fg(Color::Black)
+        .bg(theme.highlight)
+        .add_modifier(Modifier::BOLD);
+    let items: Vec<ListItem> = visible
+        .iter()
+        .map(|line| {
+            let mut spans = vec![
+                Span::styled(
+                    format!("[{}] ", format_timestamp(line.timestamp, &app.time)),
+                    Style::default().fg(theme.timestamp),
+                ),
+                Span::styled(
+                    format!("{}: ", app.sender_label(line)),
+                    Style::default()
+                        .fg(theme.user_color(&line.username))
+                        .add_modifier(Modifier::BOLD),
+                ),
+            ];
+            let ranges = match &app.search_highlight {
+                Some(query) => query.match_ranges(&line.content),
+                None => Vec::new(),
+            };
+            spans.extend(highlight_segments(&line.content, &ranges).into_iter().map(
+                |(text, matched)| {
+                    if matched {
+                        Span::styled(text, match_style)
+                    } else {
+                        Span::raw(text)
+                    }
+                },
+            ));
+            ListItem::new(Line::from(spans))
+        })
+        .collect();
+
+    if !app.search_done {
+        let hint = Paragraph::new("Enter search criteria above and press Enter")
+            .alignment(Alignment::Center)
+            .style(Style::default().fg(theme.hint));
+        f.render_widget(hint, results_area);
+    } else if items.is_empty() {
+        let hint = Paragraph::new("No results found")
+            .alignment(Alignment::Center)
+            .style(Style::default().fg(theme.hint));
+        f.render_widget(hint, results_area);
+    } else {
+        let list = List::new(items);
+        f.render_widget(list, results_area);
+        let track = Rect {
+            x: popup.right().saturating_sub(1),
+            y: results_area.y,
+            width: 1,
+            height: results_area.height,
+        };
+        draw_scrollbar(f, track, total, visible.len(), start, theme);
+    }
+}
+
+/// How far the search results can scroll up: until the oldest of `total`
+/// fills the top of a results area `height` rows tall.
+fn max_search_scroll(total: usize, height: usize) -> usize {
+    total.saturating_sub(height)
+}
+
+/// The search results shown in `height` rows, scrolled `scroll` rows up
+/// from the newest of `total`.
+fn search_window(total: usize, height: usize, scroll: usize) -> Range<usize> {
+    let end = total.saturating_sub(scroll);
+    end.saturating_sub(height)..end
+}
+
+/// Scroll position for a list showing `visible` of `total` items from
+/// index `start`; `None` when everything fits.
+fn scrollbar_state(total: usize, visible: usize, start: usize) -> Option<ScrollbarState> {
+    if total <= visible {
+        return None;
+    }
+    // One position per possible first-visible item.
+    let positions = total - visible + 1;
+    Some(ScrollbarState::new(positions).viewport_content_length(visible).position(start))
+}
+
+fn draw_scrollbar(
+    f: &mut Frame,
+    track: Rect,
+    total: usize,
+    visible: usize,
+    start: usize,
+    theme: &Theme,
+) {
+    if let Some(mut state) = scrollbar_state(total, visible, start) {
+        let scrollbar = Scrollbar::new(ScrollbarOrientation::VerticalRight)
+            .begin_symbol(None)
+            .end_symbol(None)
+            .style(Style::default().fg(theme.border_muted));
+        f.render_stateful_widget(scrollbar, track, &mut state);
+    }
+}
+
+// ─── Helpers ─────────────────────────────────────────────────────────────────
+
+fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
+    let popup_layout = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Percentage((100 - percent_y) / 2),
+            Constraint::Percentage(percent_y),
+            Constraint::Percentage((100 - percent_y) / 2),
+        ])
+        .split(r);
+
+    Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage((100 - percent_x) / 2),
+            Constraint::Percentage(percent_x),
+            Constraint::Percentage((100 - percent_x) / 2),
+        ])
+        .split(popup_layout[1])[1]
+}
+
+fn user_label(u: &UserInfo) -> String {
+    let name = u.display_name.as_deref().unwrap_or(&u.username);
+    match &u.avatar {
+        Some(avatar) => format!("{} {}", avatar, name),
+        None => name.to_string(),
+    }
+}
+
+fn format_profile(p: &Profile, time: &TimeDisplay) -> String {
+    let seen = if p.online {
+        "online now".to_string()
+    } else {
+        match p.last_seen {
+            Some(ts) => format!("last seen {} {}", time.date(ts), time.time(ts)),
+            None => "not seen recently".to_string(),
+        }
+    };
+    let mut name = p.username.clone();
+    if let Some(display) = &p.display_name {
+        name = format!("{} ({})", display, name);
+    }
+    if let Some(avatar) = &p.avatar {
+        name = format!("{} {}", avatar, name);
+    }
+    let status = match &p.status_text {
+        Some(status) => format!(" \"{}\"", status),
+        None => String::new(),
+    };
+    format!(
+        "{}{}: joined {}, {} message(s), {}",
+        name,
+        status,
+        time.date(p.created_at),
+        p.message_count,
+        seen
+    )
+}
+
+fn format_timestamp(ts: Option<DateTime<Utc>>, time: &TimeDisplay) -> String {
+    ts.map(|t| time.time(t)).unwrap_or_default()
+}
+
+/// Time left until `at`, in its largest whole unit: `45s`, `12m`, `3h`, `2d`.
+fn until(at: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let secs = (at - now).num_seconds().max(0);
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+/// Sends a chat message and, with local echo, shows it straight away. The
+/// local copy has no message ID until history is reloaded. Emoji shortcodes
+/// are expanded first, except in code.
+async fn send_chat(app: &mut App, client: &Client, mut payload: ChatPayload) -> Result<()> {
+    if payload.format != Some(MessageFormat::Code) {
+        payload.content = emoji::expand(&payload.content);
+    }
+    if let Some(me) = app.me.as_ref().filter(|_| app.local_echo) {
+        let line = ChatLine {
+            id: None,
+            user_id: me.user_id.clone(),
+            username: me.username.clone(),
+            content: payload.content.clone(),
+            timestamp: Some(app.time.now()),
+            is_system: false,
+            direct: None,
+            entities: entities::extract(&payload.content),
+            format: payload.format.unwrap_or_default(),
+            expires_at: payload.ttl_secs.map(|ttl| app.time.now() + TimeDelta::seconds(ttl as i64)),
+        };
+        app.push_to_room(payload.room.as_deref(), line);
+    }
+    app.away = false;
+    send_packet(client, MessageType::Chat, payload).await
+}
+
+async fn send_packet(
+    client: &Client,
+    msg_type: MessageType,
+    payload: impl serde::Serialize,
+) -> Result<()> {
+    // A dead connection is reported through NetMsg::Disconnected instead.
+    client.send(msg_type, payload).await.ok();
+    Ok(())
+}
+
+fn parse_datetime(s: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    let s = s.trim();
+    if s.is_empty() {
+        return None;
+    }
+    // Try YYYY-MM-DD format, treating as midnight UTC
+    chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .ok()
+        .map(|d| {
+            d.and_hms_opt(0, 0, 0)
+                .unwrap()
+                .and_utc()
+        })
+        .or_else(|| chrono::DateTime::parse_from_rfc3339(s).ok().map(|dt| dt.with_timezone(&chrono::Utc)))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    const THEMES: [ThemeName; 3] = [ThemeName::Dark, ThemeName::Light, ThemeName::HighContrast];
+
+    #[test]
+    fn every_theme_sets_each_color() {
+        for name in THEMES {
+            let theme = Theme::from_name(name);
+            let colors = [
+                theme.header_fg,
+                theme.header_bg,
+                theme.title,
+                theme.text,
+                theme.hint,
+                theme.timestamp,
+                theme.system,
+                theme.error,
+                theme.border,
+                theme.border_focused,
+                theme.border_muted,
+                theme.highlight,
+                theme.code,
+            ];
+            assert!(!colors.contains(&Color::Reset), "{:?} leaves a color unset", name);
+            assert!(theme.user_palette.len() >= 4, "{:?} has too few user colors", name);
+            assert_ne!(theme.header_fg, theme.header_bg, "{:?} header is unreadable", name);
+        }
+    }
+
+    #[test]
+    fn themes_are_chosen_by_flag_name() {
+        let parse = |s| <ThemeName as ValueEnum>::from_str(s, false);
+        assert_eq!(parse("dark"), Ok(ThemeName::Dark));
+        assert_eq!(parse("light"), Ok(ThemeName::Light));
+        assert_eq!(parse("high-contrast"), Ok(ThemeName::HighContrast));
+        assert!(parse("neon").is_err());
+    }
+
+    #[test]
+    fn user_colors_are_stable_and_spread_out() {
+        let theme = Theme::dark();
+        // FNV-1a is fixed, so these hold across sessions and builds.
+        assert_eq!(theme.user_color("alice"), theme.user_color("alice"));
+        assert_eq!(theme.user_color("Alice"), theme.user_color("alice"));
+
+        let names: Vec<String> = (0..200).map(|i| format!("user{}", i)).collect();
+        for name in THEMES {
+            let theme = Theme::from_name(name);
+            let mut used: Vec<Color> = names.iter().map(|n| theme.user_color(n)).collect();
+            used.sort_by_key(|c| format!("{:?}", c));
+            used.dedup();
+            assert_eq!(used.len(), theme.user_palette.len(), "{:?} leaves colors unused", name);
+            for muted in [theme.system, theme.timestamp, theme.hint, theme.error] {
+                assert!(!used.contains(&muted), "{:?} gives users a muted color", name);
+            }
+        }
+    }
+
+    fn utc_display(format: &str) -> TimeDisplay {
+        TimeDisplay::new(format.to_string(), DisplayZone::Named(chrono_tz::UTC))
+    }
+
+    fn at(rfc3339: &str) -> ChatLine {
+        ChatLine {
+            timestamp: Some(DateTime::parse_from_rfc3339(rfc3339).unwrap().to_utc()),
+            ..ChatLine::system("message")
+        }
+    }
+
+    /// `chat_rows` as `"-"` for each message and the day for each separator.
+    fn row_outline(lines: &[ChatLine], time: &TimeDisplay) -> Vec<String> {
+        chat_rows(lines, time)
+            .iter()
+            .map(|row| match row {
+                ChatRow::DateSeparator(day) => day.to_string(),
+                ChatRow::Message(_) => "-".to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn date_separators_mark_each_new_day() {
+        let time = utc_display("%H:%M");
+        let lines = [
+            at("2024-03-02T23:59:00Z"),
+            at("2024-03-03T00:01:00Z"),
+            at("2024-03-03T12:00:00Z"),
+            // Live system lines carry no timestamp and never split days.
+            ChatLine::system("alice joined"),
+            at("2024-03-05T08:00:00Z"),
+        ];
+        let outline = row_outline(&lines, &time);
+        assert_eq!(outline, ["-", "2024-03-03", "-", "-", "-", "2024-03-05", "-"]);
+
+        // Nothing precedes the first message, so it gets no separator.
+        assert_eq!(row_outline(&lines[1..3], &time), ["-", "-"]);
+        assert!(row_outline(&[], &time).is_empty());
+    }
+
+    #[test]
+    fn search_results_scroll_within_the_results_area() {
+        assert_eq!(search_window(10, 4, 0), 6..10);
+        assert_eq!(search_window(10, 4, 6), 0..4);
+        assert_eq!(search_window(3, 4, 0), 0..3);
+        assert_eq!(search_window(0, 4, 0), 0..0);
+        assert_eq!(max_search_scroll(10, 4), 6);
+        assert_eq!(max_search_scroll(3, 4), 0);
+        // Beyond the maximum the window would shrink below the area.
+        assert_eq!(search_window(10, 4, 8), 0..2);
+
+        let mut app = App::new(utc_display("%H:%M"));
+        app.search_results = (0..10).map(|_| at("2024-03-02T10:00:00Z")).collect();
+        app.search_height = 4;
+        for _ in 0..5 {
+            app.search_scroll_up();
+        }
+        assert_eq!(app.search_scroll, 6);
+        assert_eq!(search_window(10, 4, app.search_scroll).len(), 4);
+        app.search_scroll_down();
+        assert_eq!(app.search_scroll, 3);
+
+        // New results start from the newest again.
+        app.search_query.value = "message".to_string();
+        app.search_locally();
+        assert_eq!(app.search_scroll, 0);
+
+        // "No results found" only once something was searched for.
+        app.search_query.value = "nothing like it".to_string();
+        app.search_locally();
+        assert!(app.search_done && app.search_results.is_empty());
+        app.search_query.value.clear();
+        app.search_locally();
+        assert!(!app.search_done);
+    }
+
+    fn presence(event: PresenceEvent, user_id: &str, username: &str) -> Presence {
+        let user = UserInfo {
+            user_id: user_id.to_string(),
+            username: username.to_string(),
+            ..UserInfo::default()
+        };
+        Presence { event, user }
+    }
+
+    #[test]
+    fn presence_events_keep_the_online_count() {
+        let mut app = App::new(utc_display("%H:%M"));
+        app.set_online(vec![presence(PresenceEvent::Join, "u1", "alice").user]);
+        let events = [
+            (PresenceEvent::Join, "u2", "bob", 2),
+            (PresenceEvent::Join, "u3", "carol", 3),
+            // A second connection of someone already online.
+            (PresenceEvent::Join, "u2", "bob", 3),
+            (PresenceEvent::Rename, "u2", "robert", 3),
+            (PresenceEvent::Leave, "u3", "carol", 2),
+            (PresenceEvent::Leave, "u9", "stranger", 2),
+            (PresenceEvent::Leave, "u1", "alice", 1),
+        ];
+        for (event, user_id, username, online) in events {
+            app.apply_presence(presence(event, user_id, username));
+            assert_eq!(app.online.len(), online, "after {:?} {}", event, username);
+        }
+        assert_eq!(app.online["u2"].username, "robert");
+    }
+
+    #[test]
+    fn exports_carry_full_utc_timestamps() {
+        let alice = ChatLine {
+            username: "alice".to_string(),
+            content: "hi: there".to_string(),
+            is_system: false,
+            ..at("2024-03-02T23:30:05.250+01:00")
+        };
+        let notice = at("2024-03-03T08:00:00Z");
+        let live = ChatLine::system("bob joined");
+        assert_eq!(
+            format_export(&[alice, notice, live]),
+            "2024-03-02T22:30:05Z alice: hi: there\n\
+             2024-03-03T08:00:00Z * message\n\
+             - * bob joined\n"
+        );
+        assert_eq!(format_export(&[]), "");
+    }
+
+    #[test]
+    fn days_and_times_follow_the_display_settings() {
+        let ts = DateTime::parse_from_rfc3339("2024-03-02T23:30:00Z").unwrap().to_utc();
+        assert_eq!(utc_display("%H:%M:%S").time(ts), "23:30:00");
+        assert_eq!(utc_display("%-I:%M %p").time(ts), "11:30 PM");
+
+        // Already the next day in Berlin: the separator must follow the zone.
+        let berlin = TimeDisplay::new("%H:%M".into(), "Europe/Berlin".parse().unwrap());
+        assert_eq!(berlin.time(ts), "00:30");
+        let lines = [at("2024-03-02T22:00:00Z"), at("2024-03-02T23:30:00Z")];
+        assert_eq!(row_outline(&lines, &berlin), ["-", "2024-03-03", "-"]);
+        assert_eq!(row_outline(&lines, &utc_display("%H:%M")), ["-", "-"]);
+
+        let label = date_separator_label(NaiveDate::from_ymd_opt(2001, 3, 3).unwrap(), &berlin);
+        assert_eq!(label, "── March 3, 2001 ──");
+    }
+
+    #[test]
+    fn the_input_box_grows_with_its_lines_up_to_the_cap() {
+        let mut input = Input::default();
+        assert_eq!(input_height(&input), 3);
+        input.set("one\ntwo\nthree".to_string());
+        assert_eq!(input_height(&input), 5);
+        input.set("line\n".repeat(MAX_INPUT_LINES + 3));
+        assert_eq!(input_height(&input), MAX_INPUT_LINES as u16 + 2);
+    }
+
+    /// Rows of a 10-row scrollbar track that its thumb covers.
+    fn thumb_rows(total: usize, visible: usize, start: usize) -> Vec<u16> {
+        let mut terminal = Terminal::new(ratatui::backend::TestBackend::new(1, 10)).unwrap();
+        let theme = Theme::from_name(ThemeName::Dark);
+        terminal
+            .draw(|f| draw_scrollbar(f, Rect::new(0, 0, 1, 10), total, visible, start, &theme))
+            .unwrap();
+        let buffer = terminal.backend().buffer();
+        (0..10).filter(|&y| buffer[(0, y)].symbol() == "█").collect()
+    }
+
+    #[test]
+    fn the_scrollbar_tracks_the_first_visible_row() {
+        assert!(scrollbar_state(10, 10, 0).is_none());
+        assert!(scrollbar_state(3, 10, 0).is_none());
+        assert!(thumb_rows(10, 10, 0).is_empty());
+
+        // 40 rows, 10 on screen: a quarter-height thumb.
+        let top = thumb_rows(40, 10, 0);
+        assert_eq!(top.first(), Some(&0));
+        assert!((2..=4).contains(&top.len()), "thumb: {:?}", top);
+        let bottom = thumb_rows(40, 10, 30);
+        assert_eq!(bottom.last(), Some(&9));
+        let middle = thumb_rows(40, 10, 15);
+        assert!(top[0] < middle[0] && middle[0] < bottom[0], "{:?} {:?} {:?}", top, middle, bottom);
+    }
+
+    fn chat(content: &str) -> ChatLine {
+        ChatLine {
+            user_id: "u1".to_string(),
+            username: "alice".to_string(),
+            is_system: false,
+            ..ChatLine::system(content)
+        }
+    }
+
+    #[test]
+    fn unread_counts_chat_that_arrives_while_scrolled_up() {
+        let mut app = App::new(utc_display("%H:%M"));
+        app.viewport_height = 5;
+        for n in 0..20 {
+            app.push_message(chat(&format!("old {}", n)));
+        }
+        assert_eq!(app.tab().unread, 0);
+
+        app.scroll_up();
+        let end = app.tab().view_end(&app.time);
+        for n in 0..3 {
+            app.push_message(chat(&format!("new {}", n)));
+        }
+        app.push_message(ChatLine::system("carol joined the chat"));
+        assert_eq!(app.tab().unread, 3);
+        assert_eq!(app.tab().view_end(&app.time), end, "the view moved");
+
+        // Scrolling back down by hand clears the count on reaching the bottom.
+        app.scroll_down();
+        assert_eq!(app.tab().unread, 3);
+        for _ in 0..3 {
+            app.scroll_down();
+        }
+        assert_eq!((app.tab().anchor, app.tab().unread), (None, 0));
+
+        app.scroll_to_top();
+        assert_eq!(app.tab().view_end(&app.time), 5);
+        app.push_message(chat("newest"));
+        assert_eq!(app.tab().unread, 1);
+        app.scroll_to_bottom();
+        assert_eq!((app.tab().anchor, app.tab().unread), (None, 0));
+        app.push_message(chat("seen at once"));
+        assert_eq!(app.tab().unread, 0);
+    }
+
+    /// The newest message in view.
+    fn bottom_line(app: &App) -> String {
+        let rows = chat_rows(&app.tab().messages, &app.time);
+        match rows[app.tab().view_end(&app.time) - 1] {
+            ChatRow::Message(line) => line.content.clone(),
+            ChatRow::DateSeparator(day) => day.to_string(),
+        }
+    }
+
+    fn with_id(n: usize) -> ChatLine {
+        ChatLine {
+            id: Some(format!("m{}", n)),
+            ..chat(&format!("message {}", n))
+        }
+    }
+
+    #[test]
+    fn scrolled_up_the_view_stays_on_the_same_messages() {
+        let mut app = App::new(utc_display("%H:%M"));
+        app.viewport_height = 5;
+        for n in 10..30 {
+            app.push_message(with_id(n));
+        }
+        app.scroll_up();
+        app.scroll_up();
+        assert_eq!(bottom_line(&app), "message 23");
+
+        for n in 30..40 {
+            app.push_message(with_id(n));
+        }
+        assert_eq!(bottom_line(&app), "message 23", "appending moved the view");
+
+        let older = (0..10).map(|n| StoredMessage {
+            id: format!("m{}", n),
+            user_id: "u1".to_string(),
+            username: "alice".to_string(),
+            content: format!("message {}", n),
+            timestamp: Utc::now(),
+            kind: MessageKind::Chat,
+            entities: Vec::new(),
+            format: None,
+            room: None,
+            expires_at: None,
+            seq: n as u64,
+        });
+        app.prepend_history(older.collect());
+        assert_eq!(bottom_line(&app), "message 23", "older history moved the view");
+
+        let gone: Vec<String> = ["m5", "m20", "m21", "m35"].map(String::from).to_vec();
+        app.remove_messages(&gone);
+        assert_eq!(bottom_line(&app), "message 23", "deleting messages moved the view");
+
+        // At the bottom the view follows new messages again.
+        app.scroll_to_bottom();
+        app.push_message(with_id(40));
+        assert_eq!(bottom_line(&app), "message 40");
+    }
+
+    /// A chat line from `username` on day `day` of January 2026.
+    fn said(username: &str, day: u32, content: &str) -> ChatLine {
+        ChatLine {
+            username: username.to_string(),
+            timestamp: Some(Utc.with_ymd_and_hms(2026, 1, day, 12, 0, 0).unwrap()),
+            ..chat(content)
+        }
+    }
+
+    fn result_contents(app: &App) -> Vec<&str> {
+        app.search_results.iter().map(|l| l.content.as_str()).collect()
+    }
+
+    #[test]
+    fn local_search_narrows_with_each_keystroke() {
+        let mut app = App::new(utc_display("%H:%M"));
+        app.push_message(said("alice", 10, "deploy on friday"));
+        app.push_message(ChatLine::system("deploy bot joined"));
+        app.push_message(said("Bob", 11, "the deploy went fine"));
+        app.push_message(said("alice", 12, "lunch?"));
+        app.search_local = true;
+
+        let mut seen = Vec::new();
+        for c in "dep".chars() {
+            app.search_query.insert(c);
+            app.search_locally();
+            seen.push(result_contents(&app).len());
+        }
+        assert_eq!(seen, [2, 2, 2], "system lines are never matched");
+        app.search_query.insert_str("loy fine");
+        app.search_locally();
+        assert_eq!(result_contents(&app), ["the deploy went fine"]);
+        assert!(app.search_highlight.is_some());
+
+        app.search_query.set(String::new());
+        app.search_user.insert_str("bob");
+        app.search_locally();
+        assert_eq!(result_contents(&app), ["the deploy went fine"]);
+        assert_eq!(app.search_status.as_deref(), Some("1 match(es) in loaded messages"));
+
+        // Clearing every field clears the results.
+        app.search_user.set(String::new());
+        app.search_locally();
+        assert!(app.search_results.is_empty() && !app.search_done);
+    }
+
+    #[test]
+    fn local_search_defers_to_the_server_for_older_dates() {
+        let mut app = App::new(utc_display("%H:%M"));
+        app.push_message(said("alice", 10, "deploy on friday"));
+        app.push_message(said("alice", 12, "lunch?"));
+
+        app.search_from.insert_str("2026-01-11");
+        app.search_locally();
+        assert_eq!(result_contents(&app), ["lunch?"]);
+        assert!(!app.reaches_past_loaded(parse_datetime("2026-01-11"), None));
+
+        app.search_from.set("2026-01-01".to_string());
+        app.search_locally();
+        assert_eq!(result_contents(&app), ["deploy on friday", "lunch?"]);
+        let status = app.search_status.as_deref().unwrap_or_default();
+        assert!(status.contains("Enter searches the server"), "status: {}", status);
+        assert!(app.reaches_past_loaded(None, parse_datetime("2026-01-11")));
+    }
+
+    fn joined(room: &str) -> JoinResult {
+        JoinResult { room: room.to_string(), messages: Vec::new(), topic: None }
+    }
+
+    fn contents(tab: &Tab) -> Vec<&str> {
+        tab.messages.iter().map(|l| l.content.as_str()).collect()
+    }
+
+    #[test]
+    fn lines_go_to_their_rooms_tab_and_count_as_unread_there() {
+        let mut app = App::new(utc_display("%H:%M"));
+        app.open_room(joined("rust"));
+        app.open_room(joined("go"));
+        assert_eq!(app.tab().label(), "#go");
+
+        app.push_to_room(None, chat("in the lobby"));
+        app.push_to_room(Some("rust"), chat("in rust"));
+        app.push_to_room(Some("rust"), ChatLine::system("carol joined #rust"));
+        app.push_to_room(Some("go"), chat("in go"));
+        app.push_to_room(Some("left"), chat("dropped"));
+        assert_eq!(contents(&app.tabs[0]), ["in the lobby"]);
+        assert_eq!(contents(&app.tabs[1]), ["in rust", "carol joined #rust"]);
+        assert_eq!(contents(&app.tabs[2]), ["in go"]);
+        let unread: Vec<usize> = app.tabs.iter().map(|t| t.unread).collect();
+        assert_eq!(unread, [1, 1, 0], "system lines and the shown tab don't count");
+
+        // Cycling wraps both ways and clears the count of the tab shown.
+        app.cycle_tab(1);
+        assert_eq!((app.active, app.tabs[0].unread), (0, 0));
+        app.cycle_tab(-1);
+        assert_eq!(app.active, 2);
+        app.show_tab(1);
+        assert_eq!(app.tabs[1].unread, 0);
+
+        // Rejoining shows the open tab rather than adding another.
+        app.open_room(joined("go"));
+        assert_eq!((app.tabs.len(), app.active), (3, 2));
+
+        // Closing the shown tab shows its left neighbour.
+        app.close_room("go");
+        assert_eq!((app.tabs.len(), app.tab().label()), (2, "#rust".to_string()));
+        app.close_room("rust");
+        assert_eq!((app.tabs.len(), app.tab().label()), (1, "lobby".to_string()));
+    }
+
+    /// `text` with each highlighted segment in brackets.
+    fn highlighted(query: &str, text: &str) -> String {
+        let ranges = Query::parse(query).match_ranges(text);
+        highlight_segments(text, &ranges)
+            .into_iter()
+            .map(|(s, matched)| if matched { format!("[{}]", s) } else { s.to_string() })
+            .collect()
+    }
+
+    #[test]
+    fn search_matches_are_split_out_for_highlighting() {
+        assert_eq!(highlighted("cat", "Cat and cat and CAT"), "[Cat] and [cat] and [CAT]");
+        assert_eq!(highlighted("dog OR cat", "a dog, a cat"), "a [dog], a [cat]");
+        assert_eq!(highlighted("\"big cat\"", "big dog, big cat"), "big dog, [big cat]");
+        assert_eq!(highlighted("café", "Café ☕ café"), "[Café] ☕ [café]");
+        assert_eq!(highlighted("zebra", "no match here"), "no match here");
+        assert_eq!(highlighted("", "anything"), "anything");
+        assert!(highlight_segments("", &[]).is_empty());
+    }
+
+    /// `markdown_runs` as `**bold**`, `_italic_`, `` `code` `` and plain text.
+    fn runs(line: &str) -> String {
+        markdown_runs(line)
+            .into_iter()
+            .map(|run| match run {
+                MarkdownRun::Code(r) => format!("`{}`", &line[r]),
+                MarkdownRun::Text(r, m) if m == Modifier::BOLD => format!("**{}**", &line[r]),
+                MarkdownRun::Text(r, m) if m == Modifier::ITALIC => format!("_{}_", &line[r]),
+                MarkdownRun::Text(r, _) => format!("[{}]", &line[r]),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn markdown_splits_into_styled_runs() {
+        assert_eq!(runs("plain"), "[plain]");
+        assert_eq!(runs("a **b** *c* _d_ `e`"), "[a ]**b**[ ]_c_[ ]_d_[ ]`e`");
+        assert_eq!(runs("`**not bold**` here"), "`**not bold**`[ here]");
+        assert_eq!(runs("snake_case_name and 2 * 3 * 4"), "[snake_case_name and 2 * 3 * 4]");
+        assert_eq!(runs("** padded ** and **open"), "[** padded ** and **open]");
+        assert_eq!(runs("`` and **x**y"), "[`` and **x**y]");
+        assert_eq!(runs("çà **été**"), "[çà ]**été**");
+    }
+
+    #[test]
+    fn fenced_blocks_switch_lines_to_code() {
+        let theme = Theme::from_name(ThemeName::Dark);
+        let line = ChatLine {
+            format: MessageFormat::Markdown,
+            ..chat("")
+        };
+        let mut in_fence = false;
+        let styles: Vec<_> = ["say **hi**", "```rust", "let **x** = 1;", "```", "**bye**"]
+            .into_iter()
+            .map(|text| {
+                let spans = content_spans(text, 0, &line, &mut in_fence, None, &theme);
+                (spans.len(), spans[0].style.fg)
+            })
+            .collect();
+        let code = Some(theme.code);
+        assert_eq!(styles[2], (1, code), "code inside the fence is left alone");
+        assert_eq!(styles[1].1, Some(theme.hint));
+        assert_eq!(styles[3].1, Some(theme.hint));
+        assert_eq!(styles[0].0, 2);
+        assert!(!in_fence);
+
+        let code_line = ChatLine {
+            format: MessageFormat::Code,
+            ..chat("")
+        };
+        let spans = content_spans("**x**", 0, &code_line, &mut in_fence, None, &theme);
+        assert_eq!((spans.len(), spans[0].content.as_ref()), (1, "**x**"));
+    }
+}
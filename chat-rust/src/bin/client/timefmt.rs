@@ -0,0 +1,131 @@
+//! Timestamp rendering in the user's chosen timezone. Timestamps stay UTC
+//! everywhere else (wire, storage, exports); they are converted only here,
+//! at display time.
+
+use std::str::FromStr;
+
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, TimeDelta, Utc};
+use chrono_tz::Tz;
+
+/// The zone timestamps are shown in.
+#[derive(Debug, Clone, Copy)]
+pub enum DisplayZone {
+    /// The system's local zone.
+    Local,
+    Named(Tz),
+}
+
+impl FromStr for DisplayZone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("local") {
+            return Ok(DisplayZone::Local);
+        }
+        s.parse::<Tz>().map(DisplayZone::Named).map_err(|_| {
+            format!("unknown timezone {:?} (expected local or an IANA name like Europe/Berlin)", s)
+        })
+    }
+}
+
+/// Formats timestamps with the user's `--time-format` in their zone.
+pub struct TimeDisplay {
+    format: String,
+    zone: DisplayZone,
+    /// How far the server's clock is ahead of ours; applied to our own
+    /// notion of "now" so it agrees with the server's timestamps.
+    clock_offset: TimeDelta,
+}
+
+impl TimeDisplay {
+    pub fn new(format: String, zone: DisplayZone) -> Self {
+        Self {
+            format,
+            zone,
+            clock_offset: TimeDelta::zero(),
+        }
+    }
+
+    pub fn set_clock_offset(&mut self, offset: TimeDelta) {
+        self.clock_offset = offset;
+    }
+
+    /// The current time by the server's clock (as far as we know it).
+    pub fn now(&self) -> DateTime<Utc> {
+        Utc::now() + self.clock_offset
+    }
+
+    pub fn in_zone(&self, ts: DateTime<Utc>) -> DateTime<FixedOffset> {
+        match self.zone {
+            DisplayZone::Local => ts.with_timezone(&Local).fixed_offset(),
+            DisplayZone::Named(tz) => ts.with_timezone(&tz).fixed_offset(),
+        }
+    }
+
+    /// `ts` rendered with the configured time format.
+    pub fn time(&self, ts: DateTime<Utc>) -> String {
+        self.in_zone(ts).format(&self.format).to_string()
+    }
+
+    /// `ts` as `YYYY-MM-DD`.
+    pub fn date(&self, ts: DateTime<Utc>) -> String {
+        self.in_zone(ts).format("%Y-%m-%d").to_string()
+    }
+
+    /// The calendar day `ts` falls on in the display zone.
+    pub fn day(&self, ts: DateTime<Utc>) -> NaiveDate {
+        self.in_zone(ts).date_naive()
+    }
+
+    pub fn today(&self) -> NaiveDate {
+        self.day(self.now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(rfc3339: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap().to_utc()
+    }
+
+    fn shown_in(zone: &str) -> TimeDisplay {
+        TimeDisplay::new("%H:%M %Z".into(), zone.parse().unwrap())
+    }
+
+    #[test]
+    fn zones_parse_from_the_flag() {
+        assert!(matches!("local".parse(), Ok(DisplayZone::Local)));
+        assert!(matches!("LOCAL".parse(), Ok(DisplayZone::Local)));
+        assert!(matches!("Asia/Tokyo".parse(), Ok(DisplayZone::Named(chrono_tz::Asia::Tokyo))));
+        let err = "Mars/Olympus".parse::<DisplayZone>().unwrap_err();
+        assert!(err.starts_with("unknown timezone \"Mars/Olympus\""), "unexpected: {}", err);
+    }
+
+    #[test]
+    fn utc_instants_render_in_the_chosen_zone() {
+        let winter = utc("2024-01-15T12:00:00Z");
+        let summer = utc("2024-07-15T12:00:00Z");
+        let new_york = shown_in("America/New_York");
+        assert_eq!(new_york.time(winter), "07:00 -05:00");
+        assert_eq!(new_york.time(summer), "08:00 -04:00");
+        assert_eq!(shown_in("Asia/Kolkata").time(winter), "17:30 +05:30");
+        assert_eq!(shown_in("UTC").time(winter), "12:00 +00:00");
+
+        // Late evening in UTC is already tomorrow in Tokyo.
+        let late = utc("2024-01-15T20:00:00Z");
+        let tokyo = shown_in("Asia/Tokyo");
+        assert_eq!(tokyo.date(late), "2024-01-16");
+        assert_eq!(tokyo.day(late), NaiveDate::from_ymd_opt(2024, 1, 16).unwrap());
+        assert_eq!(shown_in("UTC").date(late), "2024-01-15");
+    }
+
+    #[test]
+    fn now_follows_the_server_clock() {
+        let mut time = shown_in("UTC");
+        time.set_clock_offset(TimeDelta::hours(2));
+        let ahead = time.now() - Utc::now();
+        assert!((ahead - TimeDelta::hours(2)).abs() < TimeDelta::seconds(5), "{:?}", ahead);
+    }
+}
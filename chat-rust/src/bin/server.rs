@@ -1,38 +1,467 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use clap::Parser;
 use anyhow::Result;
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
 
-use chat::server::Server;
+use chat::protocol::{Framing, DEFAULT_MAX_FRAME};
+use chat::server::bot::{self, BotConfig};
+use chat::server::bridge::{self, BridgeConfig};
+use chat::server::filter::FilterMode;
+use chat::server::sanitize::SanitizeMode;
+use chat::server::send_queue::OverflowPolicy;
+use chat::server::{
+    Server, ServerConfig, DEFAULT_FLUSH_INTERVAL, DEFAULT_PRESENCE_DEBOUNCE, DEFAULT_SEND_BUFFER,
+};
+use chat::store::{FsyncPolicy, Store, StoreOptions};
+
+const DEFAULT_ADDR: &str = "0.0.0.0:8080";
 
 #[derive(Parser)]
 #[command(name = "server", about = "RustChat TCP server")]
 struct Args {
-    /// TCP address to listen on
-    #[arg(long, default_value = "0.0.0.0:8080")]
-    addr: String,
+    /// TCP address to listen on [default: 0.0.0.0:8080, unless only
+    /// --unix-socket is given]
+    #[arg(long)]
+    addr: Option<String>,
+
+    /// Also (or, without --addr, only) listen on a Unix domain socket at
+    /// this path; the socket file is removed on shutdown
+    #[arg(long)]
+    unix_socket: Option<PathBuf>,
 
     /// Directory for persistent storage
     #[arg(long, default_value = "./data")]
     data: String,
 
-    /// Number of message-persistence worker tasks
+    /// Keep users and messages in memory only; nothing is read from or written to --data
+    #[arg(long, conflicts_with_all = ["export", "export_users", "import"])]
+    ephemeral: bool,
+
+    /// Number of message-persistence worker tasks (0 = one per CPU)
     #[arg(long, default_value_t = 4)]
     workers: usize,
+
+    /// Comma-separated usernames granted admin privileges
+    #[arg(long, value_delimiter = ',')]
+    admins: Vec<String>,
+
+    /// Program that decides logins and registrations instead of users.json
+    /// (run as `<path> login|register`, with the username and password on stdin)
+    #[arg(long)]
+    auth_command: Option<PathBuf>,
+
+    /// Delete messages older than this many days
+    #[arg(long)]
+    retention_days: Option<u32>,
+
+    /// Keep at most this many messages, dropping the oldest
+    #[arg(long)]
+    max_messages: Option<usize>,
+
+    /// Wire framing: newline (JSON lines) or length (4-byte length prefix)
+    #[arg(long, default_value_t = Framing::Newline)]
+    framing: Framing,
+
+    /// Largest packet accepted from a client, in bytes
+    #[arg(long, default_value_t = DEFAULT_MAX_FRAME)]
+    max_packet_bytes: usize,
+
+    /// Packets queued for each client before --overflow-policy applies
+    #[arg(long, default_value_t = DEFAULT_SEND_BUFFER)]
+    send_buffer: usize,
+
+    /// What to do when a client's send buffer is full: disconnect (with a
+    /// notice), drop_newest or drop_oldest
+    #[arg(long, default_value_t = OverflowPolicy::Disconnect)]
+    overflow_policy: OverflowPolicy,
+
+    /// File of words (one per line, `#` comments) to filter from chat messages
+    /// (re-read on SIGHUP)
+    #[arg(long)]
+    filter_list: Option<PathBuf>,
+
+    /// What to do with filtered messages: reject or mask
+    #[arg(long, default_value_t = FilterMode::Mask)]
+    filter_mode: FilterMode,
+
+    /// What to do with control characters and terminal escape sequences in
+    /// messages: strip them, or escape them as visible \xNN text
+    #[arg(long, default_value_t = SanitizeMode::Strip)]
+    sanitize: SanitizeMode,
+
+    /// Also store join/leave and other system notices in the message history
+    #[arg(long)]
+    persist_system: bool,
+
+    /// Allow clients to negotiate zlib compression of their connection
+    #[arg(long)]
+    compress: bool,
+
+    /// Drop a message identical to the sender's previous one if it arrives
+    /// within this many seconds (off by default)
+    #[arg(long)]
+    dedup_secs: Option<u64>,
+
+    /// Hold "left the chat" notices back this many seconds, and announce
+    /// neither the leave nor the join if the user reconnects meanwhile
+    /// (0 = announce both at once)
+    #[arg(long, default_value_t = DEFAULT_PRESENCE_DEBOUNCE.as_secs())]
+    presence_debounce_secs: u64,
+
+    /// Write indented (human-readable) JSON data files instead of compact ones
+    #[arg(long)]
+    pretty_storage: bool,
+
+    /// When data files reach the disk: always (fsync every write; nothing
+    /// lost, slowest), interval (write and fsync changes together every
+    /// --flush-interval-ms; up to that much lost on a crash) or never (write
+    /// every change, leave syncing to the OS; power loss can lose recent
+    /// writes)
+    #[arg(long, default_value_t = FsyncPolicy::Never)]
+    fsync_policy: FsyncPolicy,
+
+    /// Same as --fsync-policy always
+    #[arg(long, conflicts_with = "fsync_policy")]
+    fsync: bool,
+
+    /// How often --fsync-policy interval flushes changes
+    #[arg(long, default_value_t = DEFAULT_FLUSH_INTERVAL.as_millis() as u64)]
+    flush_interval_ms: u64,
+
+    /// Refuse to start if a data file is corrupt (default: back it up and
+    /// recover what parses)
+    #[arg(long)]
+    strict: bool,
+
+    /// Keep only the newest N messages in memory and page older ones out to
+    /// archive.jsonl, read back on demand by history, search and sync
+    /// (0 = keep every message in memory)
+    #[arg(long, default_value_t = 0)]
+    memory_window: usize,
+
+    /// Send this file's contents as the welcome message (re-read for every
+    /// connection, so edits apply without a restart)
+    #[arg(long)]
+    motd_file: Option<PathBuf>,
+
+    /// Run the built-in bot described by this JSON file (name, trigger/response
+    /// pairs, scheduled messages)
+    #[arg(long)]
+    bot_config: Option<PathBuf>,
+
+    /// POST each chat message as JSON to this http:// URL
+    #[arg(long)]
+    webhook_url: Option<String>,
+
+    /// Only send messages matching this search query (same syntax as search)
+    /// to the webhook
+    #[arg(long, requires = "webhook_url")]
+    webhook_query: Option<String>,
+
+    /// Append an audit trail (logins, admin actions, deletions) to this file as
+    /// JSON lines; safe to rotate by renaming
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+
+    /// Post messages published on a Redis channel (see --bridge-channel) to
+    /// chat; redis://[[user]:password@]host[:port]
+    #[arg(long, requires = "bridge_channel")]
+    bridge_redis: Option<String>,
+
+    /// Redis pub/sub channel to read bridged messages from
+    #[arg(long, requires = "bridge_redis")]
+    bridge_channel: Option<String>,
+
+    /// Username bridged messages are posted under
+    #[arg(long, default_value = "bridge")]
+    bridge_name: String,
+
+    /// Let people start read-only guest sessions without registering
+    #[arg(long)]
+    guests: bool,
+
+    /// Also let guests post chat messages (at most one every 10s)
+    #[arg(long, requires = "guests")]
+    guest_chat: bool,
+
+    /// Serve existing data without changing it: chat, registration and other
+    /// writes are refused and nothing is written to --data (admins can switch
+    /// it off at runtime)
+    #[arg(long)]
+    read_only: bool,
+
+    /// Reject client packets with unknown fields, or missing fields that
+    /// would otherwise be defaulted, naming the field (invalid_payload)
+    #[arg(long)]
+    strict_protocol: bool,
+
+    /// Refuse TCP connections from an IP address that already has this many
+    /// open (unlimited by default)
+    #[arg(long)]
+    max_conns_per_ip: Option<usize>,
+
+    /// Failed logins allowed per username within --auth-window-secs before
+    /// it is locked out, with the lockout doubling on repeats (0 = never)
+    #[arg(long, default_value_t = 5)]
+    auth_max_failures: u32,
+
+    /// Window over which failed logins are counted
+    #[arg(long, default_value_t = 300)]
+    auth_window_secs: u64,
+
+    /// JSON file overriding --dedup-secs, --max-conns-per-ip,
+    /// --auth-max-failures and --auth-window-secs (re-read on SIGHUP)
+    #[arg(long)]
+    limits_file: Option<PathBuf>,
+
+    /// Close connections that send nothing (not even a keepalive ping) for
+    /// this many seconds (off by default)
+    #[arg(long)]
+    idle_timeout_secs: Option<u64>,
+
+    /// Also serve a read-only HTTP/JSON API on this address
+    #[arg(long, requires = "http_token")]
+    http_addr: Option<String>,
+
+    /// Bearer token required by the HTTP API
+    #[arg(long, env = "CHAT_HTTP_TOKEN")]
+    http_token: Option<String>,
+
+    /// Serve Prometheus metrics at /metrics on this address
+    #[arg(long)]
+    metrics_addr: Option<String>,
+
+    /// Validate the data files in --data, print any problems and exit
+    /// (non-zero if there are problems); nothing is modified
+    #[arg(long, conflicts_with_all = ["ephemeral", "export", "export_users", "import"])]
+    check: bool,
+
+    /// Write all messages to this file as JSONL and exit
+    #[arg(long)]
+    export: Option<PathBuf>,
+
+    /// Write all users (without password hashes) to this file as JSONL and exit
+    #[arg(long)]
+    export_users: Option<PathBuf>,
+
+    /// Merge messages from this JSONL file into the store and exit
+    #[arg(long)]
+    import: Option<PathBuf>,
+
+    /// Log filter (e.g. "info", "chat=debug"); RUST_LOG takes precedence
+    #[arg(long, default_value = "info")]
+    log_level: String,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
-    let srv = Arc::new(Server::new(&args.data, args.workers)?);
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&args.log_level)),
+        )
+        .with_writer(std::io::stderr)
+        .init();
+
+    if args.check {
+        return run_check(&args.data);
+    }
+    if args.export.is_some() || args.export_users.is_some() || args.import.is_some() {
+        return run_archive_commands(&args);
+    }
+
+    let fsync = fsync_policy(&args);
+    let bot_config = args.bot_config.as_deref().map(BotConfig::load).transpose()?;
+
+    let srv = Arc::new(Server::new(ServerConfig {
+        data_dir: args.data,
+        ephemeral: args.ephemeral,
+        workers: args.workers,
+        admins: args.admins,
+        auth_command: args.auth_command,
+        retention_days: args.retention_days,
+        max_messages: args.max_messages,
+        framing: args.framing,
+        max_packet_bytes: args.max_packet_bytes,
+        send_buffer: args.send_buffer,
+        overflow_policy: args.overflow_policy,
+        filter_list: args.filter_list,
+        filter_mode: args.filter_mode,
+        sanitize: args.sanitize,
+        persist_system: args.persist_system,
+        compression: args.compress,
+        dedup_window: args.dedup_secs.map(Duration::from_secs),
+        presence_debounce: (args.presence_debounce_secs > 0)
+            .then(|| Duration::from_secs(args.presence_debounce_secs)),
+        pretty_storage: args.pretty_storage,
+        fsync,
+        flush_interval: Duration::from_millis(args.flush_interval_ms.max(1)),
+        strict: args.strict,
+        memory_window: args.memory_window,
+        motd_file: args.motd_file,
+        max_conns_per_ip: args.max_conns_per_ip,
+        auth_max_failures: args.auth_max_failures,
+        auth_window: Duration::from_secs(args.auth_window_secs),
+        limits_file: args.limits_file,
+        idle_timeout: args.idle_timeout_secs.map(Duration::from_secs),
+        webhook_url: args.webhook_url,
+        webhook_query: args.webhook_query,
+        audit_log: args.audit_log,
+        guests: args.guests,
+        guest_chat: args.guest_chat,
+        read_only: args.read_only,
+        strict_protocol: args.strict_protocol,
+    })?);
+
+    if let Some(config) = bot_config {
+        bot::start(srv.clone(), config).await?;
+    }
+    if let (Some(url), Some(channel)) = (args.bridge_redis, args.bridge_channel) {
+        let config = BridgeConfig {
+            url,
+            channel,
+            name: args.bridge_name,
+        };
+        bridge::start(srv.clone(), config).await?;
+    }
+
+    if let (Some(addr), Some(token)) = (args.http_addr, args.http_token) {
+        let srv = srv.clone();
+        tokio::spawn(async move {
+            if let Err(e) = chat::server::http::serve(srv, &addr, token).await {
+                error!(error = %e, "http: server failed");
+            }
+        });
+    }
+
+    if let Some(addr) = args.metrics_addr {
+        let srv = srv.clone();
+        tokio::spawn(async move {
+            if let Err(e) = chat::server::metrics::serve(srv, &addr).await {
+                error!(error = %e, "metrics: server failed");
+            }
+        });
+    }
+
+    let unix_socket = args.unix_socket;
+    let tcp_addr = match (args.addr, &unix_socket) {
+        (Some(addr), _) => Some(addr),
+        (None, Some(_)) => None,
+        (None, None) => Some(DEFAULT_ADDR.to_string()),
+    };
+
+    // Reload the filter list and limits file on SIGHUP
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        let srv = srv.clone();
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("SIGHUP received, reloading configuration");
+                if let Err(e) = srv.reload() {
+                    error!(error = %e, "reload failed; keeping the current settings");
+                }
+            }
+        });
+    }
 
     // Graceful shutdown on Ctrl-C
+    let socket_file = unix_socket.clone();
+    let flushed = srv.clone();
     tokio::spawn(async move {
         tokio::signal::ctrl_c().await.ok();
-        eprintln!("[server] shutting down…");
+        info!("shutting down");
+        if let Err(e) = flushed.flush().await {
+            error!(error = %e, "failed to flush the store on shutdown");
+        }
+        if let Some(path) = socket_file {
+            std::fs::remove_file(path).ok();
+        }
         std::process::exit(0);
     });
 
-    srv.listen_and_serve(&args.addr).await?;
+    let tcp = async {
+        match &tcp_addr {
+            Some(addr) => srv.clone().listen_and_serve(addr).await,
+            None => Ok(()),
+        }
+    };
+    let unix = async {
+        match &unix_socket {
+            #[cfg(unix)]
+            Some(path) => srv.clone().listen_unix(path).await,
+            #[cfg(not(unix))]
+            Some(_) => anyhow::bail!("--unix-socket is not supported on this platform"),
+            None => Ok(()),
+        }
+    };
+    tokio::try_join!(tcp, unix)?;
+    Ok(())
+}
+
+fn fsync_policy(args: &Args) -> FsyncPolicy {
+    if args.fsync {
+        FsyncPolicy::Always
+    } else {
+        args.fsync_policy
+    }
+}
+
+/// Handles --check: prints a report of the data directory and exits with
+/// status 1 if anything is inconsistent.
+fn run_check(data: &str) -> Result<()> {
+    let report = Store::check(data)?;
+    println!("checked {} users and {} messages in {}", report.users, report.messages, data);
+    if report.problems.is_empty() {
+        println!("no problems found");
+        return Ok(());
+    }
+    for problem in &report.problems {
+        println!("  - {}", problem);
+    }
+    println!("{} problem(s) found", report.problems.len());
+    std::process::exit(1);
+}
+
+/// Handles --import/--export/--export-users against the store directly,
+/// without starting the listener.
+fn run_archive_commands(args: &Args) -> Result<()> {
+    let opts = StoreOptions {
+        pretty: args.pretty_storage,
+        fsync: fsync_policy(args),
+        strict: args.strict,
+        memory_window: args.memory_window,
+        ..StoreOptions::default()
+    };
+    let mut store = Store::with_options(&args.data, opts)?;
+
+    if let Some(path) = &args.import {
+        let report = store.import_from_reader(BufReader::new(File::open(path)?))?;
+        info!(
+            path = %path.display(),
+            imported = report.imported,
+            duplicates = report.duplicates,
+            "import finished"
+        );
+        if report.malformed > 0 {
+            warn!(skipped = report.malformed, "import skipped malformed lines");
+        }
+    }
+    if let Some(path) = &args.export {
+        let n = store.export_to_writer(BufWriter::new(File::create(path)?))?;
+        info!(path = %path.display(), messages = n, "export finished");
+    }
+    if let Some(path) = &args.export_users {
+        let n = store.export_users_to_writer(BufWriter::new(File::create(path)?))?;
+        info!(path = %path.display(), users = n, "user export finished");
+    }
     Ok(())
 }
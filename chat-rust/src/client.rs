@@ -0,0 +1,777 @@
+//! Async client for the RustChat line protocol, for bots and other tools.
+//!
+//! [`Client`] owns the connection (TCP, or a Unix socket via
+//! [`Client::connect_addr`]) and handles framing (newline JSON by
+//! default, or length-prefixed via [`Client::connect_with_framing`]) and,
+//! optionally, zlib compression and keepalive pings (see [`ConnectOptions`]).
+//! Request-style calls (`login`, `history`, `search`, ...) wait for the
+//! server's `Response`; everything else the server sends is delivered to
+//! [`Client::subscribe`] receivers.
+//!
+//! Bots that only care about some events can use the typed callbacks
+//! instead (`on_chat`, `on_system`, `on_user_join`, ...). Each one runs on a
+//! subscription of its own, decodes the packets it cares about and skips
+//! the rest, so they can be mixed with each other and with raw receivers.
+//!
+//! Each request carries an `id` that the server copies onto its response,
+//! so responses reach the call that asked even when other packets are in
+//! flight. Packets sent with [`Client::send`] (including `send_chat`, which
+//! only gets a response when the server rejects the message) have no id,
+//! and their responses are delivered to subscribers.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use async_compression::tokio::bufread::ZlibDecoder;
+use async_compression::tokio::write::ZlibEncoder;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Serialize;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use crate::protocol::*;
+
+const SUBSCRIBER_BUF: usize = 256;
+const BACKLOG_MAX: usize = 256;
+/// Server responses (history, search) can legitimately be much larger than
+/// anything a client sends, so the inbound bound is looser than the server's.
+const MAX_INBOUND_FRAME: usize = 16 * 1024 * 1024;
+
+type BoxedReader = Box<dyn AsyncBufRead + Send + Unpin>;
+type BoxedWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Connection settings for [`Client::connect_with_options`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectOptions {
+    /// Must match the server's `--framing`.
+    pub framing: Framing,
+    /// Ask the server to zlib-compress the connection. If the server
+    /// declines, the client carries on uncompressed.
+    pub compress: bool,
+    /// Send a `ping` whenever nothing has been sent for this long, so a
+    /// server with an idle timeout doesn't drop a user who is only reading.
+    pub keepalive: Option<Duration>,
+}
+
+/// The write half, with when it was last written to.
+struct Writer {
+    stream: BoxedWriter,
+    last_write: Instant,
+}
+
+impl Writer {
+    async fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.stream.write_all(data).await?;
+        self.stream.flush().await?;
+        self.last_write = Instant::now();
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    subscribers: Mutex<Vec<mpsc::Sender<Packet>>>,
+    /// Packets that arrived before anyone subscribed.
+    backlog: Mutex<VecDeque<Packet>>,
+    /// Requests waiting for their response, by request id.
+    pending: Mutex<HashMap<u64, oneshot::Sender<ResponsePayload>>>,
+    next_id: AtomicU64,
+}
+
+pub struct Client {
+    writer: Arc<tokio::sync::Mutex<Writer>>,
+    codec: Codec,
+    shared: Arc<Shared>,
+}
+
+impl Client {
+    /// Connects to a server using newline framing and starts the
+    /// background read task.
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        Self::connect_with_framing(addr, Framing::Newline).await
+    }
+
+    /// Like [`Client::connect`], for servers started with a non-default
+    /// `--framing`.
+    pub async fn connect_with_framing(addr: impl ToSocketAddrs, framing: Framing) -> Result<Self> {
+        let opts = ConnectOptions {
+            framing,
+            ..Default::default()
+        };
+        Self::connect_with_options(addr, opts).await
+    }
+
+    pub async fn connect_with_options(
+        addr: impl ToSocketAddrs,
+        opts: ConnectOptions,
+    ) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await.context("connect")?;
+        Self::start(stream, opts).await
+    }
+
+    /// Connects to `addr`, which is either `host:port` or `unix:/path/to.sock`
+    /// for a server started with `--unix-socket`.
+    pub async fn connect_addr(addr: &str, opts: ConnectOptions) -> Result<Self> {
+        match addr.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => {
+                let stream = UnixStream::connect(path).await.context("connect")?;
+                Self::start(stream, opts).await
+            }
+            #[cfg(not(unix))]
+            Some(_) => anyhow::bail!("unix sockets are not supported on this platform"),
+            None => Self::connect_with_options(addr, opts).await,
+        }
+    }
+
+    async fn start<S>(stream: S, opts: ConnectOptions) -> Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (reader, writer) = tokio::io::split(stream);
+        let mut reader: BoxedReader = Box::new(BufReader::new(reader));
+        let mut writer: BoxedWriter = Box::new(writer);
+        let codec = Codec::new(opts.framing).with_max_frame(MAX_INBOUND_FRAME);
+        let shared = Arc::new(Shared::default());
+        if opts.compress {
+            (reader, writer) = negotiate_compression(reader, writer, codec, &shared).await?;
+        }
+        tokio::spawn(read_loop(reader, codec, shared.clone()));
+        let writer = Arc::new(tokio::sync::Mutex::new(Writer {
+            stream: writer,
+            last_write: Instant::now(),
+        }));
+        if let Some(interval) = opts.keepalive {
+            tokio::spawn(keepalive_loop(Arc::downgrade(&writer), codec, interval));
+        }
+        Ok(Self {
+            writer,
+            codec,
+            shared,
+        })
+    }
+
+    /// Returns a receiver for every unsolicited packet (broadcasts, system
+    /// notices, and responses no request is waiting for). The first
+    /// subscriber also receives anything that arrived before it subscribed.
+    /// The receiver yields `None` once the connection closes.
+    pub fn subscribe(&self) -> mpsc::Receiver<Packet> {
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_BUF);
+        let mut subs = self.shared.subscribers.lock().unwrap();
+        if subs.is_empty() {
+            for pkt in self.shared.backlog.lock().unwrap().drain(..) {
+                tx.try_send(pkt).ok();
+            }
+        }
+        subs.push(tx);
+        rx
+    }
+
+    /// Calls `f` with each chat message broadcast to this connection.
+    /// The callbacks below all run on a task of their own, which ends when
+    /// the connection closes or the returned handle is aborted.
+    pub fn on_chat<F>(&self, f: F) -> JoinHandle<()>
+    where
+        F: FnMut(BroadcastPayload) + Send + 'static,
+    {
+        self.on_packet(|pkt| decode_packet(pkt, MessageType::Broadcast), f)
+    }
+
+    /// Calls `f` with the text of each system notice, presence notices
+    /// included.
+    pub fn on_system<F>(&self, f: F) -> JoinHandle<()>
+    where
+        F: FnMut(String) + Send + 'static,
+    {
+        self.on_packet(|pkt| system_payload(pkt).map(|sys| sys.message), f)
+    }
+
+    /// Calls `f` with each user who comes online.
+    pub fn on_user_join<F>(&self, f: F) -> JoinHandle<()>
+    where
+        F: FnMut(UserInfo) + Send + 'static,
+    {
+        self.on_packet(|pkt| presence_user(pkt, PresenceEvent::Join), f)
+    }
+
+    /// Calls `f` with each user who goes offline.
+    pub fn on_user_leave<F>(&self, f: F) -> JoinHandle<()>
+    where
+        F: FnMut(UserInfo) + Send + 'static,
+    {
+        self.on_packet(|pkt| presence_user(pkt, PresenceEvent::Leave), f)
+    }
+
+    /// Calls `f` with each direct message to (or echoed from) this user.
+    pub fn on_direct<F>(&self, f: F) -> JoinHandle<()>
+    where
+        F: FnMut(DirectMessage) + Send + 'static,
+    {
+        self.on_packet(|pkt| decode_packet(pkt, MessageType::Direct), f)
+    }
+
+    /// Calls `f` with the ids of each batch of expired messages.
+    pub fn on_deleted<F>(&self, f: F) -> JoinHandle<()>
+    where
+        F: FnMut(Vec<String>) + Send + 'static,
+    {
+        self.on_packet(
+            |pkt| {
+                let deleted: DeletedPayload = decode_packet(pkt, MessageType::Deleted)?;
+                Some(deleted.message_ids)
+            },
+            f,
+        )
+    }
+
+    /// Subscribes and calls `f` with every packet `decode` turns into a `T`.
+    fn on_packet<T, D, F>(&self, decode: D, mut f: F) -> JoinHandle<()>
+    where
+        D: Fn(Packet) -> Option<T> + Send + 'static,
+        F: FnMut(T) + Send + 'static,
+    {
+        let mut rx = self.subscribe();
+        tokio::spawn(async move {
+            while let Some(pkt) = rx.recv().await {
+                if let Some(event) = decode(pkt) {
+                    f(event);
+                }
+            }
+        })
+    }
+
+    /// Sends a packet without waiting for any reply.
+    pub async fn send(&self, msg_type: MessageType, payload: impl Serialize) -> Result<()> {
+        let data = self.codec.encode(&Packet::new(msg_type, payload)?)?;
+        self.writer.lock().await.write(&data).await?;
+        Ok(())
+    }
+
+    /// Sends a packet and waits for the server's `Response` to it.
+    pub async fn request(
+        &self,
+        msg_type: MessageType,
+        payload: impl Serialize,
+    ) -> Result<ResponsePayload> {
+        let (tx, rx) = oneshot::channel();
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut pkt = Packet::new(msg_type, payload)?;
+        pkt.id = Some(id);
+        let data = self.codec.encode(&pkt)?;
+        self.shared.pending.lock().unwrap().insert(id, tx);
+        if let Err(e) = self.writer.lock().await.write(&data).await {
+            self.shared.pending.lock().unwrap().remove(&id);
+            return Err(e.into());
+        }
+        rx.await.context("connection closed before response")
+    }
+
+    /// Announces this client's optional `features` and returns the server's
+    /// version and feature list; features on both lists are enabled. Fails
+    /// on servers that predate `hello`, which can be treated as "no features".
+    pub async fn hello(&self, features: &[&str]) -> Result<HelloPayload> {
+        let payload = HelloPayload {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            features: features.iter().map(|f| f.to_string()).collect(),
+            server_time: None,
+        };
+        decode_object(self.request(MessageType::Hello, payload).await?)
+    }
+
+    /// Registers a new account and logs in as it.
+    pub async fn register(&self, username: &str, password: &str) -> Result<UserInfo> {
+        let payload = AuthPayload {
+            username: username.to_string(),
+            password: password.to_string(),
+        };
+        decode_object(self.request(MessageType::Register, payload).await?)
+    }
+
+    pub async fn login(&self, username: &str, password: &str) -> Result<UserInfo> {
+        let payload = AuthPayload {
+            username: username.to_string(),
+            password: password.to_string(),
+        };
+        decode_object(self.request(MessageType::Login, payload).await?)
+    }
+
+    /// Starts a guest session under a temporary `guest-<tag>-<n>` name. Guests can
+    /// read but, unless the server allows it, not post. Fails if the server
+    /// doesn't accept guests.
+    pub async fn guest(&self) -> Result<UserInfo> {
+        decode_object(self.request(MessageType::Guest, serde_json::json!({})).await?)
+    }
+
+    /// Changes this account's username; the user ID stays the same.
+    pub async fn rename(&self, new_username: &str) -> Result<UserInfo> {
+        let payload = RenamePayload {
+            new_username: new_username.to_string(),
+        };
+        decode_object(self.request(MessageType::Rename, payload).await?)
+    }
+
+    pub async fn send_chat(&self, content: &str) -> Result<()> {
+        self.send_chat_formatted(content, None).await
+    }
+
+    /// Like [`Client::send_chat`], with a rendering hint for other clients.
+    pub async fn send_chat_formatted(
+        &self,
+        content: &str,
+        format: Option<MessageFormat>,
+    ) -> Result<()> {
+        let payload = ChatPayload {
+            content: content.to_string(),
+            format,
+            room: None,
+            ttl_secs: None,
+        };
+        self.send(MessageType::Chat, payload).await
+    }
+
+    /// Sends a chat message to a room joined with [`Client::join_room`].
+    pub async fn send_room_chat(&self, room: &str, content: &str) -> Result<()> {
+        let payload = ChatPayload {
+            content: content.to_string(),
+            format: None,
+            room: Some(room.to_string()),
+            ttl_secs: None,
+        };
+        self.send(MessageType::Chat, payload).await
+    }
+
+    /// Joins `room` (for this connection) and returns its recent messages.
+    /// Broadcasts for it then arrive with their `room` set.
+    pub async fn join_room(&self, room: &str) -> Result<JoinResult> {
+        let payload = RoomPayload {
+            room: room.to_string(),
+        };
+        decode_object(self.request(MessageType::Join, payload).await?)
+    }
+
+    pub async fn leave_room(&self, room: &str) -> Result<()> {
+        let payload = RoomPayload {
+            room: room.to_string(),
+        };
+        expect_success(self.request(MessageType::Leave, payload).await?)?;
+        Ok(())
+    }
+
+    /// Pins a message for everyone. Only its author or an admin may.
+    pub async fn pin(&self, message_id: &str) -> Result<()> {
+        let payload = PinPayload {
+            message_id: message_id.to_string(),
+        };
+        expect_success(self.request(MessageType::Pin, payload).await?)?;
+        Ok(())
+    }
+
+    pub async fn unpin(&self, message_id: &str) -> Result<()> {
+        let payload = PinPayload {
+            message_id: message_id.to_string(),
+        };
+        expect_success(self.request(MessageType::Unpin, payload).await?)?;
+        Ok(())
+    }
+
+    /// Every pinned message, oldest pin first.
+    pub async fn pinned(&self) -> Result<Vec<StoredMessage>> {
+        let list: PinnedList =
+            decode_object(self.request(MessageType::Pinned, serde_json::json!({})).await?)?;
+        Ok(list.pinned)
+    }
+
+    /// Sets `room`'s topic, or clears it if `topic` is empty. Admins only.
+    pub async fn set_topic(&self, room: &str, topic: &str) -> Result<()> {
+        let payload = TopicPayload {
+            room: room.to_string(),
+            topic: topic.to_string(),
+        };
+        expect_success(self.request(MessageType::Topic, payload).await?)?;
+        Ok(())
+    }
+
+    /// Sends a private message to an online user. Receipts for it arrive as
+    /// `receipt` packets on [`Client::subscribe`] if `hello` negotiated
+    /// [`FEATURE_RECEIPTS`].
+    pub async fn send_direct(&self, to: &str, content: &str) -> Result<DirectMessage> {
+        let payload = DirectPayload {
+            to: to.to_string(),
+            content: content.to_string(),
+        };
+        decode_object(self.request(MessageType::Direct, payload).await?)
+    }
+
+    /// Tells the sender of a received direct message that it was read.
+    pub async fn send_read_receipt(&self, message_id: &str) -> Result<()> {
+        let payload = ReceiptPayload {
+            message_id: message_id.to_string(),
+            kind: ReceiptKind::Read,
+        };
+        self.send(MessageType::Receipt, payload).await
+    }
+
+    pub async fn history(&self, limit: usize) -> Result<Vec<StoredMessage>> {
+        let payload = HistoryPayload {
+            limit,
+            include_system: false,
+        };
+        let resp = self.request(MessageType::History, payload).await?;
+        decode_data(expect_success(resp)?)
+    }
+
+    /// Fetches the messages after `since_id`; see [`SyncResult::complete`].
+    pub async fn sync(&self, since_id: &str) -> Result<SyncResult> {
+        let payload = SyncPayload {
+            since_id: since_id.to_string(),
+            include_system: false,
+        };
+        decode_object(self.request(MessageType::Sync, payload).await?)
+    }
+
+    /// Runs a search; page through large result sets with `limit`/`offset`.
+    pub async fn search(&self, query: SearchPayload) -> Result<SearchResult> {
+        decode_object(self.request(MessageType::Search, query).await?)
+    }
+
+    pub async fn users(&self) -> Result<Vec<UserInfo>> {
+        let resp = self.request(MessageType::Users, serde_json::json!({})).await?;
+        decode_data(expect_success(resp)?)
+    }
+
+    /// Pauses (or resumes) delivery of chat broadcasts to this connection.
+    pub async fn set_dnd(&self, enabled: bool) -> Result<()> {
+        expect_success(self.request(MessageType::Dnd, DndPayload { enabled }).await?)?;
+        Ok(())
+    }
+
+    /// Stops (or, with `blocked` false, resumes) delivery of `username`'s
+    /// chat messages to this account. Returns everyone now blocked.
+    pub async fn set_blocked(&self, username: &str, blocked: bool) -> Result<Vec<UserInfo>> {
+        let msg_type = if blocked {
+            MessageType::Block
+        } else {
+            MessageType::Unblock
+        };
+        let payload = BlockPayload {
+            username: username.to_string(),
+        };
+        let list: BlockList = decode_object(self.request(msg_type, payload).await?)?;
+        Ok(list.blocked)
+    }
+
+    pub async fn profile(&self, username: &str) -> Result<Profile> {
+        let payload = ProfilePayload {
+            username: username.to_string(),
+        };
+        decode_object(self.request(MessageType::Profile, payload).await?)
+    }
+
+    /// Sets the fields present in `update` (empty strings clear them) and
+    /// returns the account as others now see it.
+    pub async fn update_profile(&self, update: UpdateProfilePayload) -> Result<UserInfo> {
+        decode_object(self.request(MessageType::UpdateProfile, update).await?)
+    }
+
+    /// Sets the preferences present in `update` and returns them all. The
+    /// login response carries them too (see [`LoginResult`]).
+    pub async fn update_prefs(&self, update: UpdatePrefsPayload) -> Result<Prefs> {
+        decode_object(self.request(MessageType::UpdatePrefs, update).await?)
+    }
+
+    /// Users seen (logged in, chatting or leaving) within the last `hours`
+    /// (0 for the server default of 24).
+    pub async fn recent_users(&self, hours: u32) -> Result<RecentUsersResult> {
+        let payload = RecentUsersPayload { hours };
+        decode_object(self.request(MessageType::RecentUsers, payload).await?)
+    }
+
+    /// One page of every registered account, ordered by username (`limit`
+    /// 0 for the server default of 50).
+    pub async fn directory(&self, limit: usize, offset: usize) -> Result<DirectoryResult> {
+        let payload = DirectoryPayload { limit, offset };
+        decode_object(self.request(MessageType::Directory, payload).await?)
+    }
+
+    pub async fn server_time(&self) -> Result<DateTime<Utc>> {
+        let reply: TimeResult =
+            decode_object(self.request(MessageType::Time, serde_json::json!({})).await?)?;
+        Ok(reply.server_time)
+    }
+
+    /// Estimates how far the server's clock is ahead of this machine's (it
+    /// is negative if behind) from a `time` request, assuming the reply was
+    /// stamped halfway through the round trip.
+    pub async fn clock_offset(&self) -> Result<TimeDelta> {
+        let sent = Utc::now();
+        let server_time = self.server_time().await?;
+        let received = Utc::now();
+        Ok(server_time - (sent + (received - sent) / 2))
+    }
+
+    pub async fn stats(&self) -> Result<ServerStats> {
+        decode_object(self.request(MessageType::Stats, serde_json::json!({})).await?)
+    }
+
+    pub async fn whoami(&self) -> Result<SessionInfo> {
+        decode_object(self.request(MessageType::Whoami, serde_json::json!({})).await?)
+    }
+
+    pub async fn quit(&self) -> Result<()> {
+        self.send(MessageType::Quit, serde_json::json!({})).await?;
+        self.writer.lock().await.stream.shutdown().await?;
+        Ok(())
+    }
+}
+
+fn expect_success(resp: ResponsePayload) -> Result<ResponsePayload> {
+    if !resp.success {
+        anyhow::bail!("{}", resp.message);
+    }
+    Ok(resp)
+}
+
+fn decode_data<T: serde::de::DeserializeOwned>(resp: ResponsePayload) -> Result<T> {
+    let data = resp.data.unwrap_or(serde_json::Value::Array(Vec::new()));
+    Ok(serde_json::from_value(data)?)
+}
+
+/// Decodes a successful response whose `data` must be present.
+fn decode_object<T: serde::de::DeserializeOwned>(resp: ResponsePayload) -> Result<T> {
+    let data = expect_success(resp)?.data.context("response has no data")?;
+    Ok(serde_json::from_value(data)?)
+}
+
+/// The payload of `pkt` if it is a `msg_type` packet. One that fails to
+/// decode is logged and skipped.
+fn decode_packet<T>(pkt: Packet, msg_type: MessageType) -> Option<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    if pkt.msg_type != msg_type {
+        return None;
+    }
+    match serde_json::from_value(pkt.payload) {
+        Ok(payload) => Some(payload),
+        Err(e) => {
+            warn!(error = %e, msg_type = ?msg_type, "client: ignoring malformed payload");
+            None
+        }
+    }
+}
+
+fn system_payload(pkt: Packet) -> Option<SystemPayload> {
+    decode_packet(pkt, MessageType::System)
+}
+
+/// The user a presence notice of kind `event` is about.
+fn presence_user(pkt: Packet, event: PresenceEvent) -> Option<UserInfo> {
+    system_payload(pkt)?.presence.filter(|p| p.event == event).map(|p| p.user)
+}
+
+/// Sends a `compress` request and waits for its response, backlogging
+/// anything that arrives first. Returns the streams to use from then on.
+async fn negotiate_compression(
+    mut reader: BoxedReader,
+    mut writer: BoxedWriter,
+    codec: Codec,
+    shared: &Shared,
+) -> Result<(BoxedReader, BoxedWriter)> {
+    let payload = CompressPayload {
+        algorithm: COMPRESSION_ZLIB.to_string(),
+    };
+    writer.write_all(&codec.encode(&Packet::new(MessageType::Compress, payload)?)?).await?;
+
+    loop {
+        let frame = codec
+            .read_frame(&mut reader)
+            .await?
+            .context("connection closed during compression handshake")?;
+        let pkt: Packet = serde_json::from_slice(&frame)?;
+        if pkt.msg_type != MessageType::Response {
+            deliver(shared, pkt).await;
+            continue;
+        }
+        let resp: ResponsePayload = serde_json::from_value(pkt.payload)?;
+        if !resp.success {
+            debug!(reason = %resp.message, "client: server declined compression");
+            return Ok((reader, writer));
+        }
+        debug!("client: compression enabled");
+        return Ok((
+            Box::new(BufReader::new(ZlibDecoder::new(reader))),
+            Box::new(ZlibEncoder::new(writer)),
+        ));
+    }
+}
+
+/// Pings whenever the connection has been quiet for `interval`. Ends when
+/// the client is dropped or a write fails.
+async fn keepalive_loop(
+    writer: Weak<tokio::sync::Mutex<Writer>>,
+    codec: Codec,
+    interval: Duration,
+) {
+    let mut wait = interval;
+    loop {
+        tokio::time::sleep(wait).await;
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => return,
+        };
+        let mut writer = writer.lock().await;
+        wait = match until_keepalive(writer.last_write, Instant::now(), interval) {
+            Some(wait) => wait,
+            None => {
+                let ping = match Packet::new(MessageType::Ping, serde_json::json!({}))
+                    .and_then(|pkt| codec.encode(&pkt))
+                {
+                    Ok(ping) => ping,
+                    Err(_) => return,
+                };
+                if writer.write(&ping).await.is_err() {
+                    debug!("client: keepalive stopped, connection closed");
+                    return;
+                }
+                interval
+            }
+        };
+    }
+}
+
+/// How much longer the connection can stay quiet after a write at
+/// `last_write`, or `None` if a keepalive is due now.
+fn until_keepalive(last_write: Instant, now: Instant, interval: Duration) -> Option<Duration> {
+    interval
+        .checked_sub(now.saturating_duration_since(last_write))
+        .filter(|wait| !wait.is_zero())
+}
+
+async fn read_loop(mut reader: BoxedReader, codec: Codec, shared: Arc<Shared>) {
+    loop {
+        let frame = match codec.read_frame(&mut reader).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(e) => {
+                warn!(error = %e, "client: read failed");
+                break;
+            }
+        };
+        let pkt: Packet = match serde_json::from_slice(&frame) {
+            Ok(p) => p,
+            Err(e) => {
+                warn!(error = %e, "client: ignoring malformed packet");
+                continue;
+            }
+        };
+
+        if let (MessageType::Response, Some(id)) = (&pkt.msg_type, pkt.id) {
+            let waiter = shared.pending.lock().unwrap().remove(&id);
+            if let Some(waiter) = waiter {
+                if let Ok(resp) = serde_json::from_value::<ResponsePayload>(pkt.payload.clone()) {
+                    waiter.send(resp).ok();
+                    continue;
+                }
+            }
+        }
+
+        deliver(&shared, pkt).await;
+    }
+
+    debug!("client: connection closed");
+    // Dropping every sender ends the subscribers' streams; dropping the
+    // pending waiters fails any in-flight requests.
+    shared.subscribers.lock().unwrap().clear();
+    shared.pending.lock().unwrap().clear();
+}
+
+async fn deliver(shared: &Shared, pkt: Packet) {
+    let subs: Vec<mpsc::Sender<Packet>> = {
+        // Check and buffer under the subscribers lock so a concurrent
+        // `subscribe` can't miss a packet that lands in the backlog.
+        let subs = shared.subscribers.lock().unwrap();
+        if subs.is_empty() {
+            let mut backlog = shared.backlog.lock().unwrap();
+            if backlog.len() == BACKLOG_MAX {
+                backlog.pop_front();
+            }
+            backlog.push_back(pkt);
+            return;
+        }
+        subs.clone()
+    };
+
+    let mut closed = false;
+    for tx in &subs {
+        if tx.send(pkt.clone()).await.is_err() {
+            closed = true;
+        }
+    }
+    if closed {
+        shared.subscribers.lock().unwrap().retain(|tx| !tx.is_closed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncBufReadExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn keepalive_waits_out_the_quiet_interval() {
+        let interval = Duration::from_secs(30);
+        let start = Instant::now();
+        assert_eq!(until_keepalive(start, start, interval), Some(interval));
+        let later = start + Duration::from_secs(20);
+        assert_eq!(until_keepalive(start, later, interval), Some(Duration::from_secs(10)));
+        assert_eq!(until_keepalive(start, start + interval, interval), None);
+        assert_eq!(until_keepalive(start, start + interval * 3, interval), None);
+        // A write racing the check (stamped after `now`) counts as fresh.
+        assert_eq!(until_keepalive(later, start, interval), Some(interval));
+    }
+
+    /// The packet types a client connected with `keepalive` writes within
+    /// `listen` of sitting idle.
+    async fn sent_while_idle(keepalive: Option<Duration>, listen: Duration) -> Vec<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let opts = ConnectOptions {
+            keepalive,
+            ..ConnectOptions::default()
+        };
+        let client = Client::connect_with_options(listener.local_addr().unwrap(), opts);
+        let (client, accepted) = tokio::join!(client, listener.accept());
+        let _client = client.unwrap();
+        let mut lines = BufReader::new(accepted.unwrap().0).lines();
+        let mut types = Vec::new();
+        let deadline = tokio::time::sleep(listen);
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                line = lines.next_line() => {
+                    let pkt: Packet = serde_json::from_str(&line.unwrap().unwrap()).unwrap();
+                    types.push(serde_json::to_string(&pkt.msg_type).unwrap());
+                }
+                _ = &mut deadline => return types,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn an_idle_client_pings_only_when_asked_to() {
+        let pings = sent_while_idle(Some(Duration::from_millis(50)), Duration::from_millis(400));
+        let pings = pings.await;
+        assert!((3..=8).contains(&pings.len()), "sent: {:?}", pings);
+        assert!(pings.iter().all(|t| t == "\"ping\""), "sent: {:?}", pings);
+        assert!(sent_while_idle(None, Duration::from_millis(200)).await.is_empty());
+    }
+}
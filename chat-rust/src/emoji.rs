@@ -0,0 +1,180 @@
+//! `:shortcode:` → emoji expansion, applied by clients to what the user
+//! types before it is sent.
+//!
+//! A shortcode is a name of letters, digits, `_`, `+` or `-` between two
+//! colons. Names are matched case-insensitively. An unknown name is left as
+//! typed, and so is a lone or unclosed colon (`12:30`, `:smile`). The
+//! closing colon of an unknown name may open the next shortcode, so
+//! `a:b:smile:` becomes `a:b😄`. Every emoji in the table is no longer in
+//! bytes than its shortcode, so expanding never makes a message longer. A
+//! message that fit in a frame still does.
+
+/// Shortcodes and their emoji, sorted by name for [`lookup`].
+const EMOJI: &[(&str, &str)] = &[
+    ("+1", "👍"),
+    ("-1", "👎"),
+    ("100", "💯"),
+    ("angry", "😠"),
+    ("beer", "🍺"),
+    ("blush", "😊"),
+    ("boom", "💥"),
+    ("broken_heart", "💔"),
+    ("bug", "🐛"),
+    ("cake", "🍰"),
+    ("check", "✅"),
+    ("clap", "👏"),
+    ("coffee", "☕"),
+    ("cold_sweat", "😰"),
+    ("confused", "😕"),
+    ("cool", "😎"),
+    ("cry", "😢"),
+    ("eyes", "👀"),
+    ("fire", "🔥"),
+    ("grin", "😁"),
+    ("heart", "❤️"),
+    ("heart_eyes", "😍"),
+    ("hugs", "🤗"),
+    ("joy", "😂"),
+    ("kiss", "😘"),
+    ("laughing", "😆"),
+    ("muscle", "💪"),
+    ("neutral_face", "😐"),
+    ("ok_hand", "👌"),
+    ("party", "🥳"),
+    ("pensive", "😔"),
+    ("pizza", "🍕"),
+    ("pray", "🙏"),
+    ("question", "❓"),
+    ("rage", "😡"),
+    ("raised_hands", "🙌"),
+    ("rocket", "🚀"),
+    ("rofl", "🤣"),
+    ("scream", "😱"),
+    ("see_no_evil", "🙈"),
+    ("shrug", "🤷"),
+    ("skull", "💀"),
+    ("sleeping", "😴"),
+    ("slightly_smiling_face", "🙂"),
+    ("smile", "😄"),
+    ("smiley", "😃"),
+    ("smirk", "😏"),
+    ("sob", "😭"),
+    ("sparkles", "✨"),
+    ("star", "⭐"),
+    ("stuck_out_tongue", "😛"),
+    ("sunglasses", "😎"),
+    ("sweat_smile", "😅"),
+    ("tada", "🎉"),
+    ("thinking", "🤔"),
+    ("thumbsdown", "👎"),
+    ("thumbsup", "👍"),
+    ("tired_face", "😫"),
+    ("unamused", "😒"),
+    ("upside_down", "🙃"),
+    ("warning", "⚠️"),
+    ("wave", "👋"),
+    ("wink", "😉"),
+    ("x", "❌"),
+    ("yum", "😋"),
+    ("zzz", "💤"),
+];
+
+// Checked when compiling: the table is sorted, and no emoji is longer than
+// its shortcode.
+const _: () = {
+    let mut i = 0;
+    while i < EMOJI.len() {
+        let (name, emoji) = EMOJI[i];
+        assert!(emoji.len() <= name.len() + 2);
+        assert!(i == 0 || is_before(EMOJI[i - 1].0, name));
+        i += 1;
+    }
+};
+
+const fn is_before(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let mut i = 0;
+    while i < a.len() && i < b.len() {
+        if a[i] != b[i] {
+            return a[i] < b[i];
+        }
+        i += 1;
+    }
+    a.len() < b.len()
+}
+
+/// The emoji for a shortcode name (without colons), if there is one.
+pub fn lookup(name: &str) -> Option<&'static str> {
+    let name = name.to_ascii_lowercase();
+    EMOJI
+        .binary_search_by(|(n, _)| n.cmp(&name.as_str()))
+        .ok()
+        .map(|i| EMOJI[i].1)
+}
+
+/// `text` with every known shortcode replaced by its emoji.
+pub fn expand(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(open) = rest.find(':') {
+        out.push_str(&rest[..open]);
+        let after = &rest[open + 1..];
+        let emoji = after
+            .find(|c: char| !is_name_char(c))
+            .filter(|&end| end > 0 && after[end..].starts_with(':'))
+            .and_then(|end| Some((lookup(&after[..end])?, end)));
+        match emoji {
+            Some((emoji, end)) => {
+                out.push_str(emoji);
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push(':');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '+' | '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_shortcodes_expand_in_any_case() {
+        assert_eq!(expand(":smile:"), "😄");
+        assert_eq!(expand("ship it :ROCKET: now"), "ship it 🚀 now");
+        assert_eq!(expand(":+1: and :-1:"), "👍 and 👎");
+        assert_eq!(lookup("Heart_Eyes"), Some("😍"));
+    }
+
+    #[test]
+    fn adjacent_shortcodes_each_expand() {
+        assert_eq!(expand(":fire::fire:"), "🔥🔥");
+        assert_eq!(expand("a:b:smile:"), "a:b😄");
+        assert_eq!(expand(":nope::smile:"), ":nope:😄");
+    }
+
+    #[test]
+    fn unknown_and_partial_tokens_are_left_as_typed() {
+        for text in [":nope:", "12:30", ":smile", "smile:", "::", ":", ": smile :", ":smi le:"] {
+            assert_eq!(expand(text), text);
+        }
+        assert_eq!(lookup(""), None);
+    }
+
+    #[test]
+    fn expanding_never_lengthens_a_message() {
+        for (name, _) in EMOJI {
+            let text = format!("x:{}:y", name);
+            assert!(expand(&text).len() <= text.len(), "{} grew", text);
+        }
+        assert_eq!(expand("naïve ☕ :coffee:"), "naïve ☕ ☕");
+    }
+}
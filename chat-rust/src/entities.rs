@@ -0,0 +1,156 @@
+//! Finds `@mentions` and URLs in message content, so every client renders
+//! them the same way.
+//!
+//! A URL starts with `http://` or `https://` and runs to the next
+//! whitespace, minus trailing punctuation (`.,;:!?'"`) and any closing
+//! bracket without a matching opener inside the URL, so `(see
+//! https://example.com/a_(b))` keeps the inner parentheses but not the outer
+//! one. A mention is `@` followed by letters, digits, `_`, `-` or `.`, not
+//! preceded by a word character (so `bob@example.com` is not one) and not
+//! ending in `.` or `-`. Mentions inside URLs are ignored.
+
+use crate::protocol::{Entity, EntityKind};
+
+const URL_SCHEMES: [&str; 2] = ["https://", "http://"];
+const TRAILING_PUNCTUATION: &[char] = &['.', ',', ';', ':', '!', '?', '\'', '"'];
+
+/// Entities in `content`, in order and non-overlapping.
+pub fn extract(content: &str) -> Vec<Entity> {
+    let mut entities = Vec::new();
+    let mut pos = 0;
+    while pos < content.len() {
+        let rest = &content[pos..];
+        let prev = content[..pos].chars().next_back();
+        let at_boundary = prev.is_none_or(|c| !is_word_char(c));
+
+        if at_boundary {
+            if let Some(len) = url_len(rest) {
+                entities.push(Entity {
+                    kind: EntityKind::Url,
+                    start: pos,
+                    end: pos + len,
+                });
+                pos += len;
+                continue;
+            }
+            if let Some(len) = mention_len(rest) {
+                entities.push(Entity {
+                    kind: EntityKind::Mention,
+                    start: pos,
+                    end: pos + len,
+                });
+                pos += len;
+                continue;
+            }
+        }
+        pos += rest.chars().next().map_or(1, char::len_utf8);
+    }
+    entities
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn url_len(s: &str) -> Option<usize> {
+    let scheme = URL_SCHEMES.iter().find(|p| starts_with_ignore_case(s, p))?;
+    let end = s.find(char::is_whitespace).unwrap_or(s.len());
+    let mut url = &s[..end];
+    loop {
+        let trimmed = url.trim_end_matches(TRAILING_PUNCTUATION);
+        let trimmed = strip_unbalanced_close(trimmed);
+        if trimmed.len() == url.len() {
+            break;
+        }
+        url = trimmed;
+    }
+    (url.len() > scheme.len()).then_some(url.len())
+}
+
+/// Drops one trailing `)`, `]` or `>` if the URL has more closers of that
+/// kind than openers.
+fn strip_unbalanced_close(url: &str) -> &str {
+    for (open, close) in [('(', ')'), ('[', ']'), ('<', '>')] {
+        if url.ends_with(close) {
+            let opens = url.matches(open).count();
+            let closes = url.matches(close).count();
+            if closes > opens {
+                return &url[..url.len() - 1];
+            }
+        }
+    }
+    url
+}
+
+fn mention_len(s: &str) -> Option<usize> {
+    let name = s.strip_prefix('@')?;
+    let end = name
+        .find(|c: char| !(is_word_char(c) || c == '-' || c == '.'))
+        .unwrap_or(name.len());
+    let name = name[..end].trim_end_matches(['.', '-']);
+    (!name.is_empty()).then_some(1 + name.len())
+}
+
+fn starts_with_ignore_case(s: &str, prefix: &str) -> bool {
+    s.get(..prefix.len()).is_some_and(|p| p.eq_ignore_ascii_case(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Each entity in `content` as `@…` or `<…>` text.
+    fn found(content: &str) -> Vec<String> {
+        extract(content)
+            .into_iter()
+            .map(|e| {
+                let text = &content[e.start..e.end];
+                match e.kind {
+                    EntityKind::Mention => text.to_string(),
+                    EntityKind::Url => format!("<{}>", text),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mentions_stop_at_punctuation() {
+        assert_eq!(found("@alice, @bob! (@carol) @dave."), ["@alice", "@bob", "@carol", "@dave"]);
+        assert_eq!(found("ask @mary-jane.o_neil..."), ["@mary-jane.o_neil"]);
+        assert_eq!(found("héllo @zoë"), ["@zoë"]);
+        assert_eq!(found("@"), Vec::<String>::new());
+        assert_eq!(found("@-. @@"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn emails_and_mid_word_ats_are_not_mentions() {
+        assert!(found("mail bob@example.com").is_empty());
+        assert!(found("x_@y").is_empty());
+        assert_eq!(found("email:@bob"), ["@bob"]);
+    }
+
+    #[test]
+    fn urls_drop_trailing_punctuation_and_unmatched_brackets() {
+        assert_eq!(found("see https://example.com."), ["<https://example.com>"]);
+        assert_eq!(found("\"http://a.b/c?d=1&e=2\","), ["<http://a.b/c?d=1&e=2>"]);
+        assert_eq!(
+            found("(see https://en.wikipedia.org/wiki/A_(b)))"),
+            ["<https://en.wikipedia.org/wiki/A_(b)>"]
+        );
+        assert_eq!(found("[HTTPS://EXAMPLE.COM/x]"), ["<HTTPS://EXAMPLE.COM/x>"]);
+        assert_eq!(found("https:// nothing"), Vec::<String>::new());
+        assert!(found("ftp://example.com").is_empty());
+    }
+
+    #[test]
+    fn mentions_inside_urls_are_ignored() {
+        assert_eq!(
+            found("@ann https://x.com/@ann?by=@bob @bob"),
+            ["@ann", "<https://x.com/@ann?by=@bob>", "@bob"]
+        );
+        let content = "ping @ann at https://x.com";
+        let entities = extract(content);
+        assert_eq!((entities[0].start, entities[0].end), (5, 9));
+        assert_eq!(entities[1].end, content.len());
+    }
+}
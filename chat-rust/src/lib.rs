@@ -1,3 +1,8 @@
+pub mod client;
+pub mod emoji;
+pub mod entities;
 pub mod protocol;
+pub mod query;
+pub mod schema;
 pub mod store;
 pub mod server;
@@ -1,22 +1,65 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt};
 
 /// MessageType identifies what kind of packet is being sent.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum MessageType {
     // Client → Server
+    Hello,
     Register,
     Login,
+    /// Starts a temporary, unregistered session (when the server allows it).
+    Guest,
     Chat,
+    /// Also server → client, delivering a [`DirectMessage`].
+    Direct,
+    /// Also server → client, relaying a receipt to the DM's sender.
+    Receipt,
     Search,
     History,
+    Sync,
     Users,
+    Purge,
+    Admin,
+    Rename,
+    Dnd,
+    /// Sets or clears the caller's away message.
+    Away,
+    Block,
+    Unblock,
+    Profile,
+    UpdateProfile,
+    /// Changes the caller's [`Prefs`].
+    UpdatePrefs,
+    RecentUsers,
+    Directory,
+    Whoami,
+    Stats,
+    Compress,
+    /// Keepalive; the server does nothing with it beyond counting the
+    /// connection as active.
+    Ping,
+    Time,
+    Join,
+    Leave,
+    Pin,
+    Unpin,
+    Pinned,
+    /// Sets a room's topic; admins only.
+    Topic,
     Quit,
     // Server → Client
     Response,
     Broadcast,
     System,
+    /// Messages that have been deleted (expired), as a [`DeletedPayload`].
+    Deleted,
 }
 
 /// Every packet is a single JSON object followed by a newline character (\n).
@@ -25,6 +68,11 @@ pub struct Packet {
     #[serde(rename = "type")]
     pub msg_type: MessageType,
     pub payload: serde_json::Value,
+    /// Optional request id chosen by the client. The server copies it onto
+    /// the `response` to that request, so responses can be told apart from
+    /// the ones it sends unasked (such as a rejected chat message).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
 }
 
 impl Packet {
@@ -32,6 +80,7 @@ impl Packet {
         Ok(Self {
             msg_type,
             payload: serde_json::to_value(payload)?,
+            id: None,
         })
     }
 
@@ -40,6 +89,180 @@ impl Packet {
     }
 }
 
+/// How packets are delimited on the wire. Both ends must agree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Framing {
+    /// One JSON object per line.
+    #[default]
+    Newline,
+    /// A 4-byte big-endian length followed by that many bytes of JSON.
+    Length,
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "newline" => Ok(Framing::Newline),
+            "length" => Ok(Framing::Length),
+            _ => Err(format!("unknown framing {:?} (expected newline or length)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Framing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Framing::Newline => "newline",
+            Framing::Length => "length",
+        })
+    }
+}
+
+/// Default cap on a single inbound frame body.
+pub const DEFAULT_MAX_FRAME: usize = 64 * 1024;
+
+/// Encodes packets into frames and reads frames back off a stream.
+#[derive(Debug, Clone, Copy)]
+pub struct Codec {
+    pub framing: Framing,
+    /// Frames longer than this are rejected with `ErrorKind::InvalidData`
+    /// before they are buffered.
+    pub max_frame: usize,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Self::new(Framing::default())
+    }
+}
+
+impl Codec {
+    pub fn new(framing: Framing) -> Self {
+        Self {
+            framing,
+            max_frame: DEFAULT_MAX_FRAME,
+        }
+    }
+
+    pub fn with_max_frame(mut self, max_frame: usize) -> Self {
+        self.max_frame = max_frame;
+        self
+    }
+
+    /// Serializes `pkt` and wraps it in a frame ready to write.
+    pub fn encode(&self, pkt: &Packet) -> anyhow::Result<Vec<u8>> {
+        self.frame(pkt.encode()?)
+    }
+
+    /// Wraps an already encoded packet in a frame.
+    pub fn frame(&self, body: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        Ok(match self.framing {
+            Framing::Newline => {
+                let mut data = body;
+                data.push(b'\n');
+                data
+            }
+            Framing::Length => {
+                let len = u32::try_from(body.len())?;
+                let mut data = Vec::with_capacity(4 + body.len());
+                data.extend_from_slice(&len.to_be_bytes());
+                data.extend_from_slice(&body);
+                data
+            }
+        })
+    }
+
+    /// Reads the next frame body, or `None` on a clean end of stream, i.e.
+    /// one between frames. A stream that ends partway through a frame (a
+    /// last line with no newline, or a short length prefix or body) fails
+    /// with `ErrorKind::UnexpectedEof`, and the partial frame is discarded.
+    /// A frame over `max_frame` bytes fails with `ErrorKind::InvalidData`
+    /// without buffering more than the limit.
+    pub async fn read_frame<R: AsyncBufRead + Unpin>(
+        &self,
+        r: &mut R,
+    ) -> io::Result<Option<Vec<u8>>> {
+        match self.framing {
+            Framing::Newline => {
+                let mut buf = Vec::new();
+                loop {
+                    let available = r.fill_buf().await?;
+                    if available.is_empty() {
+                        if buf.is_empty() {
+                            return Ok(None);
+                        }
+                        return Err(truncated(buf.len()));
+                    }
+                    let (chunk, done) = match available.iter().position(|&b| b == b'\n') {
+                        Some(i) => (&available[..i], true),
+                        None => (available, false),
+                    };
+                    if buf.len() + chunk.len() > self.max_frame {
+                        return Err(self.too_large());
+                    }
+                    buf.extend_from_slice(chunk);
+                    let used = chunk.len() + done as usize;
+                    r.consume(used);
+                    if done {
+                        break;
+                    }
+                }
+                if buf.last() == Some(&b'\r') {
+                    buf.pop();
+                }
+                Ok(Some(buf))
+            }
+            Framing::Length => {
+                let mut len = [0u8; 4];
+                match read_full(r, &mut len).await? {
+                    0 => return Ok(None),
+                    4 => {}
+                    got => return Err(truncated(got)),
+                }
+                let len = u32::from_be_bytes(len) as usize;
+                if len > self.max_frame {
+                    return Err(self.too_large());
+                }
+                let mut buf = vec![0u8; len];
+                let got = read_full(r, &mut buf).await?;
+                if got < len {
+                    return Err(truncated(4 + got));
+                }
+                Ok(Some(buf))
+            }
+        }
+    }
+
+    fn too_large(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("packet exceeds {} bytes", self.max_frame),
+        )
+    }
+}
+
+/// Fills `buf` from `r`, stopping short only at the end of the stream.
+/// Returns how many bytes were read.
+async fn read_full<R: AsyncRead + Unpin>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut got = 0;
+    while got < buf.len() {
+        match r.read(&mut buf[got..]).await? {
+            0 => break,
+            n => got += n,
+        }
+    }
+    Ok(got)
+}
+
+fn truncated(bytes: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!("stream ended mid-packet, {} bytes discarded", bytes),
+    )
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthPayload {
     pub username: String,
@@ -49,6 +272,111 @@ pub struct AuthPayload {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatPayload {
     pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<MessageFormat>,
+    /// A room the sender has joined; `None` is the lobby everyone is in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    /// Delete the message this many seconds after sending (1 to
+    /// [`MAX_TTL_SECS`]). `None` keeps it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_secs: Option<u64>,
+}
+
+/// Longest `ttl_secs` accepted on a chat message (a week).
+pub const MAX_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Most characters in a room name.
+pub const MAX_ROOM_NAME: usize = 32;
+
+/// `join` / `leave` request, and `Response.data` for `leave`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomPayload {
+    pub room: String,
+}
+
+/// `Response.data` for a `join` request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JoinResult {
+    /// The room's canonical name.
+    pub room: String,
+    /// The room's most recent messages, oldest first.
+    pub messages: Vec<StoredMessage>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<RoomTopic>,
+}
+
+/// Most characters in a room topic.
+pub const MAX_TOPIC: usize = 200;
+
+/// `topic` request: sets `room`'s topic, or clears it if `topic` is empty.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopicPayload {
+    pub room: String,
+    pub topic: String,
+}
+
+/// A room's topic, as stored, sent on join and set on the notice that
+/// announces a change. An empty `topic` means it was cleared.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomTopic {
+    pub room: String,
+    pub topic: String,
+    /// Username of the admin who set it.
+    pub set_by: String,
+    pub set_at: DateTime<Utc>,
+}
+
+/// The canonical form of a room name: lowercase, without a leading `#`, and
+/// only letters, digits, `-` and `_`. `None` if the name is invalid.
+pub fn normalize_room(name: &str) -> Option<String> {
+    let name = name.trim();
+    let name = name.strip_prefix('#').unwrap_or(name).to_lowercase();
+    let valid = !name.is_empty()
+        && name.chars().count() <= MAX_ROOM_NAME
+        && name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+    valid.then_some(name)
+}
+
+/// `pin` / `unpin` request. Only the message's author or an admin may pin it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PinPayload {
+    pub message_id: String,
+}
+
+/// `Response.data` for a `pinned` request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PinnedList {
+    /// Oldest pin first, across the lobby and every room.
+    pub pinned: Vec<StoredMessage>,
+}
+
+/// Set on the notice broadcast when a message is pinned or unpinned.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PinEvent {
+    pub pinned: bool,
+    pub message: StoredMessage,
+    /// Who pinned or unpinned it.
+    pub by: UserInfo,
+}
+
+/// Sent to everyone when messages expire; clients should stop showing them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeletedPayload {
+    pub message_ids: Vec<String>,
+}
+
+/// How a chat message's content is meant to be rendered. The server only
+/// stores and relays it; messages without one are plain text.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageFormat {
+    #[default]
+    Plain,
+    /// `**bold**`, `*italic*`, `` `code` `` and fenced code blocks.
+    Markdown,
+    /// The whole message is code, shown verbatim.
+    Code,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -61,11 +389,377 @@ pub struct SearchPayload {
     pub from: Option<DateTime<Utc>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub to: Option<DateTime<Utc>>,
+    /// Also match persisted system events (see `--persist-system`).
+    #[serde(default)]
+    pub include_system: bool,
+    /// Treat `query` as a regular expression instead of search terms.
+    #[serde(default)]
+    pub regex: bool,
+    /// Most results to return; 0 means the server default (100). The server
+    /// caps this at 500.
+    #[serde(default)]
+    pub limit: usize,
+    /// Matches to skip, newest first, for paging.
+    #[serde(default)]
+    pub offset: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HistoryPayload {
     pub limit: usize,
+    /// Include persisted system events alongside chat messages.
+    #[serde(default)]
+    pub include_system: bool,
+}
+
+/// Requests every message stored after `since_id` (a message id the client
+/// has already seen).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncPayload {
+    pub since_id: String,
+    #[serde(default)]
+    pub include_system: bool,
+}
+
+/// `Response.data` for a `sync` request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncResult {
+    pub messages: Vec<StoredMessage>,
+    /// False when `messages` is not the exact delta: the cursor was unknown
+    /// (e.g. pruned), so recent history was sent instead, or the delta was
+    /// cut to its newest part.
+    pub complete: bool,
+}
+
+/// `Response.data` for a `search` request: one page of matches, newest first.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub messages: Vec<StoredMessage>,
+    /// Matches before `limit`/`offset` were applied.
+    pub total: usize,
+}
+
+/// Admin-only. Deletes persisted messages older than `before`, or all of them
+/// when `before` is omitted.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PurgePayload {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<DateTime<Utc>>,
+}
+
+/// `Response.data` for a `stats` request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerStats {
+    /// Messages currently in the store.
+    pub messages: usize,
+    /// Registered accounts.
+    pub users: usize,
+    /// Users currently logged in.
+    pub online: usize,
+    pub uptime_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProfilePayload {
+    pub username: String,
+}
+
+/// Changes the caller's optional profile fields. An omitted field is left
+/// as is; an empty string clears it. The username is not affected.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdateProfilePayload {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<String>,
+}
+
+/// Which incoming messages should alert the user. The server only keeps
+/// the setting; clients decide what an alert is (a bell, a desktop
+/// notification) and apply it with [`NotifyLevel::notifies`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifyLevel {
+    /// Every chat and direct message from someone else.
+    All,
+    /// Direct messages, and chat that mentions the user.
+    #[default]
+    Mentions,
+    /// Nothing.
+    None,
+}
+
+impl NotifyLevel {
+    /// Whether a message from someone else should alert the user. `mention`
+    /// is true for direct messages and chat that mentions them.
+    pub fn notifies(self, mention: bool) -> bool {
+        match self {
+            NotifyLevel::All => true,
+            NotifyLevel::Mentions => mention,
+            NotifyLevel::None => false,
+        }
+    }
+}
+
+impl FromStr for NotifyLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(NotifyLevel::All),
+            "mentions" => Ok(NotifyLevel::Mentions),
+            "none" => Ok(NotifyLevel::None),
+            _ => Err(format!("unknown notify level {:?} (expected all, mentions or none)", s)),
+        }
+    }
+}
+
+impl fmt::Display for NotifyLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NotifyLevel::All => "all",
+            NotifyLevel::Mentions => "mentions",
+            NotifyLevel::None => "none",
+        })
+    }
+}
+
+/// Per-account settings the server keeps, so they follow the user from
+/// client to client. `Response.data` for an `updateprefs` request.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Prefs {
+    pub notify: NotifyLevel,
+}
+
+impl Prefs {
+    pub fn is_default(&self) -> bool {
+        *self == Prefs::default()
+    }
+}
+
+/// Changes the caller's preferences. An omitted field is left as is, so an
+/// empty payload just returns the current ones.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdatePrefsPayload {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notify: Option<NotifyLevel>,
+}
+
+/// `Response.data` for a successful `login` or `register`: the account as
+/// others see it, plus its preferences.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LoginResult {
+    #[serde(flatten)]
+    pub user: UserInfo,
+    #[serde(default)]
+    pub prefs: Prefs,
+}
+
+/// `Response.data` for a `profile` request. Public account details only.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    pub username: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// Chat messages by this user currently in the store.
+    pub message_count: usize,
+    /// When the user last logged in, sent a message or disconnected; `None`
+    /// if never seen since this was tracked.
+    pub last_seen: Option<DateTime<Utc>>,
+    pub online: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RecentUsersPayload {
+    /// How far back to look; 0 means 24 hours.
+    #[serde(default)]
+    pub hours: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecentUser {
+    #[serde(flatten)]
+    pub user: UserInfo,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// `Response.data` for a `recentusers` request, most recently seen first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecentUsersResult {
+    pub since: DateTime<Utc>,
+    pub users: Vec<RecentUser>,
+}
+
+/// `directory` request: one page of every registered account, ordered by
+/// username.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DirectoryPayload {
+    /// Most accounts to return; 0 means the server default (50). The server
+    /// caps this at 200.
+    #[serde(default)]
+    pub limit: usize,
+    /// Accounts to skip, for paging.
+    #[serde(default)]
+    pub offset: usize,
+}
+
+/// One account in a [`DirectoryResult`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectoryEntry {
+    #[serde(flatten)]
+    pub user: UserInfo,
+    pub created_at: DateTime<Utc>,
+    pub online: bool,
+}
+
+/// `Response.data` for a `directory` request.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DirectoryResult {
+    pub users: Vec<DirectoryEntry>,
+    /// Registered accounts in all, before `limit`/`offset` were applied.
+    pub total: usize,
+}
+
+/// Payload of a `system` packet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SystemPayload {
+    pub message: String,
+    /// Set on join/leave/rename notices so clients can track who is online
+    /// without parsing `message`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence: Option<Presence>,
+    /// Set on pin/unpin notices so clients can keep a pinned-messages bar.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pin: Option<PinEvent>,
+    /// Set on the notice sent to a room's members when its topic changes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<RoomTopic>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Presence {
+    pub event: PresenceEvent,
+    /// The user as they are after the event (the new name for a rename).
+    pub user: UserInfo,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceEvent {
+    Join,
+    Leave,
+    Rename,
+    /// Display name, status or avatar changed.
+    Profile,
+}
+
+/// Do-not-disturb: while enabled the server stops sending chat broadcasts to
+/// this connection. Responses and system notices still arrive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DndPayload {
+    pub enabled: bool,
+}
+
+/// Marks the caller away with `message`, which anyone who sends them a
+/// direct message is told; empty clears it. Sending a chat or direct
+/// message clears it too.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AwayPayload {
+    #[serde(default)]
+    pub message: String,
+}
+
+/// Most characters in an away message.
+pub const MAX_AWAY_MESSAGE: usize = 200;
+
+/// Target of `block` / `unblock`. A blocked user's chat messages are no
+/// longer delivered to the blocker; blocks are stored with the account.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockPayload {
+    pub username: String,
+}
+
+/// `Response.data` for `block` / `unblock`: everyone now blocked.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockList {
+    pub blocked: Vec<UserInfo>,
+}
+
+/// Sent by a client (optionally, before anything else) to announce its
+/// version and the optional protocol features it understands; the server
+/// answers with its own in `Response.data`. Features offered by both sides
+/// are enabled for the connection. A client that never says hello gets none.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HelloPayload {
+    pub version: String,
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// The server's clock when it answered; clients leave it out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_time: Option<DateTime<Utc>>,
+}
+
+/// `Response.data` for a `time` request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimeResult {
+    pub server_time: DateTime<Utc>,
+}
+
+/// Delivery and read receipts for direct messages the client sends.
+pub const FEATURE_RECEIPTS: &str = "receipts";
+
+/// `profile` presence events (profile field changes). Clients that don't
+/// know the event would fail to parse the notice.
+pub const FEATURE_PROFILES: &str = "profiles";
+/// The `compress` request is available (only when the server enables it).
+pub const FEATURE_COMPRESSION: &str = "compression";
+/// The client shows its own chat messages as it sends them, so the server
+/// leaves that connection out of their broadcast. Without it the sender
+/// gets the broadcast like everyone else.
+pub const FEATURE_LOCAL_ECHO: &str = "local_echo";
+
+/// Only algorithm understood by `compress`.
+pub const COMPRESSION_ZLIB: &str = "zlib";
+
+/// Asks the server to compress the rest of the connection. On a successful
+/// response both directions switch to a zlib stream immediately after it;
+/// on failure the connection stays uncompressed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompressPayload {
+    pub algorithm: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RenamePayload {
+    pub new_username: String,
+}
+
+/// Admin-only change to a server-wide setting, tagged by `action`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum AdminPayload {
+    /// Minimum seconds between chat messages from each user; 0 turns slow
+    /// mode off.
+    SlowMode { seconds: u64 },
+    /// Refuse (or, with `enabled` false, accept again) every request that
+    /// would change stored data.
+    ReadOnly { enabled: bool },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Admin,
+    /// A `guest` session: read-only, unless the server lets guests chat.
+    Guest,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -74,14 +768,106 @@ pub struct ResponsePayload {
     pub message: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub data: Option<serde_json::Value>,
+    /// Set when `data` lists fewer messages than were asked for because
+    /// more wouldn't fit in one response.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+/// A private message to one online user. The sender's response carries the
+/// resulting [`DirectMessage`], including the id receipts refer to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectPayload {
+    /// Recipient's username.
+    pub to: String,
+    pub content: String,
+}
+
+/// A direct message as delivered to its recipient (and echoed to the sender).
+/// Direct messages are not stored in the public history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectMessage {
+    pub id: String,
+    /// Sender.
+    pub user_id: String,
+    pub username: String,
+    /// Recipient's username.
+    pub to: String,
+    pub content: String,
+    pub timestamp: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub entities: Vec<Entity>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReceiptKind {
+    /// The server handed the message to the recipient's connection.
+    Delivered,
+    /// The recipient's client showed the message.
+    Read,
+}
+
+/// A recipient's client sends `read` receipts; the server sends both kinds
+/// to the sender, if it negotiated [`FEATURE_RECEIPTS`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReceiptPayload {
+    pub message_id: String,
+    pub kind: ReceiptKind,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BroadcastPayload {
+    /// The stored message's id, usable as a `sync` cursor.
+    #[serde(default)]
+    pub id: String,
     pub user_id: String,
     pub username: String,
     pub content: String,
     pub timestamp: DateTime<Utc>,
+    /// Mentions and links in `content`; see [`crate::entities`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub entities: Vec<Entity>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<MessageFormat>,
+    /// The room it was sent to; `None` for the lobby.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    /// When the message will be deleted, if it was sent with a TTL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// The stored message's `seq`. Broadcasts go out in this order.
+    #[serde(default)]
+    pub seq: u64,
+}
+
+/// A span of message content with a meaning of its own. `start..end` are
+/// UTF-8 byte offsets into the content and always fall on char boundaries.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entity {
+    pub kind: EntityKind,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntityKind {
+    /// `@name`, including the `@`.
+    Mention,
+    /// An `http(s)://` link.
+    Url,
+}
+
+/// What a [`StoredMessage`] records. Files written before system events were
+/// persisted have no `kind` and load as `Chat`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageKind {
+    #[default]
+    Chat,
+    /// A join/leave/server notice; `user_id` and `username` are empty.
+    System,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -91,10 +877,161 @@ pub struct StoredMessage {
     pub username: String,
     pub content: String,
     pub timestamp: DateTime<Utc>,
+    #[serde(default)]
+    pub kind: MessageKind,
+    /// Mentions and links in `content`. Absent on messages stored before
+    /// entities were extracted.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub entities: Vec<Entity>,
+    /// The rendering hint the sender gave, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<MessageFormat>,
+    /// The room it was sent to; `None` for the lobby.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    /// When the message is deleted, if it was sent with a TTL. It is left
+    /// out of history and search from then on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Server-wide sequence number, assigned when the message is posted and
+    /// increasing with every message; history is kept in this order. 0 on
+    /// messages stored before sequence numbers were assigned.
+    #[serde(default)]
+    pub seq: u64,
+}
+
+impl StoredMessage {
+    /// Whether the message has passed its expiry at `now`.
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
 }
 
+/// `Response.data` for a `whoami` request.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub user_id: String,
+    pub username: String,
+    pub role: Role,
+    pub connected_since: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct UserInfo {
     pub user_id: String,
     pub username: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_text: Option<String>,
+    /// A single emoji.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::io::BufReader;
+
+    use super::*;
+
+    /// Every frame `codec` reads from `data`, up to the clean end.
+    async fn read_all(codec: Codec, data: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        let mut r = BufReader::new(data);
+        let mut frames = Vec::new();
+        while let Some(frame) = codec.read_frame(&mut r).await? {
+            frames.push(frame);
+        }
+        Ok(frames)
+    }
+
+    #[tokio::test]
+    async fn length_framing_carries_embedded_newlines() {
+        let codec = Codec::new(Framing::Length);
+        let pkt = Packet::new(MessageType::Chat, json!({ "content": "line one\nline two\n" }))
+            .unwrap();
+        // A body with raw newline bytes, which newline framing would split.
+        let raw = b"{\"type\":\"ping\",\n\"payload\":{}}".to_vec();
+        let mut data = codec.encode(&pkt).unwrap();
+        data.extend(codec.frame(raw.clone()).unwrap());
+        assert_eq!(data[..4], (pkt.encode().unwrap().len() as u32).to_be_bytes());
+
+        let frames = read_all(codec, &data).await.unwrap();
+        assert_eq!(frames.len(), 2);
+        let back: Packet = serde_json::from_slice(&frames[0]).unwrap();
+        assert_eq!(back.msg_type, MessageType::Chat);
+        assert_eq!(back.payload["content"], "line one\nline two\n");
+        assert_eq!(frames[1], raw);
+        let back: Packet = serde_json::from_slice(&frames[1]).unwrap();
+        assert_eq!(back.msg_type, MessageType::Ping);
+
+        let line = [&raw[..], b"\n"].concat();
+        let as_lines = read_all(Codec::new(Framing::Newline), &line).await.unwrap();
+        assert_eq!(as_lines.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn newline_framing_escapes_newlines_in_content() {
+        let codec = Codec::default();
+        let pkt = Packet::new(MessageType::Chat, json!({ "content": "a\nb" })).unwrap();
+        let data = codec.encode(&pkt).unwrap();
+        assert_eq!(data.iter().filter(|&&b| b == b'\n').count(), 1);
+        let frames = read_all(codec, &data).await.unwrap();
+        let back: Packet = serde_json::from_slice(&frames[0]).unwrap();
+        assert_eq!(back.payload["content"], "a\nb");
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_fail_without_being_buffered() {
+        // An endless line would never finish if it were buffered whole.
+        let codec = Codec::default().with_max_frame(1024);
+        let mut endless = BufReader::new(tokio::io::repeat(b'a'));
+        let err = codec.read_frame(&mut endless).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "packet exceeds 1024 bytes");
+
+        // Refused on the prefix alone, before allocating 4 GiB.
+        let codec = Codec::new(Framing::Length).with_max_frame(1024);
+        let err = read_all(codec, &u32::MAX.to_be_bytes()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let at_limit = [vec![b'a'; 1024], b"\n".to_vec()].concat();
+        let frames = read_all(Codec::default().with_max_frame(1024), &at_limit).await.unwrap();
+        assert_eq!(frames[0].len(), 1024);
+    }
+
+    #[tokio::test]
+    async fn a_stream_cut_off_mid_packet_is_an_error() {
+        let truncated = |err: io::Error, bytes: usize| {
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+            let message = format!("stream ended mid-packet, {} bytes discarded", bytes);
+            assert_eq!(err.to_string(), message);
+        };
+        let codec = Codec::default();
+        let data = b"{\"type\":\"ping\",\"payload\":{}}\n{\"type\":\"chat\"";
+        truncated(read_all(codec, data).await.unwrap_err(), 14);
+        let mut r = BufReader::new(&data[..]);
+        assert!(codec.read_frame(&mut r).await.unwrap().is_some());
+        assert!(codec.read_frame(&mut r).await.is_err());
+
+        let codec = Codec::new(Framing::Length);
+        truncated(read_all(codec, &[0, 0]).await.unwrap_err(), 2);
+        let short_body = [&10u32.to_be_bytes()[..], b"{\"ty"].concat();
+        truncated(read_all(codec, &short_body).await.unwrap_err(), 8);
+
+        // Ending between frames is the clean end, in either framing.
+        assert!(read_all(Codec::default(), b"").await.unwrap().is_empty());
+        assert!(read_all(codec, b"").await.unwrap().is_empty());
+        let whole = codec.frame(b"{}".to_vec()).unwrap();
+        assert_eq!(read_all(codec, &whole).await.unwrap(), [b"{}".to_vec()]);
+    }
+
+    #[test]
+    fn framing_parses_from_the_flag() {
+        assert_eq!("length".parse::<Framing>(), Ok(Framing::Length));
+        assert_eq!("newline".parse::<Framing>(), Ok(Framing::Newline));
+        assert!("lines".parse::<Framing>().is_err());
+        assert_eq!(Framing::default(), Framing::Newline);
+    }
 }
@@ -0,0 +1,237 @@
+//! Search query syntax for message content.
+//!
+//! Whitespace-separated terms must all appear (AND); `"quoted phrases"`
+//! match as one substring, spaces included; and a bare `OR` splits the query
+//! into alternatives. `OR` binds loosest, so `a b OR c` means `(a AND b) OR c`.
+//! Matching is case-insensitive.
+//!
+//! [`Query::regex`] instead treats the whole input as a regular expression.
+
+use std::ops::Range;
+
+use anyhow::{bail, Result};
+use regex::{Regex, RegexBuilder};
+
+/// Longest regex pattern accepted, in bytes.
+const MAX_REGEX_LEN: usize = 256;
+/// Cap on the compiled program (and lazy DFA cache), so patterns like
+/// `(\w{100}){100}` are refused instead of eating memory.
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// A parsed query.
+#[derive(Debug, Clone)]
+pub struct Query {
+    matcher: Matcher,
+    /// Finds every term (or the regex) in a line, for highlighting.
+    highlight: Option<Regex>,
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    /// Any group matches if all of its terms do.
+    Terms(Vec<Vec<String>>),
+    Regex(Regex),
+}
+
+impl Query {
+    pub fn parse(input: &str) -> Self {
+        let mut groups = Vec::new();
+        let mut group = Vec::new();
+        let mut chars = input.chars().peekable();
+        loop {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            let first = match chars.peek() {
+                Some(&c) => c,
+                None => break,
+            };
+            if first == '"' {
+                chars.next();
+                // An unterminated quote runs to the end of the input.
+                let phrase: String = chars.by_ref().take_while(|&c| c != '"').collect();
+                if !phrase.is_empty() {
+                    group.push(phrase.to_lowercase());
+                }
+                continue;
+            }
+            let mut word = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != '"') {
+                word.push(c);
+            }
+            if word == "OR" {
+                if !group.is_empty() {
+                    groups.push(std::mem::take(&mut group));
+                }
+            } else {
+                group.push(word.to_lowercase());
+            }
+        }
+        if !group.is_empty() {
+            groups.push(group);
+        }
+        let highlight = terms_regex(&groups);
+        Self {
+            matcher: Matcher::Terms(groups),
+            highlight,
+        }
+    }
+
+    /// Compiles `pattern` as a case-insensitive regex (`(?-i)` turns that
+    /// off). Errors on overlong or invalid patterns.
+    pub fn regex(pattern: &str) -> Result<Self> {
+        if pattern.len() > MAX_REGEX_LEN {
+            bail!("regex is too long (max {} bytes)", MAX_REGEX_LEN);
+        }
+        let re = RegexBuilder::new(pattern)
+            .case_insensitive(true)
+            .size_limit(REGEX_SIZE_LIMIT)
+            .dfa_size_limit(REGEX_SIZE_LIMIT)
+            .build();
+        match re {
+            Ok(re) => Ok(Self {
+                highlight: Some(re.clone()),
+                matcher: Matcher::Regex(re),
+            }),
+            Err(regex::Error::CompiledTooBig(_)) => bail!("regex is too complex"),
+            Err(e) => {
+                // Syntax errors render as several lines pointing at the
+                // problem; the last one says what it is.
+                let e = e.to_string();
+                let reason = e.lines().last().unwrap_or_default();
+                bail!("invalid regex: {}", reason.trim_start_matches("error: "))
+            }
+        }
+    }
+
+    /// True if the query has no terms (and so matches everything).
+    pub fn is_empty(&self) -> bool {
+        match &self.matcher {
+            Matcher::Terms(groups) => groups.is_empty(),
+            Matcher::Regex(re) => re.as_str().is_empty(),
+        }
+    }
+
+    pub fn matches(&self, content: &str) -> bool {
+        match &self.matcher {
+            Matcher::Terms(groups) if groups.is_empty() => true,
+            Matcher::Terms(groups) => {
+                let content = content.to_lowercase();
+                groups
+                    .iter()
+                    .any(|group| group.iter().all(|term| content.contains(term.as_str())))
+            }
+            Matcher::Regex(re) => re.is_match(content),
+        }
+    }
+
+    /// Byte ranges of `content` that the query's terms (from any
+    /// alternative) or regex match, in order and non-overlapping.
+    pub fn match_ranges(&self, content: &str) -> Vec<Range<usize>> {
+        match &self.highlight {
+            Some(re) => re
+                .find_iter(content)
+                .map(|m| m.range())
+                .filter(|r| !r.is_empty())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// One case-insensitive alternation of every term, longest first so a term
+/// that contains another wins where both start. `None` if there are no terms
+/// or they don't fit under the size limit.
+fn terms_regex(groups: &[Vec<String>]) -> Option<Regex> {
+    let mut terms: Vec<&str> = groups.iter().flatten().map(String::as_str).collect();
+    if terms.is_empty() {
+        return None;
+    }
+    terms.sort_unstable();
+    terms.dedup();
+    terms.sort_by_key(|t| std::cmp::Reverse(t.len()));
+    let pattern = terms.iter().map(|t| regex::escape(t)).collect::<Vec<_>>().join("|");
+    RegexBuilder::new(&pattern)
+        .case_insensitive(true)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .dfa_size_limit(REGEX_SIZE_LIMIT)
+        .build()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matching<'a>(query: &str, lines: &[&'a str]) -> Vec<&'a str> {
+        let query = Query::parse(query);
+        lines.iter().copied().filter(|line| query.matches(line)).collect()
+    }
+
+    const LINES: [&str; 4] = ["Hello World", "world, hello", "goodbye cruel world", "hello there"];
+
+    #[test]
+    fn terms_must_all_appear_in_any_order() {
+        assert_eq!(matching("world hello", &LINES), ["Hello World", "world, hello"]);
+        assert_eq!(matching("HELLO", &LINES), ["Hello World", "world, hello", "hello there"]);
+        assert_eq!(matching("   ", &LINES), LINES);
+        assert!(Query::parse("").is_empty());
+    }
+
+    #[test]
+    fn quoted_phrases_match_as_one_substring() {
+        assert_eq!(matching("\"hello world\"", &LINES), ["Hello World"]);
+        assert_eq!(matching("\"cruel world\" goodbye", &LINES), ["goodbye cruel world"]);
+        // An unterminated quote runs to the end.
+        assert_eq!(matching("\"hello th", &LINES), ["hello there"]);
+        assert!(Query::parse("\"\"").is_empty());
+    }
+
+    #[test]
+    fn or_splits_the_query_into_alternatives() {
+        assert_eq!(matching("goodbye OR there", &LINES), ["goodbye cruel world", "hello there"]);
+        // Lowercase `or` is an ordinary term.
+        assert!(matching("goodbye or there", &LINES).is_empty());
+        // Leading, trailing and doubled ORs add no empty alternative.
+        assert_eq!(matching("OR there OR OR", &LINES), ["hello there"]);
+    }
+
+    #[test]
+    fn or_binds_looser_than_and_and_phrases() {
+        let query = "hello world OR \"cruel world\"";
+        assert_eq!(
+            matching(query, &LINES),
+            ["Hello World", "world, hello", "goodbye cruel world"]
+        );
+        let query = "\"hello world\" OR there hello";
+        assert_eq!(matching(query, &LINES), ["Hello World", "hello there"]);
+    }
+
+    #[test]
+    fn highlights_cover_every_term_longest_first() {
+        let query = Query::parse("hell OR \"hello w\"");
+        let line = "Hello World, hell";
+        let found: Vec<_> = query.match_ranges(line).into_iter().map(|r| &line[r]).collect();
+        assert_eq!(found, ["Hello W", "hell"]);
+    }
+
+    #[test]
+    fn regexes_match_case_insensitively_unless_told_not_to() {
+        let query = Query::regex(r"^h\w+o\b").unwrap();
+        assert!(query.matches("Hello World"));
+        assert!(!query.matches("well hello"));
+        assert!(!Query::regex(r"(?-i)^h\w+o\b").unwrap().matches("Hello World"));
+        let line = "a1 b22 c333";
+        let found: Vec<_> = Query::regex(r"\d+").unwrap().match_ranges(line);
+        assert_eq!(found.into_iter().map(|r| &line[r]).collect::<Vec<_>>(), ["1", "22", "333"]);
+    }
+
+    #[test]
+    fn bad_regexes_are_errors() {
+        let err = Query::regex("(unclosed").unwrap_err().to_string();
+        assert!(err.starts_with("invalid regex: "), "unexpected: {}", err);
+        assert!(!err.contains('\n'), "multi-line error: {:?}", err);
+        let err = Query::regex(r"(\w{100}){100}").unwrap_err().to_string();
+        assert_eq!(err, "regex is too complex");
+        let err = Query::regex(&"a".repeat(MAX_REGEX_LEN + 1)).unwrap_err().to_string();
+        assert_eq!(err, format!("regex is too long (max {} bytes)", MAX_REGEX_LEN));
+    }
+}
@@ -0,0 +1,333 @@
+//! Schemas for client packets, checked in strict protocol mode
+//! (`--strict-protocol`).
+//!
+//! Normally payloads are read leniently: unknown fields are ignored and a
+//! missing field that has a default gets it. In strict mode every packet a
+//! client sends is first checked against the schema of its type: the
+//! envelope may only hold `type`, `payload` and a request `id`, the payload
+//! must be an object (or `null` for types without fields), and each field
+//! must be one the type defines, of the right JSON type. Fields marked required must be
+//! present even where the lenient reader would default them. The first
+//! problem found is reported, naming the field.
+
+use std::fmt;
+
+use chrono::DateTime;
+use serde_json::{Map, Value};
+
+use crate::protocol::MessageType;
+
+/// Why a packet failed its schema; shown to the client as
+/// `invalid_payload: <reason>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidPayload(String);
+
+impl fmt::Display for InvalidPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid_payload: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidPayload {}
+
+/// What a field's value must be.
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    String,
+    Bool,
+    /// A whole number from 0 to the bound.
+    UInt(u64),
+    /// An RFC 3339 timestamp.
+    Timestamp,
+    /// One of these strings.
+    OneOf(&'static [&'static str]),
+    StringList,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Field {
+    name: &'static str,
+    kind: Kind,
+    required: bool,
+}
+
+const fn req(name: &'static str, kind: Kind) -> Field {
+    Field { name, kind, required: true }
+}
+
+const fn opt(name: &'static str, kind: Kind) -> Field {
+    Field { name, kind, required: false }
+}
+
+const U32: Kind = Kind::UInt(u32::MAX as u64);
+const U64: Kind = Kind::UInt(u64::MAX);
+const FORMATS: Kind = Kind::OneOf(&["plain", "markdown", "code"]);
+
+const AUTH: &[Field] = &[req("username", Kind::String), req("password", Kind::String)];
+const HELLO: &[Field] = &[
+    req("version", Kind::String),
+    opt("features", Kind::StringList),
+    opt("server_time", Kind::Timestamp),
+];
+const CHAT: &[Field] = &[
+    req("content", Kind::String),
+    opt("format", FORMATS),
+    opt("room", Kind::String),
+    opt("ttl_secs", U64),
+];
+const DIRECT: &[Field] = &[req("to", Kind::String), req("content", Kind::String)];
+const RECEIPT: &[Field] = &[
+    req("message_id", Kind::String),
+    req("kind", Kind::OneOf(&["delivered", "read"])),
+];
+const SEARCH: &[Field] = &[
+    opt("query", Kind::String),
+    opt("username", Kind::String),
+    opt("from", Kind::Timestamp),
+    opt("to", Kind::Timestamp),
+    opt("include_system", Kind::Bool),
+    opt("regex", Kind::Bool),
+    opt("limit", U64),
+    opt("offset", U64),
+];
+const HISTORY: &[Field] = &[req("limit", U64), opt("include_system", Kind::Bool)];
+const SYNC: &[Field] = &[req("since_id", Kind::String), opt("include_system", Kind::Bool)];
+const PURGE: &[Field] = &[opt("before", Kind::Timestamp)];
+const ADMIN_ACTIONS: Kind = Kind::OneOf(&["slow_mode", "read_only"]);
+const ADMIN: &[Field] = &[req("action", ADMIN_ACTIONS)];
+const SLOW_MODE: &[Field] = &[req("action", ADMIN_ACTIONS), req("seconds", U64)];
+const READ_ONLY: &[Field] = &[req("action", ADMIN_ACTIONS), req("enabled", Kind::Bool)];
+const RENAME: &[Field] = &[req("new_username", Kind::String)];
+const DND: &[Field] = &[req("enabled", Kind::Bool)];
+const AWAY: &[Field] = &[opt("message", Kind::String)];
+const USERNAME: &[Field] = &[req("username", Kind::String)];
+const UPDATE_PROFILE: &[Field] = &[
+    opt("display_name", Kind::String),
+    opt("status_text", Kind::String),
+    opt("avatar", Kind::String),
+];
+const UPDATE_PREFS: &[Field] = &[opt("notify", Kind::OneOf(&["all", "mentions", "none"]))];
+const RECENT_USERS: &[Field] = &[opt("hours", U32)];
+const DIRECTORY: &[Field] = &[opt("limit", U64), opt("offset", U64)];
+const COMPRESS: &[Field] = &[req("algorithm", Kind::String)];
+const ROOM: &[Field] = &[req("room", Kind::String)];
+const PIN: &[Field] = &[req("message_id", Kind::String)];
+const TOPIC: &[Field] = &[req("room", Kind::String), req("topic", Kind::String)];
+const NONE: &[Field] = &[];
+
+/// The payload fields of a client → server type; `None` for types only the
+/// server sends. `admin` payloads depend on their `action`.
+fn fields(msg_type: &MessageType, payload: &Value) -> Option<&'static [Field]> {
+    Some(match msg_type {
+        MessageType::Hello => HELLO,
+        MessageType::Register | MessageType::Login => AUTH,
+        MessageType::Chat => CHAT,
+        MessageType::Direct => DIRECT,
+        MessageType::Receipt => RECEIPT,
+        MessageType::Search => SEARCH,
+        MessageType::History => HISTORY,
+        MessageType::Sync => SYNC,
+        MessageType::Purge => PURGE,
+        MessageType::Admin => match payload.get("action").and_then(Value::as_str) {
+            Some("slow_mode") => SLOW_MODE,
+            Some("read_only") => READ_ONLY,
+            _ => ADMIN,
+        },
+        MessageType::Rename => RENAME,
+        MessageType::Dnd => DND,
+        MessageType::Away => AWAY,
+        MessageType::Block | MessageType::Unblock | MessageType::Profile => USERNAME,
+        MessageType::UpdateProfile => UPDATE_PROFILE,
+        MessageType::UpdatePrefs => UPDATE_PREFS,
+        MessageType::RecentUsers => RECENT_USERS,
+        MessageType::Directory => DIRECTORY,
+        MessageType::Compress => COMPRESS,
+        MessageType::Join | MessageType::Leave => ROOM,
+        MessageType::Pin | MessageType::Unpin => PIN,
+        MessageType::Topic => TOPIC,
+        MessageType::Guest
+        | MessageType::Users
+        | MessageType::Whoami
+        | MessageType::Stats
+        | MessageType::Ping
+        | MessageType::Time
+        | MessageType::Pinned
+        | MessageType::Quit => NONE,
+        MessageType::Response
+        | MessageType::Broadcast
+        | MessageType::System
+        | MessageType::Deleted => return None,
+    })
+}
+
+/// Checks a whole packet, as parsed from one frame, against its schema.
+/// Types only the server sends pass; the server rejects them itself.
+pub fn validate_packet(packet: &Value) -> Result<(), InvalidPayload> {
+    let envelope = match packet {
+        Value::Object(envelope) => envelope,
+        _ => return Err(InvalidPayload("packet must be a JSON object".to_string())),
+    };
+    if let Some(key) = envelope.keys().find(|k| !["type", "payload", "id"].contains(&k.as_str())) {
+        return Err(InvalidPayload(format!("unknown packet field `{}`", key)));
+    }
+    if envelope.get("id").is_some_and(|id| !id.is_u64()) {
+        return Err(InvalidPayload("field `id` must be a non-negative integer".to_string()));
+    }
+    let msg_type: MessageType = match envelope.get("type").cloned() {
+        Some(t) => serde_json::from_value(t)
+            .map_err(|_| InvalidPayload("field `type` is not a known message type".to_string()))?,
+        None => return Err(InvalidPayload("missing field `type`".to_string())),
+    };
+    validate(&msg_type, envelope.get("payload").unwrap_or(&Value::Null))
+}
+
+/// Checks `payload` against the schema for `msg_type`.
+pub fn validate(msg_type: &MessageType, payload: &Value) -> Result<(), InvalidPayload> {
+    let fields = match fields(msg_type, payload) {
+        Some(fields) => fields,
+        None => return Ok(()),
+    };
+    let empty = Map::new();
+    let object = match payload {
+        Value::Object(object) => object,
+        Value::Null if fields.iter().all(|f| !f.required) => &empty,
+        _ => return Err(InvalidPayload("payload must be a JSON object".to_string())),
+    };
+    for key in object.keys() {
+        if !fields.iter().any(|f| f.name == key) {
+            return Err(InvalidPayload(format!("unknown field `{}`", key)));
+        }
+    }
+    for field in fields {
+        match object.get(field.name) {
+            None if field.required => {
+                return Err(InvalidPayload(format!("missing field `{}`", field.name)));
+            }
+            None => {}
+            Some(Value::Null) if !field.required => {}
+            Some(value) => check(field, value)?,
+        }
+    }
+    Ok(())
+}
+
+fn check(field: &Field, value: &Value) -> Result<(), InvalidPayload> {
+    let expected = match field.kind {
+        Kind::String if value.is_string() => return Ok(()),
+        Kind::String => "a string".to_string(),
+        Kind::Bool if value.is_boolean() => return Ok(()),
+        Kind::Bool => "true or false".to_string(),
+        Kind::UInt(max) if value.as_u64().is_some_and(|n| n <= max) => return Ok(()),
+        Kind::UInt(u64::MAX) => "a whole number of 0 or more".to_string(),
+        Kind::UInt(max) => format!("a whole number from 0 to {}", max),
+        Kind::Timestamp => match value.as_str().map(DateTime::parse_from_rfc3339) {
+            Some(Ok(_)) => return Ok(()),
+            _ => "an RFC 3339 timestamp".to_string(),
+        },
+        Kind::OneOf(options) => match value.as_str() {
+            Some(s) if options.contains(&s) => return Ok(()),
+            _ => format!("one of {}", options.join(", ")),
+        },
+        Kind::StringList => match value.as_array() {
+            Some(items) if items.iter().all(Value::is_string) => return Ok(()),
+            _ => "a list of strings".to_string(),
+        },
+    };
+    Err(InvalidPayload(format!("field `{}` must be {}", field.name, expected)))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn reason(packet: Value) -> String {
+        validate_packet(&packet).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn each_problem_names_its_field() {
+        let cases = [
+            (json!({ "content": "hi", "colour": "red" }), "unknown field `colour`"),
+            (json!({ "format": "plain" }), "missing field `content`"),
+            (json!({ "content": 5 }), "field `content` must be a string"),
+            (
+                json!({ "content": "hi", "ttl_secs": -1 }),
+                "field `ttl_secs` must be a whole number of 0 or more",
+            ),
+            (
+                json!({ "content": "hi", "format": "html" }),
+                "field `format` must be one of plain, markdown, code",
+            ),
+            (json!(["hi"]), "payload must be a JSON object"),
+        ];
+        for (payload, why) in cases {
+            let err = validate(&MessageType::Chat, &payload).unwrap_err();
+            assert_eq!(err.to_string(), format!("invalid_payload: {}", why));
+        }
+        let payload = json!({ "content": "hi", "format": null, "room": "rust" });
+        assert_eq!(validate(&MessageType::Chat, &payload), Ok(()));
+    }
+
+    #[test]
+    fn fields_the_lenient_reader_defaults_are_still_required() {
+        let err = validate(&MessageType::History, &json!({})).unwrap_err();
+        assert_eq!(err.to_string(), "invalid_payload: missing field `limit`");
+        let err = validate(&MessageType::History, &Value::Null).unwrap_err();
+        assert_eq!(err.to_string(), "invalid_payload: payload must be a JSON object");
+        // Types without required fields may leave the payload out.
+        assert_eq!(validate(&MessageType::Users, &Value::Null), Ok(()));
+        assert_eq!(validate(&MessageType::Search, &Value::Null), Ok(()));
+        let err = validate(&MessageType::Users, &json!({ "all": true })).unwrap_err();
+        assert_eq!(err.to_string(), "invalid_payload: unknown field `all`");
+    }
+
+    #[test]
+    fn admin_fields_follow_the_action() {
+        let payload = json!({ "action": "slow_mode", "seconds": 30 });
+        assert_eq!(validate(&MessageType::Admin, &payload), Ok(()));
+        let payload = json!({ "action": "slow_mode", "enabled": true });
+        let err = validate(&MessageType::Admin, &payload).unwrap_err();
+        assert_eq!(err.to_string(), "invalid_payload: unknown field `enabled`");
+        let payload = json!({ "action": "nap" });
+        let err = validate(&MessageType::Admin, &payload).unwrap_err();
+        let why = "field `action` must be one of slow_mode, read_only";
+        assert_eq!(err.to_string(), format!("invalid_payload: {}", why));
+    }
+
+    #[test]
+    fn server_only_types_and_bad_envelopes() {
+        assert_eq!(validate_packet(&json!({ "type": "broadcast", "payload": 1 })), Ok(()));
+        assert_eq!(reason(json!([])), "invalid_payload: packet must be a JSON object");
+        assert_eq!(reason(json!({ "payload": {} })), "invalid_payload: missing field `type`");
+        let packet = json!({ "type": "teleport", "payload": {} });
+        assert_eq!(reason(packet), "invalid_payload: field `type` is not a known message type");
+    }
+
+    #[test]
+    fn envelope_may_carry_a_request_id() {
+        let packet = json!({ "type": "history", "payload": { "limit": 5 }, "id": 7 });
+        assert_eq!(validate_packet(&packet), Ok(()));
+
+        let packet = json!({ "type": "history", "payload": { "limit": 5 }, "id": -1 });
+        assert_eq!(reason(packet), "invalid_payload: field `id` must be a non-negative integer");
+        let packet = json!({ "type": "history", "payload": { "limit": 5 }, "id": "7" });
+        assert_eq!(reason(packet), "invalid_payload: field `id` must be a non-negative integer");
+        let packet = json!({ "type": "history", "payload": { "limit": 5 }, "seq": 7 });
+        assert_eq!(reason(packet), "invalid_payload: unknown packet field `seq`");
+    }
+
+    #[test]
+    fn notify_must_be_a_known_level() {
+        for level in ["all", "mentions", "none"] {
+            let payload = json!({ "notify": level });
+            assert_eq!(validate(&MessageType::UpdatePrefs, &payload), Ok(()));
+        }
+        let packet = json!({ "type": "updateprefs", "payload": { "notify": "loud" } });
+        assert_eq!(
+            reason(packet),
+            "invalid_payload: field `notify` must be one of all, mentions, none"
+        );
+    }
+}
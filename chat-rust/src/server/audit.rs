@@ -0,0 +1,247 @@
+//! Append-only audit trail (`--audit-log`): who signed in or failed to,
+//! what admins changed and which messages were deleted, one JSON object per
+//! line.
+//!
+//! Entries are queued for a background task, so a slow disk never holds up
+//! a request; when the queue is full an entry is dropped and counted in
+//! `chat_audit_dropped_total`. The task opens the file in append mode for
+//! each batch it writes, so the log can be rotated by renaming it (as
+//! logrotate does without `copytruncate`) and the next entry starts a new
+//! file. Nothing is ever rewritten.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::{error, warn};
+
+use super::metrics::Metrics;
+
+const AUDIT_QUEUE: usize = 4096;
+
+/// What happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Register,
+    Login,
+    /// A login was refused; `detail` says why.
+    LoginFailed,
+    /// Too many failed logins locked the name or address out.
+    Lockout,
+    /// `target` is the new username.
+    Rename,
+    Purge,
+    SlowMode,
+    ReadOnly,
+    /// `target` is the room; `detail` the new topic (empty when cleared).
+    Topic,
+    /// A message's TTL ran out; `target` is its id.
+    Expire,
+    /// Retention removed old messages; `detail` says how many.
+    Prune,
+}
+
+/// One line of the log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Utc>,
+    /// Username of whoever acted (as given, for a failed login); `None` for
+    /// the server's own actions.
+    pub actor: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub actor_id: Option<String>,
+    /// Peer address of the actor's connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    pub action: AuditAction,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl AuditEntry {
+    /// An entry for `action` taken by the server, stamped now.
+    pub fn new(action: AuditAction) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            actor: None,
+            actor_id: None,
+            ip: None,
+            action,
+            target: None,
+            detail: None,
+        }
+    }
+
+    pub fn actor(mut self, username: impl Into<String>) -> Self {
+        self.actor = Some(username.into());
+        self
+    }
+
+    pub fn actor_id(mut self, user_id: impl Into<String>) -> Self {
+        self.actor_id = Some(user_id.into());
+        self
+    }
+
+    pub fn ip(mut self, ip: Option<IpAddr>) -> Self {
+        self.ip = ip;
+        self
+    }
+
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+/// The sending side; cheap to clone.
+#[derive(Clone)]
+pub struct AuditLog {
+    tx: mpsc::Sender<AuditEntry>,
+    metrics: Arc<Metrics>,
+}
+
+impl AuditLog {
+    /// Checks that `path` can be appended to and starts the writer task.
+    pub fn start(path: PathBuf, metrics: Arc<Metrics>) -> Result<Self> {
+        open(&path).with_context(|| format!("failed to open audit log {}", path.display()))?;
+        let (tx, rx) = mpsc::channel(AUDIT_QUEUE);
+        tokio::spawn(write_entries(path, rx));
+        Ok(Self { tx, metrics })
+    }
+
+    /// Queues `entry` to be appended. Never waits.
+    pub fn record(&self, entry: AuditEntry) {
+        if self.tx.try_send(entry).is_err() {
+            Metrics::inc(&self.metrics.audit_dropped);
+            warn!("audit: queue full, entry dropped");
+        }
+    }
+}
+
+/// Appends whatever is queued, a batch at a time, until every sender is gone.
+async fn write_entries(path: PathBuf, mut rx: mpsc::Receiver<AuditEntry>) {
+    while let Some(entry) = rx.recv().await {
+        let mut entries = vec![entry];
+        while let Ok(entry) = rx.try_recv() {
+            entries.push(entry);
+        }
+        let mut batch = Vec::new();
+        for entry in &entries {
+            if serde_json::to_writer(&mut batch, entry).is_ok() {
+                batch.push(b'\n');
+            }
+        }
+        let path = path.clone();
+        let written = tokio::task::spawn_blocking(move || open(&path)?.write_all(&batch)).await;
+        match written {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!(error = %e, lost = entries.len(), "audit: writing the log failed"),
+            Err(e) => error!(error = %e, lost = entries.len(), "audit: writer task failed"),
+        }
+    }
+}
+
+/// Opens `path` for appending, creating it readable by the owner only.
+fn open(path: &Path) -> std::io::Result<std::fs::File> {
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("chat-audit-{}-{}.log", name, std::process::id()))
+    }
+
+    /// The entries in `path` once there are `n`.
+    async fn entries_in(path: &Path, n: usize) -> Vec<serde_json::Value> {
+        for _ in 0..250 {
+            let text = std::fs::read_to_string(path).unwrap_or_default();
+            let lines: Vec<_> = text.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+            if lines.len() >= n {
+                return lines;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("{} never held {} entries", path.display(), n);
+    }
+
+    #[tokio::test]
+    async fn entries_are_appended_one_per_line_in_order() {
+        let path = temp_path("order");
+        std::fs::remove_file(&path).ok();
+        let log = AuditLog::start(path.clone(), Arc::new(Metrics::default())).unwrap();
+        log.record(
+            AuditEntry::new(AuditAction::Login)
+                .actor("alice")
+                .actor_id("u1")
+                .ip(Some("127.0.0.1".parse().unwrap())),
+        );
+        for n in 0..20 {
+            log.record(AuditEntry::new(AuditAction::Expire).target(format!("m{}", n)));
+        }
+        let entries = entries_in(&path, 21).await;
+        assert_eq!(entries[0]["action"], "login");
+        assert_eq!(entries[0]["actor"], "alice");
+        assert_eq!(entries[0]["actor_id"], "u1");
+        assert_eq!(entries[0]["ip"], "127.0.0.1");
+        // The server's own actions have a null actor and leave out the rest.
+        assert_eq!(entries[1]["actor"], serde_json::Value::Null);
+        assert!(entries[1].get("ip").is_none() && entries[1].get("detail").is_none());
+        let targets: Vec<_> = entries[1..].iter().map(|e| e["target"].as_str().unwrap()).collect();
+        let expected: Vec<_> = (0..20).map(|n| format!("m{}", n)).collect();
+        assert_eq!(targets, expected);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn a_renamed_log_is_followed_by_a_new_file() {
+        let path = temp_path("rotate");
+        let rotated = path.with_extension("log.1");
+        std::fs::remove_file(&path).ok();
+        let log = AuditLog::start(path.clone(), Arc::new(Metrics::default())).unwrap();
+        log.record(AuditEntry::new(AuditAction::Purge).actor("root"));
+        entries_in(&path, 1).await;
+
+        std::fs::rename(&path, &rotated).unwrap();
+        log.record(AuditEntry::new(AuditAction::ReadOnly).actor("root").detail("on"));
+        let entries = entries_in(&path, 1).await;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["action"], "read_only");
+        assert_eq!(entries[0]["detail"], "on");
+        assert_eq!(entries_in(&rotated, 1).await.len(), 1);
+        std::fs::remove_file(&path).ok();
+        std::fs::remove_file(&rotated).ok();
+    }
+}
@@ -0,0 +1,234 @@
+//! Where logins and registrations are checked.
+//!
+//! By default the store does it, against the password hashes in
+//! `users.json`. With `--auth-command` an external program decides instead,
+//! so accounts can come from LDAP, an SSO service or anything else a script
+//! can reach. The program is run once per attempt as `<command> login` or
+//! `<command> register`, with the username and password on separate lines
+//! of its stdin (never its arguments, which other local users can see). It
+//! accepts by exiting with status 0; otherwise the first line of its stdout,
+//! if any, is shown to the user as the reason. It is killed after
+//! [`COMMAND_TIMEOUT`].
+//!
+//! Users the program accepts get a local account on first use (see
+//! `Store::external_user`) to hold their ID, profile and blocks. That
+//! account has no password, and names already taken by local accounts are
+//! refused, so the two kinds never mix.
+
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::warn;
+
+use crate::store::{StoreHandle, User};
+
+/// Longest an `--auth-command` run may take.
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type AuthFuture<'a> = Pin<Box<dyn Future<Output = Result<User>> + Send + 'a>>;
+
+/// Checks credentials and hands back the account they belong to. An error
+/// is shown to the client as the reason the attempt failed.
+pub trait AuthProvider: Send + Sync {
+    fn authenticate<'a>(&'a self, username: &'a str, password: &'a str) -> AuthFuture<'a>;
+
+    /// Creates an account and returns it, as if logged in.
+    fn register<'a>(&'a self, username: &'a str, password: &'a str) -> AuthFuture<'a>;
+}
+
+/// The default: accounts and password hashes kept in the store.
+impl AuthProvider for StoreHandle {
+    fn authenticate<'a>(&'a self, username: &'a str, password: &'a str) -> AuthFuture<'a> {
+        Box::pin(StoreHandle::authenticate(self, username, password))
+    }
+
+    fn register<'a>(&'a self, username: &'a str, password: &'a str) -> AuthFuture<'a> {
+        Box::pin(self.register_user(username, password))
+    }
+}
+
+/// Asks `--auth-command` (see the module docs).
+pub struct CommandAuth {
+    command: PathBuf,
+    store: StoreHandle,
+}
+
+impl CommandAuth {
+    pub fn new(command: PathBuf, store: StoreHandle) -> Self {
+        Self { command, store }
+    }
+
+    async fn check(&self, action: &str, username: &str, password: &str) -> Result<()> {
+        // Either would break the one-per-line input.
+        if username.contains('\n') || password.contains('\n') {
+            bail!("incorrect username or password");
+        }
+        let mut child = Command::new(&self.command)
+            .arg(action)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| {
+                let command = self.command.display();
+                warn!(command = %command, error = %e, "auth command failed to start");
+                anyhow!("authentication is unavailable")
+            })?;
+        if let Some(mut stdin) = child.stdin.take() {
+            let input = format!("{}\n{}\n", username, password);
+            // A command that exits without reading is still heard out.
+            stdin.write_all(input.as_bytes()).await.ok();
+        }
+        let output = match tokio::time::timeout(COMMAND_TIMEOUT, child.wait_with_output()).await {
+            Ok(Ok(output)) => output,
+            Ok(Err(e)) => {
+                warn!(command = %self.command.display(), error = %e, "auth command failed");
+                bail!("authentication is unavailable");
+            }
+            Err(_) => {
+                warn!(command = %self.command.display(), "auth command timed out");
+                bail!("authentication is unavailable");
+            }
+        };
+        if output.status.success() {
+            return Ok(());
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        match stdout.lines().next().map(str::trim).filter(|l| !l.is_empty()) {
+            Some(reason) => bail!("{}", reason),
+            None if action == "register" => bail!("registration refused"),
+            None => bail!("incorrect username or password"),
+        }
+    }
+}
+
+impl AuthProvider for CommandAuth {
+    fn authenticate<'a>(&'a self, username: &'a str, password: &'a str) -> AuthFuture<'a> {
+        Box::pin(async move {
+            self.check("login", username, password).await?;
+            self.store.external_user(username, false).await
+        })
+    }
+
+    fn register<'a>(&'a self, username: &'a str, password: &'a str) -> AuthFuture<'a> {
+        Box::pin(async move {
+            self.check("register", username, password).await?;
+            self.store.external_user(username, true).await
+        })
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::store::Store;
+
+    /// Accepts the password "right", refuses "quiet" without a reason and
+    /// anything else with one.
+    const SCRIPT: &str = r#"#!/bin/sh
+read -r user
+read -r pass
+case "$pass" in
+    right) exit 0 ;;
+    quiet) exit 1 ;;
+esac
+echo "no such luck, $user ($1)"
+exit 1
+"#;
+
+    /// Tests using the script right now.
+    static SCRIPT_USERS: Mutex<usize> = Mutex::new(0);
+
+    /// The script on disk, shared by the tests running at the time: the
+    /// first writes it and the last removes it, so no test runs it while
+    /// it is being written.
+    struct Script(PathBuf);
+
+    impl Script {
+        fn new() -> Self {
+            let name = format!("chat-auth-test-{}.sh", std::process::id());
+            let path = std::env::temp_dir().join(name);
+            let mut users = SCRIPT_USERS.lock().unwrap();
+            if *users == 0 {
+                std::fs::write(&path, SCRIPT).unwrap();
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o700)).unwrap();
+            }
+            *users += 1;
+            Self(path)
+        }
+    }
+
+    impl Drop for Script {
+        fn drop(&mut self) {
+            let mut users = SCRIPT_USERS.lock().unwrap();
+            *users -= 1;
+            if *users == 0 {
+                std::fs::remove_file(&self.0).ok();
+            }
+        }
+    }
+
+    /// Keep the `Script` until the test is done with the provider.
+    fn command_auth() -> (CommandAuth, StoreHandle, Script) {
+        let store = StoreHandle::spawn(Store::new_in_memory()).unwrap();
+        let script = Script::new();
+        (CommandAuth::new(script.0.clone(), store.clone()), store, script)
+    }
+
+    fn error(result: Result<User>) -> String {
+        result.expect_err("the attempt succeeded").to_string()
+    }
+
+    #[tokio::test]
+    async fn accepted_users_get_one_external_account() {
+        let (auth, store, _script) = command_auth();
+        let registered = auth.register("Dana", "right").await.unwrap();
+        assert!(registered.external);
+        assert!(registered.password_hash.is_empty());
+        let again = auth.authenticate("dana", "right").await.unwrap();
+        assert_eq!(again.id, registered.id);
+        let err = error(auth.register("dana", "right").await);
+        assert_eq!(err, "username \"Dana\" is already taken");
+
+        // Logging in creates the account too, the first time.
+        let first = auth.authenticate("erin", "right").await.unwrap();
+        assert_eq!(store.get_user("erin").await.unwrap().id, first.id);
+    }
+
+    #[tokio::test]
+    async fn the_command_gives_the_reason_for_a_refusal() {
+        let (auth, store, _script) = command_auth();
+        assert_eq!(error(auth.authenticate("dana", "wrong").await), "no such luck, dana (login)");
+        assert_eq!(error(auth.register("dana", "wrong").await), "no such luck, dana (register)");
+        let quiet = auth.authenticate("dana", "quiet").await;
+        assert_eq!(error(quiet), "incorrect username or password");
+        assert_eq!(error(auth.register("dana", "quiet").await), "registration refused");
+        assert!(store.get_user("dana").await.is_none());
+
+        // Refused before the command runs: it reads one value per line.
+        let split = auth.authenticate("dana", "right\nright").await;
+        assert_eq!(error(split), "incorrect username or password");
+    }
+
+    #[tokio::test]
+    async fn local_accounts_and_a_missing_command_are_refused() {
+        let (auth, store, _script) = command_auth();
+        store.register_user("alice", "correct horse").await.unwrap();
+        let err = error(auth.authenticate("alice", "right").await);
+        assert_eq!(err, "username \"alice\" belongs to a local account");
+
+        let missing = CommandAuth::new(PathBuf::from("/nonexistent/auth"), store);
+        let err = error(missing.authenticate("dana", "right").await);
+        assert_eq!(err, "authentication is unavailable");
+    }
+}
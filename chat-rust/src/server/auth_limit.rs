@@ -0,0 +1,194 @@
+//! Failed-login tracking, so passwords can't be guessed at full speed.
+//!
+//! Failures are counted per username and per peer IP. Reaching the limit
+//! within the window locks that key out; each further lockout of the same
+//! key (before its record decays) doubles the duration, up to
+//! [`MAX_LOCKOUT`]. A record is forgotten once it has been quiet for a full
+//! window and any lockout has passed.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// The first lockout's length; later ones double.
+const BASE_LOCKOUT: Duration = Duration::from_secs(60);
+const MAX_LOCKOUT: Duration = Duration::from_secs(60 * 60);
+/// An IP may be shared (NAT, a bouncer), so it gets this many times the
+/// per-username allowance.
+const IP_FAILURE_FACTOR: u32 = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    User(String),
+    Ip(IpAddr),
+}
+
+struct Record {
+    failures: u32,
+    /// Start of the current counting window.
+    window_start: Instant,
+    lockouts: u32,
+    locked_until: Option<Instant>,
+}
+
+pub struct AuthLimiter {
+    /// The failure allowance and its window; see [`AuthLimiter::set_limits`].
+    limits: RwLock<(u32, Duration)>,
+    records: Mutex<HashMap<Key, Record>>,
+}
+
+impl AuthLimiter {
+    /// `max_failures` of 0 disables the limiter.
+    pub fn new(max_failures: u32, window: Duration) -> Self {
+        Self {
+            limits: RwLock::new((max_failures, window)),
+            records: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Changes the allowance for failures from now on. Lockouts already in
+    /// force run their course.
+    pub fn set_limits(&self, max_failures: u32, window: Duration) {
+        *self.limits.write().unwrap() = (max_failures, window);
+    }
+
+    /// How long until `username` (normalized) or `ip` may try again, if
+    /// either is locked out.
+    pub fn locked_for(&self, username: &str, ip: Option<IpAddr>) -> Option<Duration> {
+        let now = Instant::now();
+        let records = self.records.lock().unwrap();
+        keys(username, ip)
+            .filter_map(|key| records.get(&key)?.locked_until)
+            .filter(|&until| until > now)
+            .map(|until| until - now)
+            .max()
+    }
+
+    /// Counts a failed attempt. Returns the lockout it triggered, if any.
+    pub fn record_failure(&self, username: &str, ip: Option<IpAddr>) -> Option<Duration> {
+        let (max_failures, window) = *self.limits.read().unwrap();
+        if max_failures == 0 {
+            return None;
+        }
+        let now = Instant::now();
+        let mut records = self.records.lock().unwrap();
+        records.retain(|_, r| !expired(r, window, now));
+        let mut locked = None;
+        for key in keys(username, ip) {
+            let limit = match key {
+                Key::User(_) => max_failures,
+                Key::Ip(_) => max_failures.saturating_mul(IP_FAILURE_FACTOR),
+            };
+            let record = records.entry(key).or_insert(Record {
+                failures: 0,
+                window_start: now,
+                lockouts: 0,
+                locked_until: None,
+            });
+            if now.duration_since(record.window_start) > window {
+                record.failures = 0;
+                record.window_start = now;
+            }
+            record.failures += 1;
+            if record.failures >= limit {
+                let lockout = BASE_LOCKOUT
+                    .saturating_mul(1 << record.lockouts.min(16))
+                    .min(MAX_LOCKOUT);
+                record.lockouts += 1;
+                record.failures = 0;
+                // Keep the record (and so the doubling) for a window past
+                // the end of the lockout.
+                record.window_start = now + lockout;
+                record.locked_until = Some(now + lockout);
+                locked = locked.max(Some(lockout));
+            }
+        }
+        locked
+    }
+
+    /// Forgets `username`'s failures after a successful login. The IP's
+    /// count stays, so one working account can't launder guesses at others.
+    pub fn reset(&self, username: &str) {
+        self.records.lock().unwrap().remove(&Key::User(username.to_string()));
+    }
+}
+
+fn expired(record: &Record, window: Duration, now: Instant) -> bool {
+    let quiet = now.duration_since(record.window_start) > window;
+    let unlocked = record.locked_until.is_none_or(|until| until <= now);
+    quiet && unlocked
+}
+
+fn keys(username: &str, ip: Option<IpAddr>) -> impl Iterator<Item = Key> {
+    std::iter::once(Key::User(username.to_string())).chain(ip.map(Key::Ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: Option<IpAddr> = Some(IpAddr::V4(std::net::Ipv4Addr::LOCALHOST));
+    const WINDOW: Duration = Duration::from_secs(600);
+
+    /// Ends every lockout now, as if it had run its course.
+    fn expire_lockouts(limiter: &AuthLimiter) {
+        let now = Instant::now();
+        for record in limiter.records.lock().unwrap().values_mut() {
+            record.locked_until = record.locked_until.map(|_| now);
+            record.window_start = now;
+        }
+    }
+
+    #[test]
+    fn the_kth_failure_locks_the_name_out() {
+        let limiter = AuthLimiter::new(3, WINDOW);
+        assert_eq!(limiter.record_failure("alice", IP), None);
+        assert_eq!(limiter.record_failure("alice", IP), None);
+        assert_eq!(limiter.locked_for("alice", IP), None);
+        assert_eq!(limiter.record_failure("alice", IP), Some(BASE_LOCKOUT));
+        let left = limiter.locked_for("alice", None).unwrap();
+        assert!(left <= BASE_LOCKOUT && left > BASE_LOCKOUT / 2, "left: {:?}", left);
+        assert_eq!(limiter.locked_for("bob", None), None);
+    }
+
+    #[test]
+    fn success_resets_the_count_but_not_the_address() {
+        let limiter = AuthLimiter::new(3, WINDOW);
+        for _ in 0..2 {
+            limiter.record_failure("alice", IP);
+        }
+        limiter.reset("alice");
+        assert_eq!(limiter.record_failure("alice", IP), None);
+        assert_eq!(limiter.record_failure("alice", IP), None);
+
+        // The address has 4 failures so far, of its allowance of 12.
+        for name in ["b", "c", "d", "e", "f", "g", "h"] {
+            assert_eq!(limiter.record_failure(name, IP), None, "locked at {}", name);
+            limiter.reset(name);
+        }
+        assert_eq!(limiter.record_failure("i", IP), Some(BASE_LOCKOUT));
+        assert!(limiter.locked_for("anyone", IP).is_some());
+        assert_eq!(limiter.locked_for("anyone", None), None);
+    }
+
+    #[test]
+    fn repeat_lockouts_double_up_to_the_cap() {
+        let limiter = AuthLimiter::new(1, WINDOW);
+        let mut lockouts = Vec::new();
+        for _ in 0..8 {
+            lockouts.push(limiter.record_failure("alice", None).unwrap().as_secs());
+            expire_lockouts(&limiter);
+        }
+        assert_eq!(lockouts, [60, 120, 240, 480, 960, 1920, 3600, 3600]);
+    }
+
+    #[test]
+    fn zero_failures_disables_the_limiter() {
+        let limiter = AuthLimiter::new(0, WINDOW);
+        for _ in 0..100 {
+            assert_eq!(limiter.record_failure("alice", IP), None);
+        }
+        assert_eq!(limiter.locked_for("alice", IP), None);
+    }
+}
@@ -0,0 +1,251 @@
+//! Optional built-in bot, configured by a JSON file (`--bot-config`):
+//!
+//! ```json
+//! {
+//!   "name": "helper",
+//!   "rooms": ["support"],
+//!   "triggers": [{ "trigger": "!rules", "response": "Be kind. No spam." }],
+//!   "scheduled": [{ "every_secs": 3600, "message": "New here? Try !help" }]
+//! }
+//! ```
+//!
+//! The bot posts as an account of its own (see [`Store::bot_user`]) and is
+//! registered with the hub like a connection, so it sees chat in the lobby
+//! and in its `rooms`. A message whose first word is a trigger
+//! (case-insensitive) gets the response in the same room; `!help` lists the
+//! triggers unless the file defines it. Scheduled messages go to the lobby,
+//! or to their `room`. Everything the bot says takes the normal broadcast
+//! and persistence path.
+//!
+//! [`Store::bot_user`]: crate::store::Store::bot_user
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use super::hub::{ClientHandle, HubCommand};
+use super::send_queue::{OverflowPolicy, SendQueue};
+use super::Server;
+use crate::protocol::{normalize_room, BroadcastPayload, Codec, MessageType, Packet};
+use crate::store::User;
+
+/// Hub id of the bot; real connections are `conn-<n>`.
+const BOT_CONN_ID: &str = "bot";
+const BOT_BUF: usize = 256;
+const HELP_TRIGGER: &str = "!help";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BotConfig {
+    /// The bot's username.
+    pub name: String,
+    /// Rooms to listen in, besides the lobby.
+    #[serde(default)]
+    pub rooms: Vec<String>,
+    #[serde(default)]
+    pub triggers: Vec<Trigger>,
+    #[serde(default)]
+    pub scheduled: Vec<Scheduled>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Trigger {
+    /// A single word, such as `!rules`.
+    pub trigger: String,
+    pub response: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Scheduled {
+    pub every_secs: u64,
+    pub message: String,
+    /// `None` posts to the lobby.
+    #[serde(default)]
+    pub room: Option<String>,
+}
+
+impl BotConfig {
+    /// Reads and checks a config file; room names come back normalized.
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read_to_string(path)?;
+        let mut config: BotConfig = serde_json::from_str(&data)?;
+        for room in &mut config.rooms {
+            *room = checked_room(room)?;
+        }
+        for t in &config.triggers {
+            if t.trigger.is_empty() || t.trigger.contains(char::is_whitespace) {
+                bail!("trigger {:?} must be a single word", t.trigger);
+            }
+        }
+        for s in &mut config.scheduled {
+            if s.every_secs == 0 {
+                bail!("scheduled message {:?} needs every_secs above 0", s.message);
+            }
+            if let Some(room) = &s.room {
+                s.room = Some(checked_room(room)?);
+            }
+        }
+        Ok(config)
+    }
+
+    /// The reply to `content`, if its first word is a trigger.
+    pub fn respond(&self, content: &str) -> Option<String> {
+        let word = content.split_whitespace().next()?;
+        if let Some(t) = self.triggers.iter().find(|t| t.trigger.eq_ignore_ascii_case(word)) {
+            return Some(t.response.clone());
+        }
+        if !word.eq_ignore_ascii_case(HELP_TRIGGER) {
+            return None;
+        }
+        let names: Vec<&str> = self.triggers.iter().map(|t| t.trigger.as_str()).collect();
+        Some(match names.len() {
+            0 => "no commands configured".to_string(),
+            _ => format!("commands: {}", names.join(", ")),
+        })
+    }
+}
+
+fn checked_room(name: &str) -> Result<String> {
+    match normalize_room(name) {
+        Some(room) => Ok(room),
+        None => bail!("invalid room name {:?}", name),
+    }
+}
+
+/// Creates (or reuses) the bot's account and starts answering triggers and
+/// posting scheduled messages in the background. Fails if the bot's name
+/// belongs to a registered user.
+pub async fn start(server: Arc<Server>, config: BotConfig) -> Result<()> {
+    let user = server.store.bot_user(&config.name).await?;
+    info!(
+        name = %user.username,
+        triggers = config.triggers.len(),
+        scheduled = config.scheduled.len(),
+        "bot started"
+    );
+    for s in &config.scheduled {
+        tokio::spawn(post_every(server.clone(), user.clone(), s.clone()));
+    }
+    tokio::spawn(listen(server, user, config));
+    Ok(())
+}
+
+/// Reads chat from the hub and answers triggers, until the hub is gone.
+async fn listen(server: Arc<Server>, user: User, config: BotConfig) {
+    let rooms: HashSet<String> = config.rooms.iter().cloned().collect();
+    // Broadcasts arrive framed for the wire, and may be bigger than any
+    // client is allowed to send.
+    let codec = Codec::new(server.codec.framing).with_max_frame(usize::MAX);
+    loop {
+        let (queue, mut rx) =
+            SendQueue::new(BOT_BUF, OverflowPolicy::Disconnect, server.metrics.clone());
+        let handle = ClientHandle {
+            id: BOT_CONN_ID.to_string(),
+            username: user.username.clone(),
+            queue,
+            dnd: false,
+            blocked: HashSet::new(),
+            features: HashSet::new(),
+            rooms: rooms.clone(),
+        };
+        if !server.send_to_hub(HubCommand::Register(handle)).await {
+            return;
+        }
+        while let Some(data) = rx.recv().await {
+            let p = match decode_broadcast(codec, &data).await {
+                Some(p) if p.user_id != user.id => p,
+                _ => continue,
+            };
+            if let Some(reply) = config.respond(&p.content) {
+                post(&server, &user, reply, p.room).await;
+            }
+        }
+        // The hub only lets go of a client whose buffer filled up.
+        warn!("bot: fell behind, rejoining the hub");
+    }
+}
+
+async fn decode_broadcast(codec: Codec, mut data: &[u8]) -> Option<BroadcastPayload> {
+    let frame = codec.read_frame(&mut data).await.ok()??;
+    let pkt: Packet = serde_json::from_slice(&frame).ok()?;
+    if pkt.msg_type != MessageType::Broadcast {
+        return None;
+    }
+    serde_json::from_value(pkt.payload).ok()
+}
+
+async fn post_every(server: Arc<Server>, user: User, s: Scheduled) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(s.every_secs));
+    // The first tick is immediate; wait a full interval instead.
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        post(&server, &user, s.message.clone(), s.room.clone()).await;
+    }
+}
+
+async fn post(server: &Server, user: &User, content: String, room: Option<String>) {
+    if !server.post_as(user, content, room).await {
+        warn!("bot: message not sent (server busy or read-only)");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn config(triggers: &[(&str, &str)]) -> BotConfig {
+        let triggers: Vec<_> = triggers
+            .iter()
+            .map(|(trigger, response)| json!({ "trigger": trigger, "response": response }))
+            .collect();
+        serde_json::from_value(json!({ "name": "helper", "triggers": triggers })).unwrap()
+    }
+
+    #[test]
+    fn triggers_match_the_first_word_in_any_case() {
+        let bot = config(&[("!rules", "Be kind.")]);
+        assert_eq!(bot.respond("!rules").as_deref(), Some("Be kind."));
+        assert_eq!(bot.respond("  !RULES please").as_deref(), Some("Be kind."));
+        assert_eq!(bot.respond("what are the !rules"), None);
+        assert_eq!(bot.respond("!rulesx"), None);
+        assert_eq!(bot.respond(""), None);
+    }
+
+    #[test]
+    fn help_lists_the_triggers_unless_it_is_one() {
+        let bot = config(&[("!rules", "Be kind."), ("!faq", "See the wiki.")]);
+        assert_eq!(bot.respond("!help").as_deref(), Some("commands: !rules, !faq"));
+        assert_eq!(config(&[]).respond("!Help").as_deref(), Some("no commands configured"));
+        let bot = config(&[("!help", "Ask in #support.")]);
+        assert_eq!(bot.respond("!help").as_deref(), Some("Ask in #support."));
+    }
+
+    #[test]
+    fn load_normalizes_rooms_and_refuses_bad_entries() {
+        let path = std::env::temp_dir().join(format!("chat-bot-{}.json", std::process::id()));
+        let load = |config: serde_json::Value| {
+            fs::write(&path, config.to_string()).unwrap();
+            BotConfig::load(&path)
+        };
+        let scheduled = json!([{ "every_secs": 60, "message": "hi", "room": "Support" }]);
+        let config = json!({ "name": "helper", "rooms": ["Support"], "scheduled": scheduled });
+        let bot = load(config).unwrap();
+        assert_eq!(bot.rooms, ["support"]);
+        assert_eq!(bot.scheduled[0].room.as_deref(), Some("support"));
+
+        let two_words = json!([{ "trigger": "! rules", "response": "x" }]);
+        assert!(load(json!({ "name": "helper", "triggers": two_words })).is_err());
+        let never = json!([{ "every_secs": 0, "message": "hi" }]);
+        assert!(load(json!({ "name": "helper", "scheduled": never })).is_err());
+        assert!(load(json!({ "name": "helper", "rooms": ["no spaces"] })).is_err());
+        fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,321 @@
+//! Optional inbound bridge from a Redis pub/sub channel (`--bridge-redis`,
+//! `--bridge-channel`).
+//!
+//! Each message published to the channel is posted to chat from the
+//! bridge's own account (see [`Store::bot_user`]) and stored like any other
+//! chat message. A payload that is a JSON object `{ "content": ..., "room":
+//! ... }` goes to that room (the lobby when `room` is absent); anything else
+//! is posted to the lobby as plain text. Payloads are sanitized and pass
+//! through the word filter. A lost connection is retried with backoff, so
+//! the server starts even while Redis is down.
+//!
+//! Only what `SUBSCRIBE` needs of the Redis protocol is spoken here, over a
+//! plain TCP connection (no TLS).
+//!
+//! [`Store::bot_user`]: crate::store::Store::bot_user
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tracing::{debug, info, warn};
+
+use super::Server;
+use crate::protocol::normalize_room;
+use crate::store::User;
+
+/// First delay before reconnecting; doubled per failure up to the max.
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(30);
+/// Longest protocol line or bulk string accepted from Redis.
+const MAX_REPLY: usize = 1024 * 1024;
+
+/// Settings for [`start`].
+#[derive(Debug, Clone)]
+pub struct BridgeConfig {
+    /// `redis://[[user]:password@]host[:port][/db]`
+    pub url: String,
+    pub channel: String,
+    /// Username the bridged messages are posted under.
+    pub name: String,
+}
+
+/// A published payload in its structured form.
+#[derive(Deserialize)]
+struct BridgedMessage {
+    content: String,
+    #[serde(default)]
+    room: Option<String>,
+}
+
+/// Where to connect, from a `redis://` URL.
+#[derive(Debug, Clone)]
+struct RedisAddr {
+    host: String,
+    port: u16,
+    user: Option<String>,
+    password: Option<String>,
+}
+
+impl RedisAddr {
+    fn parse(url: &str) -> Result<Self> {
+        let rest = match url.strip_prefix("redis://") {
+            Some(rest) => rest,
+            None => bail!("bridge URL must start with redis://"),
+        };
+        let rest = rest.split('/').next().unwrap_or_default();
+        let (auth, host_port) = match rest.rsplit_once('@') {
+            Some((auth, host_port)) => (Some(auth), host_port),
+            None => (None, rest),
+        };
+        let (user, password) = match auth.map(|a| a.split_once(':').unwrap_or(("", a))) {
+            Some((user, password)) => (
+                Some(user.to_string()).filter(|u| !u.is_empty()),
+                Some(password.to_string()).filter(|p| !p.is_empty()),
+            ),
+            None => (None, None),
+        };
+        let (host, port) = match host_port.rsplit_once(':') {
+            Some((host, port)) => {
+                let port = port
+                    .parse()
+                    .with_context(|| format!("bad port in bridge URL {:?}", url))?;
+                (host, port)
+            }
+            None => (host_port, 6379),
+        };
+        if host.is_empty() {
+            bail!("bridge URL {:?} has no host", url);
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            user,
+            password,
+        })
+    }
+}
+
+/// Creates (or reuses) the bridge's account and starts relaying in the
+/// background. Fails on a bad URL or if the name belongs to a registered
+/// user.
+pub async fn start(server: Arc<Server>, config: BridgeConfig) -> Result<()> {
+    let addr = RedisAddr::parse(&config.url)?;
+    let user = server.store.bot_user(&config.name).await?;
+    info!(name = %user.username, channel = %config.channel, "bridge started");
+    tokio::spawn(run(server, addr, config.channel, user));
+    Ok(())
+}
+
+/// Subscribes and relays, reconnecting whenever the connection is lost.
+async fn run(server: Arc<Server>, addr: RedisAddr, channel: String, user: User) {
+    let mut delay = RECONNECT_MIN;
+    loop {
+        match subscribe(&server, &addr, &channel, &user).await {
+            // Subscribed, then lost: start over with a short delay.
+            Ok(()) => delay = RECONNECT_MIN,
+            Err(e) => warn!(error = %e, host = %addr.host, "bridge: connection failed"),
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(RECONNECT_MAX);
+    }
+}
+
+/// One connection: authenticates, subscribes, then posts messages until the
+/// connection drops. `Ok` once subscribed, however it ends.
+async fn subscribe(server: &Server, addr: &RedisAddr, channel: &str, user: &User) -> Result<()> {
+    let stream = TcpStream::connect((addr.host.as_str(), addr.port)).await?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    if let Some(password) = &addr.password {
+        let mut args = vec!["AUTH"];
+        args.extend(addr.user.as_deref());
+        args.push(password);
+        writer.write_all(&command(&args)).await?;
+        if let Reply::Error(e) = read_reply(&mut reader).await? {
+            bail!("AUTH failed: {}", e);
+        }
+    }
+    writer.write_all(&command(&["SUBSCRIBE", channel])).await?;
+    match read_reply(&mut reader).await? {
+        Reply::Array(parts) if parts.first().is_some_and(|p| p == b"subscribe") => {}
+        Reply::Error(e) => bail!("SUBSCRIBE failed: {}", e),
+        _ => bail!("unexpected reply to SUBSCRIBE"),
+    }
+    info!(%channel, "bridge: subscribed");
+
+    loop {
+        let parts = match read_reply(&mut reader).await {
+            Ok(Reply::Array(parts)) => parts,
+            Ok(_) => continue,
+            Err(e) => {
+                warn!(error = %e, "bridge: connection lost");
+                return Ok(());
+            }
+        };
+        if let [kind, _, payload] = parts.as_slice() {
+            if kind == b"message" {
+                relay(server, user, payload).await;
+            }
+        }
+    }
+}
+
+async fn relay(server: &Server, user: &User, payload: &[u8]) {
+    let text = String::from_utf8_lossy(payload);
+    let (content, room) = match serde_json::from_str::<BridgedMessage>(&text) {
+        Ok(m) => (m.content, m.room),
+        Err(_) => (text.into_owned(), None),
+    };
+    let room = match room {
+        Some(name) => match normalize_room(&name) {
+            Some(room) => Some(room),
+            None => {
+                warn!(room = %name, "bridge: invalid room, message skipped");
+                return;
+            }
+        },
+        None => None,
+    };
+    if content.trim().is_empty() {
+        return;
+    }
+    let content = match server.clean_content(content) {
+        Ok(content) => content,
+        Err(why) => {
+            debug!(%why, "bridge: message skipped");
+            return;
+        }
+    };
+    if !server.post_as(user, content, room).await {
+        warn!("bridge: message not sent (server busy or read-only)");
+    }
+}
+
+/// A command encoded as a RESP array of bulk strings.
+fn command(args: &[&str]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg.as_bytes());
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+/// The parts of a RESP reply the bridge cares about. Nested arrays and nil
+/// elements don't occur in pub/sub replies and are flattened or skipped.
+enum Reply {
+    Simple,
+    Error(String),
+    Array(Vec<Vec<u8>>),
+}
+
+async fn read_reply<R: AsyncBufRead + Unpin>(r: &mut R) -> Result<Reply> {
+    let line = read_line(r).await?;
+    let (kind, rest) = line.split_at(1.min(line.len()));
+    match kind {
+        "+" | ":" => Ok(Reply::Simple),
+        "-" => Ok(Reply::Error(rest.to_string())),
+        "$" => {
+            read_bulk(r, rest).await?;
+            Ok(Reply::Simple)
+        }
+        "*" => {
+            let n: i64 = rest.parse().context("bad array length")?;
+            let mut parts = Vec::new();
+            for _ in 0..n.max(0) {
+                let line = read_line(r).await?;
+                match line.split_at(1.min(line.len())) {
+                    ("$", len) => parts.extend(read_bulk(r, len).await?),
+                    (":" | "+", value) => parts.push(value.as_bytes().to_vec()),
+                    _ => bail!("unexpected array element {:?}", line),
+                }
+            }
+            Ok(Reply::Array(parts))
+        }
+        _ => bail!("unexpected reply {:?}", line),
+    }
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(r: &mut R) -> Result<String> {
+    let mut line = Vec::new();
+    let n = r.take(MAX_REPLY as u64).read_until(b'\n', &mut line).await?;
+    if n == 0 {
+        bail!("connection closed");
+    }
+    if !line.ends_with(b"\r\n") {
+        bail!("reply line too long or cut off");
+    }
+    line.truncate(line.len() - 2);
+    Ok(String::from_utf8(line)?)
+}
+
+/// Reads a bulk string body of length `len` (`-1` is nil).
+async fn read_bulk<R: AsyncBufRead + Unpin>(r: &mut R, len: &str) -> Result<Option<Vec<u8>>> {
+    let len: i64 = len.parse().context("bad bulk length")?;
+    if len < 0 {
+        return Ok(None);
+    }
+    let len = len as usize;
+    if len > MAX_REPLY {
+        bail!("bulk string of {} bytes is too long", len);
+    }
+    let mut data = vec![0; len + 2];
+    r.read_exact(&mut data).await?;
+    data.truncate(len);
+    Ok(Some(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn reply(mut raw: &[u8]) -> Result<Reply> {
+        read_reply(&mut raw).await
+    }
+
+    #[test]
+    fn urls_parse_with_and_without_credentials() {
+        let a = RedisAddr::parse("redis://cache").unwrap();
+        assert_eq!((a.host.as_str(), a.port, a.user, a.password), ("cache", 6379, None, None));
+        let a = RedisAddr::parse("redis://bridge:pw@10.0.0.5:6380/2").unwrap();
+        assert_eq!((a.host.as_str(), a.port), ("10.0.0.5", 6380));
+        assert_eq!((a.user.as_deref(), a.password.as_deref()), (Some("bridge"), Some("pw")));
+        let a = RedisAddr::parse("redis://:pw@cache").unwrap();
+        assert_eq!((a.user, a.password.as_deref()), (None, Some("pw")));
+        for bad in ["http://cache", "redis://", "redis://cache:port", "redis://pw@:6379"] {
+            assert!(RedisAddr::parse(bad).is_err(), "{} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn commands_are_arrays_of_bulk_strings() {
+        let encoded = command(&["SUBSCRIBE", "chat-in"]);
+        assert_eq!(encoded, b"*2\r\n$9\r\nSUBSCRIBE\r\n$7\r\nchat-in\r\n");
+    }
+
+    #[tokio::test]
+    async fn pubsub_replies_parse_into_their_parts() {
+        let raw = b"*3\r\n$7\r\nmessage\r\n$4\r\nchat\r\n$10\r\nhi\r\nthere!\r\n";
+        match reply(raw).await.unwrap() {
+            Reply::Array(parts) => assert_eq!(parts, [&b"message"[..], b"chat", b"hi\r\nthere!"]),
+            _ => panic!("not an array"),
+        }
+        match reply(b"*3\r\n$9\r\nsubscribe\r\n$4\r\nchat\r\n:1\r\n").await.unwrap() {
+            Reply::Array(parts) => assert_eq!(parts, [&b"subscribe"[..], b"chat", b"1"]),
+            _ => panic!("not an array"),
+        }
+        match reply(b"-NOAUTH Authentication required.\r\n").await.unwrap() {
+            Reply::Error(e) => assert_eq!(e, "NOAUTH Authentication required."),
+            _ => panic!("not an error"),
+        }
+        assert!(matches!(reply(b"+OK\r\n").await.unwrap(), Reply::Simple));
+        assert!(reply(b"*1\r\n$5\r\nab").await.is_err(), "a cut-off reply parsed");
+        assert!(reply(b"?what\r\n").await.is_err());
+    }
+}
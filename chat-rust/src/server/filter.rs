@@ -0,0 +1,158 @@
+//! Configurable word filter applied to chat content before broadcast.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::Result;
+
+/// What to do with a message containing a listed word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FilterMode {
+    /// Refuse the message with an error.
+    Reject,
+    /// Replace each listed word with asterisks.
+    #[default]
+    Mask,
+}
+
+impl FromStr for FilterMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(FilterMode::Reject),
+            "mask" => Ok(FilterMode::Mask),
+            _ => Err(format!("unknown filter mode {:?} (expected reject or mask)", s)),
+        }
+    }
+}
+
+impl fmt::Display for FilterMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FilterMode::Reject => "reject",
+            FilterMode::Mask => "mask",
+        })
+    }
+}
+
+/// Matches whole words only, case-insensitively, so a listed word inside a
+/// longer word ("Scunthorpe") is not a hit.
+pub struct WordFilter {
+    words: HashSet<String>,
+    mode: FilterMode,
+}
+
+impl WordFilter {
+    pub fn new<I, S>(words: I, mode: FilterMode) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let words = words
+            .into_iter()
+            .map(|w| w.as_ref().trim().to_lowercase())
+            .filter(|w| !w.is_empty())
+            .collect();
+        Self { words, mode }
+    }
+
+    /// Loads one word per line; blank lines and `#` comments are ignored.
+    pub fn load(path: &Path, mode: FilterMode) -> Result<Self> {
+        let data = fs::read_to_string(path)?;
+        let words = data.lines().filter(|l| !l.trim_start().starts_with('#'));
+        Ok(Self::new(words, mode))
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    /// Returns the content to deliver, or `None` if the message must be
+    /// rejected.
+    pub fn apply(&self, content: &str) -> Option<String> {
+        let mut out = String::with_capacity(content.len());
+        let mut hit = false;
+        for (is_word, token) in tokens(content) {
+            if is_word && self.words.contains(&token.to_lowercase()) {
+                hit = true;
+                out.extend(std::iter::repeat_n('*', token.chars().count()));
+            } else {
+                out.push_str(token);
+            }
+        }
+        match (hit, self.mode) {
+            (false, _) => Some(content.to_string()),
+            (true, FilterMode::Reject) => None,
+            (true, FilterMode::Mask) => Some(out),
+        }
+    }
+}
+
+/// Splits `s` into alternating runs of word characters (alphanumerics and
+/// apostrophes) and everything else, tagging each run with whether it is a word.
+fn tokens(s: &str) -> impl Iterator<Item = (bool, &str)> {
+    let is_word = |c: char| c.is_alphanumeric() || c == '\'';
+    let mut rest = s;
+    std::iter::from_fn(move || {
+        let first = rest.chars().next()?;
+        let word = is_word(first);
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| is_word(c) != word)
+            .map(|(i, _)| i)
+            .unwrap_or(rest.len());
+        let (token, tail) = rest.split_at(end);
+        rest = tail;
+        Some((word, token))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mask_mode_hides_listed_words() {
+        let filter = WordFilter::new(["darn", " Heck "], FilterMode::Mask);
+        assert_eq!(filter.len(), 2);
+        assert_eq!(filter.apply("Darn it, HECK!").as_deref(), Some("**** it, ****!"));
+        assert_eq!(filter.apply("all clean").as_deref(), Some("all clean"));
+    }
+
+    #[test]
+    fn reject_mode_refuses_the_message() {
+        let filter = WordFilter::new(["darn"], FilterMode::Reject);
+        assert_eq!(filter.apply("oh (darn)"), None);
+        assert_eq!(filter.apply("darning socks").as_deref(), Some("darning socks"));
+    }
+
+    #[test]
+    fn only_whole_words_match() {
+        let filter = WordFilter::new(["ass", "cunt", "darn"], FilterMode::Mask);
+        for clean in ["Scunthorpe", "a classic assessment", "darn's", "undarn", "darn2"] {
+            assert_eq!(filter.apply(clean).as_deref(), Some(clean));
+        }
+        assert_eq!(filter.apply("ass-darn_ass").as_deref(), Some("***-****_***"));
+        assert_eq!(filter.apply("ünd darn").as_deref(), Some("ünd ****"));
+    }
+
+    #[test]
+    fn lists_skip_comments_and_blank_lines() {
+        let path = std::env::temp_dir().join(format!("chat-filter-{}.txt", std::process::id()));
+        fs::write(&path, "# mild words\ndarn\n\n  heck\n  # not a word\n").unwrap();
+        let filter = WordFilter::load(&path, FilterMode::Mask).unwrap();
+        fs::remove_file(&path).ok();
+        assert_eq!(filter.len(), 2);
+        assert_eq!(filter.apply("# heck").as_deref(), Some("# ****"));
+        assert_eq!("reject".parse::<FilterMode>(), Ok(FilterMode::Reject));
+        assert!("block".parse::<FilterMode>().is_err());
+    }
+}
@@ -0,0 +1,134 @@
+//! Encoded `history` responses, reused while the messages behind them are
+//! unchanged.
+//!
+//! Most clients ask for the same history (the default limit, usually without
+//! system events) right after connecting. Rather than copying the messages
+//! out of the store and encoding them once per client, the response packet
+//! is encoded once per limit and `include_system` and kept along with the
+//! store's messages version at the time. An entry is served only while that
+//! version is current, so any message saved, expired, pruned or imported
+//! since retires it, and only until the first message in it with a TTL
+//! expires. At most [`MAX_ENTRIES`] are kept.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+
+use crate::protocol::StoredMessage;
+
+/// Most responses kept at once; each distinct limit asked for takes one.
+pub const MAX_ENTRIES: usize = 8;
+
+/// A request's limit and `include_system`.
+type Key = (usize, bool);
+
+struct Entry {
+    version: u64,
+    /// When the first message in it expires, if any does.
+    expires_at: Option<DateTime<Utc>>,
+    body: Arc<[u8]>,
+}
+
+#[derive(Default)]
+pub struct HistoryCache {
+    entries: Mutex<HashMap<Key, Entry>>,
+}
+
+impl HistoryCache {
+    /// The encoded response for `key`, if it was built at `version` and
+    /// none of its messages has expired by `now`.
+    pub fn get(&self, key: Key, version: u64, now: DateTime<Utc>) -> Option<Arc<[u8]>> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(&key)?;
+        let fresh = entry.version == version && entry.expires_at.is_none_or(|at| at > now);
+        fresh.then(|| entry.body.clone())
+    }
+
+    /// Keeps `body`, the encoded response listing `messages`, which were
+    /// read from the store at `version` or later.
+    pub fn insert(&self, key: Key, version: u64, messages: &[StoredMessage], body: Arc<[u8]>) {
+        let expires_at = messages.iter().filter_map(|m| m.expires_at).min();
+        let mut entries = self.entries.lock().unwrap();
+        // Entries from other versions can never be served again.
+        entries.retain(|_, e| e.version == version);
+        if entries.len() >= MAX_ENTRIES && !entries.contains_key(&key) {
+            entries.clear();
+        }
+        entries.insert(key, Entry { version, expires_at, body });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+    use crate::protocol::MessageKind;
+
+    fn expiring(expires_at: Option<DateTime<Utc>>) -> StoredMessage {
+        StoredMessage {
+            id: "m1".to_string(),
+            user_id: "u1".to_string(),
+            username: "alice".to_string(),
+            content: "hello".to_string(),
+            timestamp: Utc::now(),
+            kind: MessageKind::Chat,
+            entities: Vec::new(),
+            format: None,
+            room: None,
+            expires_at,
+            seq: 1,
+        }
+    }
+
+    fn body(text: &str) -> Arc<[u8]> {
+        Arc::from(text.as_bytes())
+    }
+
+    #[test]
+    fn an_entry_is_served_only_at_its_version() {
+        let cache = HistoryCache::default();
+        let now = Utc::now();
+        cache.insert((50, false), 3, &[expiring(None)], body("fifty"));
+        cache.insert((50, true), 3, &[], body("with system"));
+        assert_eq!(cache.get((50, false), 3, now).as_deref(), Some(&b"fifty"[..]));
+        assert_eq!(cache.get((50, true), 3, now).as_deref(), Some(&b"with system"[..]));
+        assert!(cache.get((20, false), 3, now).is_none());
+        assert!(cache.get((50, false), 4, now).is_none());
+
+        // A newer version retires everything built before it.
+        cache.insert((20, false), 4, &[], body("twenty"));
+        assert!(cache.get((50, false), 3, now).is_none());
+        assert!(cache.get((20, false), 4, now).is_some());
+    }
+
+    #[test]
+    fn an_entry_lapses_when_its_first_message_expires() {
+        let cache = HistoryCache::default();
+        let now = Utc::now();
+        let soon = now + TimeDelta::seconds(5);
+        let later = now + TimeDelta::seconds(60);
+        let messages = [expiring(Some(later)), expiring(None), expiring(Some(soon))];
+        cache.insert((50, false), 1, &messages, body("fleeting"));
+        assert!(cache.get((50, false), 1, now).is_some());
+        assert!(cache.get((50, false), 1, soon - TimeDelta::milliseconds(1)).is_some());
+        assert!(cache.get((50, false), 1, soon).is_none());
+    }
+
+    #[test]
+    fn the_cache_holds_at_most_max_entries() {
+        let cache = HistoryCache::default();
+        let now = Utc::now();
+        for limit in 1..=MAX_ENTRIES {
+            cache.insert((limit, false), 1, &[], body("x"));
+        }
+        assert!((1..=MAX_ENTRIES).all(|limit| cache.get((limit, false), 1, now).is_some()));
+        // Replacing a kept key makes no room; a new one starts afresh.
+        cache.insert((1, false), 1, &[], body("y"));
+        assert!(cache.get((MAX_ENTRIES, false), 1, now).is_some());
+        cache.insert((MAX_ENTRIES + 1, false), 1, &[], body("z"));
+        assert!(cache.get((MAX_ENTRIES + 1, false), 1, now).is_some());
+        assert!(cache.get((1, false), 1, now).is_none());
+    }
+}
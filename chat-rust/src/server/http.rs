@@ -0,0 +1,257 @@
+//! Read-only HTTP/JSON view of the chat server for dashboards and bots.
+//!
+//! Every route requires `Authorization: Bearer <token>` and returns the same
+//! JSON shapes as the `data` field of the equivalent TCP responses. History
+//! and search are held to [`MAX_RESPONSE_BYTES`](super::MAX_RESPONSE_BYTES)
+//! like their TCP counterparts; a body that had to be cut short carries
+//! `X-Truncated: true`.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use tracing::info;
+
+use super::{fitting, history_limit, search_limit, Server};
+use crate::store::SearchFilter;
+
+#[derive(Clone)]
+struct HttpState {
+    server: Arc<Server>,
+    token: Arc<str>,
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    #[serde(default)]
+    limit: usize,
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    #[serde(default)]
+    query: String,
+    #[serde(default)]
+    username: String,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    #[serde(default)]
+    limit: usize,
+    #[serde(default)]
+    offset: usize,
+}
+
+/// Serves the HTTP API on `addr` until the listener fails.
+pub async fn serve(server: Arc<Server>, addr: &str, token: String) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(%addr, "http: listening");
+    serve_on(server, listener, token).await
+}
+
+/// Serves the HTTP API on an already bound `listener`.
+pub async fn serve_on(server: Arc<Server>, listener: TcpListener, token: String) -> Result<()> {
+    let state = HttpState {
+        server,
+        token: token.into(),
+    };
+    let app = Router::new()
+        .route("/history", get(history))
+        .route("/search", get(search))
+        .route("/users", get(users))
+        .with_state(state);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn history(
+    State(st): State<HttpState>,
+    headers: HeaderMap,
+    Query(q): Query<HistoryQuery>,
+) -> Response {
+    if !authorized(&st, &headers) {
+        return unauthorized();
+    }
+    let mut msgs = st.server.store.get_history(history_limit(q.limit), false).await;
+    // Keep the newest, as the TCP `history` does.
+    let fit = fitting(msgs.iter().rev());
+    let truncated = fit < msgs.len();
+    msgs.drain(..msgs.len() - fit);
+    with_truncated(Json(msgs).into_response(), truncated)
+}
+
+async fn search(
+    State(st): State<HttpState>,
+    headers: HeaderMap,
+    Query(q): Query<SearchQuery>,
+) -> Response {
+    if !authorized(&st, &headers) {
+        return unauthorized();
+    }
+    if q.query.is_empty() && q.username.is_empty() && q.from.is_none() && q.to.is_none() {
+        return (
+            StatusCode::BAD_REQUEST,
+            "provide at least one search criterion (query, username, from, or to)",
+        )
+            .into_response();
+    }
+    let filter = SearchFilter {
+        query: crate::query::Query::parse(&q.query),
+        username: q.username,
+        from: q.from,
+        to: q.to,
+        include_system: false,
+    };
+    let mut result = st.server.store.search(&filter, search_limit(q.limit), q.offset).await;
+    let fit = fitting(&result.messages);
+    let truncated = fit < result.messages.len();
+    result.messages.truncate(fit);
+    with_truncated(Json(result).into_response(), truncated)
+}
+
+/// Marks `response` as cut short to fit, if it was.
+fn with_truncated(mut response: Response, truncated: bool) -> Response {
+    if truncated {
+        response.headers_mut().insert("x-truncated", HeaderValue::from_static("true"));
+    }
+    response
+}
+
+async fn users(State(st): State<HttpState>, headers: HeaderMap) -> Response {
+    if !authorized(&st, &headers) {
+        return unauthorized();
+    }
+    Json(st.server.online_users().await).into_response()
+}
+
+fn authorized(st: &HttpState, headers: &HeaderMap) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|tok| constant_time_eq(tok.as_bytes(), st.token.as_bytes()))
+}
+
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Bearer")],
+        "missing or invalid bearer token",
+    )
+        .into_response()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+
+    use super::*;
+    use crate::protocol::{MessageKind, StoredMessage};
+    use crate::server::{ServerConfig, MAX_HISTORY_LIMIT, MAX_RESPONSE_BYTES};
+
+    const TOKEN: &str = "s3cret";
+
+    /// An in-memory server holding `count` lobby messages of `size` bytes.
+    async fn state_with(count: u64, size: usize) -> HttpState {
+        let config = ServerConfig {
+            ephemeral: true,
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(Server::new(config).unwrap());
+        for n in 1..=count {
+            let msg = StoredMessage {
+                id: format!("m{}", n),
+                user_id: "u1".to_string(),
+                username: "alice".to_string(),
+                content: format!("{} {}", n, "x".repeat(size)),
+                timestamp: Utc::now(),
+                kind: MessageKind::Chat,
+                entities: Vec::new(),
+                format: None,
+                room: None,
+                expires_at: None,
+                seq: n,
+            };
+            server.store.save_message(msg).await.unwrap();
+        }
+        HttpState {
+            server,
+            token: TOKEN.into(),
+        }
+    }
+
+    fn bearer() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let value = HeaderValue::from_str(&format!("Bearer {}", TOKEN)).unwrap();
+        headers.insert(header::AUTHORIZATION, value);
+        headers
+    }
+
+    async fn messages(response: Response) -> Vec<StoredMessage> {
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(body.len() <= MAX_RESPONSE_BYTES, "body is {} bytes", body.len());
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn history_limit_is_clamped() {
+        let st = state_with(MAX_HISTORY_LIMIT as u64 + 10, 10).await;
+        let response = history(State(st), bearer(), Query(HistoryQuery { limit: 100_000 })).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("x-truncated").is_none());
+        let msgs = messages(response).await;
+        assert_eq!(msgs.len(), MAX_HISTORY_LIMIT);
+        assert_eq!(msgs.last().unwrap().id, format!("m{}", MAX_HISTORY_LIMIT + 10));
+    }
+
+    #[tokio::test]
+    async fn large_history_is_cut_to_fit() {
+        let st = state_with(200, 10 * 1024).await;
+        let response = history(State(st), bearer(), Query(HistoryQuery { limit: 200 })).await;
+        assert_eq!(response.headers()["x-truncated"], "true");
+        let msgs = messages(response).await;
+        assert!(msgs.len() < 200 && !msgs.is_empty(), "got {} messages", msgs.len());
+        assert_eq!(msgs.last().unwrap().id, "m200");
+    }
+
+    #[tokio::test]
+    async fn large_search_is_cut_to_fit() {
+        let st = state_with(200, 10 * 1024).await;
+        let q = SearchQuery {
+            query: String::new(),
+            username: "alice".to_string(),
+            from: None,
+            to: None,
+            limit: 200,
+            offset: 0,
+        };
+        let response = search(State(st), bearer(), Query(q)).await;
+        assert_eq!(response.headers()["x-truncated"], "true");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(body.len() <= MAX_RESPONSE_BYTES, "body is {} bytes", body.len());
+        let result: crate::protocol::SearchResult = serde_json::from_slice(&body).unwrap();
+        assert_eq!(result.total, 200);
+        assert!(result.messages.len() < 200);
+        assert_eq!(result.messages[0].id, "m200");
+    }
+
+    #[tokio::test]
+    async fn routes_need_the_token() {
+        let st = state_with(1, 10).await;
+        let response = history(State(st), HeaderMap::new(), Query(HistoryQuery { limit: 0 })).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -1,16 +1,49 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use tokio::sync::mpsc;
+use tracing::debug;
+
+use super::send_queue::{Pushed, SendQueue};
 
 pub struct ClientHandle {
     pub id: String,
     pub username: String,
-    pub tx: mpsc::Sender<Vec<u8>>,
+    /// The connection's send queue; its overflow policy decides what a
+    /// full one does with a broadcast.
+    pub queue: SendQueue,
+    /// Do-not-disturb: skip chat broadcasts, keep everything else.
+    pub dnd: bool,
+    /// User IDs whose chat broadcasts this client doesn't receive.
+    pub blocked: HashSet<String>,
+    /// Optional protocol features negotiated with `hello`.
+    pub features: HashSet<String>,
+    /// Rooms joined, besides the lobby.
+    pub rooms: HashSet<String>,
 }
 
 pub enum HubCommand {
     Register(ClientHandle),
     Unregister(String), // client id
+    Rename { id: String, username: String },
+    SetDnd { id: String, enabled: bool },
+    SetBlocked { id: String, blocked: HashSet<String> },
+    SetFeatures { id: String, features: HashSet<String> },
+    SetRooms { id: String, rooms: HashSet<String> },
+    /// Delivered to every client.
     Broadcast(Vec<u8>),
+    /// Delivered only to clients that negotiated `feature`.
+    FeatureBroadcast { feature: &'static str, data: Vec<u8> },
+    /// Delivered only to clients that joined `room`.
+    RoomBroadcast { room: String, data: Vec<u8> },
+    /// A chat message from `sender_id`; skipped for clients in
+    /// do-not-disturb and those who blocked the sender. A message for a
+    /// `room` only goes to clients that joined it. `except` names a
+    /// connection to leave out, the sender's when it echoes locally.
+    ChatBroadcast {
+        sender_id: String,
+        room: Option<String>,
+        except: Option<String>,
+        data: Vec<u8>,
+    },
 }
 
 /// run_hub fans out every broadcast to all connected clients.
@@ -21,36 +54,212 @@ pub async fn run_hub(mut rx: mpsc::Receiver<HubCommand>) {
     while let Some(cmd) = rx.recv().await {
         match cmd {
             HubCommand::Register(handle) => {
-                eprintln!(
-                    "[hub] +client {} ({})  total={}",
-                    handle.username,
-                    handle.id,
-                    clients.len() + 1
-                );
+                debug!(conn_id = %handle.id, total = clients.len() + 1, "hub: client added");
                 clients.insert(handle.id.clone(), handle);
             }
             HubCommand::Unregister(id) => {
                 if let Some(handle) = clients.remove(&id) {
-                    eprintln!(
-                        "[hub] -client {} ({})  total={}",
-                        handle.username,
-                        handle.id,
-                        clients.len()
+                    debug!(
+                        conn_id = %handle.id,
+                        username = %handle.username,
+                        total = clients.len(),
+                        "hub: client removed"
                     );
                 }
             }
-            HubCommand::Broadcast(data) => {
-                let mut to_remove = Vec::new();
-                for (id, handle) in &clients {
-                    if handle.tx.try_send(data.clone()).is_err() {
-                        eprintln!("[hub] dropped slow client {}", handle.username);
-                        to_remove.push(id.clone());
-                    }
+            HubCommand::Rename { id, username } => {
+                if let Some(handle) = clients.get_mut(&id) {
+                    handle.username = username;
+                }
+            }
+            HubCommand::SetDnd { id, enabled } => {
+                if let Some(handle) = clients.get_mut(&id) {
+                    handle.dnd = enabled;
                 }
-                for id in to_remove {
-                    clients.remove(&id);
+            }
+            HubCommand::SetBlocked { id, blocked } => {
+                if let Some(handle) = clients.get_mut(&id) {
+                    handle.blocked = blocked;
+                }
+            }
+            HubCommand::SetFeatures { id, features } => {
+                if let Some(handle) = clients.get_mut(&id) {
+                    handle.features = features;
                 }
             }
+            HubCommand::SetRooms { id, rooms } => {
+                if let Some(handle) = clients.get_mut(&id) {
+                    handle.rooms = rooms;
+                }
+            }
+            HubCommand::Broadcast(data) => fanout(&mut clients, &data, None, None, None),
+            HubCommand::FeatureBroadcast { feature, data } => {
+                fanout(&mut clients, &data, None, None, Some(feature))
+            }
+            HubCommand::RoomBroadcast { room, data } => {
+                fanout(&mut clients, &data, Some(&room), None, None)
+            }
+            HubCommand::ChatBroadcast { sender_id, room, except, data } => {
+                let chat = ChatFrom {
+                    sender: &sender_id,
+                    except: except.as_deref(),
+                };
+                fanout(&mut clients, &data, room.as_deref(), Some(chat), None)
+            }
+        }
+    }
+}
+
+/// Where a chat message comes from.
+#[derive(Clone, Copy)]
+struct ChatFrom<'a> {
+    /// The sender's user ID.
+    sender: &'a str,
+    /// Connection ID that doesn't get the message.
+    except: Option<&'a str>,
+}
+
+/// Sends `data` to every client. One whose send queue is full gets its
+/// overflow policy applied; under `disconnect` it is dropped from the hub,
+/// and its connection closes too.
+/// With `room` set, clients not in the room are skipped. For a chat
+/// message, so are clients in dnd, blocking the sender or named by
+/// `except`; with `feature` set, so is every client that didn't negotiate it.
+fn fanout(
+    clients: &mut HashMap<String, ClientHandle>,
+    data: &[u8],
+    room: Option<&str>,
+    chat: Option<ChatFrom>,
+    feature: Option<&str>,
+) {
+    let mut to_remove = Vec::new();
+    for (id, handle) in clients.iter() {
+        if feature.is_some_and(|f| !handle.features.contains(f)) {
+            continue;
+        }
+        if room.is_some_and(|room| !handle.rooms.contains(room)) {
+            continue;
+        }
+        if let Some(chat) = chat {
+            if handle.dnd || handle.blocked.contains(chat.sender) {
+                continue;
+            }
+            if chat.except == Some(id.as_str()) {
+                continue;
+            }
         }
+        match handle.queue.push(data.to_vec()) {
+            Pushed::Queued | Pushed::Dropped => {}
+            Pushed::Overflow => {
+                debug!(conn_id = %id, username = %handle.username, "hub: dropped slow client");
+                to_remove.push(id.clone());
+            }
+            Pushed::Closed => to_remove.push(id.clone()),
+        }
+    }
+    for id in to_remove {
+        clients.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::server::metrics::Metrics;
+    use crate::server::send_queue::{OverflowPolicy, SendQueueRx};
+
+    fn handle(id: &str) -> (ClientHandle, SendQueueRx) {
+        let metrics = Arc::new(Metrics::default());
+        let (queue, rx) = SendQueue::new(8, OverflowPolicy::Disconnect, metrics);
+        let handle = ClientHandle {
+            id: id.to_string(),
+            username: id.to_string(),
+            queue,
+            dnd: false,
+            blocked: HashSet::new(),
+            features: HashSet::new(),
+            rooms: HashSet::new(),
+        };
+        (handle, rx)
+    }
+
+    /// Runs `commands` through a hub and returns what each client received.
+    async fn deliver(
+        handles: Vec<(ClientHandle, SendQueueRx)>,
+        commands: Vec<HubCommand>,
+    ) -> HashMap<String, Vec<Vec<u8>>> {
+        let (tx, rx) = mpsc::channel(16);
+        let hub = tokio::spawn(run_hub(rx));
+        let mut queues = Vec::new();
+        for (handle, queue) in handles {
+            queues.push((handle.id.clone(), queue));
+            tx.send(HubCommand::Register(handle)).await.unwrap();
+        }
+        for cmd in commands {
+            tx.send(cmd).await.unwrap();
+        }
+        drop(tx);
+        hub.await.unwrap();
+        queues
+            .into_iter()
+            .map(|(id, mut q)| (id, std::iter::from_fn(|| q.try_recv()).collect()))
+            .collect()
+    }
+
+    fn chat(except: Option<&str>, room: Option<&str>, data: &[u8]) -> HubCommand {
+        HubCommand::ChatBroadcast {
+            sender_id: "u-alice".to_string(),
+            room: room.map(str::to_string),
+            except: except.map(str::to_string),
+            data: data.to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn chat_skips_the_excepted_connection_dnd_and_blockers() {
+        let (alice, alice_rx) = handle("alice");
+        let (bob, bob_rx) = handle("bob");
+        let (mut dnd, dnd_rx) = handle("dnd");
+        dnd.dnd = true;
+        let (mut blocker, blocker_rx) = handle("blocker");
+        blocker.blocked.insert("u-alice".to_string());
+        let handles = vec![(alice, alice_rx), (bob, bob_rx), (dnd, dnd_rx), (blocker, blocker_rx)];
+        let commands = vec![
+            chat(Some("alice"), None, b"echoed locally"),
+            chat(None, None, b"echoed by the server"),
+            HubCommand::Broadcast(b"notice".to_vec()),
+        ];
+        let got = deliver(handles, commands).await;
+        assert_eq!(got["alice"], [&b"echoed by the server"[..], b"notice"]);
+        assert_eq!(got["bob"], [&b"echoed locally"[..], b"echoed by the server", b"notice"]);
+        assert_eq!(got["dnd"], [b"notice"]);
+        assert_eq!(got["blocker"], [b"notice"]);
+    }
+
+    #[tokio::test]
+    async fn rooms_and_features_narrow_the_audience() {
+        let (mut member, member_rx) = handle("member");
+        member.rooms.insert("rust".to_string());
+        let (mut modern, modern_rx) = handle("modern");
+        modern.features.insert("profiles".to_string());
+        let handles = vec![(member, member_rx), (modern, modern_rx)];
+        let commands = vec![
+            chat(None, Some("rust"), b"room chat"),
+            HubCommand::RoomBroadcast { room: "rust".to_string(), data: b"topic".to_vec() },
+            HubCommand::FeatureBroadcast { feature: "profiles", data: b"profile".to_vec() },
+        ];
+        let got = deliver(handles, commands).await;
+        assert_eq!(got["member"], [&b"room chat"[..], b"topic"]);
+        assert_eq!(got["modern"], [b"profile"]);
+    }
+
+    #[tokio::test]
+    async fn a_full_queue_drops_the_client_from_the_hub() {
+        let (slow, slow_rx) = handle("slow");
+        let commands = (0..10u8).map(|n| HubCommand::Broadcast(vec![n])).collect();
+        let got = deliver(vec![(slow, slow_rx)], commands).await;
+        assert_eq!(got["slow"].len(), 8, "frames past the overflow were delivered");
     }
 }
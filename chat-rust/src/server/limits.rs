@@ -0,0 +1,116 @@
+//! Rate limits that can be changed without a restart (`--limits-file`).
+//!
+//! The limits start out from the command-line flags. A limits file, if
+//! given, is a JSON object whose fields override them:
+//!
+//! ```json
+//! { "dedup_secs": 10, "max_conns_per_ip": 8, "auth_max_failures": 5, "auth_window_secs": 300 }
+//! ```
+//!
+//! Every field is optional, and a missing one keeps the flag's value.
+//! `dedup_secs` and `max_conns_per_ip` of 0 switch that limit off. The file
+//! is read at startup and again on each reload (SIGHUP), always on top of
+//! the flags. So a field deleted from the file goes back to the flag's value.
+
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimits {
+    /// See `ServerConfig::dedup_window`.
+    pub dedup_window: Option<Duration>,
+    /// See `ServerConfig::max_conns_per_ip`.
+    pub max_conns_per_ip: Option<usize>,
+    /// See `ServerConfig::auth_max_failures`.
+    pub auth_max_failures: u32,
+    pub auth_window: Duration,
+}
+
+/// The limits file as written; `None` keeps the current value.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LimitsFile {
+    dedup_secs: Option<u64>,
+    max_conns_per_ip: Option<usize>,
+    auth_max_failures: Option<u32>,
+    auth_window_secs: Option<u64>,
+}
+
+impl RateLimits {
+    /// These limits with the fields set in the file at `path` replaced.
+    pub fn with_file(self, path: &Path) -> Result<Self> {
+        let data = fs::read_to_string(path)
+            .map_err(|e| anyhow!("failed to read limits file {}: {}", path.display(), e))?;
+        let file: LimitsFile = serde_json::from_str(&data)
+            .map_err(|e| anyhow!("bad limits file {}: {}", path.display(), e))?;
+        Ok(Self {
+            dedup_window: match file.dedup_secs {
+                Some(0) => None,
+                Some(secs) => Some(Duration::from_secs(secs)),
+                None => self.dedup_window,
+            },
+            max_conns_per_ip: match file.max_conns_per_ip {
+                Some(0) => None,
+                Some(max) => Some(max),
+                None => self.max_conns_per_ip,
+            },
+            auth_max_failures: file.auth_max_failures.unwrap_or(self.auth_max_failures),
+            auth_window: file.auth_window_secs.map_or(self.auth_window, Duration::from_secs),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    const FLAGS: RateLimits = RateLimits {
+        dedup_window: Some(Duration::from_secs(2)),
+        max_conns_per_ip: None,
+        auth_max_failures: 5,
+        auth_window: Duration::from_secs(300),
+    };
+
+    /// `FLAGS` overridden by a limits file holding `contents`.
+    fn with(contents: &str) -> Result<RateLimits> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let name = format!("chat-limits-{}-{}.json", std::process::id(), n);
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, contents).unwrap();
+        let limits = FLAGS.with_file(&path);
+        fs::remove_file(&path).ok();
+        limits
+    }
+
+    #[test]
+    fn the_file_overrides_only_the_fields_it_sets() {
+        assert_eq!(with("{}").unwrap(), FLAGS);
+        let limits = with(r#"{ "max_conns_per_ip": 8, "auth_window_secs": 60 }"#).unwrap();
+        assert_eq!(limits.max_conns_per_ip, Some(8));
+        assert_eq!(limits.auth_window, Duration::from_secs(60));
+        assert_eq!((limits.dedup_window, limits.auth_max_failures), (FLAGS.dedup_window, 5));
+    }
+
+    #[test]
+    fn zero_switches_a_limit_off() {
+        let limits = with(r#"{ "dedup_secs": 0, "max_conns_per_ip": 0 }"#).unwrap();
+        assert_eq!((limits.dedup_window, limits.max_conns_per_ip), (None, None));
+    }
+
+    #[test]
+    fn bad_files_are_errors() {
+        let err = with(r#"{ "dedup_sec": 3 }"#).unwrap_err().to_string();
+        assert!(err.contains("unknown field `dedup_sec`"), "unexpected error: {}", err);
+        assert!(with("dedup_secs = 3").is_err());
+        let missing = Path::new("/nonexistent/limits.json");
+        let err = FLAGS.with_file(missing).unwrap_err().to_string();
+        assert!(err.starts_with("failed to read limits file"), "unexpected error: {}", err);
+    }
+}
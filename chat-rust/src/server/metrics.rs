@@ -0,0 +1,144 @@
+//! Process-wide counters exposed in the Prometheus text format.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use tokio::net::TcpListener;
+use tracing::info;
+
+use super::Server;
+
+#[derive(Default)]
+pub struct Metrics {
+    pub connected_clients: AtomicI64,
+    pub messages_broadcast: AtomicU64,
+    pub messages_persisted: AtomicU64,
+    pub slow_clients_dropped: AtomicU64,
+    pub send_frames_dropped: AtomicU64,
+    pub persist_queue_full: AtomicU64,
+    pub auth_failures: AtomicU64,
+    pub auth_lockouts: AtomicU64,
+    pub hub_send_failures: AtomicU64,
+    pub webhook_dropped: AtomicU64,
+    pub audit_dropped: AtomicU64,
+}
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        gauge(
+            &mut out,
+            "chat_connected_clients",
+            "Currently open client connections.",
+            self.connected_clients.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "chat_messages_broadcast_total",
+            "Chat messages fanned out to connected clients.",
+            &self.messages_broadcast,
+        );
+        counter(
+            &mut out,
+            "chat_messages_persisted_total",
+            "Chat messages written to the store.",
+            &self.messages_persisted,
+        );
+        counter(
+            &mut out,
+            "chat_slow_clients_dropped_total",
+            "Clients disconnected because their send buffer was full.",
+            &self.slow_clients_dropped,
+        );
+        counter(
+            &mut out,
+            "chat_send_frames_dropped_total",
+            "Frames a full send buffer dropped under the drop_newest or drop_oldest policy.",
+            &self.send_frames_dropped,
+        );
+        counter(
+            &mut out,
+            "chat_persist_queue_full_total",
+            "Messages rejected or left unpersisted because the persistence queue was full.",
+            &self.persist_queue_full,
+        );
+        counter(
+            &mut out,
+            "chat_auth_failures_total",
+            "Failed login and registration attempts.",
+            &self.auth_failures,
+        );
+        counter(
+            &mut out,
+            "chat_auth_lockouts_total",
+            "Usernames or addresses locked out after repeated failed logins.",
+            &self.auth_lockouts,
+        );
+        counter(
+            &mut out,
+            "chat_hub_send_failures_total",
+            "Hub commands (including broadcasts) lost because the hub was saturated or gone.",
+            &self.hub_send_failures,
+        );
+        counter(
+            &mut out,
+            "chat_webhook_dropped_total",
+            "Messages never delivered to the webhook (queue full or out of retries).",
+            &self.webhook_dropped,
+        );
+        counter(
+            &mut out,
+            "chat_audit_dropped_total",
+            "Audit log entries dropped because the writer fell behind.",
+            &self.audit_dropped,
+        );
+        out
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: i64) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    let _ = writeln!(
+        out,
+        "# HELP {} {}\n# TYPE {} counter\n{} {}",
+        name,
+        help,
+        name,
+        name,
+        value.load(Ordering::Relaxed)
+    );
+}
+
+/// Serves `GET /metrics` on `addr` until the listener fails.
+pub async fn serve(server: Arc<Server>, addr: &str) -> Result<()> {
+    let app = Router::new()
+        .route("/metrics", get(scrape))
+        .with_state(server.metrics.clone());
+
+    let listener = TcpListener::bind(addr).await?;
+    info!(%addr, "metrics: listening");
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn scrape(State(metrics): State<Arc<Metrics>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics.render(),
+    )
+}
@@ -1,21 +1,117 @@
+pub mod audit;
+pub mod auth;
+pub mod auth_limit;
+pub mod bot;
+pub mod bridge;
+pub mod filter;
+pub mod history_cache;
+pub mod http;
 pub mod hub;
+pub mod limits;
+pub mod metrics;
+pub mod sanitize;
+pub mod send_queue;
+pub mod webhook;
 
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
-use chrono::Utc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, RwLock};
+use anyhow::{anyhow, bail, Result};
+use async_compression::tokio::bufread::ZlibDecoder;
+use async_compression::tokio::write::ZlibEncoder;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, Notify, RwLock};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
 
+use crate::entities;
 use crate::protocol::*;
-use crate::store::Store;
+use crate::query::Query;
+use crate::schema;
+use crate::store::{
+    normalize_username, FsyncPolicy, SearchFilter, Store, StoreHandle, StoreOptions, User,
+    GUEST_PREFIX,
+};
+use audit::{AuditAction, AuditEntry, AuditLog};
+use auth::{AuthProvider, CommandAuth};
+use auth_limit::AuthLimiter;
+use filter::{FilterMode, WordFilter};
+use history_cache::HistoryCache;
 use hub::{ClientHandle, HubCommand, run_hub};
+use limits::RateLimits;
+use metrics::Metrics;
+use sanitize::{sanitize, SanitizeMode};
+use send_queue::{OverflowPolicy, Pushed, SendQueue};
+use webhook::Webhook;
 
-const SEND_BUF: usize = 256;
+/// Frames a connection's send queue holds before its overflow policy applies.
+pub const DEFAULT_SEND_BUFFER: usize = 256;
+const HUB_BUF: usize = 1024;
+/// How long a hub command may wait for room before it is given up on.
+const HUB_SEND_TIMEOUT: Duration = Duration::from_secs(1);
 const WORKER_JOBS: usize = 1024;
+const DEFAULT_HISTORY_LIMIT: usize = 20;
+const DEFAULT_SEARCH_LIMIT: usize = 100;
+const DEFAULT_RECENT_HOURS: u32 = 24;
+/// Furthest back `recentusers` looks (30 days).
+const MAX_RECENT_HOURS: u32 = 24 * 30;
+/// Most messages a single `history` returns.
+const MAX_HISTORY_LIMIT: usize = 500;
+/// Most messages a single search page returns.
+const MAX_SEARCH_LIMIT: usize = 500;
+const DEFAULT_DIRECTORY_LIMIT: usize = 50;
+/// Most accounts a single `directory` page returns.
+const MAX_DIRECTORY_LIMIT: usize = 200;
+/// Most messages a single `sync` returns.
+const MAX_SYNC: usize = 500;
+/// Largest a `history` or `search` response may encode to. Messages that
+/// would take it over are left out and the response is marked `truncated`.
+pub const MAX_RESPONSE_BYTES: usize = 1024 * 1024;
+/// What a response needs besides its messages, with room to spare.
+const RESPONSE_OVERHEAD: usize = 1024;
+const RETENTION_SWEEP: Duration = Duration::from_secs(60);
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a leave notice is held back in case the user reconnects.
+pub const DEFAULT_PRESENCE_DEBOUNCE: Duration = Duration::from_secs(5);
+/// How often expired messages are looked for; they may outlive their TTL by
+/// up to this much.
+const EXPIRY_SWEEP: Duration = Duration::from_secs(1);
+/// How long a chat message may wait for room in the persistence queue before
+/// the sender is told it failed.
+const PERSIST_TIMEOUT: Duration = Duration::from_secs(1);
+/// Queued on a client's send channel to make the write pump switch to zlib.
+/// Real frames are never empty.
+const START_COMPRESSION: Vec<u8> = Vec::new();
+/// How long a client whose send buffer overflowed gets to take the notice
+/// saying so before its connection is cut.
+const LAG_GRACE: Duration = Duration::from_secs(5);
+/// Failed logins allowed per username within `DEFAULT_AUTH_WINDOW`.
+const DEFAULT_AUTH_MAX_FAILURES: u32 = 5;
+const DEFAULT_AUTH_WINDOW: Duration = Duration::from_secs(5 * 60);
+/// Direct messages remembered for relaying read receipts; older ones can no
+/// longer be acknowledged.
+const DIRECT_LOG_MAX: usize = 4096;
+/// How long after telling a sender that someone is away before a further
+/// direct message from them gets the away message again.
+const AWAY_REPLY_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// Minimum seconds between chat messages from a guest, when guests may chat
+/// at all; slow mode applies on top.
+const GUEST_SLOW_MODE_SECS: u64 = 10;
+const DEFAULT_MOTD: &str = "Welcome to RustChat! Use /register or /login to get started.";
+
+type BoxedReader = Box<dyn AsyncBufRead + Send + Unpin>;
+type BoxedWriter = Box<dyn AsyncWrite + Send + Unpin>;
 
 // ─── Per-connection identity ───────────────────────────────────────────────
 
@@ -23,21 +119,49 @@ const WORKER_JOBS: usize = 1024;
 struct Identity {
     user_id: String,
     username: String,
+    role: Role,
 }
 
 struct ClientState {
-    #[allow(dead_code)]
     id: String,
-    send_tx: mpsc::Sender<Vec<u8>>,
+    /// `None` for Unix socket connections.
+    peer_ip: Option<IpAddr>,
+    /// Frames for the write pump. When it is full under the `disconnect`
+    /// policy, its `lagged` is notified and the connection is closed rather
+    /// than silently losing packets.
+    send: SendQueue,
+    /// Tells the write pump to drop what is queued, send the lag notice and
+    /// stop.
+    closing: Arc<Notify>,
+    codec: Codec,
+    connected_at: DateTime<Utc>,
     identity: RwLock<Option<Identity>>,
+    /// Optional features both sides offered in `hello`; empty until then.
+    features: RwLock<HashSet<String>>,
+    /// Rooms joined on this connection, besides the lobby.
+    rooms: RwLock<HashSet<String>>,
+    /// The `id` of the packet being handled, copied onto its responses.
+    request_id: Mutex<Option<u64>>,
 }
 
 impl ClientState {
-    fn new(id: String, send_tx: mpsc::Sender<Vec<u8>>) -> Arc<Self> {
+    fn new(
+        id: String,
+        peer_ip: Option<IpAddr>,
+        send: SendQueue,
+        codec: Codec,
+    ) -> Arc<Self> {
         Arc::new(Self {
             id,
-            send_tx,
+            peer_ip,
+            send,
+            closing: Arc::new(Notify::new()),
+            codec,
+            connected_at: Utc::now(),
             identity: RwLock::new(None),
+            features: RwLock::new(HashSet::new()),
+            rooms: RwLock::new(HashSet::new()),
+            request_id: Mutex::new(None),
         })
     }
 
@@ -45,18 +169,57 @@ impl ClientState {
         self.identity.read().await.is_some()
     }
 
-    async fn set_identity(&self, user_id: String, username: String) {
-        *self.identity.write().await = Some(Identity { user_id, username });
+    async fn set_identity(&self, user_id: String, username: String, role: Role) {
+        *self.identity.write().await = Some(Identity {
+            user_id,
+            username,
+            role,
+        });
     }
 
     async fn get_identity(&self) -> Option<Identity> {
         self.identity.read().await.clone()
     }
 
+    /// An audit entry for `action`, taken by `ident` on this connection.
+    fn audit_entry(&self, ident: &Identity, action: AuditAction) -> AuditEntry {
+        AuditEntry::new(action)
+            .actor(ident.username.as_str())
+            .actor_id(ident.user_id.as_str())
+            .ip(self.peer_ip)
+    }
+
+    async fn has_feature(&self, feature: &str) -> bool {
+        self.features.read().await.contains(feature)
+    }
+
     fn send_packet(&self, pkt: &Packet) {
-        if let Ok(mut data) = serde_json::to_vec(pkt) {
-            data.push(b'\n');
-            self.send_tx.try_send(data).ok();
+        self.try_send_packet(pkt);
+    }
+
+    /// Queues `pkt` for the write pump; false if it couldn't be (the
+    /// connection is gone, or its queue is full and the overflow policy
+    /// refused the packet or is closing the connection).
+    fn try_send_packet(&self, pkt: &Packet) -> bool {
+        match self.codec.encode(pkt) {
+            Ok(data) => self.send.push(data) == Pushed::Queued,
+            Err(_) => false,
+        }
+    }
+
+    /// Queues a response that was encoded ahead of time (without an id).
+    fn send_encoded(&self, body: &[u8]) {
+        let body = match *self.request_id.lock().unwrap() {
+            // Splice the id in as the object's first member.
+            Some(id) if body.first() == Some(&b'{') => {
+                let mut spliced = format!("{{\"id\":{},", id).into_bytes();
+                spliced.extend_from_slice(&body[1..]);
+                spliced
+            }
+            _ => body.to_vec(),
+        };
+        if let Ok(data) = self.codec.frame(body) {
+            self.send.push(data);
         }
     }
 
@@ -65,8 +228,16 @@ impl ClientState {
             success,
             message: message.to_string(),
             data,
+            truncated: false,
         };
-        if let Ok(pkt) = Packet::new(MessageType::Response, payload) {
+        self.send_response_payload(payload);
+    }
+
+    /// Sends `payload` as the response to the request being handled,
+    /// tagged with its id if it had one.
+    fn send_response_payload(&self, payload: ResponsePayload) {
+        if let Ok(mut pkt) = Packet::new(MessageType::Response, payload) {
+            pkt.id = *self.request_id.lock().unwrap();
             self.send_packet(&pkt);
         }
     }
@@ -75,175 +246,1151 @@ impl ClientState {
         self.send_response(false, &format!("error: {}", msg), None);
     }
 
+    fn send_receipt(&self, message_id: &str, kind: ReceiptKind) {
+        let payload = ReceiptPayload {
+            message_id: message_id.to_string(),
+            kind,
+        };
+        if let Ok(pkt) = Packet::new(MessageType::Receipt, payload) {
+            self.send_packet(&pkt);
+        }
+    }
+
     fn send_system(&self, msg: &str) {
-        let payload = serde_json::json!({"message": msg});
+        let payload = SystemPayload {
+            message: msg.to_string(),
+            presence: None,
+            pin: None,
+            topic: None,
+        };
         if let Ok(pkt) = Packet::new(MessageType::System, payload) {
             self.send_packet(&pkt);
         }
     }
 }
 
+/// A slow-mode slot taken by `Server::reserve_chat`: when it was taken, and
+/// the send it replaced, to put back if the message never goes out.
+#[derive(Debug)]
+struct ChatSlot {
+    previous: Option<Instant>,
+    taken: Instant,
+}
+
+/// A delivered direct message whose recipient hasn't sent a read receipt.
+struct UnreadDirect {
+    message_id: String,
+    sender_id: String,
+    recipient_id: String,
+}
+
+/// A user's away message and who has been sent it.
+struct Away {
+    message: String,
+    /// When each sender (by ID) was last told.
+    replied: HashMap<String, Instant>,
+}
+
+impl Away {
+    /// Whether `sender_id` should be told at `now`: the first time, then
+    /// once per [`AWAY_REPLY_WINDOW`]. Telling them starts a new window.
+    fn reply_due(&mut self, sender_id: &str, now: Instant) -> bool {
+        let told = self.replied.get(sender_id);
+        if told.is_some_and(|at| now.duration_since(*at) < AWAY_REPLY_WINDOW) {
+            return false;
+        }
+        self.replied.insert(sender_id.to_string(), now);
+        true
+    }
+}
+
 // ─── Worker pool for async persistence ─────────────────────────────────────
 
 struct WorkerPool {
     tx: mpsc::Sender<StoredMessage>,
+    rx: Arc<Mutex<mpsc::Receiver<StoredMessage>>>,
+    /// Messages taken off the queue whose save hasn't finished.
+    busy: Arc<AtomicUsize>,
+    metrics: Arc<Metrics>,
 }
 
 impl WorkerPool {
-    fn new(n: usize, store: Arc<Store>) -> Self {
+    fn new(n: usize, store: StoreHandle, metrics: Arc<Metrics>) -> Self {
         let (tx, rx) = mpsc::channel::<StoredMessage>(WORKER_JOBS);
         // Single tokio task handles the channel; spawn n workers via rayon-style approach
         // (For simplicity: one async task per worker draining the same channel via Arc<Mutex>)
         // Actually: use n independent tasks that all share the same receiver via Arc<Mutex>
-        use std::sync::Mutex;
         let rx = Arc::new(Mutex::new(rx));
+        let busy = Arc::new(AtomicUsize::new(0));
         for _ in 0..n {
             let store = store.clone();
             let rx = rx.clone();
+            let busy = busy.clone();
+            let metrics = metrics.clone();
             tokio::spawn(async move {
                 loop {
                     let msg = {
                         let mut guard = rx.lock().unwrap();
                         // poll — if channel empty, yield
-                        match guard.try_recv() {
-                            Ok(m) => Some(m),
-                            Err(_) => None,
+                        let msg = guard.try_recv().ok();
+                        if msg.is_some() {
+                            busy.fetch_add(1, Ordering::AcqRel);
                         }
+                        msg
                     };
                     if let Some(msg) = msg {
-                        if let Err(e) = store.save_message(msg) {
-                            eprintln!("[store] save error: {}", e);
+                        match store.save_message(msg).await {
+                            Ok(()) => Metrics::inc(&metrics.messages_persisted),
+                            Err(e) => error!(error = %e, "store: failed to save message"),
                         }
+                        busy.fetch_sub(1, Ordering::AcqRel);
                     } else {
                         tokio::time::sleep(tokio::time::Duration::from_millis(5)).await;
                     }
                 }
             });
         }
-        Self { tx }
+        Self {
+            tx,
+            rx,
+            busy,
+            metrics,
+        }
     }
 
+    /// Waits until every queued message has been saved, including those
+    /// whose slot is reserved but not yet filled.
+    async fn drain(&self) {
+        loop {
+            {
+                // Workers count a message as busy under this lock, so it
+                // can't be between the queue and `busy` while we look.
+                let _rx = self.rx.lock().unwrap();
+                let queued = self.tx.max_capacity() - self.tx.capacity();
+                if queued == 0 && self.busy.load(Ordering::Acquire) == 0 {
+                    return;
+                }
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(5)).await;
+        }
+    }
+
+    /// Queues `msg` without waiting; if the queue is full the message is not
+    /// persisted. Used for best-effort records such as system events.
     fn submit(&self, msg: StoredMessage) {
         if self.tx.try_send(msg).is_err() {
-            eprintln!("[pool] job queue full – message dropped from persistence");
+            Metrics::inc(&self.metrics.persist_queue_full);
+            warn!("pool: job queue full, message dropped from persistence");
+        }
+    }
+
+    /// Waits up to `PERSIST_TIMEOUT` for a queue slot. `None` means the
+    /// workers can't keep up and the caller should refuse the message.
+    async fn reserve(&self) -> Option<mpsc::Permit<'_, StoredMessage>> {
+        match tokio::time::timeout(PERSIST_TIMEOUT, self.tx.reserve()).await {
+            Ok(Ok(permit)) => Some(permit),
+            _ => {
+                Metrics::inc(&self.metrics.persist_queue_full);
+                warn!("pool: job queue full, rejecting message");
+                None
+            }
+        }
+    }
+}
+
+/// The number of persistence workers to run for a `--workers` of
+/// `requested`: as asked, or one per CPU for 0, so there is always one.
+fn worker_count(requested: usize) -> usize {
+    match requested {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+}
+
+// ─── Reloadable settings ────────────────────────────────────────────────────
+
+/// Loads the word filter from `path`, if one is configured.
+fn load_filter(path: Option<&Path>, mode: FilterMode) -> Result<Option<Arc<WordFilter>>> {
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let filter = WordFilter::load(path, mode)
+        .map_err(|e| anyhow!("failed to load filter list {}: {}", path.display(), e))?;
+    info!(path = %path.display(), words = filter.len(), %mode, "word filter loaded");
+    Ok(Some(Arc::new(filter)))
+}
+
+/// `base` with the limits file, if any, applied on top.
+fn load_limits(base: RateLimits, path: Option<&Path>) -> Result<RateLimits> {
+    match path {
+        Some(path) => base.with_file(path),
+        None => Ok(base),
+    }
+}
+
+// ─── Persistence ────────────────────────────────────────────────────────────
+
+/// Flushes the store every `interval` under [`FsyncPolicy::Interval`]. A
+/// failed flush is retried on the next tick.
+async fn run_flush(store: StoreHandle, interval: Duration) {
+    let mut tick = tokio::time::interval(interval);
+    loop {
+        tick.tick().await;
+        if let Err(e) = store.flush().await {
+            error!(error = %e, "flush: writing data files failed");
+        }
+    }
+}
+
+// ─── Retention ──────────────────────────────────────────────────────────────
+
+/// Periodically prunes messages past the age and/or count limits, except
+/// while the server is read-only.
+async fn run_retention(
+    store: StoreHandle,
+    read_only: Arc<AtomicBool>,
+    audit: Option<AuditLog>,
+    days: Option<u32>,
+    max_messages: Option<usize>,
+) {
+    let mut tick = tokio::time::interval(RETENTION_SWEEP);
+    loop {
+        tick.tick().await;
+        if read_only.load(Ordering::Relaxed) {
+            continue;
+        }
+        if let Some(days) = days {
+            let cutoff = Utc::now() - chrono::Duration::days(days as i64);
+            match store.prune_older_than(cutoff).await {
+                Ok(0) => {}
+                Ok(n) => {
+                    info!(removed = n, %cutoff, "retention: pruned old messages");
+                    if let Some(audit) = &audit {
+                        let detail = format!("{} message(s) older than {}", n, cutoff.to_rfc3339());
+                        audit.record(AuditEntry::new(AuditAction::Prune).detail(detail));
+                    }
+                }
+                Err(e) => error!(error = %e, "retention: prune failed"),
+            }
+        }
+        if let Some(max) = max_messages {
+            match store.trim_to(max).await {
+                Ok(0) => {}
+                Ok(n) => {
+                    info!(removed = n, max, "retention: trimmed to message cap");
+                    if let Some(audit) = &audit {
+                        let detail = format!("{} message(s) over the cap of {}", n, max);
+                        audit.record(AuditEntry::new(AuditAction::Prune).detail(detail));
+                    }
+                }
+                Err(e) => error!(error = %e, "retention: trim failed"),
+            }
+        }
+    }
+}
+
+/// Deletes messages whose TTL has run out and tells every client which ones
+/// went, with a `deleted` packet. While the server is read-only they are
+/// only hidden (reads skip expired messages) and deleted afterwards.
+async fn run_expiry(
+    store: StoreHandle,
+    read_only: Arc<AtomicBool>,
+    audit: Option<AuditLog>,
+    hub_tx: mpsc::Sender<HubCommand>,
+    codec: Codec,
+) {
+    let mut tick = tokio::time::interval(EXPIRY_SWEEP);
+    loop {
+        tick.tick().await;
+        if read_only.load(Ordering::Relaxed) {
+            continue;
+        }
+        let message_ids = match store.remove_expired(Utc::now()).await {
+            Ok(ids) if ids.is_empty() => continue,
+            Ok(ids) => ids,
+            Err(e) => {
+                error!(error = %e, "expiry: removing expired messages failed");
+                continue;
+            }
+        };
+        info!(removed = message_ids.len(), "expiry: deleted expired messages");
+        if let Some(audit) = &audit {
+            for id in &message_ids {
+                audit.record(AuditEntry::new(AuditAction::Expire).target(id.as_str()));
+            }
+        }
+        let pkt = match Packet::new(MessageType::Deleted, DeletedPayload { message_ids }) {
+            Ok(pkt) => pkt,
+            Err(_) => continue,
+        };
+        if let Ok(data) = codec.encode(&pkt) {
+            if hub_tx.send(HubCommand::Broadcast(data)).await.is_err() {
+                return;
+            }
         }
     }
 }
 
 // ─── Server ─────────────────────────────────────────────────────────────────
 
+/// Startup options for [`Server::new`].
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub data_dir: String,
+    /// Persistence worker tasks; 0 means one per available CPU.
+    pub workers: usize,
+    /// Keep everything in memory and ignore `data_dir`.
+    pub ephemeral: bool,
+    /// Usernames (case-insensitive) granted the admin role on login.
+    pub admins: Vec<String>,
+    /// Program that checks logins and registrations instead of the store
+    /// (see [`auth`]).
+    pub auth_command: Option<PathBuf>,
+    /// Delete messages older than this many days.
+    pub retention_days: Option<u32>,
+    /// Keep at most this many messages, dropping the oldest.
+    pub max_messages: Option<usize>,
+    /// Wire framing expected from and used toward every client.
+    pub framing: Framing,
+    /// Largest packet accepted from a client; bigger ones close the connection.
+    pub max_packet_bytes: usize,
+    /// Frames each connection's send queue holds before `overflow_policy`
+    /// applies.
+    pub send_buffer: usize,
+    /// What a full send queue does with another frame (see [`send_queue`]).
+    pub overflow_policy: OverflowPolicy,
+    /// File of words (one per line) to filter out of chat messages.
+    pub filter_list: Option<PathBuf>,
+    /// Whether filtered messages are rejected or have the words masked.
+    pub filter_mode: FilterMode,
+    /// Whether control characters in chat and direct messages are stripped
+    /// or escaped (see [`sanitize`]).
+    pub sanitize: SanitizeMode,
+    /// Store join/leave and other system notices in the message history.
+    pub persist_system: bool,
+    /// Let clients switch their connection to zlib with a `compress` request.
+    pub compression: bool,
+    /// Drop a chat message identical to the same user's previous one if it
+    /// arrives within this window. `None` disables deduplication.
+    pub dedup_window: Option<Duration>,
+    /// Hold each leave notice back this long; if the user joins again in
+    /// the meantime, neither the leave nor the join is announced. `None`
+    /// announces both at once.
+    pub presence_debounce: Option<Duration>,
+    /// Write indented JSON files instead of compact ones.
+    pub pretty_storage: bool,
+    /// When data files are written and fsynced (see [`FsyncPolicy`]).
+    pub fsync: FsyncPolicy,
+    /// How often changes are flushed under [`FsyncPolicy::Interval`].
+    pub flush_interval: Duration,
+    /// Fail to start on corrupt data files instead of recovering.
+    pub strict: bool,
+    /// Most messages kept in memory; older ones are paged out to disk and
+    /// read back when history, search or sync reach them. 0 keeps them all.
+    pub memory_window: usize,
+    /// File whose contents are sent as the welcome notice, re-read for
+    /// every connection.
+    pub motd_file: Option<PathBuf>,
+    /// Most simultaneous TCP connections accepted from one IP address.
+    /// `None` means unlimited.
+    pub max_conns_per_ip: Option<usize>,
+    /// Failed logins for one username within `auth_window` before it is
+    /// locked out (an IP gets several times as many). 0 disables lockout.
+    pub auth_max_failures: u32,
+    pub auth_window: Duration,
+    /// JSON file overriding the rate limits above (see [`limits`]); re-read
+    /// with the filter list on [`Server::reload`].
+    pub limits_file: Option<PathBuf>,
+    /// Close connections that send nothing for this long. Clients keep
+    /// idle sessions alive with `ping`. `None` never closes them.
+    pub idle_timeout: Option<Duration>,
+    /// POST every chat message to this `http://` URL.
+    pub webhook_url: Option<String>,
+    /// Only send messages matching this search query to the webhook.
+    pub webhook_query: Option<String>,
+    /// Append an audit trail of logins, admin actions and deletions here
+    /// (see [`audit`]).
+    pub audit_log: Option<PathBuf>,
+    /// Accept `guest` sessions, which can read but not post.
+    pub guests: bool,
+    /// Let guests post chat messages (at most one per
+    /// `GUEST_SLOW_MODE_SECS`). Only meaningful with `guests`.
+    pub guest_chat: bool,
+    /// Start read-only: requests that would change stored data are refused
+    /// and nothing is written to `data_dir`. Admins can toggle it at runtime.
+    pub read_only: bool,
+    /// Check every client packet against its schema (see [`crate::schema`])
+    /// and reject unknown or missing fields, instead of reading leniently.
+    pub strict_protocol: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            data_dir: "./data".to_string(),
+            ephemeral: false,
+            workers: 4,
+            admins: Vec::new(),
+            auth_command: None,
+            retention_days: None,
+            max_messages: None,
+            framing: Framing::default(),
+            max_packet_bytes: DEFAULT_MAX_FRAME,
+            send_buffer: DEFAULT_SEND_BUFFER,
+            overflow_policy: OverflowPolicy::default(),
+            filter_list: None,
+            filter_mode: FilterMode::default(),
+            sanitize: SanitizeMode::default(),
+            persist_system: false,
+            compression: false,
+            dedup_window: None,
+            presence_debounce: Some(DEFAULT_PRESENCE_DEBOUNCE),
+            pretty_storage: false,
+            fsync: FsyncPolicy::default(),
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            strict: false,
+            memory_window: 0,
+            motd_file: None,
+            max_conns_per_ip: None,
+            auth_max_failures: DEFAULT_AUTH_MAX_FAILURES,
+            auth_window: DEFAULT_AUTH_WINDOW,
+            limits_file: None,
+            idle_timeout: None,
+            webhook_url: None,
+            webhook_query: None,
+            audit_log: None,
+            guests: false,
+            guest_chat: false,
+            read_only: false,
+            strict_protocol: false,
+        }
+    }
+}
+
 pub struct Server {
-    store: Arc<Store>,
+    store: StoreHandle,
+    /// Checks logins and registrations; the store itself unless replaced.
+    auth: Arc<dyn AuthProvider>,
+    admins: HashSet<String>,
+    codec: Codec,
+    send_buffer: usize,
+    overflow_policy: OverflowPolicy,
+    filter_list: Option<PathBuf>,
+    filter_mode: FilterMode,
+    /// Swapped whole on reload; a message is filtered by one version or the
+    /// other.
+    filter: std::sync::RwLock<Option<Arc<WordFilter>>>,
+    sanitize: SanitizeMode,
+    persist_system: bool,
+    presence_debounce: Option<Duration>,
+    compression: bool,
+    motd_file: Option<PathBuf>,
+    /// The limits from the command line, which the limits file overrides.
+    base_limits: RateLimits,
+    limits_file: Option<PathBuf>,
+    limits: std::sync::RwLock<RateLimits>,
+    idle_timeout: Option<Duration>,
+    webhook: Option<Webhook>,
+    audit: Option<AuditLog>,
+    guests: bool,
+    guest_chat: bool,
+    strict_protocol: bool,
+    /// Shared with the retention and expiry sweepers, which pause while set.
+    read_only: Arc<AtomicBool>,
+    /// Random per boot and part of every guest name, so guests of different runs (whose
+    /// messages are stored under that name) never share one.
+    guest_tag: String,
+    /// Numbers the `guest-<tag>-<n>` names handed out.
+    guest_counter: AtomicU64,
+    /// The last message sequence number handed out (see `post_chat`).
+    seq: tokio::sync::Mutex<u64>,
     pool: Arc<WorkerPool>,
     hub_tx: mpsc::Sender<HubCommand>,
     online: Arc<RwLock<HashMap<String, Arc<ClientState>>>>,
     conn_counter: Arc<AtomicU64>,
+    metrics: Arc<Metrics>,
+    started_at: Instant,
+    /// Minimum seconds between chat messages per user; 0 when slow mode is off.
+    slow_mode_secs: AtomicU64,
+    /// When each user last had a chat message accepted, keyed by user ID.
+    /// Entries older than any slow-mode interval are evicted.
+    last_chat: Mutex<HashMap<String, Instant>>,
+    /// Content hash and send time of each user's last broadcast message,
+    /// for the dedup window.
+    last_content: Mutex<HashMap<String, (u64, Instant)>>,
+    /// Open connections per peer IP, tracked even while unlimited so a
+    /// limit set by a reload counts the connections already open.
+    conns_per_ip: Mutex<HashMap<IpAddr, usize>>,
+    auth_limiter: AuthLimiter,
+    /// Delivered direct messages awaiting a read receipt, oldest first.
+    unread_directs: Mutex<VecDeque<UnreadDirect>>,
+    /// Leave notices being held back, keyed by user ID, with the task that
+    /// sends each once the debounce is up.
+    pending_leaves: Mutex<HashMap<String, tokio::task::AbortHandle>>,
+    history_cache: HistoryCache,
+    /// Away messages by user ID. Kept in memory only, through disconnects.
+    away: Mutex<HashMap<String, Away>>,
 }
 
 impl Server {
-    pub fn new(data_dir: &str, workers: usize) -> Result<Self> {
-        let store = Arc::new(Store::new(data_dir)?);
-        let (hub_tx, hub_rx) = mpsc::channel(256);
+    pub fn new(config: ServerConfig) -> Result<Self> {
+        let mut store = if config.ephemeral {
+            info!("ephemeral mode: nothing will be persisted");
+            Store::new_in_memory()
+        } else {
+            let opts = StoreOptions {
+                pretty: config.pretty_storage,
+                fsync: config.fsync,
+                strict: config.strict,
+                memory_window: config.memory_window,
+                read_only: config.read_only,
+            };
+            Store::with_options(&config.data_dir, opts)?
+        };
+        let last_seq = store.last_seq();
+        store.set_read_only(config.read_only)?;
+        let read_only = Arc::new(AtomicBool::new(config.read_only));
+        if config.read_only {
+            info!("read-only mode: requests that change data are refused");
+        }
+        let store = StoreHandle::spawn(store)?;
+        let filter = load_filter(config.filter_list.as_deref(), config.filter_mode)?;
+        let base_limits = RateLimits {
+            dedup_window: config.dedup_window,
+            max_conns_per_ip: config.max_conns_per_ip,
+            auth_max_failures: config.auth_max_failures,
+            auth_window: config.auth_window,
+        };
+        let limits = load_limits(base_limits, config.limits_file.as_deref())?;
+        let (hub_tx, hub_rx) = mpsc::channel(HUB_BUF);
+        let metrics = Arc::new(Metrics::default());
         tokio::spawn(run_hub(hub_rx));
 
-        let pool = Arc::new(WorkerPool::new(workers, store.clone()));
+        let workers = worker_count(config.workers);
+        info!(workers, "persistence workers started");
+        let pool = Arc::new(WorkerPool::new(workers, store.clone(), metrics.clone()));
+
+        let webhook = match &config.webhook_url {
+            Some(url) => {
+                let query = config.webhook_query.as_deref().map(Query::parse);
+                let webhook = Webhook::start(url, query, metrics.clone())?;
+                info!(%url, "webhook enabled");
+                Some(webhook)
+            }
+            None => None,
+        };
+        let audit = match config.audit_log {
+            Some(path) => {
+                info!(path = %path.display(), "audit log enabled");
+                Some(AuditLog::start(path, metrics.clone())?)
+            }
+            None => None,
+        };
+
+        if config.retention_days.is_some() || config.max_messages.is_some() {
+            tokio::spawn(run_retention(
+                store.clone(),
+                read_only.clone(),
+                audit.clone(),
+                config.retention_days,
+                config.max_messages,
+            ));
+        }
+        if config.fsync == FsyncPolicy::Interval && !config.ephemeral {
+            tokio::spawn(run_flush(store.clone(), config.flush_interval));
+        }
+        let codec = Codec::new(config.framing).with_max_frame(config.max_packet_bytes);
+        tokio::spawn(run_expiry(
+            store.clone(),
+            read_only.clone(),
+            audit.clone(),
+            hub_tx.clone(),
+            codec,
+        ));
+
+        let auth: Arc<dyn AuthProvider> = match config.auth_command {
+            Some(command) => {
+                info!(command = %command.display(), "authenticating with an external command");
+                Arc::new(CommandAuth::new(command, store.clone()))
+            }
+            None => Arc::new(store.clone()),
+        };
 
         Ok(Self {
             store,
+            auth,
+            admins: config.admins.iter().map(|a| normalize_username(a)).collect(),
+            codec,
+            send_buffer: config.send_buffer,
+            overflow_policy: config.overflow_policy,
+            filter_list: config.filter_list,
+            filter_mode: config.filter_mode,
+            filter: std::sync::RwLock::new(filter),
+            sanitize: config.sanitize,
+            persist_system: config.persist_system,
+            presence_debounce: config.presence_debounce,
+            compression: config.compression,
+            motd_file: config.motd_file,
+            base_limits,
+            limits_file: config.limits_file,
+            limits: std::sync::RwLock::new(limits),
+            idle_timeout: config.idle_timeout,
+            webhook,
+            audit,
+            guests: config.guests,
+            guest_chat: config.guest_chat,
+            strict_protocol: config.strict_protocol,
+            read_only,
+            guest_tag: format!("{:06x}", rand::thread_rng().gen_range(0..0x100_0000u32)),
+            guest_counter: AtomicU64::new(0),
+            seq: tokio::sync::Mutex::new(last_seq),
             pool,
             hub_tx,
             online: Arc::new(RwLock::new(HashMap::new())),
             conn_counter: Arc::new(AtomicU64::new(0)),
+            metrics,
+            started_at: Instant::now(),
+            slow_mode_secs: AtomicU64::new(0),
+            last_chat: Mutex::new(HashMap::new()),
+            last_content: Mutex::new(HashMap::new()),
+            conns_per_ip: Mutex::new(HashMap::new()),
+            auth_limiter: AuthLimiter::new(limits.auth_max_failures, limits.auth_window),
+            unread_directs: Mutex::new(VecDeque::new()),
+            pending_leaves: Mutex::new(HashMap::new()),
+            history_cache: HistoryCache::default(),
+            away: Mutex::new(HashMap::new()),
         })
     }
 
+    /// Checks logins and registrations with `provider` instead of the one
+    /// the config chose.
+    pub fn with_auth_provider(mut self, provider: Arc<dyn AuthProvider>) -> Self {
+        self.auth = provider;
+        self
+    }
+
+    /// Re-reads the word filter list and the limits file and applies them
+    /// to the running server, without touching connections (the server
+    /// binary calls this on SIGHUP). The MOTD file needs no reload, since it
+    /// is read for every connection. If either file can't be read, nothing
+    /// changes and the error is returned.
+    pub fn reload(&self) -> Result<()> {
+        let filter = load_filter(self.filter_list.as_deref(), self.filter_mode)?;
+        let limits = load_limits(self.base_limits, self.limits_file.as_deref())?;
+        *self.filter.write().unwrap() = filter;
+        *self.limits.write().unwrap() = limits;
+        self.auth_limiter.set_limits(limits.auth_max_failures, limits.auth_window);
+        info!(
+            dedup_secs = limits.dedup_window.map_or(0, |w| w.as_secs()),
+            max_conns_per_ip = limits.max_conns_per_ip.unwrap_or(0),
+            auth_max_failures = limits.auth_max_failures,
+            auth_window_secs = limits.auth_window.as_secs(),
+            "configuration reloaded"
+        );
+        Ok(())
+    }
+
+    /// Writes out store changes still waiting for a flush; the server
+    /// binary calls this before exiting.
+    pub async fn flush(&self) -> Result<()> {
+        self.store.flush().await
+    }
+
+    fn limits(&self) -> RateLimits {
+        *self.limits.read().unwrap()
+    }
+
     pub async fn listen_and_serve(self: Arc<Self>, addr: &str) -> Result<()> {
         let listener = TcpListener::bind(addr).await?;
-        eprintln!("[server] listening on {}", addr);
+        info!(%addr, "listening");
+        self.serve(listener).await
+    }
 
+    /// Serves clients from a listener the caller has bound, e.g. on port 0
+    /// to find out which port it got before anyone connects.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> Result<()> {
         loop {
             match listener.accept().await {
-                Ok((conn, _)) => {
-                    let srv = self.clone();
-                    tokio::spawn(srv.serve_conn(conn));
+                Ok((conn, peer)) => self.spawn_conn(conn, Some(peer)),
+                Err(e) => {
+                    error!(error = %e, "accept failed");
+                    return Ok(());
                 }
+            }
+        }
+    }
+
+    /// Serves clients on a Unix domain socket at `path`. A stale socket file
+    /// left by an earlier run is replaced; one that still accepts
+    /// connections, or a path that isn't a socket, is an error. The caller
+    /// removes the file on shutdown.
+    #[cfg(unix)]
+    pub async fn listen_unix(self: Arc<Self>, path: &Path) -> Result<()> {
+        use std::os::unix::fs::FileTypeExt;
+
+        if let Ok(meta) = std::fs::symlink_metadata(path) {
+            if !meta.file_type().is_socket() {
+                bail!("{} exists and is not a socket", path.display());
+            }
+            if UnixStream::connect(path).await.is_ok() {
+                bail!("{} is already in use", path.display());
+            }
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        info!(path = %path.display(), "listening");
+
+        loop {
+            match listener.accept().await {
+                Ok((conn, _)) => self.spawn_conn(conn, None),
                 Err(e) => {
-                    eprintln!("[server] accept error: {}", e);
+                    error!(error = %e, "accept failed");
                     return Ok(());
                 }
             }
         }
     }
 
-    async fn serve_conn(self: Arc<Self>, conn: TcpStream) {
+    /// Starts serving `conn`, or turns it away if its address already has
+    /// the per-IP connection limit open. `peer` is `None` for Unix
+    /// sockets, which are never limited.
+    fn spawn_conn<S>(self: &Arc<Self>, conn: S, peer: Option<SocketAddr>)
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        if let Some(peer) = peer {
+            if !self.claim_ip_slot(peer.ip()) {
+                warn!(%peer, "too many connections from one address, rejecting");
+                tokio::spawn(self.clone().reject_conn(conn));
+                return;
+            }
+        }
+        let ip = peer.map(|p| p.ip());
+        let srv = self.clone();
         let id = format!("conn-{}", self.conn_counter.fetch_add(1, Ordering::Relaxed));
-        let (send_tx, mut send_rx) = mpsc::channel::<Vec<u8>>(SEND_BUF);
-        let client = ClientState::new(id.clone(), send_tx);
+        let span = info_span!(
+            "conn",
+            conn_id = %id,
+            peer = tracing::field::Empty,
+            user_id = tracing::field::Empty
+        );
+        if let Some(peer) = peer {
+            span.record("peer", tracing::field::display(peer));
+        }
+        tokio::spawn(srv.serve_conn(id, conn, ip).instrument(span));
+    }
+
+    /// Counts a new connection from `ip` and returns false, without counting
+    /// it, if that would exceed the per-IP connection limit.
+    fn claim_ip_slot(&self, ip: IpAddr) -> bool {
+        let max = self.limits().max_conns_per_ip;
+        let mut conns = self.conns_per_ip.lock().unwrap();
+        let open = conns.entry(ip).or_insert(0);
+        if max.is_some_and(|max| *open >= max) {
+            return false;
+        }
+        *open += 1;
+        true
+    }
+
+    fn release_ip_slot(&self, ip: Option<IpAddr>) {
+        let ip = match ip {
+            Some(ip) => ip,
+            None => return,
+        };
+        let mut conns = self.conns_per_ip.lock().unwrap();
+        if let Some(open) = conns.get_mut(&ip) {
+            *open -= 1;
+            if *open == 0 {
+                conns.remove(&ip);
+            }
+        }
+    }
+
+    /// Tells an over-limit connection why it is being closed, then drops it.
+    async fn reject_conn<S>(self: Arc<Self>, mut conn: S)
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let payload = SystemPayload {
+            message: "too many connections from your address".to_string(),
+            presence: None,
+            pin: None,
+            topic: None,
+        };
+        if let Ok(pkt) = Packet::new(MessageType::System, payload) {
+            if let Ok(data) = self.codec.encode(&pkt) {
+                conn.write_all(&data).await.ok();
+            }
+        }
+        conn.shutdown().await.ok();
+    }
+
+    async fn serve_conn<S>(self: Arc<Self>, id: String, conn: S, ip: Option<IpAddr>)
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        info!("connection opened");
+        self.metrics.connected_clients.fetch_add(1, Ordering::Relaxed);
+        let (send, mut send_rx) =
+            SendQueue::new(self.send_buffer, self.overflow_policy, self.metrics.clone());
+        let client = ClientState::new(id.clone(), ip, send, self.codec);
 
         // Register with hub (unauthenticated placeholder username)
-        self.hub_tx
-            .send(HubCommand::Register(ClientHandle {
-                id: id.clone(),
-                username: String::new(),
-                tx: client.send_tx.clone(),
-            }))
-            .await
-            .ok();
+        self.send_to_hub(HubCommand::Register(ClientHandle {
+            id: id.clone(),
+            username: String::new(),
+            queue: client.send.clone(),
+            dnd: false,
+            blocked: HashSet::new(),
+            features: HashSet::new(),
+            rooms: HashSet::new(),
+        }))
+        .await;
 
-        // Split TCP stream
-        let (reader, mut writer) = conn.into_split();
+        // Split the stream (TCP or Unix socket)
+        let (reader, writer) = tokio::io::split(conn);
 
         // Write pump
-        let write_id = id.clone();
-        tokio::spawn(async move {
-            while let Some(data) = send_rx.recv().await {
-                if writer.write_all(&data).await.is_err() {
-                    break;
+        let closing = client.closing.clone();
+        let codec = self.codec;
+        let pump = tokio::spawn(
+            async move {
+                let mut writer: BoxedWriter = Box::new(writer);
+                loop {
+                    let mut last = false;
+                    let data = tokio::select! {
+                        data = send_rx.recv() => data,
+                        _ = closing.notified() => {
+                            // Too far behind to catch up: skip what is
+                            // queued and say why the connection is closing.
+                            send_rx.close();
+                            while let Some(skipped) = send_rx.try_recv() {
+                                if skipped.is_empty() {
+                                    writer = Box::new(ZlibEncoder::new(writer));
+                                }
+                            }
+                            last = true;
+                            lag_notice(codec)
+                        }
+                    };
+                    let data = match data {
+                        Some(data) => data,
+                        None => break,
+                    };
+                    if data.is_empty() {
+                        writer = Box::new(ZlibEncoder::new(writer));
+                        continue;
+                    }
+                    if writer.write_all(&data).await.is_err() || writer.flush().await.is_err() {
+                        break;
+                    }
+                    if last {
+                        break;
+                    }
                 }
+                debug!("write pump ended");
             }
-            eprintln!("[server] write pump ended for {}", write_id);
-        });
+            .instrument(Span::current()),
+        );
 
         // Send welcome
-        client.send_system("Welcome to RustChat! Use /register or /login to get started.");
+        if let Some(motd) = self.motd().await {
+            client.send_system(&motd);
+        }
 
         // Read pump (runs in this task)
         let srv = self.clone();
         let c = client.clone();
-        let mut lines = BufReader::new(reader).lines();
+        let mut reader: BoxedReader = Box::new(BufReader::new(reader));
 
-        while let Ok(Some(line)) = lines.next_line().await {
-            let pkt: Packet = match serde_json::from_str(&line) {
+        let mut lagged = false;
+        loop {
+            let idle = async {
+                match srv.idle_timeout {
+                    Some(limit) => tokio::time::sleep(limit).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let read = tokio::select! {
+                read = srv.codec.read_frame(&mut reader) => read,
+                _ = idle => {
+                    info!("idle timeout, closing connection");
+                    c.send_error("closing idle connection");
+                    break;
+                }
+                _ = c.send.lagged().notified() => {
+                    lagged = true;
+                    break;
+                }
+            };
+            let frame = match read {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                    warn!(error = %e, "oversized packet, closing connection");
+                    c.send_error(&e.to_string());
+                    break;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    warn!(error = %e, "connection closed with a partial packet");
+                    break;
+                }
+                Err(_) => break,
+            };
+            let pkt: Packet = match serde_json::from_slice(&frame) {
                 Ok(p) => p,
                 Err(_) => {
-                    c.send_error("malformed packet");
+                    c.send_error(&describe_bad_packet(&frame));
                     continue;
                 }
             };
-            srv.handle_packet(&c, pkt).await;
+            // Responses sent while handling it carry its id; anything sent
+            // later, from other tasks, doesn't.
+            *c.request_id.lock().unwrap() = pkt.id;
+            let mut compress = false;
+            // Parsed again as a whole so unknown fields are visible.
+            let invalid = srv
+                .strict_protocol
+                .then(|| serde_json::from_slice(&frame).unwrap_or_default())
+                .and_then(|value| schema::validate_packet(&value).err());
+            if let Some(e) = invalid {
+                c.send_error(&e.to_string());
+            } else if pkt.msg_type == MessageType::Compress {
+                compress = srv.start_compression(&c, pkt.payload).await;
+            } else {
+                srv.handle_packet(&c, pkt).await;
+            }
+            *c.request_id.lock().unwrap() = None;
+            if compress {
+                // Swapping the reader has to happen here, between frames.
+                reader = Box::new(BufReader::new(ZlibDecoder::new(reader)));
+            }
         }
 
         // Cleanup
-        srv.hub_tx.send(HubCommand::Unregister(id.clone())).await.ok();
-        if let Some(ident) = client.get_identity().await {
+        srv.send_to_hub(HubCommand::Unregister(id.clone())).await;
+        let ident = client.get_identity().await.filter(|i| i.role != Role::Guest);
+        if let Some(ident) = ident {
             srv.online.write().await.remove(&ident.user_id);
+            if !srv.is_read_only() {
+                if let Err(e) = srv.store.touch_last_seen(&ident.user_id).await {
+                    warn!(error = %e, "failed to record last seen");
+                }
+            }
+            let user = UserInfo {
+                user_id: ident.user_id,
+                username: ident.username,
+                ..Default::default()
+            };
+            let message = format!("{} left the chat", user.username);
+            srv.broadcast_presence(message, PresenceEvent::Leave, user).await;
+        }
+        if lagged {
+            srv.drop_lagging(&client, pump).await;
+        }
+        srv.release_ip_slot(ip);
+        srv.metrics.connected_clients.fetch_sub(1, Ordering::Relaxed);
+        info!("connection closed");
+    }
+
+    /// Closes the connection of a client whose send buffer overflowed. The
+    /// write pump drops what is queued and sends a notice saying why; a
+    /// client that isn't reading at all is cut off after `LAG_GRACE`.
+    async fn drop_lagging(&self, client: &ClientState, mut pump: JoinHandle<()>) {
+        warn!("send buffer full, disconnecting slow client");
+        Metrics::inc(&self.metrics.slow_clients_dropped);
+        client.closing.notify_one();
+        if tokio::time::timeout(LAG_GRACE, &mut pump).await.is_err() {
+            pump.abort();
         }
-        eprintln!("[server] connection {} closed", id);
     }
 
     async fn handle_packet(self: &Arc<Self>, client: &Arc<ClientState>, pkt: Packet) {
+        debug!(msg_type = ?pkt.msg_type, "packet received");
+        if !self.guest_may(client, &pkt.msg_type).await {
+            let name = serde_json::to_string(&pkt.msg_type).unwrap_or_default();
+            client.send_error(&format!("guests can't use {}; register to take part", name));
+            return;
+        }
+        if self.is_read_only() && changes_data(&pkt.msg_type) {
+            client.send_error("server is read-only");
+            return;
+        }
         match pkt.msg_type {
+            MessageType::Hello => self.handle_hello(client, pkt.payload).await,
             MessageType::Register => self.handle_register(client, pkt.payload).await,
             MessageType::Login => self.handle_login(client, pkt.payload).await,
+            MessageType::Guest => self.handle_guest(client).await,
             MessageType::Chat => self.handle_chat(client, pkt.payload).await,
+            MessageType::Direct => self.handle_direct(client, pkt.payload).await,
+            MessageType::Receipt => self.handle_receipt(client, pkt.payload).await,
             MessageType::Search => self.handle_search(client, pkt.payload).await,
             MessageType::History => self.handle_history(client, pkt.payload).await,
+            MessageType::Sync => self.handle_sync(client, pkt.payload).await,
             MessageType::Users => self.handle_users(client).await,
+            MessageType::Purge => self.handle_purge(client, pkt.payload).await,
+            MessageType::Admin => self.handle_admin(client, pkt.payload).await,
+            MessageType::Rename => self.handle_rename(client, pkt.payload).await,
+            MessageType::Dnd => self.handle_dnd(client, pkt.payload).await,
+            MessageType::Away => self.handle_away(client, pkt.payload).await,
+            MessageType::Block => self.handle_block(client, pkt.payload, true).await,
+            MessageType::Unblock => self.handle_block(client, pkt.payload, false).await,
+            MessageType::Profile => self.handle_profile(client, pkt.payload).await,
+            MessageType::UpdateProfile => self.handle_update_profile(client, pkt.payload).await,
+            MessageType::UpdatePrefs => self.handle_update_prefs(client, pkt.payload).await,
+            MessageType::RecentUsers => self.handle_recent_users(client, pkt.payload).await,
+            MessageType::Directory => self.handle_directory(client, pkt.payload).await,
+            MessageType::Whoami => self.handle_whoami(client).await,
+            MessageType::Join => self.handle_room(client, pkt.payload, true).await,
+            MessageType::Leave => self.handle_room(client, pkt.payload, false).await,
+            MessageType::Pin => self.handle_pin(client, pkt.payload, true).await,
+            MessageType::Unpin => self.handle_pin(client, pkt.payload, false).await,
+            MessageType::Pinned => self.handle_pinned(client).await,
+            MessageType::Topic => self.handle_topic(client, pkt.payload).await,
+            MessageType::Time => {
+                let reply = TimeResult {
+                    server_time: Utc::now(),
+                };
+                client.send_response(true, "server time", serde_json::to_value(reply).ok());
+            }
+            MessageType::Stats => self.handle_stats(client).await,
+            MessageType::Ping => {}
             MessageType::Quit => { /* connection will close when read pump exits */ }
-            _ => client.send_error(&format!("unknown packet type")),
+            // Response, Broadcast and System only flow server → client;
+            // Compress is intercepted by the read loop.
+            _ => {
+                let name = serde_json::to_string(&pkt.msg_type).unwrap_or_default();
+                client.send_error(&format!("{} is not a client command", name));
+            }
+        }
+    }
+
+    /// Whether the connection may send `msg_type`: anything for a signed-in
+    /// user, only reading (and logging in) for a guest.
+    async fn guest_may(&self, client: &ClientState, msg_type: &MessageType) -> bool {
+        match client.get_identity().await {
+            Some(ident) if ident.role == Role::Guest => {}
+            _ => return true,
+        }
+        match msg_type {
+            MessageType::Chat => self.guest_chat,
+            MessageType::Hello
+            | MessageType::Register
+            | MessageType::Login
+            | MessageType::Search
+            | MessageType::History
+            | MessageType::Sync
+            | MessageType::Users
+            | MessageType::Profile
+            | MessageType::RecentUsers
+            | MessageType::Directory
+            | MessageType::Whoami
+            | MessageType::Stats
+            | MessageType::Ping
+            | MessageType::Time
+            | MessageType::Join
+            | MessageType::Leave
+            | MessageType::Pinned
+            | MessageType::Quit => true,
+            _ => false,
         }
     }
 
+    /// Answers a `compress` request. On success the write pump has been told
+    /// to compress everything after the response, and the caller must wrap
+    /// its reader the same way.
+    async fn start_compression(&self, client: &Arc<ClientState>, raw: serde_json::Value) -> bool {
+        if !self.compression {
+            client.send_error("compression is not enabled on this server");
+            return false;
+        }
+        match serde_json::from_value::<CompressPayload>(raw) {
+            Ok(p) if p.algorithm == COMPRESSION_ZLIB => {}
+            _ => {
+                client.send_error("unsupported compression (expected zlib)");
+                return false;
+            }
+        }
+
+        // Both sends wait for room: dropping either would desync the stream.
+        let payload = ResponsePayload {
+            success: true,
+            message: "compression enabled".to_string(),
+            data: None,
+            truncated: false,
+        };
+        let data = match Packet::new(MessageType::Response, payload)
+            .and_then(|pkt| client.codec.encode(&pkt))
+        {
+            Ok(data) => data,
+            Err(_) => return false,
+        };
+        if !client.send.send(data).await || !client.send.send(START_COMPRESSION).await {
+            return false;
+        }
+        debug!("compression enabled");
+        true
+    }
+
+    /// The welcome notice for a new connection: the MOTD file if one is
+    /// configured and readable (`None` if it is empty), else the default.
+    async fn motd(&self) -> Option<String> {
+        let path = match &self.motd_file {
+            Some(path) => path,
+            None => return Some(DEFAULT_MOTD.to_string()),
+        };
+        match tokio::fs::read_to_string(path).await {
+            Ok(text) => Some(text.trim_end().to_string()).filter(|t| !t.is_empty()),
+            Err(e) => {
+                warn!(path = %path.display(), error = %e, "failed to read MOTD file");
+                Some(DEFAULT_MOTD.to_string())
+            }
+        }
+    }
+
+    /// Optional features this server offers in `hello`.
+    fn features(&self) -> Vec<String> {
+        let mut features = vec![
+            FEATURE_PROFILES.to_string(),
+            FEATURE_RECEIPTS.to_string(),
+            FEATURE_LOCAL_ECHO.to_string(),
+        ];
+        if self.compression {
+            features.push(FEATURE_COMPRESSION.to_string());
+        }
+        features
+    }
+
+    async fn handle_hello(self: &Arc<Self>, client: &Arc<ClientState>, raw: serde_json::Value) {
+        let p: HelloPayload = match serde_json::from_value(raw) {
+            Ok(p) => p,
+            Err(_) => {
+                client.send_error("hello requires {version, features}");
+                return;
+            }
+        };
+
+        let offered = self.features();
+        let negotiated: HashSet<String> =
+            p.features.into_iter().filter(|f| offered.contains(f)).collect();
+        debug!(client_version = %p.version, features = ?negotiated, "hello");
+        *client.features.write().await = negotiated.clone();
+        self.send_to_hub(HubCommand::SetFeatures {
+            id: client.id.clone(),
+            features: negotiated,
+        })
+        .await;
+
+        let reply = HelloPayload {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            features: offered,
+            server_time: Some(Utc::now()),
+        };
+        client.send_response(true, "hello", serde_json::to_value(reply).ok());
+    }
+
     async fn handle_register(self: &Arc<Self>, client: &Arc<ClientState>, raw: serde_json::Value) {
         let p: AuthPayload = match serde_json::from_value::<AuthPayload>(raw) {
             Ok(p) if !p.username.is_empty() && !p.password.is_empty() => p,
@@ -253,22 +1400,68 @@ impl Server {
             }
         };
 
-        match self.store.register_user(&p.username, &p.password) {
-            Err(e) => client.send_error(&e.to_string()),
+        match self.auth.register(&p.username, &p.password).await {
+            Err(e) => {
+                Metrics::inc(&self.metrics.auth_failures);
+                client.send_error(&e.to_string());
+            }
             Ok(user) => {
-                client.set_identity(user.id.clone(), user.username.clone()).await;
+                let role = self.role_for(&user.username);
+                client.set_identity(user.id.clone(), user.username.clone(), role).await;
+                self.send_to_hub(HubCommand::SetBlocked {
+                    id: client.id.clone(),
+                    blocked: HashSet::new(),
+                })
+                .await;
                 self.online.write().await.insert(user.id.clone(), client.clone());
                 client.send_response(
                     true,
                     &format!("registered and logged in as {:?}", user.username),
-                    None,
+                    serde_json::to_value(LoginResult::from(&user)).ok(),
+                );
+                let message = format!("{} joined the chat", user.username);
+                self.broadcast_presence(message, PresenceEvent::Join, UserInfo::from(&user)).await;
+                Span::current().record("user_id", user.id.as_str());
+                info!(user_id = %user.id, username = %user.username, "registered");
+                self.audit(
+                    AuditEntry::new(AuditAction::Register)
+                        .actor(user.username.as_str())
+                        .actor_id(user.id.as_str())
+                        .ip(client.peer_ip),
                 );
-                self.broadcast_system(&format!("{} joined the chat", user.username)).await;
-                eprintln!("[server] registered {} ({})", user.username, user.id);
             }
         }
     }
 
+    /// Starts a guest session under a fresh `guest-<tag>-<n>` name. Guests aren't
+    /// stored, listed as online or announced.
+    async fn handle_guest(self: &Arc<Self>, client: &Arc<ClientState>) {
+        if !self.guests {
+            client.send_error("guest access is disabled on this server");
+            return;
+        }
+        if client.is_authenticated().await {
+            client.send_error("already logged in");
+            return;
+        }
+        let n = self.guest_counter.fetch_add(1, Ordering::Relaxed) + 1;
+        let name = format!("{}{}-{}", GUEST_PREFIX, self.guest_tag, n);
+        client.set_identity(name.clone(), name.clone(), Role::Guest).await;
+        let info = UserInfo {
+            user_id: name.clone(),
+            username: name.clone(),
+            ..Default::default()
+        };
+        let mode = if self.guest_chat { "" } else { " (read-only)" };
+        client.send_response(
+            true,
+            &format!("joined as guest {:?}{}", name, mode),
+            serde_json::to_value(info).ok(),
+        );
+        Span::current().record("user_id", name.as_str());
+        info!(username = %name, "guest session started");
+    }
+
     async fn handle_login(self: &Arc<Self>, client: &Arc<ClientState>, raw: serde_json::Value) {
         let p: AuthPayload = match serde_json::from_value::<AuthPayload>(raw) {
             Ok(p) if !p.username.is_empty() && !p.password.is_empty() => p,
@@ -278,27 +1471,98 @@ impl Server {
             }
         };
 
-        match self.store.authenticate(&p.username, &p.password) {
-            Err(e) => client.send_error(&e.to_string()),
+        // Checked before the password, so a locked-out guesser learns nothing.
+        let name = normalize_username(&p.username);
+        if let Some(wait) = self.auth_limiter.locked_for(&name, client.peer_ip) {
+            let why = format!(
+                "too many failed login attempts, try again in {}s",
+                wait.as_secs().max(1)
+            );
+            self.audit(
+                AuditEntry::new(AuditAction::LoginFailed)
+                    .actor(p.username.as_str())
+                    .ip(client.peer_ip)
+                    .detail(why.as_str()),
+            );
+            client.send_error(&why);
+            return;
+        }
+
+        match self.auth.authenticate(&p.username, &p.password).await {
+            Err(e) => {
+                warn!(username = %p.username, error = %e, "login failed");
+                Metrics::inc(&self.metrics.auth_failures);
+                self.audit(
+                    AuditEntry::new(AuditAction::LoginFailed)
+                        .actor(p.username.as_str())
+                        .ip(client.peer_ip)
+                        .detail(e.to_string()),
+                );
+                if let Some(lockout) = self.auth_limiter.record_failure(&name, client.peer_ip) {
+                    warn!(
+                        username = %p.username,
+                        peer_ip = ?client.peer_ip,
+                        secs = lockout.as_secs(),
+                        "login locked out"
+                    );
+                    Metrics::inc(&self.metrics.auth_lockouts);
+                    self.audit(
+                        AuditEntry::new(AuditAction::Lockout)
+                            .actor(p.username.as_str())
+                            .ip(client.peer_ip)
+                            .detail(format!("{}s", lockout.as_secs())),
+                    );
+                }
+                client.send_error(&e.to_string());
+            }
             Ok(user) => {
-                client.set_identity(user.id.clone(), user.username.clone()).await;
+                self.auth_limiter.reset(&name);
+                if !self.is_read_only() {
+                    if let Err(e) = self.store.touch_last_seen(&user.id).await {
+                        warn!(error = %e, "failed to record last seen");
+                    }
+                }
+                let role = self.role_for(&user.username);
+                client.set_identity(user.id.clone(), user.username.clone(), role).await;
+                // Always sent, so a re-login on this connection drops the
+                // previous account's blocks.
+                self.send_to_hub(HubCommand::SetBlocked {
+                    id: client.id.clone(),
+                    blocked: user.blocked.iter().cloned().collect(),
+                })
+                .await;
                 self.online.write().await.insert(user.id.clone(), client.clone());
                 client.send_response(
                     true,
                     &format!("logged in as {:?}", user.username),
-                    None,
+                    serde_json::to_value(LoginResult::from(&user)).ok(),
+                );
+                let message = format!("{} joined the chat", user.username);
+                self.broadcast_presence(message, PresenceEvent::Join, UserInfo::from(&user)).await;
+                // Delivery takes the messages off the queue, so they wait.
+                if !self.is_read_only() {
+                    self.deliver_offline(client, &user.id).await;
+                }
+                Span::current().record("user_id", user.id.as_str());
+                info!(user_id = %user.id, username = %user.username, "login");
+                self.audit(
+                    AuditEntry::new(AuditAction::Login)
+                        .actor(user.username.as_str())
+                        .actor_id(user.id.as_str())
+                        .ip(client.peer_ip),
                 );
-                self.broadcast_system(&format!("{} joined the chat", user.username)).await;
-                eprintln!("[server] login {} ({})", user.username, user.id);
             }
         }
     }
 
     async fn handle_chat(self: &Arc<Self>, client: &Arc<ClientState>, raw: serde_json::Value) {
-        if !client.is_authenticated().await {
-            client.send_error("you must login or register first");
-            return;
-        }
+        let ident = match client.get_identity().await {
+            Some(ident) => ident,
+            None => {
+                client.send_error("you must login or register first");
+                return;
+            }
+        };
 
         let p: ChatPayload = match serde_json::from_value::<ChatPayload>(raw) {
             Ok(p) if !p.content.is_empty() => p,
@@ -307,33 +1571,600 @@ impl Server {
                 return;
             }
         };
+        self.end_away(client, &ident.user_id);
 
-        let ident = client.get_identity().await.unwrap();
-        let now = Utc::now();
-        let msg = StoredMessage {
-            id: format!("{}", now.timestamp_nanos_opt().unwrap_or(0)),
-            user_id: ident.user_id.clone(),
-            username: ident.username.clone(),
-            content: p.content.clone(),
+        let room = match p.room.as_deref() {
+            None => None,
+            Some(name) => match normalize_room(name) {
+                Some(room) if client.rooms.read().await.contains(&room) => Some(room),
+                _ => {
+                    let name = name.trim().trim_start_matches('#');
+                    client.send_error(&format!("you are not in #{}", name));
+                    return;
+                }
+            },
+        };
+
+        if p.ttl_secs.is_some_and(|ttl| ttl == 0 || ttl > MAX_TTL_SECS) {
+            client.send_error(&format!("ttl_secs must be between 1 and {}", MAX_TTL_SECS));
+            return;
+        }
+
+        let content = match self.clean_content(p.content) {
+            Ok(content) => content,
+            Err(why) => {
+                client.send_error(why);
+                return;
+            }
+        };
+
+        // Checked before slow mode so a double-send doesn't restart the clock.
+        let content_hash = hash_content(&content);
+        if self.is_duplicate(&ident.user_id, content_hash) {
+            debug!(user_id = %ident.user_id, "dropping duplicate chat message");
+            return;
+        }
+
+        let guest = ident.role == Role::Guest;
+        let slot = match self.reserve_chat(&ident.user_id, guest) {
+            Ok(slot) => slot,
+            Err(wait) => {
+                let why = if guest { "guests are rate-limited" } else { "slow mode is on" };
+                client.send_error(&format!(
+                    "{}; wait {}s before sending another message",
+                    why,
+                    wait.as_secs_f64().ceil() as u64
+                ));
+                return;
+            }
+        };
+
+        let now = Utc::now();
+        let msg = StoredMessage {
+            id: format!("{}", now.timestamp_nanos_opt().unwrap_or(0)),
+            user_id: ident.user_id.clone(),
+            username: ident.username.clone(),
+            entities: entities::extract(&content),
+            content,
+            timestamp: now,
+            kind: MessageKind::Chat,
+            format: p.format,
+            room,
+            expires_at: p.ttl_secs.map(|ttl| now + chrono::Duration::seconds(ttl as i64)),
+            seq: 0,
+        };
+
+        // A client that echoes locally has already shown the message.
+        let mut except = None;
+        if client.has_feature(FEATURE_LOCAL_ECHO).await {
+            except = Some(client.id.clone());
+        }
+        if !self.post_chat(msg, except).await {
+            self.release_chat(&ident.user_id, slot);
+            if self.is_read_only() {
+                client.send_error("server is read-only");
+            } else {
+                client.send_error("server is busy; message not sent, please retry");
+            }
+            return;
+        }
+        if self.limits().dedup_window.is_some() {
+            self.last_content
+                .lock()
+                .unwrap()
+                .insert(ident.user_id.clone(), (content_hash, Instant::now()));
+        }
+    }
+
+    /// Posts `content` as a chat message from one of the server's own
+    /// accounts (the bot's or a bridge's), through `post_chat`. False if it
+    /// wasn't sent: the server is busy or read-only.
+    async fn post_as(&self, user: &User, content: String, room: Option<String>) -> bool {
+        if self.is_read_only() {
+            return false;
+        }
+        let now = Utc::now();
+        let msg = StoredMessage {
+            id: format!("{}", now.timestamp_nanos_opt().unwrap_or(0)),
+            user_id: user.id.clone(),
+            username: user.username.clone(),
+            entities: entities::extract(&content),
+            content,
             timestamp: now,
+            kind: MessageKind::Chat,
+            format: None,
+            room,
+            expires_at: None,
+            seq: 0,
         };
+        self.post_chat(msg, None).await
+    }
+
+    /// Numbers `msg`, broadcasts it (to every connection but `except`) and
+    /// queues it for persistence. False, with nothing sent or stored, if the
+    /// persistence queue or the hub is full, or the server has just gone
+    /// read-only.
+    async fn post_chat(&self, mut msg: StoredMessage, except: Option<String>) -> bool {
+        // Hold a persistence slot before broadcasting so a message everyone
+        // saw is never missing from history.
+        let permit = match self.pool.reserve().await {
+            Some(permit) => permit,
+            None => return false,
+        };
+        // Going read-only waits for reserved slots, so past this check the
+        // message is saved before the store stops writing.
+        if self.is_read_only() {
+            return false;
+        }
+
+        // Held until the hub has the broadcast, so broadcasts reach the hub
+        // (and every client) in sequence order. A failed send leaves a gap.
+        let mut seq = self.seq.lock().await;
+        *seq += 1;
+        msg.seq = *seq;
 
         // Broadcast immediately
         let bcast_payload = BroadcastPayload {
+            id: msg.id.clone(),
             user_id: msg.user_id.clone(),
             username: msg.username.clone(),
             content: msg.content.clone(),
             timestamp: msg.timestamp,
+            entities: msg.entities.clone(),
+            format: msg.format,
+            room: msg.room.clone(),
+            expires_at: msg.expires_at,
+            seq: msg.seq,
         };
         if let Ok(pkt) = Packet::new(MessageType::Broadcast, bcast_payload) {
-            if let Ok(mut data) = serde_json::to_vec(&pkt) {
-                data.push(b'\n');
-                self.hub_tx.send(HubCommand::Broadcast(data)).await.ok();
+            if let Ok(data) = self.codec.encode(&pkt) {
+                let cmd = HubCommand::ChatBroadcast {
+                    sender_id: msg.user_id.clone(),
+                    room: msg.room.clone(),
+                    except,
+                    data,
+                };
+                if !self.send_to_hub(cmd).await {
+                    // Not persisted either: the permit is dropped unused.
+                    return false;
+                }
+                Metrics::inc(&self.metrics.messages_broadcast);
             }
         }
+        drop(seq);
+        if let Some(webhook) = &self.webhook {
+            webhook.notify(&msg);
+        }
 
         // Persist asynchronously
-        self.pool.submit(msg);
+        permit.send(msg);
+        true
+    }
+
+    /// Joins (or, with `join` false, leaves) a room on this connection. A
+    /// join answers with the room's recent messages.
+    async fn handle_room(
+        self: &Arc<Self>,
+        client: &Arc<ClientState>,
+        raw: serde_json::Value,
+        join: bool,
+    ) {
+        if !client.is_authenticated().await {
+            client.send_error("you must login first");
+            return;
+        }
+        let room = match serde_json::from_value::<RoomPayload>(raw) {
+            Ok(p) => match normalize_room(&p.room) {
+                Some(room) => room,
+                None => {
+                    client.send_error(&invalid_room(&p.room));
+                    return;
+                }
+            },
+            Err(_) => {
+                let kind = if join { "join" } else { "leave" };
+                client.send_error(&format!("{} requires {{room}}", kind));
+                return;
+            }
+        };
+
+        let rooms = {
+            let mut rooms = client.rooms.write().await;
+            let changed = if join { rooms.insert(room.clone()) } else { rooms.remove(&room) };
+            if !changed && !join {
+                client.send_error(&format!("you are not in #{}", room));
+                return;
+            }
+            rooms.clone()
+        };
+        self.send_to_hub(HubCommand::SetRooms {
+            id: client.id.clone(),
+            rooms,
+        })
+        .await;
+
+        if join {
+            let reply = JoinResult {
+                messages: self.store.get_room_history(&room, DEFAULT_HISTORY_LIMIT).await,
+                topic: self.store.topic(&room).await,
+                room,
+            };
+            let message = format!("joined #{}", reply.room);
+            client.send_response(true, &message, serde_json::to_value(reply).ok());
+        } else {
+            let message = format!("left #{}", room);
+            client.send_response(true, &message, serde_json::to_value(RoomPayload { room }).ok());
+        }
+    }
+
+    /// Pins (or, with `pin` false, unpins) a stored message and tells every
+    /// client. Only the message's author or an admin may do either.
+    async fn handle_pin(
+        self: &Arc<Self>,
+        client: &Arc<ClientState>,
+        raw: serde_json::Value,
+        pin: bool,
+    ) {
+        let kind = if pin { "pin" } else { "unpin" };
+        let ident = match client.get_identity().await {
+            Some(ident) => ident,
+            None => {
+                client.send_error("you must login first");
+                return;
+            }
+        };
+        let p = match serde_json::from_value::<PinPayload>(raw) {
+            Ok(p) => p,
+            Err(_) => {
+                client.send_error(&format!("{} requires {{message_id}}", kind));
+                return;
+            }
+        };
+        let message = match self.store.get_message(&p.message_id).await {
+            Some(m) => m,
+            None => {
+                client.send_error(&format!("no message with id {:?}", p.message_id));
+                return;
+            }
+        };
+        if ident.role != Role::Admin && message.user_id != ident.user_id {
+            client.send_error(&format!("only the author or an admin can {} this message", kind));
+            return;
+        }
+
+        match self.store.set_pinned(&message.id, pin).await {
+            Ok(true) => {}
+            Ok(false) => {
+                let state = if pin { "already pinned" } else { "not pinned" };
+                client.send_error(&format!("message is {}", state));
+                return;
+            }
+            Err(e) => {
+                client.send_error(&e.to_string());
+                return;
+            }
+        }
+        info!(user = %ident.username, message_id = %message.id, pin, "pin changed");
+        client.send_response(true, &format!("{}ned", kind), None);
+
+        let by = self.store.user_info(&ident.user_id).await.unwrap_or(UserInfo {
+            user_id: ident.user_id,
+            username: ident.username,
+            ..Default::default()
+        });
+        let payload = SystemPayload {
+            message: format!("{} {}ned a message from {}", by.username, kind, message.username),
+            presence: None,
+            pin: Some(PinEvent {
+                pinned: pin,
+                message,
+                by,
+            }),
+            topic: None,
+        };
+        self.broadcast_notice(payload, None).await;
+    }
+
+    /// Sets or clears a room's topic and tells the room's members. Admins
+    /// only; they need not be in the room.
+    async fn handle_topic(self: &Arc<Self>, client: &Arc<ClientState>, raw: serde_json::Value) {
+        let ident = match client.get_identity().await {
+            Some(ident) => ident,
+            None => {
+                client.send_error("you must login first");
+                return;
+            }
+        };
+        if ident.role != Role::Admin {
+            client.send_error("only admins can set a room topic");
+            return;
+        }
+        let p = match serde_json::from_value::<TopicPayload>(raw) {
+            Ok(p) => p,
+            Err(_) => {
+                client.send_error("topic requires {room, topic}");
+                return;
+            }
+        };
+        let room = match normalize_room(&p.room) {
+            Some(room) => room,
+            None => {
+                client.send_error(&invalid_room(&p.room));
+                return;
+            }
+        };
+        let text = sanitize(p.topic, self.sanitize).trim().to_string();
+        if text.chars().count() > MAX_TOPIC {
+            client.send_error(&format!("topic is longer than {} characters", MAX_TOPIC));
+            return;
+        }
+        let topic = RoomTopic {
+            room: room.clone(),
+            topic: text,
+            set_by: ident.username.clone(),
+            set_at: Utc::now(),
+        };
+        if let Err(e) = self.store.set_topic(topic.clone()).await {
+            client.send_error(&e.to_string());
+            return;
+        }
+        info!(user = %topic.set_by, room = %room, "topic changed");
+        let entry = client.audit_entry(&ident, AuditAction::Topic);
+        self.audit(entry.target(room.as_str()).detail(topic.topic.as_str()));
+        let message = if topic.topic.is_empty() {
+            format!("{} cleared the topic of #{}", topic.set_by, room)
+        } else {
+            format!("{} set the topic of #{}: {}", topic.set_by, room, topic.topic)
+        };
+        client.send_response(true, &message, None);
+
+        let payload = SystemPayload {
+            message,
+            presence: None,
+            pin: None,
+            topic: Some(topic),
+        };
+        if let Ok(data) = Packet::new(MessageType::System, payload)
+            .and_then(|pkt| self.codec.encode(&pkt))
+        {
+            self.send_to_hub(HubCommand::RoomBroadcast { room, data }).await;
+        }
+    }
+
+    async fn handle_pinned(&self, client: &Arc<ClientState>) {
+        if !client.is_authenticated().await {
+            client.send_error("you must login first");
+            return;
+        }
+        let reply = PinnedList {
+            pinned: self.store.pinned_messages().await,
+        };
+        let message = format!("{} pinned message(s)", reply.pinned.len());
+        client.send_response(true, &message, serde_json::to_value(reply).ok());
+    }
+
+    /// Neutralizes control characters in user content, then runs the word
+    /// filter, if any. An error says why the message is rejected.
+    fn clean_content(&self, content: String) -> Result<String, &'static str> {
+        let content = sanitize(content, self.sanitize);
+        if content.is_empty() {
+            return Err("message is empty once control characters are removed");
+        }
+        let filter = self.filter.read().unwrap().clone();
+        match filter {
+            Some(filter) => filter.apply(&content).ok_or("message contains a filtered word"),
+            None => Ok(content),
+        }
+    }
+
+    /// Hands a direct message straight to the recipient's connection. The
+    /// recipient must be online; if they have blocked the sender, the
+    /// message is dropped without telling the sender.
+    async fn handle_direct(self: &Arc<Self>, client: &Arc<ClientState>, raw: serde_json::Value) {
+        let ident = match client.get_identity().await {
+            Some(ident) => ident,
+            None => {
+                client.send_error("you must login first");
+                return;
+            }
+        };
+
+        let p: DirectPayload = match serde_json::from_value::<DirectPayload>(raw) {
+            Ok(p) if !p.to.is_empty() && !p.content.is_empty() => p,
+            _ => {
+                client.send_error("direct requires {to, content}");
+                return;
+            }
+        };
+        self.end_away(client, &ident.user_id);
+
+        let content = match self.clean_content(p.content) {
+            Ok(content) => content,
+            Err(why) => {
+                client.send_error(why);
+                return;
+            }
+        };
+
+        let target = match self.store.get_user(&p.to).await {
+            Some(user) => user,
+            None => {
+                client.send_error(&format!("no such user {:?}", p.to));
+                return;
+            }
+        };
+        if target.id == ident.user_id {
+            client.send_error("you can't message yourself");
+            return;
+        }
+        let recipient = self.online.read().await.get(&target.id).cloned();
+
+        let now = Utc::now();
+        let dm = DirectMessage {
+            id: format!("{}", now.timestamp_nanos_opt().unwrap_or(0)),
+            user_id: ident.user_id.clone(),
+            username: ident.username.clone(),
+            to: target.username.clone(),
+            entities: entities::extract(&content),
+            content,
+            timestamp: now,
+        };
+        let blocked = target.blocked.contains(&ident.user_id);
+        let recipient = match recipient {
+            Some(recipient) => recipient,
+            None => {
+                // Held for the next login; from a blocked sender, dropped as
+                // quietly as when the recipient is online.
+                if !blocked {
+                    if let Err(e) = self.store.enqueue_offline(&target.id, dm.clone()).await {
+                        client.send_error(&e.to_string());
+                        return;
+                    }
+                }
+                debug!(to = %target.id, "direct message queued");
+                let message =
+                    format!("{} is offline; they'll get it when they next log in", target.username);
+                client.send_response(true, &message, serde_json::to_value(&dm).ok());
+                if !blocked {
+                    self.send_away_reply(client, &ident.user_id, &target);
+                }
+                return;
+            }
+        };
+        let delivered = !blocked
+            && Packet::new(MessageType::Direct, &dm)
+                .is_ok_and(|pkt| recipient.try_send_packet(&pkt));
+        if delivered {
+            self.await_read_receipt(&dm, &target.id);
+        }
+        debug!(to = %target.id, delivered, "direct message");
+
+        client.send_response(
+            true,
+            &format!("sent to {}", target.username),
+            serde_json::to_value(&dm).ok(),
+        );
+        if delivered && client.has_feature(FEATURE_RECEIPTS).await {
+            client.send_receipt(&dm.id, ReceiptKind::Delivered);
+        }
+        if !blocked {
+            self.send_away_reply(client, &ident.user_id, &target);
+        }
+    }
+
+    /// Tells `client` that `target` is away, with their message, unless
+    /// `sender_id` was told within [`AWAY_REPLY_WINDOW`].
+    fn send_away_reply(&self, client: &ClientState, sender_id: &str, target: &User) {
+        let mut away = self.away.lock().unwrap();
+        let state = match away.get_mut(&target.id) {
+            Some(state) => state,
+            None => return,
+        };
+        if !state.reply_due(sender_id, Instant::now()) {
+            return;
+        }
+        let notice = format!("{} is away: {}", target.username, state.message);
+        drop(away);
+        client.send_system(&notice);
+    }
+
+    /// Clears `user_id`'s away message, if they had one, telling them so.
+    fn end_away(&self, client: &ClientState, user_id: &str) {
+        if self.away.lock().unwrap().remove(user_id).is_some() {
+            client.send_system("welcome back; you are no longer marked away");
+        }
+    }
+
+    /// Remembers a delivered direct message, so the recipient's read receipt
+    /// can be relayed to `dm`'s sender.
+    fn await_read_receipt(&self, dm: &DirectMessage, recipient_id: &str) {
+        let mut unread = self.unread_directs.lock().unwrap();
+        if unread.len() >= DIRECT_LOG_MAX {
+            unread.pop_front();
+        }
+        unread.push_back(UnreadDirect {
+            message_id: dm.id.clone(),
+            sender_id: dm.user_id.clone(),
+            recipient_id: recipient_id.to_string(),
+        });
+    }
+
+    /// Hands `user_id` the direct messages queued while they were offline,
+    /// oldest first, and sends each sender still online a delivered receipt.
+    /// Messages from users they have since blocked are dropped. Whatever
+    /// can't be handed over (the connection closed) stays queued.
+    async fn deliver_offline(&self, client: &Arc<ClientState>, user_id: &str) {
+        let queued = self.store.offline_queue(user_id).await;
+        if queued.is_empty() {
+            return;
+        }
+        let blocked = self.store.get_blocks(user_id).await;
+        let mut handed = 0;
+        for dm in &queued {
+            if !blocked.contains(&dm.user_id) {
+                let data = Packet::new(MessageType::Direct, dm)
+                    .and_then(|pkt| client.codec.encode(&pkt));
+                if let Ok(data) = data {
+                    // Waits for room: these were promised to arrive.
+                    if !client.send.send(data).await {
+                        break;
+                    }
+                    self.await_read_receipt(dm, user_id);
+                    let sender = self.online.read().await.get(&dm.user_id).cloned();
+                    if let Some(sender) = sender {
+                        if sender.has_feature(FEATURE_RECEIPTS).await {
+                            sender.send_receipt(&dm.id, ReceiptKind::Delivered);
+                        }
+                    }
+                }
+            }
+            handed += 1;
+        }
+        debug!(user_id, handed, queued = queued.len(), "offline direct messages delivered");
+        if let Err(e) = self.store.dequeue_offline(user_id, handed).await {
+            warn!(error = %e, "failed to clear delivered offline messages");
+        }
+    }
+
+    /// Relays a recipient's read receipt to the direct message's sender.
+    /// Unknown, repeated and expired message ids are ignored.
+    async fn handle_receipt(self: &Arc<Self>, client: &Arc<ClientState>, raw: serde_json::Value) {
+        let ident = match client.get_identity().await {
+            Some(ident) => ident,
+            None => {
+                client.send_error("you must login first");
+                return;
+            }
+        };
+
+        let p: ReceiptPayload = match serde_json::from_value(raw) {
+            Ok(p) => p,
+            Err(_) => {
+                client.send_error("receipt requires {message_id, kind}");
+                return;
+            }
+        };
+        if p.kind != ReceiptKind::Read {
+            client.send_error("only read receipts can be sent");
+            return;
+        }
+
+        let sender_id = {
+            let mut unread = self.unread_directs.lock().unwrap();
+            let pos = unread
+                .iter()
+                .position(|d| d.message_id == p.message_id && d.recipient_id == ident.user_id);
+            match pos.and_then(|i| unread.remove(i)) {
+                Some(d) => d.sender_id,
+                None => return,
+            }
+        };
+        let sender = self.online.read().await.get(&sender_id).cloned();
+        if let Some(sender) = sender {
+            if sender.has_feature(FEATURE_RECEIPTS).await {
+                sender.send_receipt(&p.message_id, ReceiptKind::Read);
+            }
+        }
     }
 
     async fn handle_search(self: &Arc<Self>, client: &Arc<ClientState>, raw: serde_json::Value) {
@@ -357,10 +2188,39 @@ impl Server {
             return;
         }
 
-        let results = self.store.search(&p.query, &p.username, p.from, p.to);
-        let count = results.len();
-        let data = serde_json::to_value(results).ok();
-        client.send_response(true, &format!("{} result(s)", count), data);
+        let query = if p.regex {
+            match Query::regex(&p.query) {
+                Ok(query) => query,
+                Err(e) => {
+                    client.send_error(&e.to_string());
+                    return;
+                }
+            }
+        } else {
+            Query::parse(&p.query)
+        };
+        let filter = SearchFilter {
+            query,
+            username: p.username,
+            from: p.from,
+            to: p.to,
+            include_system: p.include_system,
+        };
+        let mut result = self.store.search(&filter, search_limit(p.limit), p.offset).await;
+        let fit = fitting(&result.messages);
+        let truncated = fit < result.messages.len();
+        result.messages.truncate(fit);
+        let mut message = format!("{} of {} result(s)", result.messages.len(), result.total);
+        if truncated {
+            message.push_str("; cut short to fit in one response");
+        }
+        let payload = ResponsePayload {
+            success: true,
+            message,
+            data: serde_json::to_value(result).ok(),
+            truncated,
+        };
+        client.send_response_payload(payload);
     }
 
     async fn handle_history(self: &Arc<Self>, client: &Arc<ClientState>, raw: serde_json::Value) {
@@ -369,44 +2229,1293 @@ impl Server {
             return;
         }
 
-        let limit = serde_json::from_value::<HistoryPayload>(raw)
-            .map(|p| if p.limit == 0 { 20 } else { p.limit })
-            .unwrap_or(20);
+        let (limit, include_system) = serde_json::from_value::<HistoryPayload>(raw)
+            .map(|p| (history_limit(p.limit), p.include_system))
+            .unwrap_or((DEFAULT_HISTORY_LIMIT, false));
+
+        // Read before the messages, so a change made in between leaves the
+        // entry already out of date.
+        let key = (limit, include_system);
+        let version = self.store.messages_version();
+        if let Some(body) = self.history_cache.get(key, version, Utc::now()) {
+            client.send_encoded(&body);
+            return;
+        }
 
-        let msgs = self.store.get_history(limit);
-        let count = msgs.len();
-        let data = serde_json::to_value(msgs).ok();
-        client.send_response(true, &format!("last {} message(s)", count), data);
+        let mut msgs = self.store.get_history(limit, include_system).await;
+        let fit = fitting(msgs.iter().rev());
+        let truncated = fit < msgs.len();
+        msgs.drain(..msgs.len() - fit);
+        let mut message = format!("last {} message(s)", msgs.len());
+        if truncated {
+            message.push_str("; older ones left out to fit in one response");
+        }
+        let payload = ResponsePayload {
+            success: true,
+            message,
+            data: serde_json::to_value(&msgs).ok(),
+            truncated,
+        };
+        let body = match Packet::new(MessageType::Response, payload).and_then(|p| p.encode()) {
+            Ok(body) => Arc::<[u8]>::from(body),
+            Err(_) => return,
+        };
+        self.history_cache.insert(key, version, &msgs, body.clone());
+        client.send_encoded(&body);
     }
 
-    async fn handle_users(self: &Arc<Self>, client: &Arc<ClientState>) {
+    async fn handle_sync(self: &Arc<Self>, client: &Arc<ClientState>, raw: serde_json::Value) {
         if !client.is_authenticated().await {
             client.send_error("you must login first");
             return;
         }
 
-        let online = self.online.read().await;
-        let mut users = Vec::new();
-        for (user_id, c) in online.iter() {
-            if let Some(ident) = c.get_identity().await {
-                users.push(UserInfo {
-                    user_id: user_id.clone(),
-                    username: ident.username,
-                });
+        let p: SyncPayload = match serde_json::from_value(raw) {
+            Ok(p) => p,
+            Err(_) => {
+                client.send_error("malformed sync payload");
+                return;
             }
+        };
+
+        let (messages, complete, message) =
+            match self.store.get_messages_after(&p.since_id, p.include_system).await {
+                Some(mut msgs) if msgs.len() > MAX_SYNC => {
+                    msgs.drain(..msgs.len() - MAX_SYNC);
+                    let message = format!("too many new messages; sending the newest {}", MAX_SYNC);
+                    (msgs, false, message)
+                }
+                Some(msgs) => {
+                    let message = format!("{} new message(s)", msgs.len());
+                    (msgs, true, message)
+                }
+                None => {
+                    let msgs =
+                        self.store.get_history(DEFAULT_HISTORY_LIMIT, p.include_system).await;
+                    let message = format!(
+                        "sync cursor not found; sending the last {} message(s)",
+                        msgs.len()
+                    );
+                    (msgs, false, message)
+                }
+            };
+
+        let data = serde_json::to_value(SyncResult { messages, complete }).ok();
+        client.send_response(true, &message, data);
+    }
+
+    async fn handle_users(self: &Arc<Self>, client: &Arc<ClientState>) {
+        if !client.is_authenticated().await {
+            client.send_error("you must login first");
+            return;
         }
+
+        let users = self.online_users().await;
         let count = users.len();
         let data = serde_json::to_value(users).ok();
         client.send_response(true, &format!("{} user(s) online", count), data);
     }
 
-    async fn broadcast_system(self: &Arc<Self>, msg: &str) {
-        let payload = serde_json::json!({"message": msg});
-        if let Ok(pkt) = Packet::new(MessageType::System, payload) {
-            if let Ok(mut data) = serde_json::to_vec(&pkt) {
-                data.push(b'\n');
-                self.hub_tx.send(HubCommand::Broadcast(data)).await.ok();
+    async fn handle_rename(self: &Arc<Self>, client: &Arc<ClientState>, raw: serde_json::Value) {
+        let ident = match client.get_identity().await {
+            Some(ident) => ident,
+            None => {
+                client.send_error("you must login first");
+                return;
+            }
+        };
+
+        let p: RenamePayload = match serde_json::from_value::<RenamePayload>(raw) {
+            Ok(p) if !p.new_username.is_empty() => p,
+            _ => {
+                client.send_error("rename requires {new_username}");
+                return;
+            }
+        };
+
+        match self.store.rename_user(&ident.user_id, &p.new_username).await {
+            Err(e) => client.send_error(&e.to_string()),
+            Ok(user) => {
+                let role = self.role_for(&user.username);
+                client.set_identity(user.id.clone(), user.username.clone(), role).await;
+                self.send_to_hub(HubCommand::Rename {
+                    id: client.id.clone(),
+                    username: user.username.clone(),
+                })
+                .await;
+                client.send_response(
+                    true,
+                    &format!("you are now known as {:?}", user.username),
+                    serde_json::to_value(UserInfo::from(&user)).ok(),
+                );
+                let message = format!("{} is now known as {}", ident.username, user.username);
+                self.broadcast_presence(message, PresenceEvent::Rename, UserInfo::from(&user))
+                    .await;
+                info!(user_id = %user.id, old = %ident.username, new = %user.username, "renamed");
+                let entry = client.audit_entry(&ident, AuditAction::Rename);
+                self.audit(entry.target(user.username.as_str()));
             }
         }
     }
+
+    async fn handle_recent_users(
+        self: &Arc<Self>,
+        client: &Arc<ClientState>,
+        raw: serde_json::Value,
+    ) {
+        if !client.is_authenticated().await {
+            client.send_error("you must login first");
+            return;
+        }
+
+        let p: RecentUsersPayload = match serde_json::from_value(raw) {
+            Ok(p) => p,
+            Err(_) => {
+                client.send_error("malformed recentusers payload");
+                return;
+            }
+        };
+        let hours = match p.hours {
+            0 => DEFAULT_RECENT_HOURS,
+            n => n.min(MAX_RECENT_HOURS),
+        };
+
+        let since = Utc::now() - chrono::Duration::hours(hours as i64);
+        let users = self.store.recent_users(since).await;
+        let message = format!("{} user(s) seen in the last {}h", users.len(), hours);
+        let data = serde_json::to_value(RecentUsersResult { since, users }).ok();
+        client.send_response(true, &message, data);
+    }
+
+    /// Answers with one page of every registered account, marking who is
+    /// online.
+    async fn handle_directory(self: &Arc<Self>, client: &Arc<ClientState>, raw: serde_json::Value) {
+        if !client.is_authenticated().await {
+            client.send_error("you must login first");
+            return;
+        }
+
+        let p: DirectoryPayload = match serde_json::from_value(raw) {
+            Ok(p) => p,
+            Err(_) => {
+                client.send_error("malformed directory payload");
+                return;
+            }
+        };
+        let limit = match p.limit {
+            0 => DEFAULT_DIRECTORY_LIMIT,
+            n => n.min(MAX_DIRECTORY_LIMIT),
+        };
+
+        let mut page = self.store.list_users(limit, p.offset).await;
+        let online = self.online.read().await;
+        for entry in &mut page.users {
+            entry.online = online.contains_key(&entry.user.user_id);
+        }
+        drop(online);
+        let message = match page.users.len() {
+            0 => format!("no users at offset {} ({} in all)", p.offset, page.total),
+            n => format!("users {}-{} of {}", p.offset + 1, p.offset + n, page.total),
+        };
+        client.send_response(true, &message, serde_json::to_value(page).ok());
+    }
+
+    async fn handle_dnd(self: &Arc<Self>, client: &Arc<ClientState>, raw: serde_json::Value) {
+        if !client.is_authenticated().await {
+            client.send_error("you must login first");
+            return;
+        }
+
+        let p: DndPayload = match serde_json::from_value(raw) {
+            Ok(p) => p,
+            Err(_) => {
+                client.send_error("malformed dnd payload");
+                return;
+            }
+        };
+
+        let cmd = HubCommand::SetDnd {
+            id: client.id.clone(),
+            enabled: p.enabled,
+        };
+        if !self.send_to_hub(cmd).await {
+            client.send_error("server is busy; please retry");
+            return;
+        }
+        let message = if p.enabled {
+            "do not disturb on: chat messages are paused"
+        } else {
+            "do not disturb off"
+        };
+        client.send_response(true, message, None);
+    }
+
+    async fn handle_away(self: &Arc<Self>, client: &Arc<ClientState>, raw: serde_json::Value) {
+        let ident = match client.get_identity().await {
+            Some(ident) => ident,
+            None => {
+                client.send_error("you must login first");
+                return;
+            }
+        };
+
+        let p: AwayPayload = match serde_json::from_value(raw) {
+            Ok(p) => p,
+            Err(_) => {
+                client.send_error("malformed away payload");
+                return;
+            }
+        };
+        let message = sanitize(p.message, self.sanitize).trim().to_string();
+        if message.chars().count() > MAX_AWAY_MESSAGE {
+            client.send_error(&format!(
+                "away message is longer than {} characters",
+                MAX_AWAY_MESSAGE
+            ));
+            return;
+        }
+
+        let mut away = self.away.lock().unwrap();
+        let response = if message.is_empty() {
+            match away.remove(&ident.user_id) {
+                Some(_) => "you are no longer marked away".to_string(),
+                None => "you weren't marked away".to_string(),
+            }
+        } else {
+            let response = format!("you are marked away: {}", message);
+            let replied = HashMap::new();
+            away.insert(ident.user_id.clone(), Away { message, replied });
+            response
+        };
+        drop(away);
+        client.send_response(true, &response, None);
+    }
+
+    async fn handle_block(
+        self: &Arc<Self>,
+        client: &Arc<ClientState>,
+        raw: serde_json::Value,
+        blocked: bool,
+    ) {
+        let ident = match client.get_identity().await {
+            Some(ident) => ident,
+            None => {
+                client.send_error("you must login first");
+                return;
+            }
+        };
+
+        let p: BlockPayload = match serde_json::from_value::<BlockPayload>(raw) {
+            Ok(p) if !p.username.is_empty() => p,
+            _ => {
+                client.send_error("block requires {username}");
+                return;
+            }
+        };
+
+        let list = match self.store.set_block(&ident.user_id, &p.username, blocked).await {
+            Ok(list) => list,
+            Err(e) => {
+                client.send_error(&e.to_string());
+                return;
+            }
+        };
+        let cmd = HubCommand::SetBlocked {
+            id: client.id.clone(),
+            blocked: self.store.get_blocks(&ident.user_id).await,
+        };
+        if !self.send_to_hub(cmd).await {
+            client.send_error("server is busy; please retry");
+            return;
+        }
+        let message = if blocked {
+            format!("blocked {}", p.username)
+        } else {
+            format!("unblocked {}", p.username)
+        };
+        let data = serde_json::to_value(BlockList { blocked: list }).ok();
+        client.send_response(true, &message, data);
+        info!(user_id = %ident.user_id, target = %p.username, blocked, "block list changed");
+    }
+
+    async fn handle_whoami(self: &Arc<Self>, client: &Arc<ClientState>) {
+        let ident = match client.get_identity().await {
+            Some(ident) => ident,
+            None => {
+                client.send_error("you must login first");
+                return;
+            }
+        };
+        let info = SessionInfo {
+            user_id: ident.user_id,
+            username: ident.username,
+            role: ident.role,
+            connected_since: client.connected_at,
+        };
+        let message = format!("you are {:?}", info.username);
+        client.send_response(true, &message, serde_json::to_value(info).ok());
+    }
+
+    async fn handle_stats(self: &Arc<Self>, client: &Arc<ClientState>) {
+        if !client.is_authenticated().await {
+            client.send_error("you must login first");
+            return;
+        }
+
+        let stats = ServerStats {
+            messages: self.store.message_count().await,
+            users: self.store.user_count().await,
+            online: self.online.read().await.len(),
+            uptime_secs: self.started_at.elapsed().as_secs(),
+        };
+        client.send_response(true, "server stats", serde_json::to_value(stats).ok());
+    }
+
+    async fn handle_profile(self: &Arc<Self>, client: &Arc<ClientState>, raw: serde_json::Value) {
+        if !client.is_authenticated().await {
+            client.send_error("you must login first");
+            return;
+        }
+
+        let p: ProfilePayload = match serde_json::from_value::<ProfilePayload>(raw) {
+            Ok(p) if !p.username.is_empty() => p,
+            _ => {
+                client.send_error("profile requires {username}");
+                return;
+            }
+        };
+
+        let user = match self.store.get_user(&p.username).await {
+            Some(user) => user,
+            None => {
+                client.send_error(&format!("user {:?} not found", p.username));
+                return;
+            }
+        };
+        let profile = Profile {
+            message_count: self.store.message_count_for(&user.id).await,
+            online: self.online.read().await.contains_key(&user.id),
+            username: user.username,
+            display_name: user.display_name,
+            status_text: user.status_text,
+            avatar: user.avatar,
+            created_at: user.created_at,
+            last_seen: user.last_seen,
+        };
+        client.send_response(true, "profile", serde_json::to_value(profile).ok());
+    }
+
+    async fn handle_update_profile(
+        self: &Arc<Self>,
+        client: &Arc<ClientState>,
+        raw: serde_json::Value,
+    ) {
+        let ident = match client.get_identity().await {
+            Some(ident) => ident,
+            None => {
+                client.send_error("you must login first");
+                return;
+            }
+        };
+
+        let p: UpdateProfilePayload = match serde_json::from_value(raw) {
+            Ok(p) => p,
+            Err(_) => {
+                client.send_error("malformed profile update payload");
+                return;
+            }
+        };
+
+        match self.store.update_profile(&ident.user_id, &p).await {
+            Err(e) => client.send_error(&e.to_string()),
+            Ok(user) => {
+                let info = UserInfo::from(&user);
+                client.send_response(true, "profile updated", serde_json::to_value(&info).ok());
+                let message = format!("{} updated their profile", user.username);
+                self.broadcast_presence(message, PresenceEvent::Profile, info).await;
+                info!(user_id = %user.id, "profile updated");
+            }
+        }
+    }
+
+    async fn handle_update_prefs(
+        self: &Arc<Self>,
+        client: &Arc<ClientState>,
+        raw: serde_json::Value,
+    ) {
+        let ident = match client.get_identity().await {
+            Some(ident) => ident,
+            None => {
+                client.send_error("you must login first");
+                return;
+            }
+        };
+
+        let p: UpdatePrefsPayload = match serde_json::from_value(raw) {
+            Ok(p) => p,
+            Err(_) => {
+                client.send_error("updateprefs takes {notify?: all | mentions | none}");
+                return;
+            }
+        };
+
+        match self.store.update_prefs(&ident.user_id, &p).await {
+            Err(e) => client.send_error(&e.to_string()),
+            Ok(prefs) => {
+                let message = format!("notifications: {}", prefs.notify);
+                client.send_response(true, &message, serde_json::to_value(&prefs).ok());
+                debug!(user_id = %ident.user_id, notify = %prefs.notify, "prefs updated");
+            }
+        }
+    }
+
+    async fn handle_purge(self: &Arc<Self>, client: &Arc<ClientState>, raw: serde_json::Value) {
+        let ident = match client.get_identity().await {
+            Some(ident) => ident,
+            None => {
+                client.send_error("you must login first");
+                return;
+            }
+        };
+        if ident.role != Role::Admin {
+            client.send_error("purge requires admin privileges");
+            return;
+        }
+
+        let p: PurgePayload = match serde_json::from_value(raw) {
+            Ok(p) => p,
+            Err(_) => {
+                client.send_error("malformed purge payload");
+                return;
+            }
+        };
+
+        match self.store.purge_messages(p.before).await {
+            Err(e) => client.send_error(&e.to_string()),
+            Ok(removed) => {
+                client.send_response(true, &format!("purged {} message(s)", removed), None);
+                let scope = match p.before {
+                    Some(before) => format!(" older than {}", before.format("%Y-%m-%d %H:%M UTC")),
+                    None => String::new(),
+                };
+                self.broadcast_system(&format!(
+                    "{} purged the message history{}",
+                    ident.username, scope
+                ))
+                .await;
+                info!(user_id = %ident.user_id, removed, before = ?p.before, "history purged");
+                let entry = client.audit_entry(&ident, AuditAction::Purge);
+                self.audit(entry.detail(format!("{} message(s){}", removed, scope)));
+            }
+        }
+    }
+
+    async fn handle_admin(self: &Arc<Self>, client: &Arc<ClientState>, raw: serde_json::Value) {
+        let ident = match client.get_identity().await {
+            Some(ident) => ident,
+            None => {
+                client.send_error("you must login first");
+                return;
+            }
+        };
+        if ident.role != Role::Admin {
+            client.send_error("admin commands require admin privileges");
+            return;
+        }
+
+        let p: AdminPayload = match serde_json::from_value(raw) {
+            Ok(p) => p,
+            Err(_) => {
+                client.send_error("malformed admin payload");
+                return;
+            }
+        };
+
+        match p {
+            AdminPayload::SlowMode { seconds } => {
+                self.slow_mode_secs.store(seconds, Ordering::Relaxed);
+                let notice = if seconds == 0 {
+                    "disabled slow mode".to_string()
+                } else {
+                    format!("enabled slow mode (one message every {}s)", seconds)
+                };
+                client.send_response(true, &notice, None);
+                self.broadcast_system(&format!("{} {}", ident.username, notice)).await;
+                info!(user_id = %ident.user_id, seconds, "slow mode changed");
+                let entry = client.audit_entry(&ident, AuditAction::SlowMode);
+                self.audit(entry.detail(format!("{}s", seconds)));
+            }
+            AdminPayload::ReadOnly { enabled } => {
+                // Set on the store last when enabling and first when
+                // disabling, so no change in flight is refused half-done.
+                if enabled {
+                    self.read_only.store(true, Ordering::Relaxed);
+                    // Messages already broadcast must still reach the store.
+                    self.pool.drain().await;
+                    if let Err(e) = self.store.set_read_only(true).await {
+                        error!(error = %e, "store: failed to change read-only mode");
+                    }
+                } else {
+                    if let Err(e) = self.store.set_read_only(false).await {
+                        error!(error = %e, "store: failed to make the store writable");
+                        client.send_error("the store could not be made writable");
+                        return;
+                    }
+                    self.read_only.store(false, Ordering::Relaxed);
+                }
+                let notice = if enabled {
+                    "made the server read-only"
+                } else {
+                    "made the server writable again"
+                };
+                client.send_response(true, notice, None);
+                self.broadcast_system(&format!("{} {}", ident.username, notice)).await;
+                info!(user_id = %ident.user_id, enabled, "read-only mode changed");
+                let entry = client.audit_entry(&ident, AuditAction::ReadOnly);
+                self.audit(entry.detail(if enabled { "on" } else { "off" }));
+            }
+        }
+    }
+
+    /// Appends `entry` to the audit log, if there is one.
+    fn audit(&self, entry: AuditEntry) {
+        if let Some(audit) = &self.audit {
+            audit.record(entry);
+        }
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// Takes `user_id`'s slow-mode slot for a message about to go out, or
+    /// returns how much longer they must wait (never less than
+    /// `GUEST_SLOW_MODE_SECS` for a guest). Checking and taking happen under
+    /// one lock, so a user with several connections still gets one message
+    /// per interval. A send that then fails hands the slot back with
+    /// `release_chat`.
+    fn reserve_chat(&self, user_id: &str, guest: bool) -> Result<ChatSlot, Duration> {
+        let slow_secs = self.slow_mode_secs.load(Ordering::Relaxed);
+        let secs = if guest { slow_secs.max(GUEST_SLOW_MODE_SECS) } else { slow_secs };
+        let interval = Duration::from_secs(secs);
+        let now = Instant::now();
+        let mut last_chat = self.last_chat.lock().unwrap();
+        // Nothing older than the longest interval in force can hold anyone
+        // back, so the map only holds recent senders.
+        let longest = Duration::from_secs(slow_secs.max(GUEST_SLOW_MODE_SECS));
+        last_chat.retain(|_, at| now.duration_since(*at) < longest);
+        let previous = last_chat.get(user_id).copied();
+        if let Some(last) = previous {
+            let wait = interval.saturating_sub(now.duration_since(last));
+            if !wait.is_zero() {
+                return Err(wait);
+            }
+        }
+        last_chat.insert(user_id.to_string(), now);
+        Ok(ChatSlot { previous, taken: now })
+    }
+
+    /// Hands back a slot from `reserve_chat` whose message never went out,
+    /// unless a later message has taken its place.
+    fn release_chat(&self, user_id: &str, slot: ChatSlot) {
+        let mut last_chat = self.last_chat.lock().unwrap();
+        if last_chat.get(user_id) != Some(&slot.taken) {
+            return;
+        }
+        match slot.previous {
+            Some(previous) => last_chat.insert(user_id.to_string(), previous),
+            None => last_chat.remove(user_id),
+        };
+    }
+
+    /// Whether `user_id` already had a message with this content hash
+    /// broadcast within the dedup window. Only successful sends are recorded,
+    /// so retrying after a "server is busy" error is never treated as a repeat.
+    fn is_duplicate(&self, user_id: &str, content_hash: u64) -> bool {
+        let window = match self.limits().dedup_window {
+            Some(window) => window,
+            None => return false,
+        };
+        let last_content = self.last_content.lock().unwrap();
+        last_content
+            .get(user_id)
+            .is_some_and(|(hash, at)| *hash == content_hash && at.elapsed() < window)
+    }
+
+    /// Snapshot of every authenticated, connected user.
+    async fn online_users(&self) -> Vec<UserInfo> {
+        let online = self.online.read().await;
+        let mut users = Vec::new();
+        for (user_id, c) in online.iter() {
+            if c.is_authenticated().await {
+                users.extend(self.store.user_info(user_id).await);
+            }
+        }
+        users
+    }
+
+    fn role_for(&self, username: &str) -> Role {
+        if self.admins.contains(&normalize_username(username)) {
+            Role::Admin
+        } else {
+            Role::User
+        }
+    }
+
+    async fn broadcast_system(self: &Arc<Self>, msg: &str) {
+        let payload = SystemPayload {
+            message: msg.to_string(),
+            presence: None,
+            pin: None,
+            topic: None,
+        };
+        self.broadcast_notice(payload, None).await;
+    }
+
+    /// A system notice that also tells clients who joined, left or renamed.
+    /// Under the presence debounce a leave is sent late, and a join that
+    /// finds its user's leave still waiting cancels it and is dropped too,
+    /// so a quick reconnect goes unannounced.
+    async fn broadcast_presence(
+        self: &Arc<Self>,
+        message: String,
+        event: PresenceEvent,
+        user: UserInfo,
+    ) {
+        match (event, self.presence_debounce) {
+            (PresenceEvent::Join, _) => {
+                let pending = self.pending_leaves.lock().unwrap().remove(&user.user_id);
+                if let Some(leave) = pending {
+                    leave.abort();
+                    debug!(username = %user.username, "presence: reconnect not announced");
+                    return;
+                }
+            }
+            (PresenceEvent::Leave, Some(delay)) => {
+                self.delay_leave(message, user, delay);
+                return;
+            }
+            _ => {}
+        }
+        self.send_presence(message, event, user).await;
+    }
+
+    /// Announces `user`'s leave after `delay`, unless a join cancels it first.
+    fn delay_leave(self: &Arc<Self>, message: String, user: UserInfo, delay: Duration) {
+        let srv = self.clone();
+        let user_id = user.user_id.clone();
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let due = srv.pending_leaves.lock().unwrap().remove(&user.user_id).is_some();
+            if due {
+                srv.send_presence(message, PresenceEvent::Leave, user).await;
+            }
+        });
+        // Another of the user's connections may have left just before.
+        let earlier = self.pending_leaves.lock().unwrap().insert(user_id, task.abort_handle());
+        if let Some(earlier) = earlier {
+            earlier.abort();
+        }
+    }
+
+    async fn send_presence(
+        self: &Arc<Self>,
+        message: String,
+        event: PresenceEvent,
+        user: UserInfo,
+    ) {
+        // Clients that don't know the event would drop the whole notice.
+        let feature = match event {
+            PresenceEvent::Profile => Some(FEATURE_PROFILES),
+            _ => None,
+        };
+        let payload = SystemPayload {
+            message,
+            presence: Some(Presence { event, user }),
+            pin: None,
+            topic: None,
+        };
+        self.broadcast_notice(payload, feature).await;
+    }
+
+    /// Hands out the next message sequence number.
+    async fn next_seq(&self) -> u64 {
+        let mut seq = self.seq.lock().await;
+        *seq += 1;
+        *seq
+    }
+
+    /// Queues a command for the hub, waiting up to `HUB_SEND_TIMEOUT` for
+    /// room. Returns false (after logging and counting it) if the command was
+    /// lost.
+    async fn send_to_hub(&self, cmd: HubCommand) -> bool {
+        match tokio::time::timeout(HUB_SEND_TIMEOUT, self.hub_tx.send(cmd)).await {
+            Ok(Ok(())) => true,
+            Ok(Err(_)) => {
+                Metrics::inc(&self.metrics.hub_send_failures);
+                error!("hub: channel closed, command lost");
+                false
+            }
+            Err(_) => {
+                Metrics::inc(&self.metrics.hub_send_failures);
+                warn!("hub: channel full, command lost");
+                false
+            }
+        }
+    }
+
+    /// Broadcasts a system notice (to clients that negotiated `feature`,
+    /// if set) and persists it when `persist_system` is on.
+    async fn broadcast_notice(
+        self: &Arc<Self>,
+        payload: SystemPayload,
+        feature: Option<&'static str>,
+    ) {
+        if let Ok(pkt) = Packet::new(MessageType::System, &payload) {
+            if let Ok(data) = self.codec.encode(&pkt) {
+                let cmd = match feature {
+                    Some(feature) => HubCommand::FeatureBroadcast { feature, data },
+                    None => HubCommand::Broadcast(data),
+                };
+                self.send_to_hub(cmd).await;
+            }
+        }
+
+        if self.persist_system && !self.is_read_only() {
+            let now = Utc::now();
+            self.pool.submit(StoredMessage {
+                id: format!("{}", now.timestamp_nanos_opt().unwrap_or(0)),
+                user_id: String::new(),
+                username: String::new(),
+                content: payload.message,
+                timestamp: now,
+                kind: MessageKind::System,
+                entities: Vec::new(),
+                format: None,
+                room: None,
+                expires_at: None,
+                seq: self.next_seq().await,
+            });
+        }
+    }
+}
+
+fn invalid_room(name: &str) -> String {
+    format!("invalid room name {:?} (up to {} letters, digits, - or _)", name, MAX_ROOM_NAME)
+}
+
+/// The last frame sent to a client dropped by `drop_lagging`.
+fn lag_notice(codec: Codec) -> Option<Vec<u8>> {
+    let payload = SystemPayload {
+        message: "you are falling behind; disconnecting".to_string(),
+        presence: None,
+        pin: None,
+        topic: None,
+    };
+    let pkt = Packet::new(MessageType::System, payload).ok()?;
+    codec.encode(&pkt).ok()
+}
+
+/// Error text for a frame that didn't parse as a [`Packet`], naming the
+/// `type` when that is what's wrong.
+fn describe_bad_packet(frame: &[u8]) -> String {
+    let value: serde_json::Value = match serde_json::from_slice(frame) {
+        Ok(v) => v,
+        Err(_) => return "malformed packet: not valid JSON".to_string(),
+    };
+    match value.get("type") {
+        None => "malformed packet: missing \"type\"".to_string(),
+        Some(t) if serde_json::from_value::<MessageType>(t.clone()).is_err() => {
+            format!("unknown packet type {}", t)
+        }
+        Some(_) => "malformed packet".to_string(),
+    }
+}
+
+/// Whether a request of this type can change stored data, and so is refused
+/// while the server is read-only.
+fn changes_data(msg_type: &MessageType) -> bool {
+    matches!(
+        msg_type,
+        MessageType::Register
+            | MessageType::Chat
+            | MessageType::Direct
+            | MessageType::Purge
+            | MessageType::Rename
+            | MessageType::Block
+            | MessageType::Unblock
+            | MessageType::UpdateProfile
+            | MessageType::UpdatePrefs
+            | MessageType::Pin
+            | MessageType::Unpin
+            | MessageType::Topic
+    )
+}
+
+/// The message count for a requested history `limit`: 0 picks the
+/// default, and anything above the cap is clamped.
+fn history_limit(limit: usize) -> usize {
+    match limit {
+        0 => DEFAULT_HISTORY_LIMIT,
+        n => n.min(MAX_HISTORY_LIMIT),
+    }
+}
+
+/// The page size for a requested search `limit`: 0 picks the default, and
+/// anything above the cap is clamped.
+fn search_limit(limit: usize) -> usize {
+    match limit {
+        0 => DEFAULT_SEARCH_LIMIT,
+        n => n.min(MAX_SEARCH_LIMIT),
+    }
+}
+
+/// How many of `msgs`, taken in order, fit in one response under
+/// [`MAX_RESPONSE_BYTES`].
+fn fitting<'a>(msgs: impl IntoIterator<Item = &'a StoredMessage>) -> usize {
+    let mut size = RESPONSE_OVERHEAD;
+    msgs.into_iter()
+        .take_while(|m| {
+            // Each one after the first also takes a comma.
+            size += encoded_len(m) + 1;
+            size <= MAX_RESPONSE_BYTES
+        })
+        .count()
+}
+
+/// The length of `v` as JSON, counted without building it.
+fn encoded_len(v: &impl serde::Serialize) -> usize {
+    struct Counter(usize);
+
+    impl std::io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    serde_json::to_writer(&mut counter, v).ok();
+    counter.0
+}
+
+fn hash_content(content: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncBufReadExt, Lines};
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+    use tokio::net::TcpStream;
+    use tokio::sync::oneshot;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Subscriber};
+    use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+    use tracing_subscriber::registry::LookupSpan;
+
+    use super::*;
+
+    /// An in-memory server on a free local port.
+    async fn spawn_server() -> (Arc<Server>, SocketAddr) {
+        spawn_server_with(ServerConfig {
+            ephemeral: true,
+            ..ServerConfig::default()
+        })
+        .await
+    }
+
+    async fn spawn_server_with(config: ServerConfig) -> (Arc<Server>, SocketAddr) {
+        let server = Arc::new(Server::new(config).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(server.clone().serve(listener));
+        (server, addr)
+    }
+
+    /// A bare protocol connection; `tests/common` has the full-featured one.
+    struct Conn {
+        lines: Lines<BufReader<OwnedReadHalf>>,
+        writer: OwnedWriteHalf,
+    }
+
+    impl Conn {
+        async fn connect(addr: SocketAddr) -> Self {
+            let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+            Self {
+                lines: BufReader::new(reader).lines(),
+                writer,
+            }
+        }
+
+        async fn send(&mut self, kind: &str, payload: Value) {
+            let line = json!({ "type": kind, "payload": payload }).to_string() + "\n";
+            self.writer.write_all(line.as_bytes()).await.unwrap();
+        }
+
+        /// Sends a request and returns the payload of the next response.
+        async fn request(&mut self, kind: &str, payload: Value) -> Value {
+            self.send(kind, payload).await;
+            loop {
+                let next = tokio::time::timeout(Duration::from_secs(5), self.lines.next_line());
+                let line = next.await.unwrap().unwrap().expect("connection closed");
+                let mut packet: Value = serde_json::from_str(&line).unwrap();
+                if packet["type"] == "response" {
+                    return packet["payload"].take();
+                }
+            }
+        }
+    }
+
+    /// The fields of an event or span, rendered as strings.
+    #[derive(Default)]
+    struct Fields(HashMap<String, String>);
+
+    impl Visit for Fields {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    /// Records every event with its own fields plus those of the spans it
+    /// happened in.
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<HashMap<String, String>>>>);
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Capture {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let mut fields = Fields::default();
+            attrs.record(&mut fields);
+            ctx.span(id).unwrap().extensions_mut().insert(fields);
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+            if let Some(fields) = ctx.span(id).unwrap().extensions_mut().get_mut::<Fields>() {
+                values.record(fields);
+            }
+        }
+
+        fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            for span in ctx.event_scope(event).into_iter().flatten() {
+                if let Some(outer) = span.extensions().get::<Fields>() {
+                    for (name, value) in &outer.0 {
+                        fields.0.entry(name.clone()).or_insert_with(|| value.clone());
+                    }
+                }
+            }
+            self.0.lock().unwrap().push(fields.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn login_is_logged_with_its_connection_and_user() {
+        // Spawned tasks run on this thread, so they log to this subscriber.
+        let capture = Capture::default();
+        let subscriber = tracing_subscriber::registry().with(capture.clone());
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (_, addr) = spawn_server().await;
+        let credentials = json!({ "username": "alice", "password": "correct horse" });
+        let response = Conn::connect(addr).await.request("register", credentials.clone()).await;
+        let user_id = response["data"]["user_id"].as_str().unwrap().to_string();
+        let response = Conn::connect(addr).await.request("login", credentials).await;
+        assert_eq!(response["success"], true, "login failed: {}", response);
+
+        // Logged just after the response goes out.
+        let find_login = || {
+            let events = capture.0.lock().unwrap();
+            events.iter().find(|e| e.get("message").is_some_and(|m| m == "login")).cloned()
+        };
+        let login = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match find_login() {
+                    Some(event) => return event,
+                    None => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        });
+        let login = login.await.expect("no login event");
+        assert_eq!(login["username"], "alice");
+        assert_eq!(login["user_id"], user_id);
+        assert!(login["conn_id"].starts_with("conn-"), "conn_id is {}", login["conn_id"]);
+        assert!(login.contains_key("peer"), "no peer in {:?}", login);
+    }
+
+    #[test]
+    fn zero_workers_means_one_per_cpu() {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        assert_eq!(worker_count(0), cpus);
+        assert!(worker_count(0) >= 1);
+        assert_eq!(worker_count(3), 3);
+    }
+
+    #[test]
+    fn search_limits_default_and_clamp() {
+        assert_eq!(search_limit(0), DEFAULT_SEARCH_LIMIT);
+        assert_eq!(search_limit(7), 7);
+        assert_eq!(search_limit(MAX_SEARCH_LIMIT + 1), MAX_SEARCH_LIMIT);
+    }
+
+    #[tokio::test]
+    async fn a_chat_message_is_counted_as_broadcast_and_persisted() {
+        let (server, addr) = spawn_server().await;
+        let mut alice = Conn::connect(addr).await;
+        let credentials = json!({ "username": "alice", "password": "correct horse" });
+        alice.request("register", credentials).await;
+        let wrong = json!({ "username": "alice", "password": "wrong" });
+        assert_eq!(Conn::connect(addr).await.request("login", wrong).await["success"], false);
+        alice.send("chat", json!({ "content": "count me" })).await;
+
+        // Persisted by the worker pool after the broadcast.
+        let metrics = &server.metrics;
+        for _ in 0..100 {
+            if metrics.messages_persisted.load(Ordering::Relaxed) > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let text = metrics.render();
+        for line in [
+            "chat_messages_broadcast_total 1",
+            "chat_messages_persisted_total 1",
+            "chat_auth_failures_total 1",
+            "# TYPE chat_messages_persisted_total counter",
+            "# TYPE chat_connected_clients gauge",
+        ] {
+            assert!(text.lines().any(|l| l == line), "no {:?} in\n{}", line, text);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_full_persistence_queue_refuses_chat() {
+        let (server, addr) = spawn_server().await;
+        let mut alice = Conn::connect(addr).await;
+        let credentials = json!({ "username": "alice", "password": "correct horse" });
+        alice.request("register", credentials).await;
+
+        // Every slot taken, as if the workers had fallen far behind.
+        let backlog = server.pool.tx.reserve_many(WORKER_JOBS).await.unwrap();
+        let response = alice.request("chat", json!({ "content": "lost?" })).await;
+        assert_eq!(response["success"], false);
+        assert_eq!(response["message"], "error: server is busy; message not sent, please retry");
+        assert_eq!(server.metrics.persist_queue_full.load(Ordering::Relaxed), 1);
+        assert_eq!(server.metrics.messages_broadcast.load(Ordering::Relaxed), 0);
+
+        drop(backlog);
+        alice.send("chat", json!({ "content": "retried" })).await;
+        let mut history = Value::Null;
+        for _ in 0..100 {
+            history = alice.request("history", json!({ "limit": 10 })).await["data"].take();
+            if history.as_array().is_some_and(|h| !h.is_empty()) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(history[0]["content"], "retried");
+        assert_eq!(history.as_array().map(Vec::len), Some(1));
+    }
+
+    #[tokio::test]
+    async fn a_client_that_falls_behind_is_told_and_disconnected() {
+        let config = ServerConfig {
+            ephemeral: true,
+            send_buffer: 4,
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(Server::new(config).unwrap());
+        // A pipe too small for the replies to all the requests below.
+        let (conn, client) = tokio::io::duplex(1024);
+        server.spawn_conn(conn, None);
+        let (reader, mut writer) = tokio::io::split(client);
+        let requests = "{\"type\":\"time\",\"payload\":{}}\n".repeat(50);
+        tokio::spawn(async move { writer.write_all(requests.as_bytes()).await });
+
+        // Not reading until the queue has overflowed.
+        for _ in 0..100 {
+            if server.metrics.slow_clients_dropped.load(Ordering::Relaxed) > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(server.metrics.slow_clients_dropped.load(Ordering::Relaxed), 1);
+
+        let mut lines = BufReader::new(reader).lines();
+        let mut last = String::new();
+        let mut received = 0;
+        loop {
+            let next = tokio::time::timeout(Duration::from_secs(5), lines.next_line());
+            match next.await.expect("connection never closed") {
+                Ok(Some(line)) => {
+                    received += 1;
+                    last = line;
+                }
+                _ => break,
+            }
+        }
+        assert!(received < 50, "every reply arrived");
+        let notice: Value = serde_json::from_str(&last).unwrap();
+        assert_eq!(notice["type"], "system");
+        assert_eq!(notice["payload"]["message"], "you are falling behind; disconnecting");
+    }
+
+    #[test]
+    fn each_sender_is_told_once_per_away_window() {
+        let mut away = Away {
+            message: "at lunch".to_string(),
+            replied: HashMap::new(),
+        };
+        let start = Instant::now();
+        assert!(away.reply_due("bob", start));
+        assert!(!away.reply_due("bob", start + Duration::from_secs(60)));
+        assert!(away.reply_due("carol", start + Duration::from_secs(60)));
+        let later = start + AWAY_REPLY_WINDOW;
+        assert!(away.reply_due("bob", later));
+        // The reply just sent starts the next window.
+        assert!(!away.reply_due("bob", later + AWAY_REPLY_WINDOW / 2));
+    }
+
+    #[tokio::test]
+    async fn a_saturated_hub_fails_the_chat_instead_of_losing_it() {
+        let (server, addr) = spawn_server().await;
+        let mut alice = Conn::connect(addr).await;
+        let credentials = json!({ "username": "alice", "password": "correct horse" });
+        alice.request("register", credentials).await;
+
+        let backlog = server.hub_tx.reserve_many(HUB_BUF).await.unwrap();
+        let response = alice.request("chat", json!({ "content": "into the void?" })).await;
+        assert_eq!(response["success"], false);
+        assert_eq!(response["message"], "error: server is busy; message not sent, please retry");
+        assert_eq!(server.metrics.hub_send_failures.load(Ordering::Relaxed), 1);
+        drop(backlog);
+
+        // Neither broadcast nor stored, so a retry can't duplicate it. The
+        // pause gives the workers time to store it, were it queued.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let history = alice.request("history", json!({ "limit": 10 })).await;
+        assert_eq!(history["data"], json!([]));
+        assert_eq!(server.store.message_count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn a_slow_store_read_does_not_stall_other_connections() {
+        let (server, addr) = spawn_server().await;
+        let mut alice = Conn::connect(addr).await;
+        let credentials = json!({ "username": "alice", "password": "correct horse" });
+        assert_eq!(alice.request("register", credentials).await["success"], true);
+
+        // Stands in for a search reading through a large archive.
+        let (started_tx, started_rx) = oneshot::channel();
+        let store = server.store.clone();
+        let slow = tokio::spawn(async move {
+            store
+                .read(move |_| {
+                    started_tx.send(()).ok();
+                    std::thread::sleep(Duration::from_secs(1));
+                })
+                .await
+        });
+        started_rx.await.unwrap();
+
+        let start = Instant::now();
+        let response = alice.request("history", json!({ "limit": 10 })).await;
+        assert_eq!(response["success"], true, "history failed: {}", response);
+        assert!(start.elapsed() < Duration::from_millis(500), "took {:?}", start.elapsed());
+        slow.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn slow_mode_only_counts_messages_that_went_out() {
+        let (server, _) = spawn_server().await;
+        server.slow_mode_secs.store(60, Ordering::Relaxed);
+
+        // The first reservation wins; a second, as from another connection
+        // of the same user, has to wait.
+        let slot = server.reserve_chat("u1", false).unwrap();
+        let wait = server.reserve_chat("u1", false).expect_err("should have to wait");
+        assert!(wait > Duration::from_secs(59) && wait <= Duration::from_secs(60));
+        assert!(server.reserve_chat("u2", false).is_ok());
+
+        // A send that fails (the server was busy) leaves the user free to
+        // retry.
+        server.release_chat("u1", slot);
+        let slot = server.reserve_chat("u1", false).unwrap();
+        server.release_chat("u1", slot);
+        assert!(server.reserve_chat("u1", false).is_ok());
+
+        server.slow_mode_secs.store(0, Ordering::Relaxed);
+        assert!(server.reserve_chat("u1", false).is_ok());
+        let wait = server.reserve_chat("u1", true).expect_err("guests always wait");
+        assert!(wait <= Duration::from_secs(GUEST_SLOW_MODE_SECS));
+    }
+
+    #[tokio::test]
+    async fn going_read_only_saves_the_messages_already_broadcast_first() {
+        let (server, addr) = spawn_server_with(ServerConfig {
+            ephemeral: true,
+            admins: vec!["root".to_string()],
+            ..ServerConfig::default()
+        })
+        .await;
+        let mut root = Conn::connect(addr).await;
+        let credentials = json!({ "username": "root", "password": "correct horse" });
+        assert_eq!(root.request("register", credentials).await["success"], true);
+
+        // Hold up the store so the messages are still queued when the
+        // switch comes.
+        let store = server.store.clone();
+        let stall = tokio::spawn(async move {
+            store.call(|_| std::thread::sleep(Duration::from_millis(200))).await
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        for n in 0..20 {
+            let now = Utc::now();
+            let msg = StoredMessage {
+                id: format!("m{}", n),
+                user_id: "u1".to_string(),
+                username: "alice".to_string(),
+                content: format!("message {}", n),
+                timestamp: now,
+                kind: MessageKind::Chat,
+                entities: Vec::new(),
+                format: None,
+                room: None,
+                expires_at: None,
+                seq: 0,
+            };
+            assert!(server.post_chat(msg, None).await);
+        }
+
+        let response = root.request("admin", json!({ "action": "read_only", "enabled": true }));
+        assert_eq!(response.await["success"], true);
+        stall.await.unwrap().unwrap();
+        assert_eq!(server.store.get_history(0, false).await.len(), 20);
+    }
+
+    #[tokio::test]
+    async fn only_senders_within_an_interval_are_remembered() {
+        let (server, _) = spawn_server().await;
+        let long_ago = Instant::now() - Duration::from_secs(GUEST_SLOW_MODE_SECS + 1);
+        for n in 0..100 {
+            server.last_chat.lock().unwrap().insert(format!("guest-{}", n), long_ago);
+        }
+        server.reserve_chat("u1", false).unwrap();
+        let remembered: Vec<String> = server.last_chat.lock().unwrap().keys().cloned().collect();
+        assert_eq!(remembered, ["u1"]);
+    }
 }
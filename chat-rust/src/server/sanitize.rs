@@ -0,0 +1,178 @@
+//! Neutralizes terminal control sequences in user content (`--sanitize`)
+//! before it is broadcast or stored, so one user can't recolor, move the
+//! cursor in or retitle other users' terminals.
+//!
+//! Newlines and tabs are kept; every other control character (C0, DEL and
+//! C1) is handled by the mode. `strip` drops whole escape sequences: CSI
+//! (`ESC [` ... final byte), OSC/DCS/APC/PM/SOS strings up to their
+//! terminator, other `ESC` sequences and their 8-bit forms. `escape` keeps
+//! the text but writes each control character out as `\xNN`, so
+//! `ESC [31m` shows as `\x1b[31m`. All other Unicode passes through.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// What to do with control characters in user content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SanitizeMode {
+    /// Remove them, along with the rest of any escape sequence they start.
+    #[default]
+    Strip,
+    /// Replace each with a visible `\xNN`.
+    Escape,
+}
+
+impl FromStr for SanitizeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strip" => Ok(SanitizeMode::Strip),
+            "escape" => Ok(SanitizeMode::Escape),
+            _ => Err(format!("unknown sanitize mode {:?} (expected strip or escape)", s)),
+        }
+    }
+}
+
+impl fmt::Display for SanitizeMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SanitizeMode::Strip => "strip",
+            SanitizeMode::Escape => "escape",
+        })
+    }
+}
+
+const ESC: char = '\x1b';
+const BEL: char = '\x07';
+/// 8-bit String Terminator.
+const ST: char = '\u{9c}';
+
+/// `content` with its control characters neutralized per `mode`.
+pub fn sanitize(content: String, mode: SanitizeMode) -> String {
+    if !content.chars().any(is_unsafe) {
+        return content;
+    }
+    match mode {
+        SanitizeMode::Strip => strip(&content),
+        SanitizeMode::Escape => content
+            .chars()
+            .map(|c| {
+                if is_unsafe(c) {
+                    format!("\\x{:02x}", c as u32)
+                } else {
+                    c.to_string()
+                }
+            })
+            .collect(),
+    }
+}
+
+fn is_unsafe(c: char) -> bool {
+    c.is_control() && c != '\n' && c != '\t'
+}
+
+/// How the rest of an escape sequence runs, by what introduced it.
+enum Sequence {
+    /// Parameters and intermediates, then one final byte.
+    Csi,
+    /// Anything up to BEL, `ESC \` or ST.
+    String,
+    /// Intermediates, then one final byte.
+    Escape,
+    /// Nothing more.
+    None,
+}
+
+fn strip(content: &str) -> String {
+    let mut out = String::with_capacity(content.len());
+    let mut chars = content.chars().peekable();
+    while let Some(c) = chars.next() {
+        let sequence = match c {
+            ESC => match chars.peek() {
+                Some('[') => Sequence::Csi,
+                Some(']' | 'P' | 'X' | '^' | '_') => Sequence::String,
+                Some(_) => Sequence::Escape,
+                None => Sequence::None,
+            },
+            '\u{9b}' => Sequence::Csi,
+            '\u{90}' | '\u{98}' | '\u{9d}' | '\u{9e}' | '\u{9f}' => Sequence::String,
+            c if is_unsafe(c) => Sequence::None,
+            c => {
+                out.push(c);
+                continue;
+            }
+        };
+        match sequence {
+            Sequence::Csi => {
+                if c == ESC {
+                    chars.next();
+                }
+                while chars.next_if(|c| ('\x20'..='\x3f').contains(c)).is_some() {}
+                chars.next_if(|c| ('\x40'..='\x7e').contains(c));
+            }
+            Sequence::String => {
+                if c == ESC {
+                    chars.next();
+                }
+                while let Some(c) = chars.next() {
+                    if c == BEL || c == ST || (c == ESC && chars.next_if_eq(&'\\').is_some()) {
+                        break;
+                    }
+                }
+            }
+            Sequence::Escape => {
+                while chars.next_if(|c| ('\x20'..='\x2f').contains(c)).is_some() {}
+                chars.next_if(|c| ('\x30'..='\x7e').contains(c));
+            }
+            Sequence::None => {}
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stripped(content: &str) -> String {
+        sanitize(content.to_string(), SanitizeMode::Strip)
+    }
+
+    fn escaped(content: &str) -> String {
+        sanitize(content.to_string(), SanitizeMode::Escape)
+    }
+
+    #[test]
+    fn whole_escape_sequences_are_stripped() {
+        assert_eq!(stripped("\x1b[1;31mred\x1b[0m"), "red");
+        assert_eq!(stripped("\x1b]0;pwned\x07title"), "title");
+        assert_eq!(stripped("\x1b]8;;http://x\x1b\\link\x1b]8;;\x1b\\"), "link");
+        assert_eq!(stripped("\x1bPq#0\x1b\\dcs"), "dcs");
+        assert_eq!(stripped("\x1b(Bcharset \x1b7saved"), "charset saved");
+        assert_eq!(stripped("\u{9b}2Jeight-bit"), "eight-bit");
+        assert_eq!(stripped("bell\x07 back\x08space\x7f del\u{85}"), "bell backspace del");
+        assert_eq!(stripped("dangling \x1b"), "dangling ");
+    }
+
+    #[test]
+    fn escaping_keeps_the_text_visible() {
+        assert_eq!(escaped("\x1b[31mred"), "\\x1b[31mred");
+        assert_eq!(escaped("a\x00b\u{9b}"), "a\\x00b\\x9b");
+    }
+
+    #[test]
+    fn newlines_tabs_and_unicode_pass_through() {
+        let text = "line one\n\tindented — naïve 😄 日本語 e\u{301}";
+        assert_eq!(stripped(text), text);
+        assert_eq!(escaped(text), text);
+    }
+
+    #[test]
+    fn modes_parse_and_print() {
+        for mode in [SanitizeMode::Strip, SanitizeMode::Escape] {
+            assert_eq!(mode.to_string().parse(), Ok(mode));
+        }
+        assert!("remove".parse::<SanitizeMode>().is_err());
+    }
+}
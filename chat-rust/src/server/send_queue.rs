@@ -0,0 +1,388 @@
+//! The per-connection queue of encoded frames waiting for the write pump.
+//!
+//! The queue holds up to `--send-buffer` frames. What happens to a frame
+//! that finds it full depends on the connection's [`OverflowPolicy`]:
+//!
+//! - `disconnect` refuses the frame and notifies [`SendQueue::lagged`], and
+//!   the connection is closed with a notice. Nothing is lost without the
+//!   client knowing.
+//! - `drop_newest` refuses the frame, so the client misses what came last.
+//! - `drop_oldest` queues the frame and throws away the oldest one still
+//!   waiting, so the client misses what is most out of date. The queue
+//!   never holds more than its capacity, however far the pump falls behind.
+//!
+//! Empty frames are markers for the write pump (see `START_COMPRESSION`).
+//! They are never counted, refused or dropped. [`SendQueue::send`] waits for
+//! room instead of applying the policy. It is for frames that must arrive.
+
+use std::fmt;
+use std::str::FromStr;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+use super::metrics::Metrics;
+
+/// What to do with a frame for a connection whose send queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Refuse the new frame.
+    DropNewest,
+    /// Skip the oldest queued frame to make room.
+    DropOldest,
+    /// Close the connection, telling the client why.
+    #[default]
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop_newest" => Ok(OverflowPolicy::DropNewest),
+            "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(format!(
+                "unknown overflow policy {:?} (expected drop_newest, drop_oldest or disconnect)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OverflowPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OverflowPolicy::DropNewest => "drop_newest",
+            OverflowPolicy::DropOldest => "drop_oldest",
+            OverflowPolicy::Disconnect => "disconnect",
+        })
+    }
+}
+
+/// How [`SendQueue::push`] went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pushed {
+    /// The frame is queued; under `drop_oldest` an older one may have made
+    /// way for it.
+    Queued,
+    /// The queue was full and the frame was refused (`drop_newest`).
+    Dropped,
+    /// The queue was full and the connection is to be closed (`disconnect`).
+    Overflow,
+    /// The connection is gone.
+    Closed,
+}
+
+struct Shared {
+    capacity: usize,
+    policy: OverflowPolicy,
+    state: Mutex<State>,
+    /// Notified when a frame is queued or the queue closes.
+    ready: Notify,
+    /// Notified by the pump each time it takes a counted frame.
+    room: Notify,
+    lagged: Notify,
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Default)]
+struct State {
+    frames: VecDeque<Vec<u8>>,
+    /// Frames in `frames` that aren't markers.
+    counted: usize,
+    /// Live [`SendQueue`] clones.
+    senders: usize,
+    /// Set by the pump; nothing more is accepted.
+    closed: bool,
+}
+
+impl State {
+    fn accepting(&self) -> bool {
+        !self.closed && self.senders > 0
+    }
+
+    fn push(&mut self, frame: Vec<u8>) {
+        if !frame.is_empty() {
+            self.counted += 1;
+        }
+        self.frames.push_back(frame);
+    }
+
+    /// Removes the oldest frame that isn't a marker.
+    fn drop_oldest(&mut self) {
+        if let Some(i) = self.frames.iter().position(|f| !f.is_empty()) {
+            self.frames.remove(i);
+            self.counted -= 1;
+        }
+    }
+}
+
+/// The sending side; cheap to clone. The queue closes once every clone is
+/// dropped.
+pub struct SendQueue {
+    shared: Arc<Shared>,
+}
+
+/// The write pump's side.
+pub struct SendQueueRx {
+    shared: Arc<Shared>,
+}
+
+impl SendQueue {
+    pub fn new(
+        capacity: usize,
+        policy: OverflowPolicy,
+        metrics: Arc<Metrics>,
+    ) -> (SendQueue, SendQueueRx) {
+        let shared = Arc::new(Shared {
+            capacity: capacity.max(1),
+            policy,
+            state: Mutex::new(State {
+                senders: 1,
+                ..State::default()
+            }),
+            ready: Notify::new(),
+            room: Notify::new(),
+            lagged: Notify::new(),
+            metrics,
+        });
+        let queue = SendQueue { shared: shared.clone() };
+        (queue, SendQueueRx { shared })
+    }
+
+    /// Notified when a frame overflowed a `disconnect` queue.
+    pub fn lagged(&self) -> &Notify {
+        &self.shared.lagged
+    }
+
+    /// Queues `frame` if there is room, else applies the overflow policy.
+    pub fn push(&self, frame: Vec<u8>) -> Pushed {
+        let shared = &self.shared;
+        let mut state = shared.state.lock().unwrap();
+        if !state.accepting() {
+            return Pushed::Closed;
+        }
+        if !frame.is_empty() && state.counted >= shared.capacity {
+            match shared.policy {
+                OverflowPolicy::DropNewest => {
+                    Metrics::inc(&shared.metrics.send_frames_dropped);
+                    return Pushed::Dropped;
+                }
+                OverflowPolicy::DropOldest => {
+                    state.drop_oldest();
+                    Metrics::inc(&shared.metrics.send_frames_dropped);
+                }
+                OverflowPolicy::Disconnect => {
+                    shared.lagged.notify_one();
+                    return Pushed::Overflow;
+                }
+            }
+        }
+        state.push(frame);
+        shared.ready.notify_one();
+        Pushed::Queued
+    }
+
+    /// Queues `frame`, waiting for room if the queue is full. False if the
+    /// connection is gone.
+    pub async fn send(&self, frame: Vec<u8>) -> bool {
+        let shared = &self.shared;
+        loop {
+            // Registered before the check so a frame taken in between
+            // still wakes us.
+            let room = shared.room.notified();
+            {
+                let mut state = shared.state.lock().unwrap();
+                if !state.accepting() {
+                    return false;
+                }
+                if frame.is_empty() || state.counted < shared.capacity {
+                    state.push(frame);
+                    shared.ready.notify_one();
+                    return true;
+                }
+            }
+            room.await;
+        }
+    }
+}
+
+impl Clone for SendQueue {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        SendQueue { shared: self.shared.clone() }
+    }
+}
+
+impl Drop for SendQueue {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            // The pump drains what is left, then sees the end.
+            self.shared.ready.notify_one();
+        }
+    }
+}
+
+impl SendQueueRx {
+    /// The next frame to write. `None` once the queue is closed and empty.
+    /// Cancel-safe: a frame is only taken when it is returned.
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let ready = self.shared.ready.notified();
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(frame) = self.take(&mut state) {
+                    return Some(frame);
+                }
+                if !state.accepting() {
+                    return None;
+                }
+            }
+            ready.await;
+        }
+    }
+
+    fn take(&self, state: &mut State) -> Option<Vec<u8>> {
+        let frame = state.frames.pop_front()?;
+        if !frame.is_empty() {
+            state.counted -= 1;
+            self.shared.room.notify_waiters();
+        }
+        Some(frame)
+    }
+
+    /// Stops accepting frames. What is already queued can still be taken
+    /// with [`SendQueueRx::try_recv`].
+    pub fn close(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.room.notify_waiters();
+    }
+
+    /// The next queued frame, as sent, if there is one.
+    pub fn try_recv(&mut self) -> Option<Vec<u8>> {
+        let mut state = self.shared.state.lock().unwrap();
+        self.take(&mut state)
+    }
+}
+
+impl Drop for SendQueueRx {
+    fn drop(&mut self) {
+        // Senders waiting for room find the queue closed.
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+
+    fn queue(capacity: usize, policy: OverflowPolicy) -> (SendQueue, SendQueueRx, Arc<Metrics>) {
+        let metrics = Arc::new(Metrics::default());
+        let (tx, rx) = SendQueue::new(capacity, policy, metrics.clone());
+        (tx, rx, metrics)
+    }
+
+    fn frame(n: u8) -> Vec<u8> {
+        vec![n]
+    }
+
+    fn drain(rx: &mut SendQueueRx) -> Vec<Vec<u8>> {
+        std::iter::from_fn(|| rx.try_recv()).collect()
+    }
+
+    #[test]
+    fn disconnect_refuses_and_signals_lag_when_full() {
+        let (tx, mut rx, metrics) = queue(2, OverflowPolicy::Disconnect);
+        assert_eq!(tx.push(frame(1)), Pushed::Queued);
+        assert_eq!(tx.push(frame(2)), Pushed::Queued);
+        assert_eq!(tx.push(frame(3)), Pushed::Overflow);
+        // The lag notice is stored as a permit, so a later wait sees it.
+        let lagged = tx.lagged().notified();
+        tokio::pin!(lagged);
+        assert!(futures_ready(lagged.as_mut()));
+        assert_eq!(drain(&mut rx), [frame(1), frame(2)]);
+        assert_eq!(metrics.send_frames_dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn drop_newest_refuses_the_new_frame() {
+        let (tx, mut rx, metrics) = queue(2, OverflowPolicy::DropNewest);
+        for n in 1..=4 {
+            let expected = if n <= 2 { Pushed::Queued } else { Pushed::Dropped };
+            assert_eq!(tx.push(frame(n)), expected);
+        }
+        assert_eq!(drain(&mut rx), [frame(1), frame(2)]);
+        assert_eq!(metrics.send_frames_dropped.load(Ordering::Relaxed), 2);
+        // Taking a frame makes room again.
+        assert_eq!(tx.push(frame(5)), Pushed::Queued);
+    }
+
+    #[test]
+    fn drop_oldest_evicts_at_push_and_stays_bounded() {
+        let (tx, mut rx, metrics) = queue(3, OverflowPolicy::DropOldest);
+        for n in 1..=100 {
+            assert_eq!(tx.push(frame(n)), Pushed::Queued);
+            assert!(tx.shared.state.lock().unwrap().frames.len() <= 3);
+        }
+        assert_eq!(drain(&mut rx), [frame(98), frame(99), frame(100)]);
+        assert_eq!(metrics.send_frames_dropped.load(Ordering::Relaxed), 97);
+    }
+
+    #[test]
+    fn drop_oldest_keeps_markers() {
+        let (tx, mut rx, _) = queue(1, OverflowPolicy::DropOldest);
+        tx.push(frame(1));
+        tx.push(Vec::new());
+        tx.push(frame(2));
+        assert_eq!(drain(&mut rx), [Vec::new(), frame(2)]);
+    }
+
+    #[test]
+    fn closed_queue_refuses_frames() {
+        let (tx, mut rx, _) = queue(2, OverflowPolicy::DropOldest);
+        tx.push(frame(1));
+        rx.close();
+        assert_eq!(tx.push(frame(2)), Pushed::Closed);
+        assert_eq!(drain(&mut rx), [frame(1)]);
+    }
+
+    #[tokio::test]
+    async fn recv_ends_after_the_last_sender_is_dropped() {
+        let (tx, mut rx, _) = queue(2, OverflowPolicy::Disconnect);
+        let other = tx.clone();
+        tx.push(frame(1));
+        drop(tx);
+        other.push(frame(2));
+        drop(other);
+        assert_eq!(rx.recv().await, Some(frame(1)));
+        assert_eq!(rx.recv().await, Some(frame(2)));
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn send_waits_for_room() {
+        let (tx, mut rx, _) = queue(1, OverflowPolicy::DropNewest);
+        tx.push(frame(1));
+        let sender = tx.clone();
+        let waiting = tokio::spawn(async move { sender.send(frame(2)).await });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        assert_eq!(rx.recv().await, Some(frame(1)));
+        assert!(waiting.await.unwrap());
+        assert_eq!(rx.recv().await, Some(frame(2)));
+    }
+
+    /// Whether `fut` is ready on its first poll.
+    fn futures_ready<F: std::future::Future>(fut: std::pin::Pin<&mut F>) -> bool {
+        let waker = std::task::Waker::noop();
+        let mut cx = std::task::Context::from_waker(waker);
+        fut.poll(&mut cx).is_ready()
+    }
+}
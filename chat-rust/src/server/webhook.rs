@@ -0,0 +1,314 @@
+//! Outbound webhook: POSTs each chat message (or each one matching a
+//! search query) as JSON to `--webhook-url`.
+//!
+//! Messages are queued for a background task, so a slow or unreachable
+//! endpoint never holds up broadcasting; when the queue is full the message
+//! is skipped. A failed POST (connection error, timeout or non-2xx status)
+//! is retried with backoff and then dropped. Only plain `http://` URLs are
+//! supported.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use super::metrics::Metrics;
+use crate::protocol::StoredMessage;
+use crate::query::Query;
+
+const WEBHOOK_QUEUE: usize = 1024;
+/// Attempts per message, including the first.
+const WEBHOOK_ATTEMPTS: u32 = 4;
+/// Delay before the first retry; doubled for each one after.
+const WEBHOOK_BACKOFF: Duration = Duration::from_millis(500);
+/// Limit on one whole request, from connecting to reading the status line.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The JSON body of each POST.
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookPayload {
+    pub id: String,
+    pub user_id: String,
+    pub username: String,
+    pub content: String,
+    pub timestamp: DateTime<Utc>,
+    /// `None` for the lobby.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+}
+
+impl From<&StoredMessage> for WebhookPayload {
+    fn from(m: &StoredMessage) -> Self {
+        Self {
+            id: m.id.clone(),
+            user_id: m.user_id.clone(),
+            username: m.username.clone(),
+            content: m.content.clone(),
+            timestamp: m.timestamp,
+            room: m.room.clone(),
+        }
+    }
+}
+
+pub struct Webhook {
+    tx: mpsc::Sender<WebhookPayload>,
+    /// Only messages matching this are sent; `None` sends every one.
+    query: Option<Query>,
+    metrics: Arc<Metrics>,
+}
+
+impl Webhook {
+    /// Checks `url` and starts the delivery task.
+    pub fn start(url: &str, query: Option<Query>, metrics: Arc<Metrics>) -> Result<Self> {
+        let endpoint = Endpoint::parse(url)?;
+        let (tx, rx) = mpsc::channel(WEBHOOK_QUEUE);
+        tokio::spawn(deliver(endpoint, rx, metrics.clone()));
+        Ok(Self { tx, query, metrics })
+    }
+
+    /// Queues `msg` for delivery if it passes the query. Never waits.
+    pub fn notify(&self, msg: &StoredMessage) {
+        if self.query.as_ref().is_some_and(|q| !q.matches(&msg.content)) {
+            return;
+        }
+        if self.tx.try_send(WebhookPayload::from(msg)).is_err() {
+            Metrics::inc(&self.metrics.webhook_dropped);
+            warn!(message_id = %msg.id, "webhook: queue full, message skipped");
+        }
+    }
+}
+
+async fn deliver(
+    endpoint: Endpoint,
+    mut rx: mpsc::Receiver<WebhookPayload>,
+    metrics: Arc<Metrics>,
+) {
+    while let Some(payload) = rx.recv().await {
+        let body = match serde_json::to_vec(&payload) {
+            Ok(body) => body,
+            Err(_) => continue,
+        };
+        let mut backoff = WEBHOOK_BACKOFF;
+        for attempt in 1..=WEBHOOK_ATTEMPTS {
+            let result = match tokio::time::timeout(WEBHOOK_TIMEOUT, endpoint.post(&body)).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow::anyhow!("timed out")),
+            };
+            match result {
+                Ok(()) => {
+                    debug!(message_id = %payload.id, "webhook: delivered");
+                    break;
+                }
+                Err(e) if attempt < WEBHOOK_ATTEMPTS => {
+                    debug!(message_id = %payload.id, attempt, error = %e, "webhook: retrying");
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => {
+                    Metrics::inc(&metrics.webhook_dropped);
+                    warn!(
+                        message_id = %payload.id,
+                        attempts = attempt,
+                        error = %e,
+                        "webhook: giving up on message"
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Where to POST, from an `http://host[:port][/path]` URL.
+#[derive(Debug, Clone)]
+struct Endpoint {
+    /// As written in the URL, for the `Host` header.
+    authority: String,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Endpoint {
+    fn parse(url: &str) -> Result<Self> {
+        let rest = match url.strip_prefix("http://") {
+            Some(rest) => rest,
+            None if url.starts_with("https://") => bail!("https webhook URLs are not supported"),
+            None => bail!("webhook URL must start with http://"),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        // `[::1]:8080` style IPv6 literals keep their colons inside brackets.
+        let port_sep = match authority.rfind(':') {
+            Some(i) if !authority[i..].contains(']') => Some(i),
+            _ => None,
+        };
+        let (host, port) = match port_sep {
+            Some(i) => {
+                let port = authority[i + 1..]
+                    .parse()
+                    .with_context(|| format!("bad port in webhook URL {:?}", url))?;
+                (&authority[..i], port)
+            }
+            None => (authority, 80),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            bail!("webhook URL {:?} has no host", url);
+        }
+        Ok(Self {
+            authority: authority.to_string(),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    /// Sends one POST and succeeds on a 2xx status.
+    async fn post(&self, body: &[u8]) -> Result<()> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        let head = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path,
+            self.authority,
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body).await?;
+        stream.flush().await?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line).await?;
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse::<u16>().ok())
+            .with_context(|| format!("bad response {:?}", status_line.trim_end()))?;
+        if !(200..300).contains(&status) {
+            bail!("HTTP {}", status);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use serde_json::Value;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::protocol::MessageKind;
+
+    /// An HTTP endpoint answering each POST with the next of `statuses`
+    /// (200 once they run out) and handing over every body it is sent.
+    async fn mock_endpoint(statuses: Vec<u16>) -> (String, mpsc::UnboundedReceiver<Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut statuses = statuses.into_iter();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut reader = BufReader::new(stream);
+                let mut len = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).await.unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("Content-Length: ") {
+                        len = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).await.unwrap();
+                tx.send(serde_json::from_slice(&body).unwrap()).ok();
+                let status = statuses.next().unwrap_or(200);
+                let response = format!("HTTP/1.1 {} X\r\nContent-Length: 0\r\n\r\n", status);
+                reader.get_mut().write_all(response.as_bytes()).await.ok();
+            }
+        });
+        (url, rx)
+    }
+
+    fn message(id: &str, content: &str) -> StoredMessage {
+        StoredMessage {
+            id: id.to_string(),
+            user_id: "u1".to_string(),
+            username: "alice".to_string(),
+            content: content.to_string(),
+            timestamp: Utc::now(),
+            kind: MessageKind::Chat,
+            entities: Vec::new(),
+            format: None,
+            room: None,
+            expires_at: None,
+            seq: 0,
+        }
+    }
+
+    async fn next_body(rx: &mut mpsc::UnboundedReceiver<Value>) -> Value {
+        let next = tokio::time::timeout(WEBHOOK_TIMEOUT, rx.recv());
+        next.await.expect("no POST arrived").unwrap()
+    }
+
+    #[test]
+    fn urls_parse_into_host_port_and_path() {
+        let e = Endpoint::parse("http://example.com").unwrap();
+        assert_eq!((e.host.as_str(), e.port, e.path.as_str()), ("example.com", 80, "/"));
+        let e = Endpoint::parse("http://[::1]:8080/hooks/chat?x=1").unwrap();
+        assert_eq!((e.host.as_str(), e.port, e.path.as_str()), ("::1", 8080, "/hooks/chat?x=1"));
+        assert_eq!(e.authority, "[::1]:8080");
+        for bad in ["https://example.com", "example.com", "http://host:port/", "http://:80/"] {
+            assert!(Endpoint::parse(bad).is_err(), "{} parsed", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn matching_messages_are_posted_and_retried() {
+        let (url, mut bodies) = mock_endpoint(vec![503]).await;
+        let metrics = Arc::new(Metrics::default());
+        let query = Some(Query::parse("deploy"));
+        let webhook = Webhook::start(&url, query, metrics.clone()).unwrap();
+        webhook.notify(&message("m1", "lunch?"));
+        webhook.notify(&message("m2", "deploy is done"));
+
+        // Refused once, then sent again.
+        for _ in 0..2 {
+            let body = next_body(&mut bodies).await;
+            assert_eq!(body["id"], "m2");
+            assert_eq!(body["username"], "alice");
+            assert_eq!(body["content"], "deploy is done");
+            assert!(body["timestamp"].is_string());
+            assert!(body.get("room").is_none());
+        }
+        webhook.notify(&message("m3", "deploy again"));
+        assert_eq!(next_body(&mut bodies).await["id"], "m3");
+        assert_eq!(metrics.webhook_dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn a_message_is_dropped_after_the_last_attempt() {
+        let (url, mut bodies) = mock_endpoint(vec![500; WEBHOOK_ATTEMPTS as usize]).await;
+        let metrics = Arc::new(Metrics::default());
+        let webhook = Webhook::start(&url, None, metrics.clone()).unwrap();
+        webhook.notify(&message("m1", "lost"));
+        webhook.notify(&message("m2", "kept"));
+        for _ in 0..WEBHOOK_ATTEMPTS {
+            assert_eq!(next_body(&mut bodies).await["id"], "m1");
+        }
+        assert_eq!(next_body(&mut bodies).await["id"], "m2");
+        assert_eq!(metrics.webhook_dropped.load(Ordering::Relaxed), 1);
+    }
+}
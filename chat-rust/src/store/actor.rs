@@ -0,0 +1,453 @@
+//! An async front for [`Store`], for use from the server.
+//!
+//! The store sits behind a lock shared by a dedicated OS thread and tokio's
+//! blocking pool, so file writes, scans and lock waits never happen on a
+//! tokio worker. Changes are queued to the thread, which runs them one at a
+//! time in the order they were queued. Reads run on the blocking pool, side
+//! by side with each other, so a slow one (a search reading through the
+//! archive, say) delays the changes queued behind it but no other read.
+//! That holds only for a while: once a change has waited
+//! [`WRITER_PATIENCE`], reads that start after that wait for it too, so a
+//! steady stream of overlapping reads can't hold changes off forever.
+//! [`StoreHandle`] is the cheap, cloneable way in: each method mirrors the
+//! [`Store`] method of the same name and resolves once it has run.
+//!
+//! The thread exits when the last handle is gone, and the store (with its
+//! data-directory lock) is dropped once no read still holds it. If a change
+//! panics, the thread stops and every later call fails; methods that can't
+//! report an error log it and return an empty value.
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock, RwLockWriteGuard, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error};
+
+use super::{SearchFilter, Store, User};
+use crate::protocol::{
+    DirectMessage, DirectoryResult, Prefs, RecentUser, RoomTopic, SearchResult, StoredMessage,
+    UpdatePrefsPayload, UpdateProfilePayload, UserInfo,
+};
+
+/// Changes waiting for the store thread before senders have to wait too.
+const STORE_QUEUE: usize = 1024;
+/// How often a change waiting for reads to finish checks again.
+const WRITE_RETRY: Duration = Duration::from_millis(1);
+/// How long a change waits alongside new reads before they start waiting
+/// for it instead.
+const WRITER_PATIENCE: Duration = Duration::from_millis(100);
+
+type Job = Box<dyn FnOnce(&mut Store) + Send>;
+
+#[derive(Clone)]
+pub struct StoreHandle {
+    tx: mpsc::Sender<Job>,
+    store: Arc<RwLock<Store>>,
+    gate: Arc<ReadGate>,
+    messages_version: Arc<AtomicU64>,
+}
+
+impl StoreHandle {
+    /// Moves `store` behind a lock, starts the thread that changes it and
+    /// returns a handle to it.
+    pub fn spawn(store: Store) -> Result<Self> {
+        let (tx, mut rx) = mpsc::channel::<Job>(STORE_QUEUE);
+        let messages_version = store.files.messages_version.clone();
+        let store = Arc::new(RwLock::new(store));
+        let gate = Arc::new(ReadGate::default());
+        let shared = store.clone();
+        let writer_gate = gate.clone();
+        thread::Builder::new()
+            .name("store".to_string())
+            .spawn(move || {
+                while let Some(job) = rx.blocking_recv() {
+                    match write_lock(&shared, &writer_gate) {
+                        Some(mut store) => job(&mut store),
+                        None => break,
+                    }
+                }
+                debug!("store: thread stopped");
+            })
+            .context("failed to start the store thread")?;
+        Ok(Self {
+            tx,
+            store,
+            gate,
+            messages_version,
+        })
+    }
+
+    /// Queues `f` to change the store and returns its result once it has
+    /// run. Fails if the store thread has stopped.
+    pub async fn call<R, F>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut Store) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let job: Job = Box::new(move |store| {
+            reply_tx.send(f(store)).ok();
+        });
+        self.tx.send(job).await.map_err(|_| anyhow!("the store thread has stopped"))?;
+        reply_rx.await.map_err(|_| anyhow!("the store thread has stopped"))
+    }
+
+    /// Runs `f` against the store on the blocking pool, alongside any other
+    /// reads. Fails if a change panicked and left the store inconsistent.
+    pub async fn read<R, F>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&Store) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let store = self.store.clone();
+        let gate = self.gate.clone();
+        tokio::task::spawn_blocking(move || {
+            gate.pass();
+            let store = store.read().map_err(|_| anyhow!("a store change panicked"))?;
+            Ok(f(&store))
+        })
+        .await?
+    }
+
+    /// [`StoreHandle::read`] for methods that can't report failure: it is
+    /// logged and the default value returned instead.
+    async fn read_or_default<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(&Store) -> R + Send + 'static,
+        R: Default + Send + 'static,
+    {
+        self.read(f).await.unwrap_or_else(|e| {
+            error!(error = %e, "store: read failed");
+            R::default()
+        })
+    }
+
+    /// A number that goes up whenever stored messages are added or removed.
+    /// Unlike the other methods it is read here, without waiting for the
+    /// store thread.
+    pub fn messages_version(&self) -> u64 {
+        self.messages_version.load(Ordering::Acquire)
+    }
+
+    pub async fn flush(&self) -> Result<()> {
+        self.call(|s| s.flush()).await?
+    }
+
+    pub async fn set_read_only(&self, read_only: bool) -> Result<()> {
+        self.call(move |s| s.set_read_only(read_only)).await?
+    }
+
+    pub async fn register_user(&self, username: &str, password: &str) -> Result<User> {
+        let (username, password) = (username.to_string(), password.to_string());
+        self.call(move |s| s.register_user(&username, &password)).await?
+    }
+
+    pub async fn bot_user(&self, name: &str) -> Result<User> {
+        let name = name.to_string();
+        self.call(move |s| s.bot_user(&name)).await?
+    }
+
+    pub async fn external_user(&self, name: &str, register: bool) -> Result<User> {
+        let name = name.to_string();
+        self.call(move |s| s.external_user(&name, register)).await?
+    }
+
+    pub async fn rename_user(&self, user_id: &str, new_username: &str) -> Result<User> {
+        let (user_id, new_username) = (user_id.to_string(), new_username.to_string());
+        self.call(move |s| s.rename_user(&user_id, &new_username)).await?
+    }
+
+    pub async fn set_block(
+        &self,
+        user_id: &str,
+        target: &str,
+        blocked: bool,
+    ) -> Result<Vec<UserInfo>> {
+        let (user_id, target) = (user_id.to_string(), target.to_string());
+        self.call(move |s| s.set_block(&user_id, &target, blocked)).await?
+    }
+
+    pub async fn get_user(&self, username: &str) -> Option<User> {
+        let username = username.to_string();
+        self.read_or_default(move |s| s.get_user(&username)).await
+    }
+
+    pub async fn user_info(&self, user_id: &str) -> Option<UserInfo> {
+        let user_id = user_id.to_string();
+        self.read_or_default(move |s| s.user_info(&user_id)).await
+    }
+
+    pub async fn update_profile(
+        &self,
+        user_id: &str,
+        update: &UpdateProfilePayload,
+    ) -> Result<User> {
+        let (user_id, update) = (user_id.to_string(), update.clone());
+        self.call(move |s| s.update_profile(&user_id, &update)).await?
+    }
+
+    pub async fn update_prefs(&self, user_id: &str, update: &UpdatePrefsPayload) -> Result<Prefs> {
+        let (user_id, update) = (user_id.to_string(), update.clone());
+        self.call(move |s| s.update_prefs(&user_id, &update)).await?
+    }
+
+    pub async fn touch_last_seen(&self, user_id: &str) -> Result<()> {
+        let user_id = user_id.to_string();
+        self.call(move |s| s.touch_last_seen(&user_id)).await?
+    }
+
+    pub async fn get_blocks(&self, user_id: &str) -> HashSet<String> {
+        let user_id = user_id.to_string();
+        self.read_or_default(move |s| s.get_blocks(&user_id)).await
+    }
+
+    pub async fn enqueue_offline(&self, user_id: &str, message: DirectMessage) -> Result<()> {
+        let user_id = user_id.to_string();
+        self.call(move |s| s.enqueue_offline(&user_id, message)).await?
+    }
+
+    pub async fn offline_queue(&self, user_id: &str) -> Vec<DirectMessage> {
+        let user_id = user_id.to_string();
+        self.read_or_default(move |s| s.offline_queue(&user_id)).await
+    }
+
+    pub async fn dequeue_offline(&self, user_id: &str, count: usize) -> Result<()> {
+        let user_id = user_id.to_string();
+        self.call(move |s| s.dequeue_offline(&user_id, count)).await?
+    }
+
+    pub async fn get_message(&self, id: &str) -> Option<StoredMessage> {
+        let id = id.to_string();
+        self.read_or_default(move |s| s.get_message(&id)).await
+    }
+
+    pub async fn set_pinned(&self, id: &str, pinned: bool) -> Result<bool> {
+        let id = id.to_string();
+        self.call(move |s| s.set_pinned(&id, pinned)).await?
+    }
+
+    pub async fn pinned_messages(&self) -> Vec<StoredMessage> {
+        self.read_or_default(|s| s.pinned_messages()).await
+    }
+
+    pub async fn set_topic(&self, topic: RoomTopic) -> Result<()> {
+        self.call(move |s| s.set_topic(topic)).await?
+    }
+
+    pub async fn topic(&self, room: &str) -> Option<RoomTopic> {
+        let room = room.to_string();
+        self.read_or_default(move |s| s.topic(&room)).await
+    }
+
+    pub async fn authenticate(&self, username: &str, password: &str) -> Result<User> {
+        let (username, password) = (username.to_string(), password.to_string());
+        self.read(move |s| s.authenticate(&username, &password)).await?
+    }
+
+    pub async fn save_message(&self, msg: StoredMessage) -> Result<()> {
+        self.call(move |s| s.save_message(msg)).await?
+    }
+
+    pub async fn remove_expired(&self, now: DateTime<Utc>) -> Result<Vec<String>> {
+        self.call(move |s| s.remove_expired(now)).await?
+    }
+
+    pub async fn recent_users(&self, since: DateTime<Utc>) -> Vec<RecentUser> {
+        self.read_or_default(move |s| s.recent_users(since)).await
+    }
+
+    pub async fn list_users(&self, limit: usize, offset: usize) -> DirectoryResult {
+        self.read_or_default(move |s| s.list_users(limit, offset)).await
+    }
+
+    pub async fn purge_messages(&self, before: Option<DateTime<Utc>>) -> Result<usize> {
+        self.call(move |s| s.purge_messages(before)).await?
+    }
+
+    pub async fn prune_older_than(&self, cutoff: DateTime<Utc>) -> Result<usize> {
+        self.call(move |s| s.prune_older_than(cutoff)).await?
+    }
+
+    pub async fn trim_to(&self, max: usize) -> Result<usize> {
+        self.call(move |s| s.trim_to(max)).await?
+    }
+
+    pub async fn message_count(&self) -> usize {
+        self.read_or_default(|s| s.message_count()).await
+    }
+
+    pub async fn message_count_for(&self, user_id: &str) -> usize {
+        let user_id = user_id.to_string();
+        self.read_or_default(move |s| s.message_count_for(&user_id)).await
+    }
+
+    pub async fn user_count(&self) -> usize {
+        self.read_or_default(|s| s.user_count()).await
+    }
+
+    pub async fn get_history(&self, n: usize, include_system: bool) -> Vec<StoredMessage> {
+        self.read_or_default(move |s| s.get_history(n, include_system)).await
+    }
+
+    pub async fn get_messages_after(
+        &self,
+        id: &str,
+        include_system: bool,
+    ) -> Option<Vec<StoredMessage>> {
+        let id = id.to_string();
+        self.read_or_default(move |s| s.get_messages_after(&id, include_system)).await
+    }
+
+    pub async fn get_room_history(&self, room: &str, n: usize) -> Vec<StoredMessage> {
+        let room = room.to_string();
+        self.read_or_default(move |s| s.get_room_history(&room, n)).await
+    }
+
+    pub async fn search(&self, filter: &SearchFilter, limit: usize, offset: usize) -> SearchResult {
+        let filter = filter.clone();
+        self.read_or_default(move |s| s.search(&filter, limit, offset)).await
+    }
+}
+
+/// Where reads wait while a change that has run out of patience is waiting
+/// for the reads already running to finish.
+#[derive(Default)]
+struct ReadGate {
+    closed: Mutex<bool>,
+    opened: Condvar,
+}
+
+impl ReadGate {
+    /// Waits until the gate is open.
+    fn pass(&self) {
+        let closed = self.closed.lock().unwrap_or_else(|e| e.into_inner());
+        drop(self.opened.wait_while(closed, |closed| *closed));
+    }
+
+    fn set(&self, closed: bool) {
+        *self.closed.lock().unwrap_or_else(|e| e.into_inner()) = closed;
+        if !closed {
+            self.opened.notify_all();
+        }
+    }
+}
+
+/// Takes the write lock once no read holds it. It is polled for rather than
+/// waited on because std's lock may make new reads queue behind a waiting
+/// writer, which would let one slow read hold up every other. After
+/// [`WRITER_PATIENCE`] the gate is closed to new reads, so the ones running
+/// are the last before the change. `None` if a change panicked while holding
+/// it.
+fn write_lock<'a>(
+    store: &'a RwLock<Store>,
+    gate: &ReadGate,
+) -> Option<RwLockWriteGuard<'a, Store>> {
+    let start = Instant::now();
+    let mut closed = false;
+    let guard = loop {
+        match store.try_write() {
+            Ok(guard) => break Some(guard),
+            Err(TryLockError::WouldBlock) => {
+                if !closed && start.elapsed() >= WRITER_PATIENCE {
+                    gate.set(true);
+                    closed = true;
+                }
+                thread::sleep(WRITE_RETRY);
+            }
+            Err(TryLockError::Poisoned(_)) => break None,
+        }
+    };
+    // Reads from here on wait on the lock itself, only for this change.
+    if closed {
+        gate.set(false);
+    }
+    guard
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn a_slow_read_holds_up_changes_but_not_reads() {
+        let store = StoreHandle::spawn(Store::new_in_memory()).unwrap();
+        let (started_tx, started_rx) = oneshot::channel();
+        let slow = tokio::spawn({
+            let store = store.clone();
+            async move {
+                store
+                    .read(move |_| {
+                        started_tx.send(()).unwrap();
+                        thread::sleep(Duration::from_millis(500));
+                    })
+                    .await
+            }
+        });
+        started_rx.await.unwrap();
+        let change = tokio::spawn({
+            let store = store.clone();
+            async move { store.register_user("alice", "correct horse").await }
+        });
+
+        // Let the change start waiting for the slow read; other reads still run.
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let start = Instant::now();
+        assert_eq!(store.user_count().await, 0);
+        assert!(store.get_user("alice").await.is_none());
+        assert!(start.elapsed() < Duration::from_millis(250), "took {:?}", start.elapsed());
+
+        slow.await.unwrap().unwrap();
+        change.await.unwrap().unwrap();
+        assert_eq!(store.user_count().await, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn a_change_gets_through_reads_that_never_stop() {
+        let store = StoreHandle::spawn(Store::new_in_memory()).unwrap();
+        let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        // Eight readers, each starting a new 20ms read as the last ends, so
+        // the lock is never without one.
+        let mut readers = Vec::new();
+        for n in 0..8 {
+            let (store, stop) = (store.clone(), stop.clone());
+            readers.push(tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(n * 3)).await;
+                while !stop.load(Ordering::Relaxed) {
+                    store.read(|_| thread::sleep(Duration::from_millis(20))).await.unwrap();
+                }
+            }));
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let start = Instant::now();
+        let change = store.register_user("alice", "correct horse");
+        tokio::time::timeout(Duration::from_secs(2), change)
+            .await
+            .expect("the change never got the lock")
+            .unwrap();
+        assert!(start.elapsed() < WRITER_PATIENCE * 3, "took {:?}", start.elapsed());
+        stop.store(true, Ordering::Relaxed);
+        for reader in readers {
+            reader.await.unwrap();
+        }
+        assert_eq!(store.user_count().await, 1);
+    }
+
+    #[tokio::test]
+    async fn calls_fail_once_a_change_has_panicked() {
+        let store = StoreHandle::spawn(Store::new_in_memory()).unwrap();
+        let result = store.call(|_| -> () { panic!("boom") }).await;
+        assert!(result.is_err());
+
+        assert!(store.register_user("alice", "correct horse").await.is_err());
+        assert!(store.authenticate("alice", "correct horse").await.is_err());
+        assert_eq!(store.user_count().await, 0);
+    }
+}
@@ -0,0 +1,307 @@
+//! Older messages paged out of memory (`StoreOptions::memory_window`).
+//!
+//! When more messages are stored than the window holds, the oldest are
+//! appended to `archive.jsonl`, one JSON object per line, oldest first, and
+//! dropped from memory and `messages.json`. Reads that need more than the
+//! window (history, search, sync, export) page through the file from its
+//! end, a block at a time, so only what they return is held at once. The
+//! file is only ever appended to, except when messages are removed from it
+//! (purge, retention, TTL expiry), which rewrites it through a temporary
+//! file like the other data files.
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use tracing::{error, warn};
+
+use super::sync_dir;
+use crate::protocol::StoredMessage;
+
+pub(super) const ARCHIVE_FILE: &str = "archive.jsonl";
+
+/// How much of the file is read at a time when paging backwards.
+const BLOCK: u64 = 64 * 1024;
+
+pub(super) struct Archive {
+    path: PathBuf,
+    /// Messages in the file; lines that don't parse aren't counted.
+    len: usize,
+    /// Timestamp of the oldest archived message.
+    oldest: Option<DateTime<Utc>>,
+    /// No archived message expires before this; `None` if none expire.
+    next_expiry: Option<DateTime<Utc>>,
+}
+
+impl Archive {
+    /// An archive at `path`, which is created on the first append.
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            len: 0,
+            oldest: None,
+            next_expiry: None,
+        }
+    }
+
+    /// Reads through the existing file at `path` once to count it. Lines
+    /// that don't parse are fatal with `strict`; otherwise they are left in
+    /// place and skipped by every read.
+    pub fn open(path: PathBuf, strict: bool) -> Result<Self> {
+        let mut archive = Self::new(path);
+        let mut malformed = 0;
+        for (i, line) in BufReader::new(File::open(&archive.path)?).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<StoredMessage>(&line) {
+                Ok(m) => archive.count(&m),
+                Err(e) if strict => anyhow::bail!(
+                    "{} line {} is corrupt: {} (started with --strict)",
+                    archive.path.display(),
+                    i + 1,
+                    e
+                ),
+                Err(_) => malformed += 1,
+            }
+        }
+        if malformed > 0 {
+            warn!(
+                path = %archive.path.display(),
+                malformed,
+                "store: skipping archived messages that don't parse"
+            );
+        }
+        Ok(archive)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn oldest(&self) -> Option<DateTime<Utc>> {
+        self.oldest
+    }
+
+    pub fn next_expiry(&self) -> Option<DateTime<Utc>> {
+        self.next_expiry
+    }
+
+    fn count(&mut self, m: &StoredMessage) {
+        self.len += 1;
+        self.oldest = Some(self.oldest.map_or(m.timestamp, |t| t.min(m.timestamp)));
+        if let Some(at) = m.expires_at {
+            self.next_expiry = Some(self.next_expiry.map_or(at, |next| next.min(at)));
+        }
+    }
+
+    /// Adds `messages`, which must be newer than everything already here.
+    pub fn append(&mut self, messages: &[StoredMessage], fsync: bool) -> Result<()> {
+        let mut data = Vec::new();
+        for m in messages {
+            serde_json::to_writer(&mut data, m)?;
+            data.push(b'\n');
+        }
+        let mut opts = File::options();
+        opts.create(true).append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            opts.mode(0o600);
+        }
+        let mut file = opts.open(&self.path)?;
+        file.write_all(&data)?;
+        if fsync {
+            file.sync_all()?;
+        }
+        for m in messages {
+            self.count(m);
+        }
+        Ok(())
+    }
+
+    /// Every archived message, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = StoredMessage> {
+        let lines = match File::open(&self.path) {
+            Ok(file) => Some(BufReader::new(file).lines()),
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    error!(path = %self.path.display(), error = %e, "store: archive unreadable");
+                }
+                None
+            }
+        };
+        lines
+            .into_iter()
+            .flatten()
+            .map_while(|line| match line {
+                Ok(line) => Some(line),
+                Err(e) => {
+                    error!(error = %e, "store: reading the archive failed");
+                    None
+                }
+            })
+            .filter_map(|line| serde_json::from_str(&line).ok())
+    }
+
+    /// Every archived message, newest first, read from the end of the file
+    /// as needed.
+    pub fn iter_rev(&self) -> Rev {
+        let file = match File::open(&self.path) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    error!(path = %self.path.display(), error = %e, "store: archive unreadable");
+                }
+                None
+            }
+        };
+        let pos = file.as_ref().and_then(|f| f.metadata().ok()).map_or(0, |m| m.len());
+        Rev {
+            file,
+            pos,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Rewrites the file without the messages `keep` rejects and returns
+    /// their ids. Lines that don't parse are kept as they are.
+    pub fn retain(
+        &mut self,
+        mut keep: impl FnMut(&StoredMessage) -> bool,
+        fsync: bool,
+    ) -> Result<Vec<String>> {
+        let mut removed = Vec::new();
+        let mut kept = Self::new(self.path.clone());
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        let tmp_path = dir.join(format!(".{}.tmp", ARCHIVE_FILE));
+        let mut out = std::io::BufWriter::new(create(&tmp_path)?);
+        for line in BufReader::new(File::open(&self.path)?).lines() {
+            let line = line?;
+            if let Ok(m) = serde_json::from_str::<StoredMessage>(&line) {
+                if !keep(&m) {
+                    removed.push(m.id);
+                    continue;
+                }
+                kept.count(&m);
+            }
+            out.write_all(line.as_bytes())?;
+            out.write_all(b"\n")?;
+        }
+        let file = out.into_inner().map_err(|e| e.into_error())?;
+        if removed.is_empty() {
+            drop(file);
+            fs::remove_file(&tmp_path)?;
+            return Ok(removed);
+        }
+        if fsync {
+            file.sync_all()?;
+        }
+        drop(file);
+        fs::rename(&tmp_path, &self.path)?;
+        if fsync {
+            sync_dir(dir)?;
+        }
+        *self = kept;
+        Ok(removed)
+    }
+
+    /// Replaces the whole archive with `messages`, oldest first.
+    pub fn replace(&mut self, messages: &[StoredMessage], fsync: bool) -> Result<()> {
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        let tmp_path = dir.join(format!(".{}.tmp", ARCHIVE_FILE));
+        let mut out = std::io::BufWriter::new(create(&tmp_path)?);
+        let mut replaced = Self::new(self.path.clone());
+        for m in messages {
+            serde_json::to_writer(&mut out, m)?;
+            out.write_all(b"\n")?;
+            replaced.count(m);
+        }
+        let file = out.into_inner().map_err(|e| e.into_error())?;
+        if fsync {
+            file.sync_all()?;
+        }
+        drop(file);
+        fs::rename(&tmp_path, &self.path)?;
+        if fsync {
+            sync_dir(dir)?;
+        }
+        *self = replaced;
+        Ok(())
+    }
+}
+
+/// Creates (or truncates) `path`, readable by the owner only.
+fn create(path: &Path) -> std::io::Result<File> {
+    let mut opts = File::options();
+    opts.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+    opts.open(path)
+}
+
+/// See [`Archive::iter_rev`].
+pub(super) struct Rev {
+    file: Option<File>,
+    /// Where in the file `buf` starts; everything before it is unread.
+    pos: u64,
+    /// Read but not yet returned.
+    buf: Vec<u8>,
+}
+
+impl Rev {
+    /// Reads the block before `buf` into its front.
+    fn read_block(&mut self) -> std::io::Result<()> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let n = BLOCK.min(self.pos);
+        self.pos -= n;
+        let mut block = vec![0; n as usize];
+        file.seek(SeekFrom::Start(self.pos))?;
+        file.read_exact(&mut block)?;
+        block.append(&mut self.buf);
+        self.buf = block;
+        Ok(())
+    }
+}
+
+impl Iterator for Rev {
+    type Item = StoredMessage;
+
+    fn next(&mut self) -> Option<StoredMessage> {
+        self.file.as_ref()?;
+        loop {
+            while self.buf.last() == Some(&b'\n') {
+                self.buf.pop();
+            }
+            let line = match self.buf.iter().rposition(|&b| b == b'\n') {
+                Some(i) => self.buf.split_off(i + 1),
+                None if self.pos == 0 => {
+                    if self.buf.is_empty() {
+                        return None;
+                    }
+                    std::mem::take(&mut self.buf)
+                }
+                None => {
+                    if let Err(e) = self.read_block() {
+                        error!(error = %e, "store: reading the archive failed");
+                        self.file = None;
+                        return None;
+                    }
+                    continue;
+                }
+            };
+            if let Ok(m) = serde_json::from_slice(&line) {
+                return Some(m);
+            }
+        }
+    }
+}
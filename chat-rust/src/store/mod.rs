@@ -0,0 +1,2512 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::fs::{self, File, TryLockError};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use hex;
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{error, warn};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::protocol::{
+    DirectMessage, DirectoryEntry, DirectoryResult, LoginResult, MessageKind, Prefs, RecentUser,
+    RoomTopic, SearchResult, StoredMessage, UpdatePrefsPayload, UpdateProfilePayload, UserInfo,
+};
+use crate::query::Query;
+
+mod actor;
+mod archive;
+
+pub use actor::StoreHandle;
+use archive::{Archive, ARCHIVE_FILE};
+
+const MAX_DISPLAY_NAME: usize = 32;
+const MAX_STATUS_TEXT: usize = 100;
+/// Most direct messages held for one offline user; further ones are refused.
+pub const OFFLINE_QUEUE_MAX: usize = 100;
+/// Most messages pinned at once.
+pub const MAX_PINNED: usize = 50;
+/// Prefix of the temporary names (and user IDs) given to guest sessions.
+/// Such names can't be registered.
+pub const GUEST_PREFIX: &str = "guest-";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub id: String,
+    pub username: String,
+    pub password_hash: String,
+    pub created_at: DateTime<Utc>,
+    /// IDs of users whose chat messages this user doesn't receive.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blocked: Vec<String>,
+    /// When the user last logged in, sent a message or disconnected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_text: Option<String>,
+    /// A single emoji shown before the name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<String>,
+    /// The built-in bot's account, which has no password.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub bot: bool,
+    /// An account whose password is checked by `--auth-command` rather than
+    /// kept here; it has no password hash.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub external: bool,
+    #[serde(default, skip_serializing_if = "Prefs::is_default")]
+    pub prefs: Prefs,
+}
+
+/// Criteria for [`Store::search`]; empty or `None` fields match everything.
+#[derive(Debug, Clone)]
+pub struct SearchFilter {
+    pub query: Query,
+    /// Exact username, case-insensitive.
+    pub username: String,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub include_system: bool,
+}
+
+/// A `User` without its password hash, safe to export or hand to clients.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublicUser {
+    pub id: String,
+    pub username: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<&User> for PublicUser {
+    fn from(u: &User) -> Self {
+        Self {
+            id: u.id.clone(),
+            username: u.username.clone(),
+            created_at: u.created_at,
+        }
+    }
+}
+
+impl From<&User> for UserInfo {
+    fn from(u: &User) -> Self {
+        Self {
+            user_id: u.id.clone(),
+            username: u.username.clone(),
+            display_name: u.display_name.clone(),
+            status_text: u.status_text.clone(),
+            avatar: u.avatar.clone(),
+        }
+    }
+}
+
+impl From<&User> for LoginResult {
+    fn from(u: &User) -> Self {
+        Self {
+            user: UserInfo::from(u),
+            prefs: u.prefs.clone(),
+        }
+    }
+}
+
+/// Outcome of [`Store::import_from_reader`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub duplicates: usize,
+    pub malformed: usize,
+}
+
+/// Outcome of [`Store::check`].
+#[derive(Debug, Default, Clone)]
+pub struct CheckReport {
+    pub users: usize,
+    pub messages: usize,
+    /// One human-readable line per inconsistency found.
+    pub problems: Vec<String>,
+}
+
+/// A direct message waiting for its recipient to log in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct QueuedDirect {
+    recipient_id: String,
+    message: DirectMessage,
+}
+
+#[derive(Default)]
+struct Inner {
+    users: HashMap<String, User>,  // keyed by normalize_username(username)
+    by_id: HashMap<String, User>,  // keyed by user ID
+    /// The newest messages; with a memory window, older ones are in `archive`.
+    messages: Vec<StoredMessage>,
+    /// Messages paged out of memory, all older than those in `messages`.
+    archive: Option<Archive>,
+    /// Undelivered direct messages for all users, oldest first.
+    offline: Vec<QueuedDirect>,
+    /// IDs of pinned messages, oldest pin first.
+    pinned: Vec<String>,
+    /// One per room that has a topic.
+    topics: Vec<RoomTopic>,
+    /// No message expires before this; `None` if none expire. May be early
+    /// after messages are removed, never late.
+    next_expiry: Option<DateTime<Utc>>,
+}
+
+impl Inner {
+    /// Every message, newest first. The archive is only read once the
+    /// messages in memory run out.
+    fn newest_first(&self) -> impl Iterator<Item = Cow<'_, StoredMessage>> {
+        let archived = self.archive.iter().flat_map(Archive::iter_rev);
+        self.messages.iter().rev().map(Cow::Borrowed).chain(archived.map(Cow::Owned))
+    }
+
+    /// The messages with ids in `ids`, keyed by id, expired or not. The
+    /// archive is read only if some aren't in memory.
+    fn find_messages(&self, ids: &[&str]) -> HashMap<String, StoredMessage> {
+        let mut found = HashMap::new();
+        for m in self.newest_first() {
+            if found.len() == ids.len() {
+                break;
+            }
+            if ids.contains(&m.id.as_str()) && !found.contains_key(&m.id) {
+                found.insert(m.id.clone(), m.into_owned());
+            }
+        }
+        found
+    }
+}
+
+/// When data files reach the disk. Whatever the policy, a crash can only
+/// leave the previous or the new version of a file, never a torn one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FsyncPolicy {
+    /// Write and fsync each file (and the directory) on every change. A
+    /// completed change survives power loss.
+    Always,
+    /// Keep changes in memory until [`Store::flush`], which writes and
+    /// fsyncs every changed file at once. Up to a flush interval of changes
+    /// can be lost, to a crash as well as to power loss.
+    Interval,
+    /// Write each file on every change but leave it to the OS when it
+    /// reaches the disk. A crash of the server loses nothing; power loss can
+    /// lose what the OS hadn't written back yet.
+    #[default]
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "interval" => Ok(FsyncPolicy::Interval),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => Err(format!("unknown fsync policy {:?} (expected always, interval or never)", s)),
+        }
+    }
+}
+
+impl fmt::Display for FsyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FsyncPolicy::Always => "always",
+            FsyncPolicy::Interval => "interval",
+            FsyncPolicy::Never => "never",
+        })
+    }
+}
+
+/// Settings for [`Store::with_options`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StoreOptions {
+    /// Indent the JSON files for reading by hand. Compact output is much
+    /// smaller (about 18% for a typical `messages.json`).
+    pub pretty: bool,
+    pub fsync: FsyncPolicy,
+    /// Refuse to open a store whose data files don't parse, instead of
+    /// backing them up and loading whatever can be recovered.
+    pub strict: bool,
+    /// Keep at most this many messages in memory, paging older ones out to
+    /// `archive.jsonl` (see the `archive` module). 0 keeps them all.
+    pub memory_window: usize,
+    /// Open without writing anything in the data directory: no `.lock`,
+    /// no permission changes, no backups of corrupt files and no paging
+    /// out, and the store starts read-only (see [`Store::set_read_only`]).
+    /// A missing directory opens as an empty store.
+    pub read_only: bool,
+}
+
+/// Users, messages and the rest, in memory and (unless in-memory) on disk.
+/// Changes take `&mut self`; to read while it changes, share it behind a
+/// lock as [`StoreHandle`] does.
+pub struct Store {
+    inner: Inner,
+    files: Files,
+}
+
+/// Where and how the store's files are written, kept apart from `Inner` so
+/// that a change can borrow both.
+struct Files {
+    opts: StoreOptions,
+    /// Under [`FsyncPolicy::Interval`], the contents of each file changed
+    /// since the last flush.
+    pending: BTreeMap<String, String>,
+    /// `None` for an in-memory store, which never touches the filesystem.
+    data_dir: Option<PathBuf>,
+    /// Exclusive lock on `<data_dir>/.lock`, released when the store is
+    /// dropped. `None` for a store opened read-only until it is made
+    /// writable.
+    lock: Option<File>,
+    /// Refuse every file write (see [`Store::set_read_only`]).
+    read_only: bool,
+    /// Goes up on every change to the message list, so copies of it made
+    /// elsewhere can tell they are out of date.
+    messages_version: Arc<AtomicU64>,
+}
+
+impl Files {
+    fn new(opts: StoreOptions, data_dir: Option<PathBuf>, lock: Option<File>) -> Self {
+        Self {
+            opts,
+            pending: BTreeMap::new(),
+            data_dir,
+            lock,
+            read_only: opts.read_only,
+            messages_version: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl Store {
+    pub fn new(data_dir: impl AsRef<Path>) -> Result<Self> {
+        Self::with_options(data_dir, StoreOptions::default())
+    }
+
+    pub fn with_options(data_dir: impl AsRef<Path>, opts: StoreOptions) -> Result<Self> {
+        let data_dir = data_dir.as_ref().to_path_buf();
+        let lock = if opts.read_only {
+            None
+        } else {
+            fs::create_dir_all(&data_dir)?;
+            Some(lock_data_dir(&data_dir)?)
+        };
+        let restrict = |path: &Path| match opts.read_only {
+            true => Ok(()),
+            false => restrict_permissions(path),
+        };
+
+        let mut inner = Inner::default();
+
+        let users_path = data_dir.join("users.json");
+        if users_path.exists() {
+            restrict(&users_path)?;
+            let users: Vec<User> = load_array(&users_path, &opts)?;
+            for u in users {
+                let key = normalize_username(&u.username);
+                if let Some(prev) = inner.users.get(&key) {
+                    warn!(
+                        existing = %prev.username,
+                        duplicate = %u.username,
+                        "store: usernames collide after normalization"
+                    );
+                }
+                inner.users.insert(key, u.clone());
+                inner.by_id.insert(u.id.clone(), u);
+            }
+        }
+
+        let msgs_path = data_dir.join("messages.json");
+        if msgs_path.exists() {
+            restrict(&msgs_path)?;
+            inner.messages = load_array(&msgs_path, &opts)?;
+            inner.next_expiry = inner.messages.iter().filter_map(|m| m.expires_at).min();
+        }
+
+        let archive_path = data_dir.join(ARCHIVE_FILE);
+        if archive_path.exists() {
+            restrict(&archive_path)?;
+            let archive = Archive::open(archive_path, opts.strict)?;
+            // A crash between archiving messages and rewriting messages.json
+            // leaves them in both.
+            let archived: HashSet<String> =
+                archive.iter_rev().take(inner.messages.len()).map(|m| m.id).collect();
+            inner.messages.retain(|m| !archived.contains(&m.id));
+            inner.archive = Some(archive);
+        }
+
+        let offline_path = data_dir.join("offline.json");
+        if offline_path.exists() {
+            restrict(&offline_path)?;
+            inner.offline = load_array(&offline_path, &opts)?;
+        }
+
+        let pinned_path = data_dir.join("pinned.json");
+        if pinned_path.exists() {
+            restrict(&pinned_path)?;
+            inner.pinned = load_array(&pinned_path, &opts)?;
+        }
+
+        let topics_path = data_dir.join("topics.json");
+        if topics_path.exists() {
+            restrict(&topics_path)?;
+            inner.topics = load_array(&topics_path, &opts)?;
+        }
+
+        let mut files = Files::new(opts, Some(data_dir), lock);
+        // The window may have shrunk since the last run.
+        if !opts.read_only && files.page_out(&mut inner)? {
+            files.write_file("messages.json", &inner.messages)?;
+        }
+        Ok(Self { inner, files })
+    }
+
+    /// Validates the data files in `data_dir` without opening a store or
+    /// changing anything: every entry must parse, ids must be non-empty and
+    /// unique, usernames unique after normalization, password hashes
+    /// well-formed, and blocks and chat messages must refer to existing
+    /// users. Archived messages are checked too. Takes no lock, so it is
+    /// safe to run against a live server's directory (writes replace files
+    /// atomically). A file that isn't a JSON array at all is reported as one
+    /// problem.
+    pub fn check(data_dir: impl AsRef<Path>) -> Result<CheckReport> {
+        let dir = data_dir.as_ref();
+        let mut report = CheckReport::default();
+        let users: Vec<(usize, User)> =
+            check_entries(&dir.join("users.json"), &mut report.problems)?;
+        let archived = check_lines(&dir.join(ARCHIVE_FILE), &mut report.problems)?;
+        let messages: Vec<(usize, StoredMessage)> =
+            check_entries(&dir.join("messages.json"), &mut report.problems)?;
+        report.users = users.len();
+        report.messages = archived.len() + messages.len();
+
+        let mut ids = HashSet::new();
+        let mut names = HashMap::new();
+        for (_, u) in &users {
+            if u.id.is_empty() {
+                report.problems.push(format!("user {:?} has an empty id", u.username));
+            } else if !ids.insert(u.id.as_str()) {
+                report.problems.push(format!("user id {} is used more than once", u.id));
+            }
+            if let Some(prev) = names.insert(normalize_username(&u.username), &u.username) {
+                report.problems.push(format!(
+                    "usernames {:?} and {:?} collide after normalization",
+                    prev, u.username
+                ));
+            }
+            let hash = &u.password_hash;
+            let malformed = hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit());
+            if !u.bot && !u.external && malformed {
+                report
+                    .problems
+                    .push(format!("user {:?} has a malformed password hash", u.username));
+            }
+        }
+        for (_, u) in &users {
+            for id in u.blocked.iter().filter(|id| !ids.contains(id.as_str())) {
+                report
+                    .problems
+                    .push(format!("user {:?} blocks unknown user id {}", u.username, id));
+            }
+        }
+
+        let mut msg_ids = HashSet::new();
+        let archived = archived.iter().map(|(i, m)| (format!("{} line {}", ARCHIVE_FILE, i), m));
+        let current = messages.iter().map(|(i, m)| (format!("messages.json entry #{}", i), m));
+        for (at, m) in archived.chain(current) {
+            if m.id.is_empty() {
+                report.problems.push(format!("{} has an empty id", at));
+            } else if !msg_ids.insert(m.id.as_str()) {
+                report.problems.push(format!("message id {} is used more than once", m.id));
+            }
+            // Guests have no account; their messages are expected to be orphans.
+            let known = ids.contains(m.user_id.as_str()) || m.user_id.starts_with(GUEST_PREFIX);
+            if m.kind == MessageKind::Chat && !known {
+                report.problems.push(format!("{} is from unknown user id {:?}", at, m.user_id));
+            }
+        }
+        Ok(report)
+    }
+
+    /// A store that starts empty and keeps everything in memory only; nothing
+    /// is read from or written to disk.
+    pub fn new_in_memory() -> Self {
+        Self {
+            inner: Inner::default(),
+            files: Files::new(StoreOptions::default(), None, None),
+        }
+    }
+
+    pub fn register_user(&mut self, username: &str, password: &str) -> Result<User> {
+        let (display, key) = check_username(username)?;
+
+        let inner = &mut self.inner;
+        if let Some(existing) = inner.users.get(&key) {
+            anyhow::bail!(
+                "username {:?} is already taken (conflicts with {:?})",
+                display,
+                existing.username
+            );
+        }
+
+        let user = User {
+            id: generate_id(),
+            username: display,
+            password_hash: hash_password(password),
+            created_at: Utc::now(),
+            blocked: Vec::new(),
+            last_seen: Some(Utc::now()),
+            display_name: None,
+            status_text: None,
+            avatar: None,
+            bot: false,
+            external: false,
+            prefs: Prefs::default(),
+        };
+
+        inner.users.insert(key, user.clone());
+        inner.by_id.insert(user.id.clone(), user.clone());
+
+        let users: Vec<User> = inner.by_id.values().cloned().collect();
+        self.files.write_file("users.json", &users)?;
+
+        Ok(user)
+    }
+
+    /// The account a built-in poster (the bot or a bridge) posts as,
+    /// created the first time. Its password hash is empty, which no password
+    /// hashes to, so nobody can log in as it. Fails if a person has already
+    /// registered `name`.
+    pub fn bot_user(&mut self, name: &str) -> Result<User> {
+        let (display, key) = check_username(name)?;
+
+        let inner = &mut self.inner;
+        if let Some(existing) = inner.users.get(&key) {
+            if !existing.bot {
+                anyhow::bail!("username {:?} belongs to a registered user", existing.username);
+            }
+            return Ok(existing.clone());
+        }
+
+        let user = User {
+            id: generate_id(),
+            username: display,
+            password_hash: String::new(),
+            created_at: Utc::now(),
+            blocked: Vec::new(),
+            last_seen: None,
+            display_name: None,
+            status_text: None,
+            avatar: None,
+            bot: true,
+            external: false,
+            prefs: Prefs::default(),
+        };
+        inner.users.insert(key, user.clone());
+        inner.by_id.insert(user.id.clone(), user.clone());
+
+        let users: Vec<User> = inner.by_id.values().cloned().collect();
+        self.files.write_file("users.json", &users)?;
+
+        Ok(user)
+    }
+
+    /// The local account for a user the auth hook accepted, created the
+    /// first time (or, with `register`, only then). Like a bot account it
+    /// has no password hash. Fails if `name` belongs to a local account.
+    pub fn external_user(&mut self, name: &str, register: bool) -> Result<User> {
+        let (display, key) = check_username(name)?;
+
+        let inner = &mut self.inner;
+        if let Some(existing) = inner.users.get(&key) {
+            if !existing.external {
+                anyhow::bail!("username {:?} belongs to a local account", existing.username);
+            }
+            if register {
+                anyhow::bail!("username {:?} is already taken", existing.username);
+            }
+            return Ok(existing.clone());
+        }
+
+        let user = User {
+            id: generate_id(),
+            username: display,
+            password_hash: String::new(),
+            created_at: Utc::now(),
+            blocked: Vec::new(),
+            last_seen: Some(Utc::now()),
+            display_name: None,
+            status_text: None,
+            avatar: None,
+            bot: false,
+            external: true,
+            prefs: Prefs::default(),
+        };
+        inner.users.insert(key, user.clone());
+        inner.by_id.insert(user.id.clone(), user.clone());
+
+        let users: Vec<User> = inner.by_id.values().cloned().collect();
+        self.files.write_file("users.json", &users)?;
+
+        Ok(user)
+    }
+
+    /// Changes the username of `user_id`. The ID is unchanged, so messages
+    /// already stored keep the name they were sent under. Accounts from the
+    /// auth hook keep the name the hook knows them by.
+    pub fn rename_user(&mut self, user_id: &str, new_username: &str) -> Result<User> {
+        let (display, key) = check_username(new_username)?;
+
+        let inner = &mut self.inner;
+        let old_key = match inner.by_id.get(user_id) {
+            Some(u) if u.external => anyhow::bail!("this account's name is managed externally"),
+            Some(u) => normalize_username(&u.username),
+            None => anyhow::bail!("user {:?} not found", user_id),
+        };
+        if let Some(existing) = inner.users.get(&key) {
+            if existing.id != user_id {
+                anyhow::bail!(
+                    "username {:?} is already taken (conflicts with {:?})",
+                    display,
+                    existing.username
+                );
+            }
+        }
+
+        let mut user = inner.users.remove(&old_key).expect("users and by_id out of sync");
+        user.username = display;
+        inner.users.insert(key, user.clone());
+        inner.by_id.insert(user.id.clone(), user.clone());
+
+        let users: Vec<User> = inner.by_id.values().cloned().collect();
+        self.files.write_file("users.json", &users)?;
+
+        Ok(user)
+    }
+
+    /// Adds `target` to (or, with `blocked` false, removes it from)
+    /// `user_id`'s block list and returns the updated list.
+    pub fn set_block(
+        &mut self,
+        user_id: &str,
+        target: &str,
+        blocked: bool,
+    ) -> Result<Vec<UserInfo>> {
+        let inner = &mut self.inner;
+        let target_id = match inner.users.get(&normalize_username(target)) {
+            Some(u) => u.id.clone(),
+            None => anyhow::bail!("user {:?} not found", target),
+        };
+        if target_id == user_id {
+            anyhow::bail!("you can't block yourself");
+        }
+        let user = match inner.by_id.get_mut(user_id) {
+            Some(u) => u,
+            None => anyhow::bail!("user {:?} not found", user_id),
+        };
+        user.blocked.retain(|id| *id != target_id);
+        if blocked {
+            user.blocked.push(target_id);
+        }
+        let user = user.clone();
+        inner.users.insert(normalize_username(&user.username), user.clone());
+
+        let users: Vec<User> = inner.by_id.values().cloned().collect();
+        let list = user
+            .blocked
+            .iter()
+            .filter_map(|id| inner.by_id.get(id))
+            .map(UserInfo::from)
+            .collect();
+        self.files.write_file("users.json", &users)?;
+        Ok(list)
+    }
+
+    pub fn get_user(&self, username: &str) -> Option<User> {
+        let inner = &self.inner;
+        inner.users.get(&normalize_username(username)).cloned()
+    }
+
+    pub fn user_info(&self, user_id: &str) -> Option<UserInfo> {
+        self.inner.by_id.get(user_id).map(UserInfo::from)
+    }
+
+    /// Applies the fields set in `update` to `user_id`'s profile; empty
+    /// strings clear a field. Nothing changes if any field is invalid.
+    pub fn update_profile(&mut self, user_id: &str, update: &UpdateProfilePayload) -> Result<User> {
+        let display_name = update
+            .display_name
+            .as_deref()
+            .map(|s| check_profile_text("display name", s, MAX_DISPLAY_NAME))
+            .transpose()?;
+        let status_text = update
+            .status_text
+            .as_deref()
+            .map(|s| check_profile_text("status", s, MAX_STATUS_TEXT))
+            .transpose()?;
+        let avatar = update.avatar.as_deref().map(check_avatar).transpose()?;
+
+        let inner = &mut self.inner;
+        let user = match inner.by_id.get_mut(user_id) {
+            Some(u) => u,
+            None => anyhow::bail!("user {:?} not found", user_id),
+        };
+        if let Some(v) = display_name {
+            user.display_name = v;
+        }
+        if let Some(v) = status_text {
+            user.status_text = v;
+        }
+        if let Some(v) = avatar {
+            user.avatar = v;
+        }
+        let user = user.clone();
+        inner.users.insert(normalize_username(&user.username), user.clone());
+
+        let users: Vec<User> = inner.by_id.values().cloned().collect();
+        self.files.write_file("users.json", &users)?;
+        Ok(user)
+    }
+
+    /// Sets the preferences present in `update` and returns them all.
+    pub fn update_prefs(&mut self, user_id: &str, update: &UpdatePrefsPayload) -> Result<Prefs> {
+        let inner = &mut self.inner;
+        let user = match inner.by_id.get_mut(user_id) {
+            Some(u) => u,
+            None => anyhow::bail!("user {:?} not found", user_id),
+        };
+        if let Some(notify) = update.notify {
+            user.prefs.notify = notify;
+        }
+        let user = user.clone();
+        inner.users.insert(normalize_username(&user.username), user.clone());
+
+        let users: Vec<User> = inner.by_id.values().cloned().collect();
+        self.files.write_file("users.json", &users)?;
+        Ok(user.prefs)
+    }
+
+    /// Records that `user_id` was just online.
+    pub fn touch_last_seen(&mut self, user_id: &str) -> Result<()> {
+        let inner = &mut self.inner;
+        let user = match inner.by_id.get_mut(user_id) {
+            Some(u) => u,
+            None => anyhow::bail!("user {:?} not found", user_id),
+        };
+        user.last_seen = Some(Utc::now());
+        let user = user.clone();
+        inner.users.insert(normalize_username(&user.username), user);
+
+        let users: Vec<User> = inner.by_id.values().cloned().collect();
+        self.files.write_file("users.json", &users)
+    }
+
+    /// IDs of the users `user_id` has blocked.
+    pub fn get_blocks(&self, user_id: &str) -> HashSet<String> {
+        let inner = &self.inner;
+        match inner.by_id.get(user_id) {
+            Some(u) => u.blocked.iter().cloned().collect(),
+            None => HashSet::new(),
+        }
+    }
+
+    /// Holds `message` until `user_id` next logs in. Fails if that user
+    /// already has [`OFFLINE_QUEUE_MAX`] messages waiting.
+    pub fn enqueue_offline(&mut self, user_id: &str, message: DirectMessage) -> Result<()> {
+        let inner = &mut self.inner;
+        let waiting = inner.offline.iter().filter(|q| q.recipient_id == user_id).count();
+        if waiting >= OFFLINE_QUEUE_MAX {
+            anyhow::bail!("{} has too many undelivered messages", message.to);
+        }
+        inner.offline.push(QueuedDirect {
+            recipient_id: user_id.to_string(),
+            message,
+        });
+        self.files.write_file("offline.json", &inner.offline)
+    }
+
+    /// The direct messages waiting for `user_id`, oldest first. They stay
+    /// queued until [`Store::dequeue_offline`] removes them.
+    pub fn offline_queue(&self, user_id: &str) -> Vec<DirectMessage> {
+        let inner = &self.inner;
+        inner
+            .offline
+            .iter()
+            .filter(|q| q.recipient_id == user_id)
+            .map(|q| q.message.clone())
+            .collect()
+    }
+
+    /// Removes the oldest `count` messages waiting for `user_id`, once they
+    /// have been handed over.
+    pub fn dequeue_offline(&mut self, user_id: &str, count: usize) -> Result<()> {
+        let inner = &mut self.inner;
+        let mut left = count;
+        inner.offline.retain(|q| {
+            if left > 0 && q.recipient_id == user_id {
+                left -= 1;
+                return false;
+            }
+            true
+        });
+        self.files.write_file("offline.json", &inner.offline)
+    }
+
+    pub fn get_message(&self, id: &str) -> Option<StoredMessage> {
+        let inner = &self.inner;
+        let now = Utc::now();
+        inner.find_messages(&[id]).remove(id).filter(|m| !m.is_expired(now))
+    }
+
+    /// Pins or unpins message `id`. Returns whether anything changed, so
+    /// pinning a pinned message is a no-op. Fails if the message doesn't
+    /// exist or [`MAX_PINNED`] messages are already pinned.
+    pub fn set_pinned(&mut self, id: &str, pinned: bool) -> Result<bool> {
+        let inner = &mut self.inner;
+        let is_pinned = inner.pinned.iter().any(|p| p == id);
+        if pinned == is_pinned {
+            return Ok(false);
+        }
+        if pinned {
+            let mut ids: Vec<&str> = inner.pinned.iter().map(String::as_str).collect();
+            ids.push(id);
+            let found = inner.find_messages(&ids);
+            if !found.contains_key(id) {
+                anyhow::bail!("no message with id {:?}", id);
+            }
+            // Pins of pruned messages don't count against the limit.
+            let live = inner.pinned.iter().filter(|p| found.contains_key(*p)).count();
+            if live >= MAX_PINNED {
+                anyhow::bail!("at most {} messages can be pinned", MAX_PINNED);
+            }
+            inner.pinned.push(id.to_string());
+        } else {
+            inner.pinned.retain(|p| p != id);
+        }
+        self.files.write_file("pinned.json", &inner.pinned)?;
+        Ok(true)
+    }
+
+    /// Pinned messages, oldest pin first. Pins whose message has since been
+    /// purged, pruned or has expired are skipped.
+    pub fn pinned_messages(&self) -> Vec<StoredMessage> {
+        let inner = &self.inner;
+        let now = Utc::now();
+        let ids: Vec<&str> = inner.pinned.iter().map(String::as_str).collect();
+        let mut found = inner.find_messages(&ids);
+        inner
+            .pinned
+            .iter()
+            .filter_map(|id| found.remove(id))
+            .filter(|m| !m.is_expired(now))
+            .collect()
+    }
+
+    /// Sets `topic.room`'s topic, replacing the one before. An empty topic
+    /// clears it.
+    pub fn set_topic(&mut self, topic: RoomTopic) -> Result<()> {
+        let inner = &mut self.inner;
+        inner.topics.retain(|t| t.room != topic.room);
+        if !topic.topic.is_empty() {
+            inner.topics.push(topic);
+        }
+        self.files.write_file("topics.json", &inner.topics)
+    }
+
+    pub fn topic(&self, room: &str) -> Option<RoomTopic> {
+        let inner = &self.inner;
+        inner.topics.iter().find(|t| t.room == room).cloned()
+    }
+
+    pub fn authenticate(&self, username: &str, password: &str) -> Result<User> {
+        let inner = &self.inner;
+        let key = normalize_username(username);
+
+        let user = inner
+            .users
+            .get(&key)
+            .ok_or_else(|| anyhow::anyhow!("user {:?} not found", username))?;
+
+        if user.password_hash != hash_password(password) {
+            anyhow::bail!("incorrect password");
+        }
+
+        Ok(user.clone())
+    }
+
+    /// Stores `msg`. Messages are kept in `seq` order even when saved out of
+    /// order, as the persistence workers may; unnumbered ones are appended.
+    pub fn save_message(&mut self, msg: StoredMessage) -> Result<()> {
+        let inner = &mut self.inner;
+        if msg.kind == MessageKind::Chat {
+            // In memory only; users.json picks it up on its next write.
+            if let Some(user) = inner.by_id.get_mut(&msg.user_id) {
+                if user.last_seen.is_none_or(|seen| seen < msg.timestamp) {
+                    user.last_seen = Some(msg.timestamp);
+                    let user = user.clone();
+                    inner.users.insert(normalize_username(&user.username), user);
+                }
+            }
+        }
+        if let Some(at) = msg.expires_at {
+            inner.next_expiry = Some(inner.next_expiry.map_or(at, |next| next.min(at)));
+        }
+        let pos = match msg.seq {
+            0 => inner.messages.len(),
+            seq => inner.messages.iter().rposition(|m| m.seq < seq).map_or(0, |i| i + 1),
+        };
+        inner.messages.insert(pos, msg);
+        self.files.messages_changed();
+        self.files.page_out(inner)?;
+        self.files.write_file("messages.json", &inner.messages)?;
+        Ok(())
+    }
+
+    /// The highest `seq` stored, for numbering to carry on from after a
+    /// restart.
+    pub fn last_seq(&self) -> u64 {
+        let inner = &self.inner;
+        // Archived messages are all older than those in memory.
+        match inner.messages.iter().map(|m| m.seq).max() {
+            Some(seq) => seq,
+            None => inner.newest_first().next().map_or(0, |m| m.seq),
+        }
+    }
+
+    /// Deletes every message whose expiry is at or before `now` (and its
+    /// pin) and returns their ids.
+    pub fn remove_expired(&mut self, now: DateTime<Utc>) -> Result<Vec<String>> {
+        let inner = &mut self.inner;
+        let archive_due = inner.archive.as_ref().and_then(Archive::next_expiry);
+        let archive_due = archive_due.is_some_and(|next| next <= now);
+        if !archive_due && inner.next_expiry.is_none_or(|next| next > now) {
+            return Ok(Vec::new());
+        }
+        let mut removed = Vec::new();
+        if let Some(archive) = inner.archive.as_mut().filter(|_| archive_due) {
+            self.files.check_writable()?;
+            let fsync = self.files.opts.fsync == FsyncPolicy::Always;
+            removed = archive.retain(|m| !m.is_expired(now), fsync)?;
+        }
+        inner.messages.retain(|m| {
+            if m.is_expired(now) {
+                removed.push(m.id.clone());
+            }
+            !m.is_expired(now)
+        });
+        inner.next_expiry = inner.messages.iter().filter_map(|m| m.expires_at).min();
+        if removed.is_empty() {
+            return Ok(removed);
+        }
+        self.files.messages_changed();
+        self.files.write_file("messages.json", &inner.messages)?;
+        let pins = inner.pinned.len();
+        inner.pinned.retain(|id| !removed.contains(id));
+        if inner.pinned.len() != pins {
+            self.files.write_file("pinned.json", &inner.pinned)?;
+        }
+        Ok(removed)
+    }
+
+    /// Users last seen at or after `since`, most recent first.
+    pub fn recent_users(&self, since: DateTime<Utc>) -> Vec<RecentUser> {
+        let inner = &self.inner;
+        let mut users: Vec<RecentUser> = inner
+            .by_id
+            .values()
+            .filter_map(|u| match u.last_seen {
+                Some(seen) if seen >= since => Some(RecentUser {
+                    user: UserInfo::from(u),
+                    last_seen: seen,
+                }),
+                _ => None,
+            })
+            .collect();
+        users.sort_by_key(|u| std::cmp::Reverse(u.last_seen));
+        users
+    }
+
+    /// One page of every account, ordered by normalized username. `online` is
+    /// left false for the caller to fill in.
+    pub fn list_users(&self, limit: usize, offset: usize) -> DirectoryResult {
+        let inner = &self.inner;
+        let mut names: Vec<&String> = inner.users.keys().collect();
+        names.sort();
+        let users = names
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .map(|name| {
+                let u = &inner.users[name];
+                DirectoryEntry {
+                    user: UserInfo::from(u),
+                    created_at: u.created_at,
+                    online: false,
+                }
+            })
+            .collect();
+        DirectoryResult {
+            users,
+            total: inner.users.len(),
+        }
+    }
+
+    /// Removes messages with a timestamp before `before` (or every message when
+    /// `None`) and returns how many were removed.
+    pub fn purge_messages(&mut self, before: Option<DateTime<Utc>>) -> Result<usize> {
+        match before {
+            Some(cutoff) => self.prune_older_than(cutoff),
+            None => self.remove_messages(|_| true, |_| false),
+        }
+    }
+
+    /// Drops every message timestamped before `cutoff`.
+    pub fn prune_older_than(&mut self, cutoff: DateTime<Utc>) -> Result<usize> {
+        self.remove_messages(
+            |archive| archive.oldest().is_some_and(|t| t < cutoff),
+            |m| m.timestamp >= cutoff,
+        )
+    }
+
+    /// Drops the oldest messages so at most `max` remain.
+    pub fn trim_to(&mut self, max: usize) -> Result<usize> {
+        let excess = self.message_count().saturating_sub(max);
+        if excess == 0 {
+            return Ok(0);
+        }
+        let mut seen = 0;
+        self.remove_messages(
+            |_| true,
+            |_| {
+                seen += 1;
+                seen > excess
+            },
+        )
+    }
+
+    /// Removes the messages `keep` rejects, which is shown the archived ones
+    /// (if `in_archive` says any might go, as reading them all is slow) and
+    /// then the rest, oldest first. Rewrites the files if anything was
+    /// removed.
+    fn remove_messages(
+        &mut self,
+        in_archive: impl FnOnce(&Archive) -> bool,
+        mut keep: impl FnMut(&StoredMessage) -> bool,
+    ) -> Result<usize> {
+        let inner = &mut self.inner;
+        let mut removed = 0;
+        if let Some(archive) = inner.archive.as_mut().filter(|a| a.len() > 0 && in_archive(a)) {
+            self.files.check_writable()?;
+            let fsync = self.files.opts.fsync == FsyncPolicy::Always;
+            removed += archive.retain(&mut keep, fsync)?.len();
+        }
+        let old_len = inner.messages.len();
+        inner.messages.retain(|m| keep(m));
+        removed += old_len - inner.messages.len();
+        if removed > 0 {
+            self.files.messages_changed();
+            self.files.write_file("messages.json", &inner.messages)?;
+        }
+        Ok(removed)
+    }
+
+    /// Writes every message as one JSON object per line, oldest first.
+    pub fn export_to_writer(&self, mut w: impl Write) -> Result<usize> {
+        let inner = &self.inner;
+        let mut count = 0;
+        let archived = inner.archive.iter().flat_map(Archive::iter);
+        for m in archived.map(Cow::Owned).chain(inner.messages.iter().map(Cow::Borrowed)) {
+            serde_json::to_writer(&mut w, &*m)?;
+            w.write_all(b"\n")?;
+            count += 1;
+        }
+        w.flush()?;
+        Ok(count)
+    }
+
+    /// Writes every user (without password hashes) as JSONL, ordered by
+    /// creation time so the output is stable.
+    pub fn export_users_to_writer(&self, mut w: impl Write) -> Result<usize> {
+        let inner = &self.inner;
+        let mut users: Vec<PublicUser> = inner.by_id.values().map(PublicUser::from).collect();
+        users.sort_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.id.cmp(&b.id)));
+        for u in &users {
+            serde_json::to_writer(&mut w, u)?;
+            w.write_all(b"\n")?;
+        }
+        w.flush()?;
+        Ok(users.len())
+    }
+
+    /// Merges a JSONL message stream into the store. Messages whose id is
+    /// already present are skipped, as are lines that don't parse. The
+    /// whole stream is read before anything changes. Imported messages no
+    /// newer than the newest archived one are merged into the archive, which
+    /// is rewritten with them before `messages.json`; the rest join memory
+    /// and are paged out as usual.
+    pub fn import_from_reader(&mut self, r: impl BufRead) -> Result<ImportReport> {
+        let mut report = ImportReport::default();
+        let inner = &mut self.inner;
+        let archived = inner.archive.iter().flat_map(Archive::iter).map(|m| m.id);
+        let mut seen: HashSet<String> = archived.collect();
+        seen.extend(inner.messages.iter().map(|m| m.id.clone()));
+
+        let mut imported = Vec::new();
+        for line in r.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<StoredMessage>(&line) {
+                Ok(m) if seen.insert(m.id.clone()) => imported.push(m),
+                Ok(_) => report.duplicates += 1,
+                Err(_) => report.malformed += 1,
+            }
+        }
+        report.imported = imported.len();
+        if imported.is_empty() {
+            return Ok(report);
+        }
+        self.files.check_writable()?;
+        imported.sort_by_key(|m| m.timestamp);
+
+        // Only ever adds to the archive, so a failure (or crash) before
+        // `messages.json` is written leaves every message somewhere.
+        let newest_archived = inner.archive.iter().flat_map(Archive::iter_rev).next();
+        if let Some(newest) = newest_archived.map(|m| m.timestamp) {
+            let (older, newer): (Vec<_>, Vec<_>) =
+                imported.into_iter().partition(|m| m.timestamp <= newest);
+            imported = newer;
+            if !older.is_empty() {
+                let archive = inner.archive.as_mut().expect("newest came from the archive");
+                let messages = merge_by_time(archive.iter().collect(), older);
+                archive.replace(&messages, self.files.opts.fsync == FsyncPolicy::Always)?;
+            }
+        }
+
+        inner.messages = merge_by_time(std::mem::take(&mut inner.messages), imported);
+        inner.next_expiry = inner.messages.iter().filter_map(|m| m.expires_at).min();
+        self.files.messages_changed();
+        self.files.page_out(inner)?;
+        self.files.write_file("messages.json", &inner.messages)?;
+        Ok(report)
+    }
+
+    /// A number that goes up whenever messages are added or removed.
+    pub fn messages_version(&self) -> u64 {
+        self.files.messages_version.load(Ordering::Acquire)
+    }
+
+    pub fn message_count(&self) -> usize {
+        let inner = &self.inner;
+        inner.messages.len() + inner.archive.as_ref().map_or(0, Archive::len)
+    }
+
+    /// Chat messages stored for `user_id`.
+    pub fn message_count_for(&self, user_id: &str) -> usize {
+        let inner = &self.inner;
+        inner
+            .newest_first()
+            .filter(|m| m.kind == MessageKind::Chat && m.user_id == user_id)
+            .count()
+    }
+
+    pub fn user_count(&self) -> usize {
+        self.inner.by_id.len()
+    }
+
+    /// While set, every change that would be written to disk fails with
+    /// "the store is read-only" instead. Callers are expected to stop making
+    /// changes first; this only guarantees the files are left alone.
+    /// Changes still waiting for a flush are written before it is set.
+    /// Clearing it on a store opened read-only takes the data directory's
+    /// lock first, and fails (leaving it read-only) if that can't be done.
+    pub fn set_read_only(&mut self, read_only: bool) -> Result<()> {
+        if read_only {
+            if let Err(e) = self.flush() {
+                error!(error = %e, "failed to flush the store before going read-only");
+            }
+        } else if let (Some(dir), None) = (&self.files.data_dir, &self.files.lock) {
+            fs::create_dir_all(dir)?;
+            self.files.lock = Some(lock_data_dir(dir)?);
+        }
+        self.files.read_only = read_only;
+        Ok(())
+    }
+
+    /// Writes and fsyncs every file changed since the last flush. Only
+    /// [`FsyncPolicy::Interval`] leaves anything to flush; the server calls
+    /// this every flush interval and on shutdown, and dropping the store
+    /// does too. A file that fails to write stays pending for the next try.
+    pub fn flush(&mut self) -> Result<()> {
+        self.files.flush()
+    }
+
+
+    /// Returns the last `n` lobby messages (all of them when `n` is 0),
+    /// oldest first. System events are skipped unless `include_system` is
+    /// set.
+    pub fn get_history(&self, n: usize, include_system: bool) -> Vec<StoredMessage> {
+        let inner = &self.inner;
+        let n = if n == 0 { usize::MAX } else { n };
+        let now = Utc::now();
+        let mut msgs: Vec<StoredMessage> = inner
+            .newest_first()
+            .filter(|m| m.room.is_none() && (include_system || m.kind == MessageKind::Chat))
+            .filter(|m| !m.is_expired(now))
+            .take(n)
+            .map(Cow::into_owned)
+            .collect();
+        msgs.reverse();
+        msgs
+    }
+
+    /// Lobby messages stored after the one with id `id`, oldest first, or
+    /// `None` if no message has that id.
+    pub fn get_messages_after(&self, id: &str, include_system: bool) -> Option<Vec<StoredMessage>> {
+        let inner = &self.inner;
+        // How far back it is, so only what comes after it is kept.
+        let back = inner.newest_first().position(|m| m.id == id)?;
+        let now = Utc::now();
+        let mut msgs: Vec<StoredMessage> = inner
+            .newest_first()
+            .take(back)
+            .filter(|m| m.room.is_none() && (include_system || m.kind == MessageKind::Chat))
+            .filter(|m| !m.is_expired(now))
+            .map(Cow::into_owned)
+            .collect();
+        msgs.reverse();
+        Some(msgs)
+    }
+
+    /// The last `n` messages sent to `room`, oldest first.
+    pub fn get_room_history(&self, room: &str, n: usize) -> Vec<StoredMessage> {
+        let inner = &self.inner;
+        let now = Utc::now();
+        let mut msgs: Vec<StoredMessage> = inner
+            .newest_first()
+            .filter(|m| m.room.as_deref() == Some(room) && !m.is_expired(now))
+            .take(n)
+            .map(Cow::into_owned)
+            .collect();
+        msgs.reverse();
+        msgs
+    }
+
+    /// Messages matching `filter`, newest first, skipping `offset` matches
+    /// and returning at most `limit`. `total` counts every match.
+    pub fn search(&self, filter: &SearchFilter, limit: usize, offset: usize) -> SearchResult {
+        let inner = &self.inner;
+        let u = filter.username.to_lowercase();
+
+        let now = Utc::now();
+        let mut total = 0;
+        let mut messages = Vec::new();
+        for m in inner.newest_first() {
+            if !filter.include_system && m.kind != MessageKind::Chat {
+                continue;
+            }
+            if m.is_expired(now) {
+                continue;
+            }
+            if !filter.query.matches(&m.content) {
+                continue;
+            }
+            if !u.is_empty() && m.username.to_lowercase() != u {
+                continue;
+            }
+            if filter.from.is_some_and(|from| m.timestamp < from) {
+                continue;
+            }
+            if filter.to.is_some_and(|to| m.timestamp > to) {
+                continue;
+            }
+            if total >= offset && messages.len() < limit {
+                messages.push(m.into_owned());
+            }
+            total += 1;
+        }
+        SearchResult { messages, total }
+    }
+}
+
+impl Files {
+    /// Moves the messages beyond the memory window, oldest first, to the
+    /// archive and returns whether there were any. The caller rewrites
+    /// `messages.json`; until it does, the moved messages are in both, which
+    /// loading the store again sorts out.
+    fn page_out(&self, inner: &mut Inner) -> Result<bool> {
+        let window = self.opts.memory_window;
+        let dir = match &self.data_dir {
+            Some(dir) if window > 0 && inner.messages.len() > window => dir,
+            _ => return Ok(false),
+        };
+        self.check_writable()?;
+        let excess = inner.messages.len() - window;
+        let archive = inner.archive.get_or_insert_with(|| Archive::new(dir.join(ARCHIVE_FILE)));
+        archive.append(&inner.messages[..excess], self.opts.fsync == FsyncPolicy::Always)?;
+        inner.messages.drain(..excess);
+        Ok(true)
+    }
+
+    /// Called after changing the message list.
+    fn messages_changed(&self) {
+        self.messages_version.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Rewrites `<data_dir>/<name>`, or under [`FsyncPolicy::Interval`]
+    /// keeps its new contents for the next [`Store::flush`]. A no-op for an
+    /// in-memory store.
+    fn write_file(&mut self, name: &str, v: &impl Serialize) -> Result<()> {
+        self.check_writable()?;
+        let dir = match &self.data_dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let data = encode_json(v, self.opts.pretty)?;
+        match self.opts.fsync {
+            FsyncPolicy::Always => {
+                write_data(dir, name, &data, true)?;
+                sync_dir(dir)
+            }
+            FsyncPolicy::Interval => {
+                self.pending.insert(name.to_string(), data);
+                Ok(())
+            }
+            FsyncPolicy::Never => write_data(dir, name, &data, false),
+        }
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            anyhow::bail!("the store is read-only");
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let dir = match &self.data_dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        // Messages archived since the last flush may be gone from the
+        // pending messages.json, so they have to reach the disk first.
+        match File::open(dir.join(ARCHIVE_FILE)) {
+            Ok(archive) => archive.sync_all()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        while let Some((name, data)) = self.pending.pop_first() {
+            if let Err(e) = write_data(dir, &name, &data, true) {
+                self.pending.insert(name, data);
+                return Err(e);
+            }
+        }
+        sync_dir(dir)
+    }
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!(error = %e, "failed to flush the store");
+        }
+    }
+}
+
+/// Canonical form used to detect duplicate usernames: NFKC-folded,
+/// lowercased, with all whitespace and invisible format characters removed,
+/// so "Admin", " admin " and "ad min" all collide.
+pub fn normalize_username(name: &str) -> String {
+    name.nfkc()
+        .filter(|c| !c.is_whitespace() && !is_invisible(*c))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// The name as shown to others: trimmed, with internal whitespace runs
+/// collapsed to a single space. Casing is preserved.
+pub fn display_username(name: &str) -> String {
+    name.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Validates a requested username, returning its display form and
+/// normalized key.
+fn check_username(name: &str) -> Result<(String, String)> {
+    let display = display_username(name);
+    if display.chars().any(char::is_control) {
+        anyhow::bail!("username must not contain control characters");
+    }
+    let key = normalize_username(&display);
+    if key.is_empty() {
+        anyhow::bail!("username must contain visible characters");
+    }
+    if key.starts_with(GUEST_PREFIX) {
+        anyhow::bail!("usernames starting with {:?} are reserved for guests", GUEST_PREFIX);
+    }
+    Ok((display, key))
+}
+
+/// Trims `s` and checks it for a profile field; `None` means "clear".
+fn check_profile_text(field: &str, s: &str, max: usize) -> Result<Option<String>> {
+    let s = s.split_whitespace().collect::<Vec<_>>().join(" ");
+    if s.chars().any(char::is_control) {
+        anyhow::bail!("{} must not contain control characters", field);
+    }
+    if s.chars().count() > max {
+        anyhow::bail!("{} is too long (max {} characters)", field, max);
+    }
+    Ok(Some(s).filter(|s| !s.is_empty()))
+}
+
+/// Accepts a single non-ASCII symbol, optionally followed by the emoji
+/// presentation selector (U+FE0F); `None` means "clear".
+fn check_avatar(s: &str) -> Result<Option<String>> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(None);
+    }
+    let mut chars = s.chars().filter(|&c| c != '\u{FE0F}');
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if !c.is_ascii() && !c.is_alphanumeric() && !c.is_control() => {
+            Ok(Some(s.to_string()))
+        }
+        _ => anyhow::bail!("avatar must be a single emoji"),
+    }
+}
+
+fn is_invisible(c: char) -> bool {
+    matches!(c, '\u{200B}'..='\u{200D}' | '\u{2060}' | '\u{FEFF}' | '\u{00AD}')
+}
+
+fn hash_password(pw: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(pw.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+fn generate_id() -> String {
+    let ts = Utc::now().timestamp_nanos_opt().unwrap_or(0);
+    let rand_part: u16 = rand::thread_rng().gen_range(0..0xFFFF);
+    format!("{}-{:04x}", ts, rand_part)
+}
+
+/// Takes an exclusive lock so two servers can't interleave rewrites of the
+/// same files.
+fn lock_data_dir(dir: &Path) -> Result<File> {
+    let path = dir.join(".lock");
+    let file = File::options().create(true).truncate(false).write(true).open(&path)?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => anyhow::bail!(
+            "data directory {} is in use by another process (lock held on {})",
+            dir.display(),
+            path.display()
+        ),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// The data files hold password hashes, so keep them owner-only.
+#[cfg(unix)]
+fn restrict_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn restrict_permissions(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Reads a JSON array file. If it doesn't parse and `strict` is off, the
+/// file is copied to `<name>.corrupt.<timestamp>` (unless opening read-only)
+/// and every element that still parses is returned (a truncated file keeps
+/// everything before the cut; entries that don't match `T` are skipped).
+fn load_array<T: DeserializeOwned>(path: &Path, opts: &StoreOptions) -> Result<Vec<T>> {
+    let bytes = fs::read(path)?;
+    let err = match serde_json::from_slice(&bytes) {
+        Ok(items) => return Ok(items),
+        Err(e) => e,
+    };
+    if opts.strict {
+        anyhow::bail!("{} is corrupt: {} (started with --strict)", path.display(), err);
+    }
+
+    let backup = if opts.read_only {
+        "none (read-only)".to_string()
+    } else {
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(format!(".corrupt.{}", Utc::now().format("%Y%m%dT%H%M%S")));
+        fs::copy(path, &backup)?;
+        restrict_permissions(Path::new(&backup))?;
+        Path::new(&backup).display().to_string()
+    };
+
+    let text = String::from_utf8_lossy(&bytes);
+    let (items, skipped) = recover_array(&text);
+    error!(
+        path = %path.display(),
+        %backup,
+        error = %err,
+        recovered = items.len(),
+        skipped,
+        "store: data file is corrupt; starting with what could be recovered"
+    );
+    Ok(items)
+}
+
+/// Reads a data file for [`Store::check`], recording a problem for each
+/// entry that doesn't deserialize as `T` (with the reason, e.g. a bad
+/// timestamp) and returning the rest with their positions in the file. A
+/// missing file has no entries.
+fn check_entries<T: DeserializeOwned>(
+    path: &Path,
+    problems: &mut Vec<String>,
+) -> Result<Vec<(usize, T)>> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let values: Vec<serde_json::Value> = match serde_json::from_slice(&bytes) {
+        Ok(values) => values,
+        Err(e) => {
+            problems.push(format!("{} is not a valid JSON array: {}", name, e));
+            return Ok(Vec::new());
+        }
+    };
+    let mut items = Vec::with_capacity(values.len());
+    for (i, value) in values.into_iter().enumerate() {
+        match serde_json::from_value(value) {
+            Ok(item) => items.push((i, item)),
+            Err(e) => problems.push(format!("{} entry #{} is invalid: {}", name, i, e)),
+        }
+    }
+    Ok(items)
+}
+
+/// Like [`check_entries`] for a JSONL file, numbering lines from 1.
+fn check_lines(path: &Path, problems: &mut Vec<String>) -> Result<Vec<(usize, StoredMessage)>> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut items = Vec::new();
+    for (i, line) in std::io::BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(item) => items.push((i + 1, item)),
+            Err(e) => problems.push(format!("{} line {} is invalid: {}", name, i + 1, e)),
+        }
+    }
+    Ok(items)
+}
+
+/// Merges `imported` (sorted by timestamp) into `existing` by timestamp,
+/// leaving `existing` in its own order: stored messages are kept by
+/// sequence number, which timestamps needn't agree with.
+fn merge_by_time(existing: Vec<StoredMessage>, imported: Vec<StoredMessage>) -> Vec<StoredMessage> {
+    let mut merged = Vec::with_capacity(existing.len() + imported.len());
+    let mut imported = imported.into_iter().peekable();
+    for m in existing {
+        while let Some(older) = imported.next_if(|i| i.timestamp < m.timestamp) {
+            merged.push(older);
+        }
+        merged.push(m);
+    }
+    merged.extend(imported);
+    merged
+}
+
+/// Parses array elements one at a time until the input runs out or stops
+/// being valid JSON. Returns the elements that deserialized as `T` and how
+/// many valid JSON values didn't.
+fn recover_array<T: DeserializeOwned>(text: &str) -> (Vec<T>, usize) {
+    let mut items = Vec::new();
+    let mut skipped = 0;
+    let mut rest = text.trim_start();
+    rest = rest.strip_prefix('[').unwrap_or(rest);
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+        if rest.is_empty() || rest.starts_with(']') {
+            break;
+        }
+        let mut values = serde_json::Deserializer::from_str(rest).into_iter::<serde_json::Value>();
+        match values.next() {
+            Some(Ok(value)) => {
+                match serde_json::from_value(value) {
+                    Ok(item) => items.push(item),
+                    Err(_) => skipped += 1,
+                }
+                rest = &rest[values.byte_offset()..];
+            }
+            _ => break,
+        }
+    }
+    (items, skipped)
+}
+
+fn encode_json(v: &impl Serialize, pretty: bool) -> Result<String> {
+    Ok(if pretty {
+        serde_json::to_string_pretty(v)?
+    } else {
+        serde_json::to_string(v)?
+    })
+}
+
+/// Replaces `dir/name` atomically: the data goes to a temporary file in the
+/// same directory, which is then renamed over the target. A crash mid-write
+/// leaves at most a stray `.<name>.tmp`, which the next write overwrites.
+/// With `fsync` the file reaches the disk before the rename; the caller
+/// syncs the directory to make the rename durable too.
+fn write_data(dir: &Path, name: &str, data: &str, fsync: bool) -> Result<()> {
+    let tmp_path = dir.join(format!(".{}.tmp", name));
+    let mut opts = File::options();
+    opts.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+    let mut file = opts.open(&tmp_path)?;
+    file.write_all(data.as_bytes())?;
+    if fsync {
+        file.sync_all()?;
+    }
+    drop(file);
+    fs::rename(&tmp_path, dir.join(name))?;
+    Ok(())
+}
+
+/// Makes a rename in `dir` durable.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Cursor, Read};
+    use std::sync::atomic::AtomicUsize;
+
+    use chrono::TimeZone;
+
+    use super::*;
+
+    /// A fresh directory under the system temp dir, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let name = format!(
+                "chat-store-test-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            );
+            let path = std::env::temp_dir().join(name);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    fn windowed(dir: &TempDir, memory_window: usize) -> Store {
+        let opts = StoreOptions {
+            memory_window,
+            ..StoreOptions::default()
+        };
+        Store::with_options(&dir.0, opts).unwrap()
+    }
+
+    /// The `n`th message, `n` seconds into the epoch.
+    fn message(n: u64) -> StoredMessage {
+        StoredMessage {
+            id: format!("m{}", n),
+            user_id: "u1".to_string(),
+            username: "alice".to_string(),
+            content: format!("message number {}", n),
+            timestamp: Utc.timestamp_opt(n as i64, 0).unwrap(),
+            kind: MessageKind::Chat,
+            entities: Vec::new(),
+            format: None,
+            room: None,
+            expires_at: None,
+            seq: n,
+        }
+    }
+
+    fn ids(messages: &[StoredMessage]) -> Vec<&str> {
+        messages.iter().map(|m| m.id.as_str()).collect()
+    }
+
+    fn jsonl(messages: &[StoredMessage]) -> String {
+        messages.iter().map(|m| serde_json::to_string(m).unwrap() + "\n").collect()
+    }
+
+    fn lines_in(path: PathBuf) -> usize {
+        fs::read_to_string(path).map_or(0, |s| s.lines().count())
+    }
+
+    #[test]
+    fn messages_beyond_the_window_are_paged_out() {
+        let dir = TempDir::new();
+        let mut store = windowed(&dir, 3);
+        for n in 1..=5 {
+            store.save_message(message(n)).unwrap();
+        }
+        assert_eq!(store.message_count(), 5);
+        assert_eq!(lines_in(dir.0.join(ARCHIVE_FILE)), 2);
+        let in_memory: Vec<StoredMessage> =
+            serde_json::from_str(&fs::read_to_string(dir.0.join("messages.json")).unwrap())
+                .unwrap();
+        assert_eq!(ids(&in_memory), ["m3", "m4", "m5"]);
+
+        drop(store);
+        let store = windowed(&dir, 3);
+        assert_eq!(store.message_count(), 5);
+        assert_eq!(ids(&store.get_history(0, false)), ["m1", "m2", "m3", "m4", "m5"]);
+    }
+
+    #[test]
+    fn history_and_search_reach_into_the_archive() {
+        let dir = TempDir::new();
+        let mut store = windowed(&dir, 2);
+        for n in 1..=6 {
+            store.save_message(message(n)).unwrap();
+        }
+        assert_eq!(ids(&store.get_history(3, false)), ["m4", "m5", "m6"]);
+        assert_eq!(ids(&store.get_history(5, false)), ["m2", "m3", "m4", "m5", "m6"]);
+        assert_eq!(store.get_messages_after("m1", false).map(|m| m.len()), Some(5));
+
+        let filter = SearchFilter {
+            query: Query::parse("number 1"),
+            username: String::new(),
+            from: None,
+            to: None,
+            include_system: false,
+        };
+        let found = store.search(&filter, 10, 0);
+        assert_eq!(found.total, 1);
+        assert_eq!(ids(&found.messages), ["m1"]);
+        assert_eq!(store.message_count_for("u1"), 6);
+    }
+
+    #[test]
+    fn search_pages_newest_first_with_the_full_total() {
+        let dir = TempDir::new();
+        let mut store = windowed(&dir, 3);
+        for n in 1..=8 {
+            store.save_message(message(n)).unwrap();
+        }
+        let filter = SearchFilter {
+            query: Query::parse("number"),
+            username: String::new(),
+            from: None,
+            to: None,
+            include_system: false,
+        };
+        let pages: Vec<_> = [0, 3, 6, 9]
+            .into_iter()
+            .map(|offset| store.search(&filter, 3, offset))
+            .collect();
+        assert!(pages.iter().all(|page| page.total == 8));
+        let pages: Vec<_> = pages.iter().map(|page| ids(&page.messages)).collect();
+        assert_eq!(pages[0], ["m8", "m7", "m6"]);
+        assert_eq!(pages[1], ["m5", "m4", "m3"]);
+        assert_eq!(pages[2], ["m2", "m1"]);
+        assert!(pages[3].is_empty());
+    }
+
+    #[test]
+    fn import_merges_with_the_archive() {
+        let dir = TempDir::new();
+        let mut store = windowed(&dir, 2);
+        for n in 3..=6 {
+            store.save_message(message(n)).unwrap();
+        }
+        let input = jsonl(&[message(1), message(3), message(7)]) + "not json\n";
+        let report = store.import_from_reader(Cursor::new(input)).unwrap();
+        assert_eq!((report.imported, report.duplicates, report.malformed), (2, 1, 1));
+
+        let all = ["m1", "m3", "m4", "m5", "m6", "m7"];
+        assert_eq!(ids(&store.get_history(0, false)), all);
+        drop(store);
+        let store = windowed(&dir, 2);
+        assert_eq!(ids(&store.get_history(0, false)), all);
+        assert_eq!(lines_in(dir.0.join(ARCHIVE_FILE)), 4);
+    }
+
+    #[test]
+    fn import_leaves_local_messages_in_sequence_order() {
+        let dir = TempDir::new();
+        let mut store = windowed(&dir, 10);
+        // Numbered in the order they were sent, though the clock went back.
+        store.save_message(StoredMessage { seq: 1, ..message(20) }).unwrap();
+        store.save_message(StoredMessage { seq: 2, ..message(10) }).unwrap();
+        let input = jsonl(&[message(25), message(5)]);
+        assert_eq!(store.import_from_reader(Cursor::new(input)).unwrap().imported, 2);
+
+        let expected = ["m5", "m20", "m10", "m25"];
+        assert_eq!(ids(&store.get_history(0, false)), expected);
+        drop(store);
+        assert_eq!(ids(&windowed(&dir, 10).get_history(0, false)), expected);
+    }
+
+    fn search_all(store: &Store) -> SearchResult {
+        let filter = SearchFilter {
+            query: Query::parse("number"),
+            username: String::new(),
+            from: None,
+            to: None,
+            include_system: false,
+        };
+        store.search(&filter, 100, 0)
+    }
+
+    #[test]
+    fn the_directory_pages_through_users_by_name() {
+        let dir = TempDir::new();
+        let mut store = windowed(&dir, 10);
+        for name in ["carol", "Alice", "bob"] {
+            store.register_user(name, "pw").unwrap();
+        }
+        let names = |page: &DirectoryResult| -> Vec<String> {
+            page.users.iter().map(|e| e.user.username.clone()).collect()
+        };
+        let first = store.list_users(2, 0);
+        assert_eq!((names(&first), first.total), (vec!["Alice".into(), "bob".into()], 3));
+        assert_eq!(names(&store.list_users(2, 2)), ["carol"]);
+        assert!(store.list_users(2, 5).users.is_empty());
+
+        let json = serde_json::to_string(&first).unwrap();
+        assert!(!json.contains("password"), "directory leaks a hash: {}", json);
+        assert!(first.users.iter().all(|e| !e.online));
+    }
+
+    #[test]
+    fn a_read_only_store_refuses_writes_and_leaves_the_files_alone() {
+        let dir = TempDir::new();
+        let mut store = windowed(&dir, 10);
+        let alice = store.register_user("alice", "pw").unwrap();
+        store.save_message(message(1)).unwrap();
+        store.set_read_only(true).unwrap();
+        let files = || fs::read_dir(&dir.0).unwrap().map(|e| fs::read(e.unwrap().path()).unwrap());
+        let before: Vec<_> = files().collect();
+
+        for err in [
+            store.save_message(message(2)).unwrap_err(),
+            store.register_user("bob", "pw").map(drop).unwrap_err(),
+            store.rename_user(&alice.id, "alicia").map(drop).unwrap_err(),
+            store.set_pinned("m1", true).map(drop).unwrap_err(),
+        ] {
+            assert_eq!(err.to_string(), "the store is read-only");
+        }
+        assert_eq!(files().collect::<Vec<_>>(), before);
+        assert!(store.get_message("m1").is_some());
+
+        store.set_read_only(false).unwrap();
+        store.save_message(message(3)).unwrap();
+        drop(store);
+        let store = windowed(&dir, 10);
+        assert!(store.get_message("m3").is_some(), "writing again failed");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn opening_read_only_changes_nothing_in_the_data_directory() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new();
+        let mut store = windowed(&dir, 10);
+        store.register_user("alice", "pw").unwrap();
+        for n in 1..=5 {
+            store.save_message(message(n)).unwrap();
+        }
+        drop(store);
+        // Everything a normal open would fix up: a lock file to create,
+        // loose modes, a corrupt file to back up and a smaller window.
+        fs::remove_file(dir.0.join(".lock")).unwrap();
+        fs::write(dir.0.join("offline.json"), "[{\"id\":").unwrap();
+        for entry in fs::read_dir(&dir.0).unwrap() {
+            fs::set_permissions(entry.unwrap().path(), fs::Permissions::from_mode(0o644)).unwrap();
+        }
+        let snapshot = || {
+            let mut files: Vec<_> = fs::read_dir(&dir.0)
+                .unwrap()
+                .map(|e| {
+                    let path = e.unwrap().path();
+                    let mode = fs::metadata(&path).unwrap().permissions().mode();
+                    (path.file_name().unwrap().to_owned(), mode, fs::read(&path).unwrap())
+                })
+                .collect();
+            files.sort();
+            files
+        };
+        let before = snapshot();
+
+        let opts = StoreOptions {
+            memory_window: 2,
+            read_only: true,
+            ..StoreOptions::default()
+        };
+        let mut store = Store::with_options(&dir.0, opts).unwrap();
+        assert!(store.get_user("alice").is_some());
+        assert_eq!(store.get_history(0, false).len(), 5);
+        let err = store.save_message(message(6)).unwrap_err();
+        assert_eq!(err.to_string(), "the store is read-only");
+        assert_eq!(snapshot(), before);
+        assert!(!dir.0.join(".lock").exists());
+
+        // Becoming writable takes the lock like a normal open would.
+        store.set_read_only(false).unwrap();
+        assert!(dir.0.join(".lock").exists());
+        assert!(Store::new(&dir.0).is_err(), "a second store got the lock");
+
+        let missing = dir.0.join("missing");
+        let store = Store::with_options(&missing, opts).unwrap();
+        assert_eq!(store.user_count(), 0);
+        assert!(!missing.exists());
+    }
+
+    #[test]
+    fn messages_saved_out_of_order_are_kept_in_seq_order() {
+        let dir = TempDir::new();
+        let mut store = windowed(&dir, 10);
+        assert_eq!(store.last_seq(), 0);
+        for n in [2, 4, 1, 3] {
+            store.save_message(message(n)).unwrap();
+        }
+        // Unnumbered messages, from before sequence numbers, go last.
+        store.save_message(StoredMessage { seq: 0, ..message(9) }).unwrap();
+        store.save_message(message(5)).unwrap();
+        assert_eq!(ids(&store.get_history(0, false)), ["m1", "m2", "m3", "m4", "m9", "m5"]);
+        assert_eq!(store.last_seq(), 5);
+
+        drop(store);
+        let store = windowed(&dir, 2);
+        assert_eq!(store.last_seq(), 5);
+    }
+
+    #[test]
+    fn expired_messages_are_hidden_then_removed_with_their_pins() {
+        let dir = TempDir::new();
+        let mut store = windowed(&dir, 3);
+        let now = Utc::now();
+        for n in 1..=6 {
+            let expires_at = match n {
+                1 | 5 => Some(now - chrono::Duration::seconds(1)),
+                2 => Some(now + chrono::Duration::hours(1)),
+                _ => None,
+            };
+            store.save_message(StoredMessage { expires_at, ..message(n) }).unwrap();
+        }
+        store.set_pinned("m5", true).unwrap();
+        store.set_pinned("m2", true).unwrap();
+
+        // Hidden from reads even before the sweep gets to them.
+        let live = ["m2", "m3", "m4", "m6"];
+        assert_eq!(ids(&store.get_history(0, false)), live);
+        assert_eq!(search_all(&store).total, 4);
+        assert!(store.get_message("m5").is_none());
+        assert_eq!(ids(&store.pinned_messages()), ["m2"]);
+
+        let mut removed = store.remove_expired(now).unwrap();
+        removed.sort();
+        assert_eq!(removed, ["m1", "m5"]);
+        assert!(store.remove_expired(now).unwrap().is_empty());
+        drop(store);
+
+        let mut store = windowed(&dir, 3);
+        assert_eq!(ids(&store.get_history(0, false)), live);
+        assert_eq!(store.remove_expired(now + chrono::Duration::hours(2)).unwrap(), ["m2"]);
+        assert!(store.pinned_messages().is_empty());
+        assert_eq!(ids(&store.get_history(0, false)), ["m3", "m4", "m6"]);
+    }
+
+    #[test]
+    fn pruned_messages_are_gone_from_history_and_search() {
+        let dir = TempDir::new();
+        let mut store = windowed(&dir, 3);
+        for n in 1..=8 {
+            store.save_message(message(n)).unwrap();
+        }
+        // Reaches past the memory window into the archive.
+        assert_eq!(store.prune_older_than(Utc.timestamp_opt(4, 0).unwrap()).unwrap(), 3);
+        assert_eq!(ids(&store.get_history(0, false)), ["m4", "m5", "m6", "m7", "m8"]);
+        assert_eq!(ids(&search_all(&store).messages), ["m8", "m7", "m6", "m5", "m4"]);
+        assert!(store.get_messages_after("m1", false).is_none());
+
+        assert_eq!(store.trim_to(2).unwrap(), 3);
+        assert_eq!(store.trim_to(2).unwrap(), 0);
+        assert_eq!(ids(&store.get_history(0, false)), ["m7", "m8"]);
+        assert_eq!(search_all(&store).total, 2);
+        assert_eq!(store.message_count_for("u1"), 2);
+
+        drop(store);
+        let store = windowed(&dir, 3);
+        assert_eq!(ids(&store.get_history(0, false)), ["m7", "m8"]);
+    }
+
+    #[test]
+    fn purge_before_a_date_keeps_later_messages() {
+        let dir = TempDir::new();
+        let mut store = windowed(&dir, 2);
+        for n in 1..=5 {
+            store.save_message(message(n)).unwrap();
+        }
+        let cutoff = Utc.timestamp_opt(3, 0).unwrap();
+        assert_eq!(store.purge_messages(Some(cutoff)).unwrap(), 2);
+        assert_eq!(ids(&store.get_history(0, false)), ["m3", "m4", "m5"]);
+        assert_eq!(store.purge_messages(Some(cutoff)).unwrap(), 0);
+        drop(store);
+
+        let mut store = windowed(&dir, 2);
+        assert_eq!(ids(&store.get_history(0, false)), ["m3", "m4", "m5"]);
+        assert_eq!(store.purge_messages(None).unwrap(), 3);
+        assert_eq!(store.message_count(), 0);
+        drop(store);
+        assert_eq!(windowed(&dir, 2).message_count(), 0);
+        assert_eq!(lines_in(dir.0.join(ARCHIVE_FILE)), 0);
+    }
+
+    #[test]
+    fn export_then_import_round_trips() {
+        let dir = TempDir::new();
+        let mut store = windowed(&dir, 2);
+        for n in 1..=5 {
+            store.save_message(message(n)).unwrap();
+        }
+        let mut exported = Vec::new();
+        assert_eq!(store.export_to_writer(&mut exported).unwrap(), 5);
+
+        let fresh_dir = TempDir::new();
+        let mut fresh = windowed(&fresh_dir, 2);
+        let report = fresh.import_from_reader(Cursor::new(&exported)).unwrap();
+        assert_eq!((report.imported, report.duplicates, report.malformed), (5, 0, 0));
+        let mut again = Vec::new();
+        fresh.export_to_writer(&mut again).unwrap();
+        assert_eq!(String::from_utf8(again).unwrap(), String::from_utf8(exported).unwrap());
+    }
+
+    #[test]
+    fn user_export_leaves_out_password_hashes() {
+        let mut store = Store::new_in_memory();
+        let alice = store.register_user("alice", "correct horse").unwrap();
+        store.register_user("bob", "battery staple").unwrap();
+        let mut out = Vec::new();
+        assert_eq!(store.export_users_to_writer(&mut out).unwrap(), 2);
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(!out.contains(&alice.password_hash) && !out.contains("password"), "{}", out);
+        let users: Vec<PublicUser> =
+            out.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(users.iter().filter(|u| u.username == "alice").count(), 1);
+    }
+
+    #[test]
+    fn a_second_store_on_the_same_dir_is_refused() {
+        let dir = TempDir::new();
+        let first = Store::new(&dir.0).unwrap();
+        let err = Store::new(&dir.0).err().expect("second store opened");
+        assert!(err.to_string().contains("in use by another process"), "{}", err);
+        drop(first);
+        Store::new(&dir.0).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn data_files_are_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new();
+        let mut store = Store::new(&dir.0).unwrap();
+        store.register_user("alice", "correct horse").unwrap();
+        store.save_message(message(1)).unwrap();
+        for name in ["users.json", "messages.json"] {
+            let mode = fs::metadata(dir.0.join(name)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600, "{} is {:o}", name, mode);
+        }
+    }
+
+    /// The same calls against `store`, reported as comparable strings.
+    fn exercise(store: &mut Store) -> Vec<String> {
+        let alice = store.register_user("alice", "correct horse").unwrap();
+        for n in 1..=4 {
+            store.save_message(message(n)).unwrap();
+        }
+        let cutoff = Utc.timestamp_opt(2, 0).unwrap();
+        let same_user = |u: User| u.id == alice.id;
+        vec![
+            store.authenticate("ALICE", "correct horse").is_ok_and(same_user).to_string(),
+            store.authenticate("alice", "wrong").is_err().to_string(),
+            store.register_user("Alice", "again").is_err().to_string(),
+            ids(&store.get_history(2, false)).join(","),
+            ids(&search_all(store).messages).join(","),
+            store.purge_messages(Some(cutoff)).unwrap().to_string(),
+            store.message_count().to_string(),
+            store.user_count().to_string(),
+        ]
+    }
+
+    #[test]
+    fn in_memory_store_behaves_like_the_file_store() {
+        let dir = TempDir::new();
+        let on_disk = exercise(&mut Store::new(&dir.0).unwrap());
+        assert_eq!(exercise(&mut Store::new_in_memory()), on_disk);
+        assert_eq!(on_disk[3], "m3,m4");
+    }
+
+    #[test]
+    fn padded_and_mixed_case_names_collide() {
+        let mut store = Store::new_in_memory();
+        let admin = store.register_user("  Big   Admin ", "correct horse").unwrap();
+        assert_eq!(admin.username, "Big Admin");
+        for attempt in ["big admin", "BIG ADMIN", "bigadmin", " Big\tAdmin", "ｂｉｇ ａｄｍｉｎ"] {
+            let err = store.register_user(attempt, "correct horse").unwrap_err();
+            assert!(err.to_string().contains("already taken"), "{:?}: {}", attempt, err);
+        }
+        assert!(store.register_user("big\u{200b}admin", "correct horse").is_err());
+        assert!(store.register_user("big admin2", "correct horse").is_ok());
+        assert_eq!(store.get_user("BIGADMIN").unwrap().id, admin.id);
+    }
+
+    #[test]
+    fn display_names_keep_their_case() {
+        assert_eq!(display_username("  Alice \t Smith "), "Alice Smith");
+        assert_eq!(normalize_username("  Alice \t Smith "), "alicesmith");
+        assert_eq!(normalize_username("ＡＬＩＣＥ"), "alice");
+        assert!(check_username("guest-1").is_err());
+        assert!(check_username(" \u{200b} ").is_err());
+    }
+
+    fn profile_update(
+        display_name: Option<&str>,
+        status_text: Option<&str>,
+        avatar: Option<&str>,
+    ) -> UpdateProfilePayload {
+        UpdateProfilePayload {
+            display_name: display_name.map(str::to_string),
+            status_text: status_text.map(str::to_string),
+            avatar: avatar.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn profile_fields_update_one_at_a_time_and_persist() {
+        let dir = TempDir::new();
+        let mut store = windowed(&dir, 10);
+        let alice = store.register_user("alice", "correct horse").unwrap();
+
+        let updates = [
+            profile_update(Some(" Alice   Liddell "), None, None),
+            profile_update(None, Some("down the hole"), None),
+            profile_update(None, None, Some("🐇")),
+        ];
+        for update in &updates {
+            store.update_profile(&alice.id, update).unwrap();
+        }
+        let user = store.get_user("alice").unwrap();
+        assert_eq!(user.username, "alice");
+        assert_eq!(user.display_name.as_deref(), Some("Alice Liddell"));
+        assert_eq!(user.status_text.as_deref(), Some("down the hole"));
+        assert_eq!(user.avatar.as_deref(), Some("🐇"));
+
+        // Empty strings clear a field; bad values change nothing.
+        let user = store.update_profile(&alice.id, &profile_update(None, Some(""), None)).unwrap();
+        assert_eq!(user.status_text, None);
+        for bad in ["ab", "a", "🐇🐇"] {
+            let err = store.update_profile(&alice.id, &profile_update(Some("X"), None, Some(bad)));
+            assert!(err.is_err(), "avatar {:?} was accepted", bad);
+        }
+        let long = "x".repeat(MAX_DISPLAY_NAME + 1);
+        assert!(store.update_profile(&alice.id, &profile_update(Some(&long), None, None)).is_err());
+
+        drop(store);
+        let store = windowed(&dir, 10);
+        let user = store.get_user("alice").unwrap();
+        assert_eq!(user.display_name.as_deref(), Some("Alice Liddell"));
+        assert_eq!((user.status_text, user.avatar.as_deref()), (None, Some("🐇")));
+    }
+
+    #[test]
+    fn compact_files_are_smaller_and_load_the_same() {
+        let mut sizes = Vec::new();
+        for pretty in [false, true] {
+            let dir = TempDir::new();
+            let opts = StoreOptions {
+                pretty,
+                ..StoreOptions::default()
+            };
+            let mut store = Store::with_options(&dir.0, opts).unwrap();
+            store.register_user("alice", "correct horse").unwrap();
+            for n in 1..=20 {
+                store.save_message(message(n)).unwrap();
+            }
+            drop(store);
+            let size = |name| fs::metadata(dir.0.join(name)).unwrap().len();
+            sizes.push((size("messages.json"), size("users.json")));
+            let store = Store::with_options(&dir.0, opts).unwrap();
+            assert_eq!(store.message_count(), 20);
+            assert!(store.get_user("alice").is_some());
+        }
+        let [(compact_messages, compact_users), (pretty_messages, pretty_users)] = sizes[..] else {
+            unreachable!()
+        };
+        assert!(compact_messages * 10 < pretty_messages * 9, "sizes: {:?}", sizes);
+        assert!(compact_users < pretty_users, "sizes: {:?}", sizes);
+    }
+
+    fn with_fsync(dir: &TempDir, fsync: FsyncPolicy) -> Store {
+        let opts = StoreOptions {
+            fsync,
+            ..StoreOptions::default()
+        };
+        Store::with_options(&dir.0, opts).unwrap()
+    }
+
+    fn on_disk(dir: &TempDir, id: &str) -> bool {
+        let messages = fs::read_to_string(dir.0.join("messages.json")).unwrap_or_default();
+        messages.contains(&format!("\"{}\"", id))
+    }
+
+    #[test]
+    fn always_and_never_write_every_change() {
+        for fsync in [FsyncPolicy::Always, FsyncPolicy::Never] {
+            let dir = TempDir::new();
+            let mut store = with_fsync(&dir, fsync);
+            store.save_message(message(1)).unwrap();
+            assert!(on_disk(&dir, "m1"), "{} left m1 unwritten", fsync);
+        }
+    }
+
+    #[test]
+    fn interval_writes_changes_together_on_flush_and_drop() {
+        let dir = TempDir::new();
+        let mut store = with_fsync(&dir, FsyncPolicy::Interval);
+        for n in 1..=3 {
+            store.save_message(message(n)).unwrap();
+        }
+        store.register_user("alice", "correct horse").unwrap();
+        assert!(!on_disk(&dir, "m1"));
+        assert!(!dir.0.join("users.json").exists());
+
+        store.flush().unwrap();
+        assert!(["m1", "m2", "m3"].iter().all(|id| on_disk(&dir, id)));
+        assert!(fs::read_to_string(dir.0.join("users.json")).unwrap().contains("alice"));
+
+        store.save_message(message(4)).unwrap();
+        assert!(!on_disk(&dir, "m4"));
+        drop(store);
+        assert!(on_disk(&dir, "m4"));
+        let store = with_fsync(&dir, FsyncPolicy::Interval);
+        assert_eq!(store.message_count(), 4);
+    }
+
+    #[test]
+    fn fsync_policies_parse_and_print() {
+        for fsync in [FsyncPolicy::Always, FsyncPolicy::Interval, FsyncPolicy::Never] {
+            assert_eq!(fsync.to_string().parse(), Ok(fsync));
+        }
+        assert!("sometimes".parse::<FsyncPolicy>().is_err());
+    }
+
+    #[test]
+    fn a_write_cut_short_leaves_the_real_file_whole() {
+        let dir = TempDir::new();
+        let mut store = windowed(&dir, 10);
+        for n in 1..=3 {
+            store.save_message(message(n)).unwrap();
+        }
+        drop(store);
+        let tmp = dir.0.join(".messages.json.tmp");
+        assert!(!tmp.exists());
+        let before = fs::read(dir.0.join("messages.json")).unwrap();
+
+        // A crash partway through the next write leaves only the temp file.
+        fs::write(&tmp, &before[..before.len() / 2]).unwrap();
+        let mut store = windowed(&dir, 10);
+        assert_eq!(fs::read(dir.0.join("messages.json")).unwrap(), before);
+        assert_eq!(ids(&store.get_history(10, false)), ["m1", "m2", "m3"]);
+
+        store.save_message(message(4)).unwrap();
+        assert!(!tmp.exists());
+        drop(store);
+        assert_eq!(windowed(&dir, 10).message_count(), 4);
+    }
+
+    #[test]
+    fn a_truncated_file_is_backed_up_and_partly_recovered() {
+        let dir = TempDir::new();
+        let mut store = windowed(&dir, 10);
+        for n in 1..=4 {
+            store.save_message(message(n)).unwrap();
+        }
+        drop(store);
+        let path = dir.0.join("messages.json");
+        let whole = fs::read_to_string(&path).unwrap();
+        let cut = &whole[..whole.find("message number 4").unwrap()];
+        fs::write(&path, cut).unwrap();
+
+        let strict = StoreOptions {
+            strict: true,
+            ..StoreOptions::default()
+        };
+        let err = Store::with_options(&dir.0, strict).err().expect("strict store opened");
+        assert!(err.to_string().contains("--strict"), "unexpected: {}", err);
+
+        let store = windowed(&dir, 10);
+        assert_eq!(ids(&store.get_history(10, false)), ["m1", "m2", "m3"]);
+        let backups: Vec<_> = fs::read_dir(&dir.0)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.starts_with("messages.json.corrupt."))
+            .collect();
+        assert_eq!(backups.len(), 1, "files: {:?}", backups);
+        assert_eq!(fs::read_to_string(dir.0.join(&backups[0])).unwrap(), cut);
+    }
+
+    #[test]
+    fn recovery_skips_entries_of_the_wrong_shape() {
+        let text = r#"[{"n": 1}, {"other": true}, {"n": 2}, {"n": "#;
+        #[derive(Deserialize)]
+        struct Entry {
+            n: u32,
+        }
+        let (items, skipped) = recover_array::<Entry>(text);
+        assert_eq!(items.iter().map(|e| e.n).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(skipped, 1);
+        assert_eq!(recover_array::<Entry>("not json").0.len(), 0);
+    }
+
+    #[test]
+    fn recent_users_are_those_seen_since_the_cutoff() {
+        let dir = TempDir::new();
+        let mut store = windowed(&dir, 10);
+        let alice = store.register_user("alice", "correct horse").unwrap();
+        let bob = store.register_user("bob", "correct horse").unwrap();
+        store.register_user("carol", "correct horse").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let cutoff = Utc::now();
+        assert!(store.recent_users(cutoff).is_empty());
+
+        store.touch_last_seen(&alice.id).unwrap();
+        let mut later = message(1);
+        later.user_id = bob.id;
+        later.timestamp = cutoff + chrono::Duration::minutes(5);
+        store.save_message(later).unwrap();
+
+        let recent = store.recent_users(cutoff);
+        let names: Vec<_> = recent.iter().map(|r| r.user.username.as_str()).collect();
+        assert_eq!(names, ["bob", "alice"]);
+        assert_eq!(recent[0].last_seen, cutoff + chrono::Duration::minutes(5));
+    }
+
+    #[test]
+    fn check_reports_each_inconsistency() {
+        let dir = TempDir::new();
+        let mut store = windowed(&dir, 10);
+        let alice = store.register_user("alice", "correct horse").unwrap();
+        store.register_user("bob", "correct horse").unwrap();
+        for n in 1..=2 {
+            let mut msg = message(n);
+            msg.user_id = alice.id.clone();
+            store.save_message(msg).unwrap();
+        }
+        drop(store);
+        let report = Store::check(&dir.0).unwrap();
+        assert_eq!((report.users, report.messages), (2, 2));
+        assert!(report.problems.is_empty(), "problems: {:?}", report.problems);
+
+        let read = |name: &str| -> Vec<serde_json::Value> {
+            serde_json::from_str(&fs::read_to_string(dir.0.join(name)).unwrap()).unwrap()
+        };
+        let write = |name: &str, v: &[serde_json::Value]| {
+            fs::write(dir.0.join(name), serde_json::to_string(v).unwrap()).unwrap();
+        };
+        let mut users = read("users.json");
+        let mut messages = read("messages.json");
+        // users.json isn't in any particular order.
+        let (a, b) = if users[0]["username"] == "alice" { (0, 1) } else { (1, 0) };
+        users[b]["id"] = alice.id.as_str().into();
+        users[b]["password_hash"] = "not a hash".into();
+        let mut shouting = users[a].clone();
+        shouting["id"] = "u-shout".into();
+        shouting["username"] = "ALICE".into();
+        users.push(shouting);
+        messages[0]["id"] = "".into();
+        messages[1]["user_id"] = "ghost".into();
+        let mut bad_time = messages[1].clone();
+        bad_time["timestamp"] = "yesterday".into();
+        messages.push(bad_time);
+        write("users.json", &users);
+        write("messages.json", &messages);
+
+        let report = Store::check(&dir.0).unwrap();
+        let expected = [
+            format!("user id {} is used more than once", alice.id),
+            "usernames \"alice\" and \"ALICE\" collide after normalization".to_string(),
+            "user \"bob\" has a malformed password hash".to_string(),
+            "messages.json entry #2 is invalid".to_string(),
+            "messages.json entry #0 has an empty id".to_string(),
+            "messages.json entry #1 is from unknown user id \"ghost\"".to_string(),
+        ];
+        for problem in &expected {
+            assert!(
+                report.problems.iter().any(|p| p.starts_with(problem.as_str())),
+                "{:?} missing from {:#?}",
+                problem,
+                report.problems
+            );
+        }
+        assert_eq!(report.problems.len(), expected.len(), "{:#?}", report.problems);
+    }
+
+    fn direct(n: usize) -> DirectMessage {
+        DirectMessage {
+            id: format!("d{}", n),
+            user_id: "u1".to_string(),
+            username: "alice".to_string(),
+            to: "bob".to_string(),
+            content: format!("direct {}", n),
+            timestamp: Utc.timestamp_opt(n as i64, 0).unwrap(),
+            entities: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn offline_queues_are_capped_ordered_and_kept() {
+        let dir = TempDir::new();
+        let mut store = windowed(&dir, 10);
+        for n in 0..OFFLINE_QUEUE_MAX {
+            store.enqueue_offline("bob", direct(n)).unwrap();
+        }
+        let err = store.enqueue_offline("bob", direct(OFFLINE_QUEUE_MAX)).unwrap_err();
+        assert_eq!(err.to_string(), "bob has too many undelivered messages");
+        store.enqueue_offline("carol", direct(0)).unwrap();
+
+        store.dequeue_offline("bob", OFFLINE_QUEUE_MAX - 2).unwrap();
+        drop(store);
+        let mut store = windowed(&dir, 10);
+        let left: Vec<_> = store.offline_queue("bob").into_iter().map(|m| m.id).collect();
+        assert_eq!(left, ["d98", "d99"]);
+        store.dequeue_offline("bob", 5).unwrap();
+        assert!(store.offline_queue("bob").is_empty());
+        assert_eq!(store.offline_queue("carol").len(), 1);
+    }
+
+    #[test]
+    fn pins_are_capped_ordered_and_kept() {
+        let dir = TempDir::new();
+        let mut store = windowed(&dir, 10);
+        for n in 0..=MAX_PINNED as u64 {
+            store.save_message(message(n)).unwrap();
+        }
+        assert!(store.set_pinned("m3", true).unwrap());
+        assert!(!store.set_pinned("m3", true).unwrap(), "pinning twice changed something");
+        assert!(store.set_pinned("m1", true).unwrap());
+        assert!(store.set_pinned("missing", true).is_err());
+        assert!(!store.set_pinned("m2", false).unwrap(), "unpinning an unpinned message");
+        assert_eq!(ids(&store.pinned_messages()), ["m3", "m1"]);
+
+        drop(store);
+        let mut store = windowed(&dir, 10);
+        assert_eq!(ids(&store.pinned_messages()), ["m3", "m1"]);
+        assert!(store.set_pinned("m3", false).unwrap());
+        assert_eq!(ids(&store.pinned_messages()), ["m1"]);
+
+        for n in 2..=MAX_PINNED as u64 {
+            store.set_pinned(&format!("m{}", n), true).unwrap();
+        }
+        let err = store.set_pinned("m0", true).unwrap_err();
+        assert_eq!(err.to_string(), format!("at most {} messages can be pinned", MAX_PINNED));
+    }
+
+    fn topic(room: &str, text: &str) -> RoomTopic {
+        RoomTopic {
+            room: room.to_string(),
+            topic: text.to_string(),
+            set_by: "root".to_string(),
+            set_at: Utc.timestamp_opt(0, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn topics_are_replaced_cleared_and_kept() {
+        let dir = TempDir::new();
+        let mut store = windowed(&dir, 10);
+        assert!(store.topic("ops").is_none());
+        store.set_topic(topic("ops", "deploys only")).unwrap();
+        store.set_topic(topic("ops", "deploys and incidents")).unwrap();
+        store.set_topic(topic("random", "anything goes")).unwrap();
+        assert_eq!(store.topic("ops").unwrap().topic, "deploys and incidents");
+
+        drop(store);
+        let mut store = windowed(&dir, 10);
+        assert_eq!(store.topic("ops").unwrap().topic, "deploys and incidents");
+        assert_eq!(store.topic("random").unwrap().set_by, "root");
+        store.set_topic(topic("ops", "")).unwrap();
+        assert!(store.topic("ops").is_none());
+        assert!(store.topic("random").is_some());
+    }
+
+    /// Yields an error once the data before it is read.
+    struct Broken;
+
+    impl Read for Broken {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("disk on fire"))
+        }
+    }
+
+    #[test]
+    fn failed_import_changes_nothing() {
+        let dir = TempDir::new();
+        let mut store = windowed(&dir, 2);
+        for n in 3..=6 {
+            store.save_message(message(n)).unwrap();
+        }
+        let input = Cursor::new(jsonl(&[message(1), message(7)])).chain(Broken);
+        assert!(store.import_from_reader(BufReader::new(input)).is_err());
+
+        let all = ["m3", "m4", "m5", "m6"];
+        assert_eq!(ids(&store.get_history(0, false)), all);
+        assert_eq!(store.message_count(), 4);
+        drop(store);
+        let store = windowed(&dir, 2);
+        assert_eq!(ids(&store.get_history(0, false)), all);
+    }
+}
@@ -0,0 +1,230 @@
+//! Drives a server through the `chat::client` library.
+
+mod common;
+
+use std::time::Duration;
+
+use chat::client::{Client, ConnectOptions};
+use chat::protocol::{Framing, MessageType, Packet, ResponsePayload, SearchPayload};
+use chat::server::ServerConfig;
+use common::{spawn_server, spawn_test_server, spawn_test_server_with};
+use serde_json::json;
+use tokio::sync::mpsc;
+
+const PASSWORD: &str = "correct horse";
+
+/// The next packet of type `kind` on `rx`, skipping others.
+async fn next_of(rx: &mut mpsc::Receiver<Packet>, kind: MessageType) -> Packet {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let pkt = rx.recv().await.expect("connection closed");
+            if pkt.msg_type == kind {
+                return pkt;
+            }
+        }
+    })
+    .await
+    .expect("timed out waiting for a packet")
+}
+
+#[tokio::test]
+async fn register_login_chat_history_search() {
+    let addr = spawn_test_server().await;
+
+    let alice = Client::connect(addr).await.unwrap();
+    let registered = alice.register("alice", PASSWORD).await.unwrap();
+    assert_eq!(registered.username, "alice");
+
+    let bob = Client::connect(addr).await.unwrap();
+    bob.register("bob", PASSWORD).await.unwrap();
+    let mut bob_events = bob.subscribe();
+
+    let again = Client::connect(addr).await.unwrap();
+    let logged_in = again.login("alice", PASSWORD).await.unwrap();
+    assert_eq!(logged_in.user_id, registered.user_id);
+    assert!(again.login("alice", "wrong").await.is_err());
+
+    alice.send_chat("hello from the library").await.unwrap();
+    let broadcast = next_of(&mut bob_events, MessageType::Broadcast).await;
+    assert_eq!(broadcast.payload["content"], "hello from the library");
+    assert_eq!(broadcast.payload["username"], "alice");
+
+    // Stored by the worker pool after the broadcast.
+    let mut history = Vec::new();
+    for _ in 0..100 {
+        history = bob.history(10).await.unwrap();
+        if !history.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].content, "hello from the library");
+
+    let query: SearchPayload = serde_json::from_value(json!({ "query": "library" })).unwrap();
+    let found = bob.search(query).await.unwrap();
+    assert_eq!(found.total, 1);
+    assert_eq!(found.messages[0].username, "alice");
+}
+
+#[tokio::test]
+async fn rejected_chat_goes_to_subscribers_not_to_a_request() {
+    let addr = spawn_test_server().await;
+    let client = Client::connect(addr).await.unwrap();
+    client.register("alice", PASSWORD).await.unwrap();
+    let mut events = client.subscribe();
+
+    // The server answers the empty message with an error and `whoami`
+    // with the session; each must reach the right place.
+    client.send_chat("\u{1b}").await.unwrap();
+    let session = client.whoami().await.unwrap();
+    assert_eq!(session.username, "alice");
+    let users = client.users().await.unwrap();
+    assert_eq!(users.len(), 1);
+
+    let rejection = next_of(&mut events, MessageType::Response).await;
+    assert_eq!(rejection.id, None);
+    let rejection: ResponsePayload = serde_json::from_value(rejection.payload).unwrap();
+    assert!(!rejection.success);
+    assert!(rejection.message.contains("empty"), "unexpected error: {}", rejection.message);
+}
+
+#[tokio::test]
+async fn concurrent_requests_get_their_own_responses() {
+    let addr = spawn_test_server().await;
+    let client = Client::connect(addr).await.unwrap();
+    client.register("alice", PASSWORD).await.unwrap();
+
+    let (session, users, history) =
+        tokio::join!(client.whoami(), client.users(), client.history(5));
+    assert_eq!(session.unwrap().username, "alice");
+    assert_eq!(users.unwrap()[0].username, "alice");
+    assert!(history.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn compression_works_with_length_framing_and_falls_back() {
+    let addr = spawn_test_server_with(ServerConfig {
+        ephemeral: true,
+        framing: Framing::Length,
+        compression: true,
+        ..ServerConfig::default()
+    })
+    .await;
+    let opts = ConnectOptions {
+        framing: Framing::Length,
+        compress: true,
+        ..ConnectOptions::default()
+    };
+    let client = Client::connect_with_options(addr, opts).await.unwrap();
+    client.register("alice", PASSWORD).await.unwrap();
+    let mut events = client.subscribe();
+    client.send_chat("line one\nline two").await.unwrap();
+    let broadcast = next_of(&mut events, MessageType::Broadcast).await;
+    assert_eq!(broadcast.payload["content"], "line one\nline two");
+
+    // A server without --compress declines; the client carries on plain.
+    let plain = spawn_test_server().await;
+    let opts = ConnectOptions {
+        compress: true,
+        ..ConnectOptions::default()
+    };
+    let client = Client::connect_with_options(plain, opts).await.unwrap();
+    assert_eq!(client.register("bob", PASSWORD).await.unwrap().username, "bob");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn register_and_login_over_a_unix_socket() {
+    let path = std::env::temp_dir().join(format!("chat-test-{}.sock", std::process::id()));
+    let (server, _) = spawn_server(ServerConfig {
+        ephemeral: true,
+        ..ServerConfig::default()
+    })
+    .await;
+    let listening = path.clone();
+    let srv = server.clone();
+    tokio::spawn(async move { srv.listen_unix(&listening).await });
+    let addr = format!("unix:{}", path.display());
+    let mut alice = None;
+    for _ in 0..100 {
+        if let Ok(client) = Client::connect_addr(&addr, ConnectOptions::default()).await {
+            alice = Some(client);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let alice = alice.expect("the unix socket never came up");
+    let registered = alice.register("alice", PASSWORD).await.unwrap();
+
+    let again = Client::connect_addr(&addr, ConnectOptions::default()).await.unwrap();
+    assert_eq!(again.login("alice", PASSWORD).await.unwrap().user_id, registered.user_id);
+
+    // A live socket isn't taken over by a second listener.
+    let err = server.clone().listen_unix(&path).await.unwrap_err();
+    assert!(err.to_string().contains("already in use"), "unexpected: {}", err);
+    std::fs::remove_file(&path).ok();
+}
+
+#[tokio::test]
+async fn a_same_host_server_has_no_clock_offset() {
+    let client = Client::connect(spawn_test_server().await).await.unwrap();
+    let offset = client.clock_offset().await.unwrap();
+    assert!(offset.abs() < chrono::TimeDelta::milliseconds(500), "offset: {}", offset);
+}
+
+/// The next value a callback passed to `rx`.
+async fn next_event<T>(rx: &mut mpsc::UnboundedReceiver<T>) -> T {
+    tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("timed out waiting for a callback")
+        .expect("the callback task ended")
+}
+
+#[tokio::test]
+async fn each_callback_gets_only_its_own_events() {
+    let addr = spawn_test_server_with(ServerConfig {
+        ephemeral: true,
+        presence_debounce: None,
+        ..ServerConfig::default()
+    })
+    .await;
+    let alice = Client::connect(addr).await.unwrap();
+    alice.register("alice", PASSWORD).await.unwrap();
+    let (chat_tx, mut chats) = mpsc::unbounded_channel();
+    alice.on_chat(move |msg| chat_tx.send(msg.content).unwrap());
+    let (system_tx, mut notices) = mpsc::unbounded_channel();
+    alice.on_system(move |text| system_tx.send(text).unwrap());
+    let (join_tx, mut joins) = mpsc::unbounded_channel();
+    alice.on_user_join(move |user| join_tx.send(user.username).unwrap());
+    let (leave_tx, mut leaves) = mpsc::unbounded_channel();
+    alice.on_user_leave(move |user| leave_tx.send(user.username).unwrap());
+    let (direct_tx, mut directs) = mpsc::unbounded_channel();
+    alice.on_direct(move |dm| direct_tx.send((dm.username, dm.content)).unwrap());
+    let (deleted_tx, mut deletions) = mpsc::unbounded_channel();
+    alice.on_deleted(move |ids| deleted_tx.send(ids).unwrap());
+
+    let bob = Client::connect(addr).await.unwrap();
+    bob.register("bob", PASSWORD).await.unwrap();
+    assert_eq!(next_event(&mut joins).await, "bob");
+    assert_eq!(next_event(&mut notices).await, "bob joined the chat");
+
+    bob.send_chat("plain").await.unwrap();
+    bob.send(MessageType::Chat, json!({ "content": "fleeting", "ttl_secs": 1 })).await.unwrap();
+    assert_eq!(next_event(&mut chats).await, "plain");
+    assert_eq!(next_event(&mut chats).await, "fleeting");
+    bob.send_direct("alice", "psst").await.unwrap();
+    assert_eq!(next_event(&mut directs).await, ("bob".to_string(), "psst".to_string()));
+    let expired = next_event(&mut deletions).await;
+    assert_eq!(expired.len(), 1);
+
+    bob.quit().await.unwrap();
+    assert_eq!(next_event(&mut leaves).await, "bob");
+    assert_eq!(next_event(&mut notices).await, "bob left the chat");
+    // Nothing crossed over: the chat, direct and deleted packets reached
+    // only their own callbacks.
+    assert!(chats.try_recv().is_err());
+    assert!(notices.try_recv().is_err());
+    assert!(joins.try_recv().is_err());
+    assert!(directs.try_recv().is_err());
+}
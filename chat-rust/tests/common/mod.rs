@@ -0,0 +1,144 @@
+//! Helpers for the integration tests: a server running in-process on an
+//! ephemeral port, and a client that speaks the line-delimited JSON
+//! protocol to it over a real TCP connection.
+
+#![allow(dead_code)] // each test file uses its own share of these
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chat::server::{Server, ServerConfig};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Longest a test waits for a packet before failing.
+pub const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts an ephemeral (in-memory) server with the default settings and
+/// returns its address.
+pub async fn spawn_test_server() -> SocketAddr {
+    spawn_test_server_with(ServerConfig {
+        ephemeral: true,
+        ..ServerConfig::default()
+    })
+    .await
+}
+
+/// Starts a server with `config` on a free local port and returns its
+/// address. It runs until the test's runtime shuts down.
+pub async fn spawn_test_server_with(config: ServerConfig) -> SocketAddr {
+    spawn_server(config).await.1
+}
+
+/// Like [`spawn_test_server_with`], but also hands back the server, for
+/// tests that attach more listeners to it or look inside.
+pub async fn spawn_server(config: ServerConfig) -> (Arc<Server>, SocketAddr) {
+    spawn_built(Server::new(config).expect("failed to create server")).await
+}
+
+/// Like [`spawn_server`], for a server the test has already built, such as
+/// one with its own auth provider.
+pub async fn spawn_built(server: Server) -> (Arc<Server>, SocketAddr) {
+    let server = Arc::new(server);
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("failed to bind");
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(server.clone().serve(listener));
+    (server, addr)
+}
+
+pub struct TestClient {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+impl TestClient {
+    /// Connects to `addr` and reads the welcome notice.
+    pub async fn connect(addr: SocketAddr) -> Self {
+        let stream = TcpStream::connect(addr).await.expect("failed to connect");
+        let (reader, writer) = stream.into_split();
+        let mut client = Self {
+            lines: BufReader::new(reader).lines(),
+            writer,
+        };
+        let welcome = client.recv_packet().await;
+        assert_eq!(welcome["type"], "system", "expected a welcome notice, got {}", welcome);
+        client
+    }
+
+    /// Sends one packet of type `kind`.
+    pub async fn send(&mut self, kind: &str, payload: Value) {
+        let mut line = json!({ "type": kind, "payload": payload }).to_string();
+        line.push('\n');
+        self.writer.write_all(line.as_bytes()).await.expect("failed to send");
+    }
+
+    /// Writes `bytes` as they are, framed or not.
+    pub async fn send_raw(&mut self, bytes: &[u8]) {
+        self.writer.write_all(bytes).await.expect("failed to send");
+    }
+
+    /// Ends the stream to the server, leaving the read side open.
+    pub async fn finish(&mut self) {
+        self.writer.shutdown().await.expect("failed to shut down");
+    }
+
+    /// Waits for the server to close the connection, skipping any packets
+    /// it sends first.
+    pub async fn expect_closed(&mut self) {
+        loop {
+            let next = tokio::time::timeout(RECV_TIMEOUT, self.lines.next_line())
+                .await
+                .expect("timed out waiting for the connection to close");
+            match next {
+                Ok(Some(_)) => continue,
+                Ok(None) | Err(_) => return,
+            }
+        }
+    }
+
+    /// The next packet from the server. Panics if none arrives within
+    /// [`RECV_TIMEOUT`] or the connection closes.
+    pub async fn recv_packet(&mut self) -> Value {
+        let line = tokio::time::timeout(RECV_TIMEOUT, self.lines.next_line())
+            .await
+            .expect("timed out waiting for a packet")
+            .expect("failed to read")
+            .expect("connection closed");
+        serde_json::from_str(&line).unwrap_or_else(|e| panic!("bad packet {:?}: {}", line, e))
+    }
+
+    /// The payload of the next packet of type `kind`, skipping any others
+    /// (presence notices, broadcasts) that arrive first.
+    pub async fn recv_type(&mut self, kind: &str) -> Value {
+        loop {
+            let mut packet = self.recv_packet().await;
+            if packet["type"] == kind {
+                return packet["payload"].take();
+            }
+        }
+    }
+
+    /// Sends a request and returns the payload of its response.
+    pub async fn request(&mut self, kind: &str, payload: Value) -> Value {
+        self.send(kind, payload).await;
+        self.recv_type("response").await
+    }
+
+    /// Registers `username`, which also logs in, and fails the test if the
+    /// server refuses.
+    pub async fn register(&mut self, username: &str, password: &str) -> Value {
+        let payload = json!({ "username": username, "password": password });
+        let response = self.request("register", payload).await;
+        assert_eq!(response["success"], true, "register failed: {}", response);
+        response
+    }
+
+    /// Logs in as `username` and returns the response, successful or not.
+    pub async fn login(&mut self, username: &str, password: &str) -> Value {
+        let payload = json!({ "username": username, "password": password });
+        self.request("login", payload).await
+    }
+}
@@ -0,0 +1,93 @@
+//! The HTTP API against the same server's TCP protocol.
+
+mod common;
+
+use std::net::SocketAddr;
+
+use chat::server::{http, ServerConfig};
+use common::{spawn_server, TestClient, RECV_TIMEOUT};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const PASSWORD: &str = "correct horse";
+const TOKEN: &str = "s3cret";
+
+/// Starts a server with the HTTP API on another port; returns the TCP and
+/// HTTP addresses.
+async fn spawn_with_http() -> (SocketAddr, SocketAddr) {
+    let config = ServerConfig {
+        ephemeral: true,
+        ..ServerConfig::default()
+    };
+    let (server, addr) = spawn_server(config).await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let http_addr = listener.local_addr().unwrap();
+    tokio::spawn(http::serve_on(server, listener, TOKEN.to_string()));
+    (addr, http_addr)
+}
+
+/// `GET path` with `token`, returning the status and the body.
+async fn get(addr: SocketAddr, path: &str, token: Option<&str>) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let auth = token.map(|t| format!("Authorization: Bearer {}\r\n", t)).unwrap_or_default();
+    let request =
+        format!("GET {} HTTP/1.1\r\nHost: test\r\n{}Connection: close\r\n\r\n", path, auth);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut raw = String::new();
+    tokio::time::timeout(RECV_TIMEOUT, stream.read_to_string(&mut raw))
+        .await
+        .expect("timed out waiting for the HTTP response")
+        .unwrap();
+    let (head, body) = raw.split_once("\r\n\r\n").expect("no end of headers");
+    let status = head.split(' ').nth(1).and_then(|s| s.parse().ok()).expect("no status");
+    (status, body.to_string())
+}
+
+/// The JSON body of a successful `GET path`.
+async fn get_json(addr: SocketAddr, path: &str) -> Value {
+    let (status, body) = get(addr, path, Some(TOKEN)).await;
+    assert_eq!(status, 200, "GET {} failed: {}", path, body);
+    serde_json::from_str(&body).unwrap()
+}
+
+#[tokio::test]
+async fn http_routes_match_the_tcp_responses() {
+    let (addr, http_addr) = spawn_with_http().await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    alice.send("chat", json!({ "content": "hello over http" })).await;
+    alice.recv_type("broadcast").await;
+
+    // Stored by the worker pool after the broadcast.
+    let mut history = Value::Null;
+    for _ in 0..100 {
+        history = alice.request("history", json!({ "limit": 10 })).await["data"].take();
+        if history.as_array().is_some_and(|h| !h.is_empty()) {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(history[0]["content"], "hello over http");
+    assert_eq!(get_json(http_addr, "/history?limit=10").await, history);
+
+    let search = alice.request("search", json!({ "query": "http" })).await["data"].take();
+    assert_eq!(search["total"], 1);
+    assert_eq!(get_json(http_addr, "/search?query=http").await, search);
+    let by_user = get_json(http_addr, "/search?username=alice").await;
+    assert_eq!(by_user["messages"], search["messages"]);
+
+    let users = alice.request("users", json!({})).await["data"].take();
+    assert_eq!(users[0]["username"], "alice");
+    assert_eq!(get_json(http_addr, "/users").await, users);
+}
+
+#[tokio::test]
+async fn http_routes_need_the_bearer_token() {
+    let (_, http_addr) = spawn_with_http().await;
+    for path in ["/history", "/search?query=x", "/users"] {
+        assert_eq!(get(http_addr, path, None).await.0, 401, "{} let us in", path);
+        assert_eq!(get(http_addr, path, Some("guess")).await.0, 401, "{} let us in", path);
+    }
+    assert_eq!(get(http_addr, "/search", Some(TOKEN)).await.0, 400);
+}
@@ -0,0 +1,68 @@
+//! The Redis bridge against a real Redis. Needs the `redis-tests` feature
+//! and a server at `$REDIS_URL` (default `redis://127.0.0.1:6379`, no
+//! password): `cargo test --features redis-tests --test redis`.
+#![cfg(feature = "redis-tests")]
+
+mod common;
+
+use std::time::Duration;
+
+use chat::server::bridge::{self, BridgeConfig};
+use chat::server::ServerConfig;
+use common::{spawn_server, TestClient};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+const PASSWORD: &str = "correct horse";
+
+fn redis_url() -> String {
+    std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string())
+}
+
+/// Publishes `payload` on `channel` and returns how many subscribers got it.
+async fn publish(channel: &str, payload: &str) -> u64 {
+    let url = redis_url();
+    let host = url.trim_start_matches("redis://").split('/').next().unwrap().to_string();
+    let mut stream = TcpStream::connect(host).await.expect("no Redis at $REDIS_URL");
+    let mut command = String::from("*3\r\n$7\r\nPUBLISH\r\n");
+    for arg in [channel, payload] {
+        command += &format!("${}\r\n{}\r\n", arg.len(), arg);
+    }
+    stream.write_all(command.as_bytes()).await.unwrap();
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply).await.unwrap();
+    reply.trim_start_matches(':').trim_end().parse().expect("bad reply to PUBLISH")
+}
+
+#[tokio::test]
+async fn a_published_message_is_broadcast() {
+    let (server, addr) = spawn_server(ServerConfig {
+        ephemeral: true,
+        ..ServerConfig::default()
+    })
+    .await;
+    // A channel of its own, so runs sharing a Redis don't see each other.
+    let channel = format!("chat-test-{}", std::process::id());
+    let config = BridgeConfig {
+        url: redis_url(),
+        channel: channel.clone(),
+        name: "alerts".to_string(),
+    };
+    bridge::start(server, config).await.unwrap();
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+
+    // Nobody hears it until the bridge has subscribed.
+    let mut subscribed = false;
+    for _ in 0..100 {
+        if publish(&channel, "hello from redis").await > 0 {
+            subscribed = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(subscribed, "the bridge never subscribed");
+    let broadcast = alice.recv_type("broadcast").await;
+    assert_eq!(broadcast["username"], "alerts");
+    assert_eq!(broadcast["content"], "hello from redis");
+}
@@ -0,0 +1,2020 @@
+//! End-to-end tests against a server running in-process.
+
+mod common;
+
+use std::time::Duration;
+
+use async_compression::tokio::bufread::ZlibDecoder;
+use async_compression::tokio::write::ZlibEncoder;
+use chat::server::auth::{AuthFuture, AuthProvider};
+use chat::server::filter::FilterMode;
+use chat::server::sanitize::SanitizeMode;
+use chat::server::{bot, bridge, Server, ServerConfig};
+use chat::store::{FsyncPolicy, User};
+use common::{spawn_built, spawn_server, spawn_test_server, spawn_test_server_with, TestClient};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+const PASSWORD: &str = "correct horse";
+
+/// Lobby history as seen by `client`, once it holds at least `n` messages.
+/// Chat messages are stored by background workers after the broadcast, so
+/// history can briefly lag behind it.
+async fn history_with(client: &mut TestClient, n: usize) -> Vec<Value> {
+    for _ in 0..100 {
+        let response = client.request("history", json!({ "limit": 50 })).await;
+        assert_eq!(response["success"], true, "history failed: {}", response);
+        let messages = response["data"].as_array().cloned().unwrap_or_default();
+        if messages.len() >= n {
+            return messages;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("history never reached {} message(s)", n);
+}
+
+#[tokio::test]
+async fn register_login_chat_history_search_users() {
+    let addr = spawn_test_server().await;
+
+    let mut alice = TestClient::connect(addr).await;
+    let response = alice.register("alice", PASSWORD).await;
+    assert_eq!(response["data"]["username"], "alice");
+    let alice_id = response["data"]["user_id"].clone();
+
+    let mut bob = TestClient::connect(addr).await;
+    bob.register("bob", PASSWORD).await;
+
+    // Another connection logs in to the account just registered.
+    let mut alice_again = TestClient::connect(addr).await;
+    let response = alice_again.login("alice", PASSWORD).await;
+    assert_eq!(response["success"], true, "login failed: {}", response);
+    assert_eq!(response["data"]["username"], "alice");
+    assert_eq!(response["data"]["user_id"], alice_id);
+
+    alice.send("chat", json!({ "content": "hello everyone" })).await;
+    for client in [&mut bob, &mut alice_again, &mut alice] {
+        let broadcast = client.recv_type("broadcast").await;
+        assert_eq!(broadcast["content"], "hello everyone");
+        assert_eq!(broadcast["username"], "alice");
+    }
+
+    let history = history_with(&mut bob, 1).await;
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0]["content"], "hello everyone");
+    assert_eq!(history[0]["username"], "alice");
+
+    let response = bob.request("search", json!({ "query": "hello" })).await;
+    assert_eq!(response["success"], true, "search failed: {}", response);
+    assert_eq!(response["data"]["total"], 1);
+    assert_eq!(response["data"]["messages"][0]["content"], "hello everyone");
+    let response = bob.request("search", json!({ "query": "goodbye" })).await;
+    assert_eq!(response["data"]["total"], 0);
+
+    let response = bob.request("users", json!({})).await;
+    assert_eq!(response["success"], true, "users failed: {}", response);
+    let mut names: Vec<&str> = response["data"]
+        .as_array()
+        .expect("users data is a list")
+        .iter()
+        .filter_map(|u| u["username"].as_str())
+        .collect();
+    names.sort();
+    names.dedup();
+    assert_eq!(names, ["alice", "bob"]);
+}
+
+#[tokio::test]
+async fn wrong_password_is_refused() {
+    let addr = spawn_test_server().await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+
+    let mut mallory = TestClient::connect(addr).await;
+    let response = mallory.login("alice", "not the password").await;
+    assert_eq!(response["success"], false);
+    assert!(response.get("data").is_none(), "refused login carried data: {}", response);
+
+    // Still logged out, so chatting is refused.
+    let response = mallory.request("chat", json!({ "content": "hi" })).await;
+    assert_eq!(response["success"], false);
+    let message = response["message"].as_str().unwrap_or_default();
+    assert!(message.contains("login or register"), "unexpected error: {}", message);
+
+    let response = mallory.login("alice", PASSWORD).await;
+    assert_eq!(response["success"], true, "login failed: {}", response);
+}
+
+#[tokio::test]
+async fn usernames_are_unique_after_normalization() {
+    let addr = spawn_test_server().await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+
+    let mut other = TestClient::connect(addr).await;
+    let payload = json!({ "username": " ALICE ", "password": PASSWORD });
+    let response = other.request("register", payload).await;
+    assert_eq!(response["success"], false);
+    let message = response["message"].as_str().unwrap_or_default();
+    assert!(message.contains("already taken"), "unexpected error: {}", message);
+}
+
+#[tokio::test]
+async fn slow_mode_spaces_out_chat() {
+    let addr = spawn_test_server_with(ServerConfig {
+        ephemeral: true,
+        admins: vec!["root".to_string()],
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut root = TestClient::connect(addr).await;
+    root.register("root", PASSWORD).await;
+    let mut bob = TestClient::connect(addr).await;
+    bob.register("bob", PASSWORD).await;
+
+    let response = root.request("admin", json!({ "action": "slow_mode", "seconds": 60 })).await;
+    assert_eq!(response["success"], true, "admin failed: {}", response);
+
+    bob.send("chat", json!({ "content": "first" })).await;
+    assert_eq!(bob.recv_type("broadcast").await["content"], "first");
+    let response = bob.request("chat", json!({ "content": "second" })).await;
+    assert_eq!(response["success"], false);
+    let message = response["message"].as_str().unwrap_or_default();
+    assert!(message.contains("slow mode is on"), "unexpected error: {}", message);
+
+    root.request("admin", json!({ "action": "slow_mode", "seconds": 0 })).await;
+    bob.send("chat", json!({ "content": "third" })).await;
+    assert_eq!(bob.recv_type("broadcast").await["content"], "third");
+}
+
+#[tokio::test]
+async fn slow_mode_holds_across_a_users_connections() {
+    let addr = spawn_test_server_with(ServerConfig {
+        ephemeral: true,
+        admins: vec!["root".to_string()],
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut root = TestClient::connect(addr).await;
+    root.register("root", PASSWORD).await;
+    root.request("admin", json!({ "action": "slow_mode", "seconds": 60 })).await;
+    let mut bob = TestClient::connect(addr).await;
+    bob.register("bob", PASSWORD).await;
+    let mut conns = vec![bob];
+    for _ in 1..6 {
+        let mut conn = TestClient::connect(addr).await;
+        conn.login("bob", PASSWORD).await;
+        conns.push(conn);
+    }
+
+    // All at once, each from its own connection.
+    for (n, conn) in conns.iter_mut().enumerate() {
+        conn.send("chat", json!({ "content": format!("from {}", n) })).await;
+    }
+    let mut sent = 0;
+    for (n, conn) in conns.iter_mut().enumerate() {
+        loop {
+            let packet = conn.recv_packet().await;
+            let payload = &packet["payload"];
+            if packet["type"] == "response" && payload["success"] == false {
+                let message = payload["message"].as_str().unwrap_or_default();
+                assert!(message.contains("slow mode is on"), "unexpected error: {}", message);
+                break;
+            }
+            if packet["type"] == "broadcast" && payload["content"] == format!("from {}", n) {
+                sent += 1;
+                break;
+            }
+        }
+    }
+    assert_eq!(sent, 1, "slow mode let {} messages through", sent);
+}
+
+#[tokio::test]
+async fn notify_preference_follows_the_account() {
+    let addr = spawn_test_server().await;
+    let mut alice = TestClient::connect(addr).await;
+    let response = alice.register("alice", PASSWORD).await;
+    assert_eq!(response["data"]["prefs"]["notify"], "mentions");
+
+    let response = alice.request("updateprefs", json!({ "notify": "all" })).await;
+    assert_eq!(response["success"], true, "updateprefs failed: {}", response);
+    assert_eq!(response["data"]["notify"], "all");
+    let response = alice.request("updateprefs", json!({ "notify": "loud" })).await;
+    assert_eq!(response["success"], false);
+    drop(alice);
+
+    let mut again = TestClient::connect(addr).await;
+    let response = again.login("alice", PASSWORD).await;
+    assert_eq!(response["data"]["prefs"]["notify"], "all");
+    let response = again.request("updateprefs", json!({})).await;
+    assert_eq!(response["data"]["notify"], "all");
+}
+
+#[tokio::test]
+async fn strict_protocol_rejects_an_unknown_notify_level() {
+    let addr = spawn_test_server_with(ServerConfig {
+        ephemeral: true,
+        strict_protocol: true,
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    let response = alice.request("updateprefs", json!({ "notify": "loud" })).await;
+    let message = response["message"].as_str().unwrap_or_default();
+    assert!(message.contains("must be one of all, mentions, none"), "unexpected: {}", message);
+    let response = alice.request("updateprefs", json!({ "notify": "none" })).await;
+    assert_eq!(response["data"]["notify"], "none");
+}
+
+#[tokio::test]
+async fn unknown_fields_are_refused_only_in_strict_mode() {
+    for strict_protocol in [false, true] {
+        let addr = spawn_test_server_with(ServerConfig {
+            ephemeral: true,
+            strict_protocol,
+            ..ServerConfig::default()
+        })
+        .await;
+        let mut alice = TestClient::connect(addr).await;
+        alice.register("alice", PASSWORD).await;
+        let response = alice.request("history", json!({ "limit": 5, "colour": "red" })).await;
+        let history = alice.request("history", json!({})).await;
+        if strict_protocol {
+            assert_eq!(response["message"], "error: invalid_payload: unknown field `colour`");
+            assert_eq!(history["message"], "error: invalid_payload: missing field `limit`");
+        } else {
+            assert_eq!(response["success"], true, "lenient mode refused: {}", response);
+            assert_eq!(history["success"], true, "lenient mode refused: {}", history);
+        }
+    }
+}
+
+/// Starts a guest session on `addr` and returns its name.
+async fn guest_name(addr: std::net::SocketAddr) -> (TestClient, String) {
+    let mut guest = TestClient::connect(addr).await;
+    let response = guest.request("guest", json!({})).await;
+    assert_eq!(response["success"], true, "guest failed: {}", response);
+    let name = response["data"]["username"].as_str().unwrap_or_default().to_string();
+    assert_eq!(response["data"]["user_id"], name.as_str());
+    (guest, name)
+}
+
+#[tokio::test]
+async fn guests_read_along_under_names_that_differ_between_boots() {
+    let config = || ServerConfig {
+        ephemeral: true,
+        guests: true,
+        ..ServerConfig::default()
+    };
+    let addr = spawn_test_server_with(config()).await;
+    let (mut guest, first) = guest_name(addr).await;
+    let (_other, second) = guest_name(addr).await;
+    assert!(first.starts_with("guest-"), "unexpected name {}", first);
+    assert_ne!(first, second);
+
+    // A restarted server numbers from 1 again but must not reuse the name,
+    // or its guests' stored messages would read as the earlier guest's.
+    let restarted = spawn_test_server_with(config()).await;
+    let (_, after_restart) = guest_name(restarted).await;
+    assert_ne!(first, after_restart);
+
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    alice.send("chat", json!({ "content": "hello guests" })).await;
+    assert_eq!(guest.recv_type("broadcast").await["content"], "hello guests");
+
+    let response = guest.request("chat", json!({ "content": "hi" })).await;
+    assert_eq!(response["success"], false);
+    let message = response["message"].as_str().unwrap_or_default();
+    assert!(message.contains("guests can't"), "unexpected error: {}", message);
+}
+
+#[tokio::test]
+async fn only_admins_may_purge_history() {
+    let addr = spawn_test_server_with(ServerConfig {
+        ephemeral: true,
+        admins: vec!["root".to_string()],
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut root = TestClient::connect(addr).await;
+    root.register("root", PASSWORD).await;
+    let mut bob = TestClient::connect(addr).await;
+    bob.register("bob", PASSWORD).await;
+    bob.send("chat", json!({ "content": "keep me" })).await;
+    history_with(&mut bob, 1).await;
+
+    let response = bob.request("purge", json!({})).await;
+    assert_eq!(response["success"], false);
+    let message = response["message"].as_str().unwrap_or_default();
+    assert!(message.contains("admin"), "unexpected error: {}", message);
+    assert_eq!(history_with(&mut bob, 1).await.len(), 1);
+
+    // Only messages from before 2000 go, so today's stays.
+    let response = root.request("purge", json!({ "before": "2000-01-01T00:00:00Z" })).await;
+    assert_eq!(response["success"], true, "purge failed: {}", response);
+    let notice = bob.recv_type("system").await;
+    let expected = "root purged the message history older than 2000-01-01 00:00 UTC";
+    assert_eq!(notice["message"], expected);
+    assert_eq!(history_with(&mut bob, 1).await.len(), 1);
+
+    let response = root.request("purge", json!({})).await;
+    assert_eq!(response["message"], "purged 1 message(s)");
+    let notice = bob.recv_type("system").await;
+    assert_eq!(notice["message"], "root purged the message history");
+    assert!(history_with(&mut bob, 0).await.is_empty());
+}
+
+#[tokio::test]
+async fn an_oversized_line_closes_the_connection() {
+    let addr = spawn_test_server_with(ServerConfig {
+        ephemeral: true,
+        max_packet_bytes: 1024,
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut mallory = TestClient::connect(addr).await;
+    // No newline: the server must give up at the limit, not keep reading.
+    // Kept small enough to be read in full, as closing a socket with
+    // unread data resets it and could lose the error.
+    mallory.send_raw(&[b'a'; 2048]).await;
+    let response = mallory.recv_type("response").await;
+    assert_eq!(response["success"], false);
+    assert_eq!(response["message"], "error: packet exceeds 1024 bytes");
+    mallory.expect_closed().await;
+
+    // Others are unaffected, and packets up to the limit still go through.
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    alice.send("chat", json!({ "content": "x".repeat(900) })).await;
+    assert_eq!(alice.recv_type("broadcast").await["content"].as_str().map(str::len), Some(900));
+}
+
+#[tokio::test]
+async fn whoami_reports_the_logged_in_user() {
+    let addr = spawn_test_server().await;
+    let mut alice = TestClient::connect(addr).await;
+    let response = alice.request("whoami", json!({})).await;
+    assert_eq!(response["success"], false);
+    assert!(response.get("data").is_none(), "anonymous whoami carried data: {}", response);
+
+    let before = chrono::Utc::now();
+    let registered = alice.register("alice", PASSWORD).await;
+    let response = alice.request("whoami", json!({})).await;
+    assert_eq!(response["success"], true, "whoami failed: {}", response);
+    let session = &response["data"];
+    assert_eq!(session["user_id"], registered["data"]["user_id"]);
+    assert_eq!(session["username"], "alice");
+    assert_eq!(session["role"], "user");
+    let since: chrono::DateTime<chrono::Utc> =
+        serde_json::from_value(session["connected_since"].clone()).unwrap();
+    // The connection was opened just before registering.
+    assert!(since <= chrono::Utc::now() && before - since < chrono::TimeDelta::seconds(5));
+}
+
+#[tokio::test]
+async fn auth_responses_carry_the_account() {
+    let addr = spawn_test_server().await;
+    let mut alice = TestClient::connect(addr).await;
+    let registered = alice.register("Alice", PASSWORD).await;
+    let data = &registered["data"];
+    assert_eq!(data["username"], "Alice");
+    let user_id = data["user_id"].as_str().expect("register data has no user_id");
+    assert!(!user_id.is_empty());
+
+    // Logging in under another casing still reports the account's own.
+    let mut again = TestClient::connect(addr).await;
+    let response = again.login("ALICE", PASSWORD).await;
+    assert_eq!(response["success"], true, "login failed: {}", response);
+    assert_eq!(response["data"]["username"], "Alice");
+    assert_eq!(response["data"]["user_id"], user_id);
+}
+
+#[tokio::test]
+async fn filtered_words_are_masked_or_rejected() {
+    let list = std::env::temp_dir().join(format!("chat-test-filter-{}.txt", std::process::id()));
+    std::fs::write(&list, "darn\n").unwrap();
+    let config = |filter_mode| ServerConfig {
+        ephemeral: true,
+        filter_list: Some(list.clone()),
+        filter_mode,
+        ..ServerConfig::default()
+    };
+    let masking = spawn_test_server_with(config(FilterMode::Mask)).await;
+    let rejecting = spawn_test_server_with(config(FilterMode::Reject)).await;
+    std::fs::remove_file(&list).ok();
+
+    let mut alice = TestClient::connect(masking).await;
+    alice.register("alice", PASSWORD).await;
+    alice.send("chat", json!({ "content": "Darn, Scunthorpe again" })).await;
+    assert_eq!(alice.recv_type("broadcast").await["content"], "****, Scunthorpe again");
+    assert_eq!(history_with(&mut alice, 1).await[0]["content"], "****, Scunthorpe again");
+
+    let mut bob = TestClient::connect(rejecting).await;
+    bob.register("bob", PASSWORD).await;
+    let response = bob.request("chat", json!({ "content": "darn it" })).await;
+    assert_eq!(response["success"], false);
+    assert_eq!(response["message"], "error: message contains a filtered word");
+    bob.send("chat", json!({ "content": "darning" })).await;
+    assert_eq!(bob.recv_type("broadcast").await["content"], "darning");
+}
+
+#[tokio::test]
+async fn rename_keeps_the_account_and_its_history() {
+    let addr = spawn_test_server().await;
+    let mut alice = TestClient::connect(addr).await;
+    let user_id = alice.register("alice", PASSWORD).await["data"]["user_id"].clone();
+    let mut bob = TestClient::connect(addr).await;
+    bob.register("bob", PASSWORD).await;
+    alice.send("chat", json!({ "content": "before" })).await;
+    history_with(&mut bob, 1).await;
+
+    let response = alice.request("rename", json!({ "new_username": " BOB " })).await;
+    assert_eq!(response["success"], false);
+    let message = response["message"].as_str().unwrap_or_default();
+    assert!(message.contains("already taken"), "unexpected error: {}", message);
+
+    let response = alice.request("rename", json!({ "new_username": "Alicia" })).await;
+    assert_eq!(response["success"], true, "rename failed: {}", response);
+    assert_eq!(response["data"]["user_id"], user_id);
+    assert_eq!(bob.recv_type("system").await["message"], "alice is now known as Alicia");
+    let session = alice.request("whoami", json!({})).await;
+    assert_eq!(session["data"]["username"], "Alicia");
+
+    alice.send("chat", json!({ "content": "after" })).await;
+    assert_eq!(bob.recv_type("broadcast").await["username"], "Alicia");
+    let history = history_with(&mut bob, 2).await;
+    // Stored messages keep the name they were sent under.
+    assert_eq!(history[0]["username"], "alice");
+    assert_eq!(history[1]["username"], "Alicia");
+    assert!(history.iter().all(|m| m["user_id"] == user_id));
+
+    let mut again = TestClient::connect(addr).await;
+    assert_eq!(again.login("alice", PASSWORD).await["success"], false);
+    assert_eq!(again.login("alicia", PASSWORD).await["data"]["user_id"], user_id);
+}
+
+/// Lobby history with system events, once it holds `content`.
+async fn system_history_with(client: &mut TestClient, content: &str) -> Vec<Value> {
+    let payload = json!({ "limit": 50, "include_system": true });
+    for _ in 0..100 {
+        let response = client.request("history", payload.clone()).await;
+        let messages = response["data"].as_array().cloned().unwrap_or_default();
+        if messages.iter().any(|m| m["content"] == content) {
+            return messages;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("history never showed {:?}", content);
+}
+
+/// Alice, then bob, registered on a server with `persist_system` as given.
+async fn alice_and_bob(persist_system: bool) -> (TestClient, TestClient) {
+    let addr = spawn_test_server_with(ServerConfig {
+        ephemeral: true,
+        persist_system,
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    let mut bob = TestClient::connect(addr).await;
+    bob.register("bob", PASSWORD).await;
+    (alice, bob)
+}
+
+#[tokio::test]
+async fn join_events_are_stored_only_when_enabled() {
+    let (mut alice, _bob) = alice_and_bob(true).await;
+    let history = system_history_with(&mut alice, "bob joined the chat").await;
+    let join = history.iter().find(|m| m["content"] == "bob joined the chat").unwrap();
+    assert_eq!(join["kind"], "system");
+    assert_eq!(join["user_id"], "");
+    // Left out unless asked for, from history and search alike.
+    let response = alice.request("history", json!({ "limit": 50 })).await;
+    assert_eq!(response["data"], json!([]));
+    let response = alice.request("search", json!({ "query": "bob joined" })).await;
+    assert_eq!(response["data"]["total"], 0);
+    let payload = json!({ "query": "bob joined", "include_system": true });
+    assert_eq!(alice.request("search", payload).await["data"]["total"], 1);
+
+    let (mut alice, mut bob) = alice_and_bob(false).await;
+    bob.send("chat", json!({ "content": "hi" })).await;
+    let history = system_history_with(&mut alice, "hi").await;
+    assert!(history.iter().all(|m| m["kind"] != "system"), "stored {:?}", history);
+}
+
+#[tokio::test]
+async fn compression_wraps_everything_after_the_handshake() {
+    let addr = spawn_test_server_with(ServerConfig {
+        ephemeral: true,
+        compression: true,
+        ..ServerConfig::default()
+    })
+    .await;
+    let (reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    reader.read_line(&mut line).await.unwrap();
+    let request = json!({ "type": "compress", "payload": { "algorithm": "zlib" } });
+    writer.write_all(format!("{}\n", request).as_bytes()).await.unwrap();
+    line.clear();
+    reader.read_line(&mut line).await.unwrap();
+    let response: Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(response["payload"]["success"], true, "compress failed: {}", response);
+
+    // From here on both directions are zlib streams of ordinary frames.
+    let mut lines = BufReader::new(ZlibDecoder::new(reader)).lines();
+    let mut writer = ZlibEncoder::new(writer);
+    let content = "squeeze me ".repeat(100);
+    for (kind, payload) in [
+        ("register", json!({ "username": "alice", "password": PASSWORD })),
+        ("chat", json!({ "content": content })),
+    ] {
+        let packet = json!({ "type": kind, "payload": payload });
+        writer.write_all(format!("{}\n", packet).as_bytes()).await.unwrap();
+        writer.flush().await.unwrap();
+    }
+    let mut packets: Vec<Value> = Vec::new();
+    while !packets.iter().any(|p| p["type"] == "broadcast") {
+        let line = tokio::time::timeout(Duration::from_secs(5), lines.next_line());
+        let line = line.await.unwrap().unwrap().expect("connection closed");
+        packets.push(serde_json::from_str(&line).unwrap());
+    }
+    let response = packets.iter().find(|p| p["type"] == "response").unwrap();
+    assert_eq!(response["payload"]["data"]["username"], "alice", "got {}", response);
+    assert_eq!(packets.last().unwrap()["payload"]["content"], content.as_str());
+}
+
+#[tokio::test]
+async fn compression_is_refused_unless_enabled() {
+    let addr = spawn_test_server().await;
+    let mut alice = TestClient::connect(addr).await;
+    let response = alice.request("compress", json!({ "algorithm": "zlib" })).await;
+    assert_eq!(response["success"], false);
+    // Still speaking plain JSON.
+    alice.register("alice", PASSWORD).await;
+}
+
+#[tokio::test]
+async fn ephemeral_servers_leave_the_data_dir_alone() {
+    let dir = std::env::temp_dir().join(format!("chat-test-ephemeral-{}", std::process::id()));
+    let addr = spawn_test_server_with(ServerConfig {
+        ephemeral: true,
+        data_dir: dir.to_string_lossy().into_owned(),
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    alice.send("chat", json!({ "content": "gone on restart" })).await;
+    assert_eq!(history_with(&mut alice, 1).await[0]["content"], "gone on restart");
+    assert!(!dir.exists(), "{} was created", dir.display());
+}
+
+#[tokio::test]
+async fn auto_sized_workers_still_persist_chat() {
+    let addr = spawn_test_server_with(ServerConfig {
+        ephemeral: true,
+        workers: 0,
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    alice.send("chat", json!({ "content": "kept" })).await;
+    assert_eq!(history_with(&mut alice, 1).await[0]["content"], "kept");
+}
+
+#[tokio::test]
+async fn do_not_disturb_mutes_broadcasts_but_not_direct_messages() {
+    let addr = spawn_test_server().await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    let mut bob = TestClient::connect(addr).await;
+    bob.register("bob", PASSWORD).await;
+
+    let response = alice.request("dnd", json!({ "enabled": true })).await;
+    assert_eq!(response["success"], true, "dnd failed: {}", response);
+    bob.send("chat", json!({ "content": "everyone look" })).await;
+    assert_eq!(bob.recv_type("broadcast").await["content"], "everyone look");
+    bob.send("direct", json!({ "to": "alice", "content": "just you" })).await;
+    loop {
+        let packet = alice.recv_packet().await;
+        assert_ne!(packet["type"], "broadcast", "dnd let through {}", packet);
+        if packet["type"] == "direct" {
+            assert_eq!(packet["payload"]["content"], "just you");
+            break;
+        }
+    }
+
+    alice.request("dnd", json!({ "enabled": false })).await;
+    bob.send("chat", json!({ "content": "welcome back" })).await;
+    assert_eq!(alice.recv_type("broadcast").await["content"], "welcome back");
+}
+
+#[tokio::test]
+async fn sync_returns_exactly_the_messages_after_the_cursor() {
+    let addr = spawn_test_server().await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    for n in 1..=4 {
+        alice.send("chat", json!({ "content": format!("message {}", n) })).await;
+    }
+    let history = history_with(&mut alice, 4).await;
+    let cursor = history[1]["id"].clone();
+
+    let response = alice.request("sync", json!({ "since_id": cursor })).await;
+    assert_eq!(response["message"], "2 new message(s)");
+    let data = &response["data"];
+    assert_eq!(data["complete"], true);
+    let messages = data["messages"].as_array().unwrap();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0]["content"], "message 3");
+    assert_eq!(messages[1]["content"], "message 4");
+
+    let newest = history[3]["id"].clone();
+    let response = alice.request("sync", json!({ "since_id": newest })).await;
+    assert_eq!(response["data"]["messages"], json!([]));
+
+    // An unknown (say, pruned) cursor falls back to recent history.
+    let response = alice.request("sync", json!({ "since_id": "no-such-id" })).await;
+    assert_eq!(response["success"], true);
+    assert_eq!(response["data"]["complete"], false);
+    assert_eq!(response["data"]["messages"].as_array().map(Vec::len), Some(4));
+    let message = response["message"].as_str().unwrap_or_default();
+    assert!(message.starts_with("sync cursor not found"), "unexpected: {}", message);
+}
+
+#[tokio::test]
+async fn stats_count_users_messages_and_connections() {
+    let addr = spawn_test_server().await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    let mut bob = TestClient::connect(addr).await;
+    bob.register("bob", PASSWORD).await;
+    let mut carol = TestClient::connect(addr).await;
+    carol.register("carol", PASSWORD).await;
+    drop(carol);
+    for n in 1..=3 {
+        alice.send("chat", json!({ "content": format!("message {}", n) })).await;
+    }
+    history_with(&mut alice, 3).await;
+
+    // Carol's connection closes in the background.
+    let mut stats = Value::Null;
+    for _ in 0..100 {
+        stats = bob.request("stats", json!({})).await["data"].take();
+        if stats["online"] == 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(stats["messages"], 3);
+    assert_eq!(stats["users"], 3);
+    assert_eq!(stats["online"], 2);
+    assert!(stats["uptime_secs"].as_u64().is_some_and(|s| s < 60), "stats: {}", stats);
+}
+
+#[tokio::test]
+async fn repeated_messages_inside_the_window_go_out_once() {
+    let addr = spawn_test_server_with(ServerConfig {
+        ephemeral: true,
+        dedup_window: Some(Duration::from_secs(30)),
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    let mut bob = TestClient::connect(addr).await;
+    bob.register("bob", PASSWORD).await;
+
+    alice.send("chat", json!({ "content": "hello" })).await;
+    alice.send("chat", json!({ "content": "hello" })).await;
+    alice.send("chat", json!({ "content": "something else" })).await;
+    assert_eq!(bob.recv_type("broadcast").await["content"], "hello");
+    assert_eq!(bob.recv_type("broadcast").await["content"], "something else");
+
+    // Another user's repeat is their own message.
+    bob.send("chat", json!({ "content": "hello" })).await;
+    let broadcast = bob.recv_type("broadcast").await;
+    assert_eq!((&broadcast["username"], &broadcast["content"]), (&json!("bob"), &json!("hello")));
+
+    let history = history_with(&mut alice, 3).await;
+    let hellos = history.iter().filter(|m| m["content"] == "hello").count();
+    assert_eq!((history.len(), hellos), (3, 2));
+
+    // Off by default: both copies go out.
+    let addr = spawn_test_server().await;
+    let mut carol = TestClient::connect(addr).await;
+    carol.register("carol", PASSWORD).await;
+    carol.send("chat", json!({ "content": "twice" })).await;
+    carol.send("chat", json!({ "content": "twice" })).await;
+    assert_eq!(carol.recv_type("broadcast").await["content"], "twice");
+    assert_eq!(carol.recv_type("broadcast").await["content"], "twice");
+}
+
+#[tokio::test]
+async fn regex_search_is_opt_in_and_reports_bad_patterns() {
+    let addr = spawn_test_server().await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    for content in ["hello", "hallo", "h.llo"] {
+        alice.send("chat", json!({ "content": content })).await;
+    }
+    history_with(&mut alice, 3).await;
+
+    let response = alice.request("search", json!({ "query": "h.llo", "regex": true })).await;
+    assert_eq!(response["success"], true, "search failed: {}", response);
+    assert_eq!(response["data"]["total"], 3);
+    let response = alice.request("search", json!({ "query": "h.llo" })).await;
+    assert_eq!(response["data"]["total"], 1);
+    assert_eq!(response["data"]["messages"][0]["content"], "h.llo");
+
+    let response = alice.request("search", json!({ "query": "h(", "regex": true })).await;
+    assert_eq!(response["success"], false);
+    let message = response["message"].as_str().unwrap_or_default();
+    assert!(message.starts_with("error: invalid regex"), "unexpected: {}", message);
+}
+
+/// Reads `client`'s packets up to the broadcast of `content`, failing on
+/// any broadcast or direct message from `muted` before it.
+async fn nothing_from_before(client: &mut TestClient, muted: &str, content: &str) {
+    loop {
+        let packet = client.recv_packet().await;
+        if packet["type"] != "broadcast" && packet["type"] != "direct" {
+            continue;
+        }
+        let payload = &packet["payload"];
+        assert_ne!(payload["username"], muted, "blocked user got through: {}", packet);
+        if payload["content"] == content {
+            return;
+        }
+    }
+}
+
+#[tokio::test]
+async fn blocks_hide_a_user_from_the_blocker_only_and_survive_reconnects() {
+    let addr = spawn_test_server().await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    let mut bob = TestClient::connect(addr).await;
+    bob.register("bob", PASSWORD).await;
+    let mut carol = TestClient::connect(addr).await;
+    carol.register("carol", PASSWORD).await;
+
+    let response = alice.request("block", json!({ "username": "bob" })).await;
+    assert_eq!(response["success"], true, "block failed: {}", response);
+    assert_eq!(response["data"]["blocked"][0]["username"], "bob");
+
+    bob.send("chat", json!({ "content": "from bob" })).await;
+    let response = bob.request("direct", json!({ "to": "alice", "content": "psst" })).await;
+    assert_eq!(response["success"], true, "direct failed: {}", response);
+    assert_eq!(carol.recv_type("broadcast").await["content"], "from bob");
+    carol.send("chat", json!({ "content": "from carol" })).await;
+    nothing_from_before(&mut alice, "bob", "from carol").await;
+
+    // A new session of the same account keeps the block.
+    drop(alice);
+    let mut alice = TestClient::connect(addr).await;
+    assert_eq!(alice.login("alice", PASSWORD).await["success"], true);
+    bob.send("chat", json!({ "content": "still bob" })).await;
+    while carol.recv_type("broadcast").await["content"] != "still bob" {}
+    carol.send("chat", json!({ "content": "carol again" })).await;
+    nothing_from_before(&mut alice, "bob", "carol again").await;
+
+    let response = alice.request("unblock", json!({ "username": "bob" })).await;
+    assert_eq!(response["data"]["blocked"], json!([]));
+    bob.send("chat", json!({ "content": "bob is back" })).await;
+    assert_eq!(alice.recv_type("broadcast").await["content"], "bob is back");
+}
+
+#[tokio::test]
+async fn profiles_show_join_date_and_message_count_but_no_hash() {
+    let addr = spawn_test_server().await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    let mut bob = TestClient::connect(addr).await;
+    bob.register("bob", PASSWORD).await;
+    for n in 1..=2 {
+        alice.send("chat", json!({ "content": format!("message {}", n) })).await;
+    }
+    bob.send("chat", json!({ "content": "bob's" })).await;
+    history_with(&mut bob, 3).await;
+
+    let response = bob.request("profile", json!({ "username": "alice" })).await;
+    assert_eq!(response["success"], true, "profile failed: {}", response);
+    let profile = &response["data"];
+    assert_eq!(profile["username"], "alice");
+    assert_eq!(profile["message_count"], 2);
+    assert_eq!(profile["online"], true);
+    assert!(profile["created_at"].is_string(), "profile: {}", profile);
+    assert!(profile.get("password_hash").is_none(), "profile: {}", profile);
+    let hash = hex::encode(Sha256::digest(PASSWORD.as_bytes()));
+    assert!(!response.to_string().contains(&hash), "hash leaked: {}", response);
+    let response = alice.request("profile", json!({ "username": "bob" })).await;
+    assert_eq!(response["data"]["message_count"], 1);
+
+    let response = bob.request("profile", json!({ "username": "nobody" })).await;
+    assert_eq!(response["success"], false);
+    assert_eq!(response["message"], "error: user \"nobody\" not found");
+}
+
+#[tokio::test]
+async fn profile_updates_show_in_the_user_list() {
+    let addr = spawn_test_server().await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    // Profile notices only go to clients that know them.
+    let mut bob = TestClient::connect(addr).await;
+    bob.request("hello", json!({ "version": "test", "features": ["profiles"] })).await;
+    bob.register("bob", PASSWORD).await;
+
+    let update = json!({ "display_name": "Alice L.", "status_text": "busy", "avatar": "🐇" });
+    let response = alice.request("updateprofile", update).await;
+    assert_eq!(response["success"], true, "update failed: {}", response);
+    assert_eq!(response["data"]["display_name"], "Alice L.");
+    loop {
+        let notice = bob.recv_type("system").await;
+        if notice["message"] == "alice updated their profile" {
+            assert_eq!(notice["presence"]["user"]["avatar"], "🐇", "notice: {}", notice);
+            break;
+        }
+    }
+
+    let users = bob.request("users", json!({})).await["data"].take();
+    let alice_info = users.as_array().unwrap().iter().find(|u| u["username"] == "alice");
+    let alice_info = alice_info.expect("alice is listed");
+    assert_eq!(alice_info["display_name"], "Alice L.");
+    assert_eq!(alice_info["status_text"], "busy");
+    assert_eq!(alice_info["avatar"], "🐇");
+
+    let response = alice.request("updateprofile", json!({ "avatar": "no" })).await;
+    assert_eq!(response["message"], "error: avatar must be a single emoji");
+    let response = bob.request("profile", json!({ "username": "alice" })).await;
+    assert_eq!(response["data"]["avatar"], "🐇");
+}
+
+#[tokio::test]
+async fn features_reach_only_the_clients_that_asked_for_them() {
+    let addr = spawn_test_server().await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    let mut old = TestClient::connect(addr).await;
+    old.register("old", PASSWORD).await;
+    let mut new = TestClient::connect(addr).await;
+    let hello = json!({ "version": "9.9.9", "features": ["profiles", "teleport"] });
+    let response = new.request("hello", hello).await;
+    assert_eq!(response["success"], true, "hello failed: {}", response);
+    let offered = response["data"]["features"].as_array().unwrap();
+    assert!(offered.contains(&json!("profiles")), "offered: {:?}", offered);
+    assert!(!offered.contains(&json!("teleport")), "offered: {:?}", offered);
+    assert!(response["data"]["version"].is_string());
+    new.register("new", PASSWORD).await;
+
+    alice.request("updateprofile", json!({ "status_text": "away" })).await;
+    alice.send("chat", json!({ "content": "done" })).await;
+    loop {
+        let packet = old.recv_packet().await;
+        let presence = &packet["payload"]["presence"];
+        assert_ne!(presence["event"], "profile", "old client got {}", packet);
+        if packet["payload"]["content"] == "done" {
+            break;
+        }
+    }
+    loop {
+        let notice = new.recv_type("system").await;
+        if notice["presence"]["event"] == "profile" {
+            assert_eq!(notice["presence"]["user"]["status_text"], "away");
+            break;
+        }
+    }
+}
+
+#[tokio::test]
+async fn bad_packet_types_are_named_in_the_error() {
+    let addr = spawn_test_server().await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+
+    let response = alice.request("teleport", json!({})).await;
+    assert_eq!(response["message"], "error: unknown packet type \"teleport\"");
+    for kind in ["response", "broadcast", "system"] {
+        let response = alice.request(kind, json!({})).await;
+        assert_eq!(response["message"], format!("error: \"{}\" is not a client command", kind));
+    }
+    alice.send_raw(b"{\"payload\": {}}\n").await;
+    let response = alice.recv_type("response").await;
+    assert_eq!(response["message"], "error: malformed packet: missing \"type\"");
+    alice.send_raw(b"not json\n").await;
+    let response = alice.recv_type("response").await;
+    assert_eq!(response["message"], "error: malformed packet: not valid JSON");
+}
+
+/// The first packet a new connection to `addr` gets.
+async fn first_packet(addr: std::net::SocketAddr) -> Value {
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).await.unwrap();
+    serde_json::from_str(&line).unwrap()
+}
+
+#[tokio::test]
+async fn the_motd_file_is_read_for_each_connection() {
+    let motd = std::env::temp_dir().join(format!("chat-test-motd-{}.txt", std::process::id()));
+    std::fs::write(&motd, "Welcome!\nBe nice.\n").unwrap();
+    let addr = spawn_test_server_with(ServerConfig {
+        ephemeral: true,
+        motd_file: Some(motd.clone()),
+        ..ServerConfig::default()
+    })
+    .await;
+
+    let welcome = first_packet(addr).await;
+    assert_eq!(welcome["type"], "system");
+    assert_eq!(welcome["payload"]["message"], "Welcome!\nBe nice.");
+
+    // Edits apply to the next connection, without a restart.
+    std::fs::write(&motd, "Maintenance at noon").unwrap();
+    assert_eq!(first_packet(addr).await["payload"]["message"], "Maintenance at noon");
+
+    // A missing file falls back to the built-in greeting.
+    std::fs::remove_file(&motd).unwrap();
+    let welcome = first_packet(addr).await["payload"]["message"].take();
+    assert!(welcome.as_str().unwrap().starts_with("Welcome to RustChat!"), "got {}", welcome);
+    let default = first_packet(spawn_test_server().await).await;
+    assert_eq!(default["payload"]["message"], welcome);
+}
+
+#[tokio::test]
+async fn connections_past_the_per_address_limit_are_turned_away() {
+    let addr = spawn_test_server_with(ServerConfig {
+        ephemeral: true,
+        max_conns_per_ip: Some(2),
+        ..ServerConfig::default()
+    })
+    .await;
+    let first = TestClient::connect(addr).await;
+    let _second = TestClient::connect(addr).await;
+    let rejected = first_packet(addr).await;
+    assert_eq!(rejected["payload"]["message"], "too many connections from your address");
+
+    // Closing a connection frees its slot.
+    drop(first);
+    for _ in 0..100 {
+        let welcome = first_packet(addr).await;
+        if welcome["payload"]["message"] != rejected["payload"]["message"] {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("the closed connection's slot was never freed");
+}
+
+#[tokio::test]
+async fn repeated_bad_passwords_lock_the_account_out() {
+    let addr = spawn_test_server_with(ServerConfig {
+        ephemeral: true,
+        auth_max_failures: 3,
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    let mut guesser = TestClient::connect(addr).await;
+
+    // Two misses, then a success, which starts the count again.
+    for _ in 0..2 {
+        assert_eq!(guesser.login("alice", "wrong").await["success"], false);
+    }
+    assert_eq!(guesser.login("alice", PASSWORD).await["success"], true);
+    for _ in 0..3 {
+        let response = guesser.login("ALICE", "wrong").await;
+        assert_ne!(response["message"].as_str().map(|m| m.contains("too many")), Some(true));
+    }
+    // Locked out, even with the right password.
+    let response = guesser.login("alice", PASSWORD).await;
+    let message = response["message"].as_str().unwrap_or_default();
+    assert!(message.starts_with("error: too many failed login attempts"), "got {}", message);
+}
+
+#[tokio::test]
+async fn direct_messages_are_receipted_on_handoff_and_when_read() {
+    let addr = spawn_test_server().await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.request("hello", json!({ "version": "test", "features": ["receipts"] })).await;
+    alice.register("alice", PASSWORD).await;
+    let mut bob = TestClient::connect(addr).await;
+    bob.register("bob", PASSWORD).await;
+    let mut carol = TestClient::connect(addr).await;
+    carol.register("carol", PASSWORD).await;
+    drop(carol);
+
+    let sent = alice.request("direct", json!({ "to": "bob", "content": "hi" })).await;
+    let id = sent["data"]["id"].clone();
+    let receipt = alice.recv_type("receipt").await;
+    assert_eq!(receipt, json!({ "message_id": id, "kind": "delivered" }));
+    let dm = bob.recv_type("direct").await;
+    assert_eq!(dm["id"], id);
+    bob.send("receipt", json!({ "message_id": id, "kind": "read" })).await;
+    assert_eq!(alice.recv_type("receipt").await, json!({ "message_id": id, "kind": "read" }));
+
+    // Nothing is delivered to an offline user until they come back.
+    let mut sent = Value::Null;
+    for _ in 0..100 {
+        sent = alice.request("direct", json!({ "to": "carol", "content": "later" })).await;
+        if sent["message"].as_str().is_some_and(|m| m.contains("offline")) {
+            break;
+        }
+        alice.recv_type("receipt").await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let id = sent["data"]["id"].clone();
+    let mut carol = TestClient::connect(addr).await;
+    carol.login("carol", PASSWORD).await;
+    assert_eq!(carol.recv_type("direct").await["id"], id);
+    let receipt = alice.recv_type("receipt").await;
+    assert_eq!(receipt, json!({ "message_id": id, "kind": "delivered" }));
+}
+
+#[tokio::test]
+async fn offline_direct_messages_arrive_once_in_order_at_next_login() {
+    let addr = spawn_test_server().await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    let mut bob = TestClient::connect(addr).await;
+    bob.register("bob", PASSWORD).await;
+    drop(bob);
+
+    let queued =
+        |response: &Value| response["message"].as_str().is_some_and(|m| m.contains("offline"));
+    // Bob's disconnect is handled in the background.
+    for _ in 0..100 {
+        let response = alice.request("direct", json!({ "to": "bob", "content": "one" })).await;
+        if queued(&response) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    for content in ["two", "three"] {
+        let response = alice.request("direct", json!({ "to": "bob", "content": content })).await;
+        assert!(queued(&response), "not queued: {}", response);
+    }
+
+    let mut bob = TestClient::connect(addr).await;
+    bob.login("bob", PASSWORD).await;
+    for content in ["one", "two", "three"] {
+        let dm = bob.recv_type("direct").await;
+        assert_eq!((&dm["username"], &dm["content"]), (&json!("alice"), &json!(content)));
+    }
+
+    // A second login has nothing left to hand over.
+    let mut again = TestClient::connect(addr).await;
+    again.login("bob", PASSWORD).await;
+    alice.send("chat", json!({ "content": "all caught up" })).await;
+    loop {
+        let packet = again.recv_packet().await;
+        assert_ne!(packet["type"], "direct", "delivered twice: {}", packet);
+        if packet["payload"]["content"] == "all caught up" {
+            break;
+        }
+    }
+}
+
+#[tokio::test]
+async fn time_and_hello_carry_the_server_clock() {
+    let addr = spawn_test_server().await;
+    let mut client = TestClient::connect(addr).await;
+    let close_to_now = |value: &Value| {
+        let time = chrono::DateTime::parse_from_rfc3339(value.as_str().unwrap()).unwrap();
+        (chrono::Utc::now() - time.to_utc()).abs() < chrono::TimeDelta::seconds(2)
+    };
+
+    let response = client.request("time", json!({})).await;
+    assert_eq!(response["success"], true, "time failed: {}", response);
+    assert!(close_to_now(&response["data"]["server_time"]), "time: {}", response);
+    let response = client.request("hello", json!({ "version": "test", "features": [] })).await;
+    assert!(close_to_now(&response["data"]["server_time"]), "hello: {}", response);
+}
+
+#[tokio::test]
+async fn the_format_hint_is_relayed_and_stored() {
+    let addr = spawn_test_server().await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    let mut bob = TestClient::connect(addr).await;
+    bob.register("bob", PASSWORD).await;
+
+    alice.send("chat", json!({ "content": "**hi**", "format": "markdown" })).await;
+    alice.send("chat", json!({ "content": "fn main() {}", "format": "code" })).await;
+    alice.send("chat", json!({ "content": "plain" })).await;
+    let mut formats = Vec::new();
+    for _ in 0..3 {
+        formats.push(bob.recv_type("broadcast").await["format"].take());
+    }
+    assert_eq!(formats, [json!("markdown"), json!("code"), Value::Null]);
+
+    let history = history_with(&mut bob, 3).await;
+    let stored = |content: &str| history.iter().find(|m| m["content"] == content).unwrap().clone();
+    assert_eq!(stored("**hi**")["format"], "markdown");
+    assert_eq!(stored("fn main() {}")["format"], "code");
+    assert_eq!(stored("plain")["format"], Value::Null);
+
+    let response = alice.request("chat", json!({ "content": "x", "format": "html" })).await;
+    assert_eq!(response["success"], false, "unknown format accepted: {}", response);
+}
+
+#[tokio::test]
+async fn authors_and_admins_pin_and_everyone_hears_of_it() {
+    let addr = spawn_test_server_with(ServerConfig {
+        ephemeral: true,
+        admins: vec!["root".to_string()],
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut root = TestClient::connect(addr).await;
+    root.register("root", PASSWORD).await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    let mut bob = TestClient::connect(addr).await;
+    bob.register("bob", PASSWORD).await;
+
+    alice.send("chat", json!({ "content": "read the rules" })).await;
+    let id = bob.recv_type("broadcast").await["id"].clone();
+    history_with(&mut alice, 1).await;
+
+    let response = bob.request("pin", json!({ "message_id": id })).await;
+    assert_eq!(response["success"], false, "bob pinned alice's message: {}", response);
+    let message = response["message"].as_str().unwrap_or_default();
+    assert!(message.contains("only the author or an admin"), "unexpected error: {}", message);
+    let response = bob.request("pin", json!({ "message_id": "nope" })).await;
+    assert_eq!(response["success"], false);
+
+    let response = alice.request("pin", json!({ "message_id": id })).await;
+    assert_eq!(response["success"], true, "pin failed: {}", response);
+    let notice = loop {
+        let system = bob.recv_type("system").await;
+        if !system["pin"].is_null() {
+            break system;
+        }
+    };
+    assert_eq!(notice["pin"]["pinned"], true);
+    assert_eq!(notice["pin"]["message"]["content"], "read the rules");
+    assert_eq!(notice["pin"]["by"]["username"], "alice");
+    let response = alice.request("pin", json!({ "message_id": id })).await;
+    assert_eq!(response["message"], "error: message is already pinned");
+
+    let response = bob.request("pinned", json!({})).await;
+    assert_eq!(response["data"]["pinned"][0]["id"], id);
+
+    // An admin may unpin anyone's message.
+    let response = bob.request("unpin", json!({ "message_id": id })).await;
+    assert_eq!(response["success"], false);
+    let response = root.request("unpin", json!({ "message_id": id })).await;
+    assert_eq!(response["success"], true, "unpin failed: {}", response);
+    let notice = bob.recv_type("system").await;
+    assert_eq!(notice["pin"]["pinned"], false);
+    assert_eq!(notice["pin"]["by"]["username"], "root");
+    let response = bob.request("pinned", json!({})).await;
+    assert_eq!(response["data"]["pinned"], json!([]));
+}
+
+#[tokio::test]
+async fn the_bot_answers_triggers_and_posts_on_schedule() {
+    let (server, addr) = spawn_server(ServerConfig {
+        ephemeral: true,
+        ..ServerConfig::default()
+    })
+    .await;
+    let config = json!({
+        "name": "helper",
+        "triggers": [{ "trigger": "!rules", "response": "Be kind." }],
+        "scheduled": [{ "every_secs": 1, "message": "New here? Try !help" }],
+    });
+    bot::start(server, serde_json::from_value(config).unwrap()).await.unwrap();
+
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    alice.send("chat", json!({ "content": "!Rules please" })).await;
+    let reply = loop {
+        let broadcast = alice.recv_type("broadcast").await;
+        if broadcast["username"] == "helper" && broadcast["content"] != "New here? Try !help" {
+            break broadcast;
+        }
+    };
+    assert_eq!(reply["content"], "Be kind.");
+    loop {
+        let broadcast = alice.recv_type("broadcast").await;
+        if broadcast["content"] == "New here? Try !help" {
+            assert_eq!(broadcast["username"], "helper");
+            break;
+        }
+    }
+
+    // The bot's name is taken.
+    let mut imposter = TestClient::connect(addr).await;
+    let credentials = json!({ "username": "helper", "password": PASSWORD });
+    let response = imposter.request("register", credentials).await;
+    assert_eq!(response["success"], false, "registered as the bot: {}", response);
+}
+
+#[tokio::test]
+async fn chat_is_posted_to_the_webhook() {
+    // A bare-bones HTTP endpoint that hands over the first request it gets.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let endpoint = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let request_line = lines.next_line().await.unwrap().unwrap();
+        let mut len = 0;
+        while let Some(header) = lines.next_line().await.unwrap().filter(|l| !l.is_empty()) {
+            if let Some(value) = header.strip_prefix("Content-Length: ") {
+                len = value.parse().unwrap();
+            }
+        }
+        let mut reader = lines.into_inner();
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).await.unwrap();
+        reader.into_inner().write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+        (request_line, String::from_utf8(body).unwrap())
+    });
+
+    let addr = spawn_test_server_with(ServerConfig {
+        ephemeral: true,
+        webhook_url: Some(url),
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    alice.send("chat", json!({ "content": "ship it" })).await;
+    let broadcast = alice.recv_type("broadcast").await;
+
+    let (request_line, body) = tokio::time::timeout(Duration::from_secs(5), endpoint)
+        .await
+        .expect("the webhook was never called")
+        .unwrap();
+    assert_eq!(request_line, "POST /hook HTTP/1.1");
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["id"], broadcast["id"]);
+    assert_eq!(body["user_id"], broadcast["user_id"]);
+    assert_eq!(body["username"], "alice");
+    assert_eq!(body["content"], "ship it");
+    assert_eq!(body["timestamp"], broadcast["timestamp"]);
+}
+
+/// A RESP `message` push for `channel`, as Redis sends a published payload.
+fn published(channel: &str, payload: &str) -> Vec<u8> {
+    let mut out = format!("*3\r\n$7\r\nmessage\r\n${}\r\n{}\r\n", channel.len(), channel);
+    out += &format!("${}\r\n{}\r\n", payload.len(), payload);
+    out.into_bytes()
+}
+
+#[tokio::test]
+async fn the_bridge_posts_what_is_published_and_reconnects() {
+    // Stands in for Redis: confirms each SUBSCRIBE, then publishes.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("redis://{}", listener.local_addr().unwrap());
+    let subscribed = || async {
+        let accept = tokio::time::timeout(Duration::from_secs(5), listener.accept());
+        let (stream, _) = accept.await.expect("the bridge never connected").unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        for expected in ["*2", "$9", "SUBSCRIBE", "$7", "chat-in"] {
+            assert_eq!(lines.next_line().await.unwrap().unwrap(), expected);
+        }
+        let mut stream = lines.into_inner().into_inner();
+        stream.write_all(b"*3\r\n$9\r\nsubscribe\r\n$7\r\nchat-in\r\n:1\r\n").await.unwrap();
+        stream
+    };
+
+    let (server, addr) = spawn_server(ServerConfig {
+        ephemeral: true,
+        ..ServerConfig::default()
+    })
+    .await;
+    let config = bridge::BridgeConfig {
+        url,
+        channel: "chat-in".to_string(),
+        name: "alerts".to_string(),
+    };
+    bridge::start(server, config).await.unwrap();
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    alice.request("join", json!({ "room": "ops" })).await;
+
+    let mut redis = subscribed().await;
+    redis.write_all(&published("chat-in", "disk \x1b[31mfull")).await.unwrap();
+    let broadcast = alice.recv_type("broadcast").await;
+    assert_eq!(broadcast["username"], "alerts");
+    assert_eq!(broadcast["content"], "disk full");
+    assert_eq!(broadcast["room"], Value::Null);
+    let structured = json!({ "content": "deploy done", "room": "Ops" }).to_string();
+    redis.write_all(&published("chat-in", &structured)).await.unwrap();
+    let broadcast = alice.recv_type("broadcast").await;
+    assert_eq!(broadcast["content"], "deploy done");
+    assert_eq!(broadcast["room"], "ops");
+    let history = history_with(&mut alice, 1).await;
+    assert_eq!(history[0]["username"], "alerts");
+
+    // Losing the connection isn't the end of the bridge.
+    drop(redis);
+    let mut redis = subscribed().await;
+    redis.write_all(&published("chat-in", "back")).await.unwrap();
+    assert_eq!(alice.recv_type("broadcast").await["content"], "back");
+}
+
+#[tokio::test]
+async fn local_echo_leaves_the_sender_out_of_its_own_broadcasts() {
+    let addr = spawn_test_server().await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.request("hello", json!({ "version": "1.0.0", "features": ["local_echo"] })).await;
+    alice.register("alice", PASSWORD).await;
+    let mut bob = TestClient::connect(addr).await;
+    bob.register("bob", PASSWORD).await;
+
+    alice.send("chat", json!({ "content": "shown locally" })).await;
+    assert_eq!(bob.recv_type("broadcast").await["content"], "shown locally");
+    bob.send("chat", json!({ "content": "echoed to bob" })).await;
+    assert_eq!(bob.recv_type("broadcast").await["content"], "echoed to bob");
+    // Alice's next broadcast is bob's: her own never came back.
+    assert_eq!(alice.recv_type("broadcast").await["content"], "echoed to bob");
+}
+
+#[tokio::test]
+async fn expiring_messages_are_deleted_after_their_ttl() {
+    let (mut alice, mut bob) = alice_and_bob(false).await;
+    let response = alice.request("chat", json!({ "content": "x", "ttl_secs": 0 })).await;
+    assert_eq!(response["success"], false, "a zero ttl was accepted: {}", response);
+
+    alice.send("chat", json!({ "content": "self-destructs", "ttl_secs": 1 })).await;
+    alice.send("chat", json!({ "content": "stays" })).await;
+    let fleeting = bob.recv_type("broadcast").await;
+    assert_eq!(fleeting["content"], "self-destructs");
+    let sent: chrono::DateTime<chrono::Utc> =
+        serde_json::from_value(fleeting["timestamp"].clone()).unwrap();
+    let expires: chrono::DateTime<chrono::Utc> =
+        serde_json::from_value(fleeting["expires_at"].clone()).unwrap();
+    assert_eq!(expires - sent, chrono::Duration::seconds(1));
+    assert_eq!(bob.recv_type("broadcast").await["expires_at"], Value::Null);
+    history_with(&mut bob, 2).await;
+
+    let deleted = bob.recv_type("deleted").await;
+    assert_eq!(deleted["message_ids"], json!([fleeting["id"]]));
+    let history = history_with(&mut bob, 1).await;
+    let contents: Vec<&Value> = history.iter().map(|m| &m["content"]).collect();
+    assert_eq!(contents, [&json!("stays")]);
+    let response = bob.request("search", json!({ "query": "self" })).await;
+    assert_eq!(response["data"]["total"], 0);
+}
+
+#[tokio::test]
+async fn concurrent_chat_has_one_order_live_and_in_history() {
+    let addr = spawn_test_server().await;
+    let mut observer = TestClient::connect(addr).await;
+    observer.register("observer", PASSWORD).await;
+    let mut senders = Vec::new();
+    for name in ["alice", "bob", "carol"] {
+        let mut client = TestClient::connect(addr).await;
+        client.register(name, PASSWORD).await;
+        senders.push(tokio::spawn(async move {
+            for n in 0..30 {
+                client.send("chat", json!({ "content": format!("{} {}", name, n) })).await;
+            }
+            client
+        }));
+    }
+    let mut live = Vec::new();
+    while live.len() < 90 {
+        let broadcast = observer.recv_type("broadcast").await;
+        live.push((broadcast["seq"].as_u64().unwrap(), broadcast["id"].clone()));
+    }
+    for sender in senders {
+        sender.await.unwrap();
+    }
+    assert!(live.windows(2).all(|w| w[0].0 < w[1].0), "seq out of order: {:?}", live);
+
+    let mut stored = Vec::new();
+    for _ in 0..100 {
+        let response = observer.request("history", json!({ "limit": 100 })).await;
+        stored = response["data"].as_array().cloned().unwrap_or_default();
+        if stored.len() == 90 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let stored: Vec<(u64, Value)> =
+        stored.iter().map(|m| (m["seq"].as_u64().unwrap(), m["id"].clone())).collect();
+    assert_eq!(stored, live);
+}
+
+/// Every file under `dir` with its contents.
+fn snapshot(dir: &std::path::Path) -> Vec<(std::path::PathBuf, Vec<u8>)> {
+    let mut files: Vec<_> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let data = std::fs::read(&path).unwrap();
+            (path, data)
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+#[tokio::test]
+async fn read_only_refuses_changes_but_serves_reads() {
+    let dir = std::env::temp_dir().join(format!("chat-test-read-only-{}", std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    let addr = spawn_test_server_with(ServerConfig {
+        data_dir: dir.to_string_lossy().into_owned(),
+        admins: vec!["root".to_string()],
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut root = TestClient::connect(addr).await;
+    root.register("root", PASSWORD).await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    alice.send("chat", json!({ "content": "archived" })).await;
+    let id = history_with(&mut alice, 1).await[0]["id"].clone();
+
+    let response = root.request("admin", json!({ "action": "read_only", "enabled": true })).await;
+    assert_eq!(response["success"], true, "admin failed: {}", response);
+    let before = snapshot(&dir);
+    assert!(before.iter().any(|(path, _)| path.ends_with("messages.json")));
+
+    let refused = [
+        ("chat", json!({ "content": "refused" })),
+        ("direct", json!({ "to": "root", "content": "refused" })),
+        ("rename", json!({ "new_username": "alicia" })),
+        ("updateprofile", json!({ "status_text": "refused" })),
+        ("block", json!({ "username": "root" })),
+        ("pin", json!({ "message_id": id })),
+    ];
+    for (kind, payload) in refused {
+        let response = alice.request(kind, payload).await;
+        assert_eq!(response["message"], "error: server is read-only", "{} got {}", kind, response);
+    }
+    let mut carol = TestClient::connect(addr).await;
+    let credentials = json!({ "username": "carol", "password": PASSWORD });
+    let response = carol.request("register", credentials).await;
+    assert_eq!(response["message"], "error: server is read-only");
+
+    for (kind, payload) in [
+        ("history", json!({ "limit": 10 })),
+        ("search", json!({ "query": "archived" })),
+        ("users", json!({})),
+    ] {
+        let response = alice.request(kind, payload).await;
+        assert_eq!(response["success"], true, "{} failed: {}", kind, response);
+    }
+    assert_eq!(alice.request("history", json!({ "limit": 10 })).await["data"][0]["id"], id);
+    carol.login("alice", PASSWORD).await;
+    drop(carol);
+    drop(alice);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(snapshot(&dir) == before, "files changed while read-only");
+
+    root.request("admin", json!({ "action": "read_only", "enabled": false })).await;
+    root.send("chat", json!({ "content": "writable" })).await;
+    assert_eq!(root.recv_type("broadcast").await["content"], "writable");
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[tokio::test]
+async fn the_directory_lists_everyone_with_their_online_state() {
+    let addr = spawn_test_server().await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    let mut bob = TestClient::connect(addr).await;
+    bob.register("bob", PASSWORD).await;
+    let mut carol = TestClient::connect(addr).await;
+    carol.register("carol", PASSWORD).await;
+    drop(carol);
+
+    let mut page = Value::Null;
+    for _ in 0..100 {
+        page = alice.request("directory", json!({ "limit": 2, "offset": 1 })).await;
+        if page["data"]["users"][1]["online"] == false {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(page["message"], "users 2-3 of 3");
+    assert_eq!(page["data"]["total"], 3);
+    let users = page["data"]["users"].as_array().unwrap();
+    let listed: Vec<(&Value, &Value)> =
+        users.iter().map(|u| (&u["username"], &u["online"])).collect();
+    assert_eq!(listed, [(&json!("bob"), &json!(true)), (&json!("carol"), &json!(false))]);
+    assert!(users.iter().all(|u| u["created_at"].is_string()));
+    assert!(!page.to_string().contains("password"), "hash leaked: {}", page);
+
+    let page = alice.request("directory", json!({ "offset": 3 })).await;
+    assert_eq!(page["message"], "no users at offset 3 (3 in all)");
+}
+
+/// Whether chat `content` from `client` is refused by the word filter.
+async fn filtered(client: &mut TestClient, content: &str) -> bool {
+    client.send("chat", json!({ "content": content })).await;
+    loop {
+        let packet = client.recv_packet().await;
+        match packet["type"].as_str() {
+            Some("response") => {
+                let message = &packet["payload"]["message"];
+                assert_eq!(message, "error: message contains a filtered word");
+                return true;
+            }
+            Some("broadcast") if packet["payload"]["content"] == content => return false,
+            _ => {}
+        }
+    }
+}
+
+#[tokio::test]
+async fn reload_picks_up_a_new_filter_list_and_limits() {
+    let dir = std::env::temp_dir();
+    let list = dir.join(format!("chat-test-reload-filter-{}.txt", std::process::id()));
+    let limits = dir.join(format!("chat-test-reload-limits-{}.json", std::process::id()));
+    std::fs::write(&list, "darn\n").unwrap();
+    std::fs::write(&limits, "{}").unwrap();
+    let (server, addr) = spawn_server(ServerConfig {
+        ephemeral: true,
+        filter_list: Some(list.clone()),
+        filter_mode: FilterMode::Reject,
+        limits_file: Some(limits.clone()),
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    assert!(!filtered(&mut alice, "drat").await);
+
+    std::fs::write(&list, "drat\n").unwrap();
+    std::fs::write(&limits, r#"{ "max_conns_per_ip": 1 }"#).unwrap();
+    server.reload().unwrap();
+    assert!(filtered(&mut alice, "drat").await);
+    assert!(!filtered(&mut alice, "darn").await);
+    // The open connection counts against the new limit; it isn't dropped.
+    let packet = first_packet(addr).await;
+    assert_eq!(packet["payload"]["message"], "too many connections from your address");
+    alice.request("whoami", json!({})).await;
+
+    // A broken file changes nothing.
+    std::fs::write(&list, "darn\n").unwrap();
+    std::fs::write(&limits, "not json").unwrap();
+    assert!(server.reload().is_err());
+    assert!(filtered(&mut alice, "drat").await);
+    std::fs::remove_file(&list).ok();
+    std::fs::remove_file(&limits).ok();
+}
+
+/// Sends SIGHUP to the server binary itself, which reloads its filter list.
+#[cfg(unix)]
+#[tokio::test]
+async fn sighup_reloads_the_running_server_binary() {
+    let list = std::env::temp_dir().join(format!("chat-test-sighup-{}.txt", std::process::id()));
+    std::fs::write(&list, "darn\n").unwrap();
+    let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut server = tokio::process::Command::new(env!("CARGO_BIN_EXE_server"))
+        .args(["--ephemeral", "--addr", &addr.to_string(), "--filter-mode", "reject"])
+        .arg("--filter-list")
+        .arg(&list)
+        .stderr(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    for _ in 0..250 {
+        if TcpStream::connect(addr).await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    assert!(!filtered(&mut alice, "drat").await);
+
+    std::fs::write(&list, "drat\n").unwrap();
+    let pid = server.id().unwrap().to_string();
+    let status = std::process::Command::new("kill").args(["-HUP", &pid]).status().unwrap();
+    assert!(status.success());
+    let mut refused = false;
+    for _ in 0..100 {
+        if filtered(&mut alice, "drat").await {
+            refused = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(refused, "the new filter list was never applied");
+    let response = alice.request("whoami", json!({})).await;
+    assert_eq!(response["success"], true, "the connection didn't survive: {}", response);
+    server.kill().await.ok();
+    std::fs::remove_file(&list).ok();
+}
+
+#[tokio::test]
+async fn control_sequences_are_neutralized_before_anyone_sees_them() {
+    let (mut alice, mut bob) = alice_and_bob(false).await;
+    alice.send("chat", json!({ "content": "disk \x1b[31mfull\x1b]0;pwned\x07" })).await;
+    assert_eq!(bob.recv_type("broadcast").await["content"], "disk full");
+    assert_eq!(history_with(&mut bob, 1).await[0]["content"], "disk full");
+    let direct = json!({ "to": "bob", "content": "\u{9b}2Jhi\tthere" });
+    let response = alice.request("direct", direct).await;
+    assert_eq!(response["success"], true, "direct failed: {}", response);
+    assert_eq!(bob.recv_type("direct").await["content"], "hi\tthere");
+    let response = alice.request("chat", json!({ "content": "\x1b[2J\x1b[H" })).await;
+    assert_eq!(
+        response["message"],
+        "error: message is empty once control characters are removed"
+    );
+
+    let addr = spawn_test_server_with(ServerConfig {
+        ephemeral: true,
+        sanitize: SanitizeMode::Escape,
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut carol = TestClient::connect(addr).await;
+    carol.register("carol", PASSWORD).await;
+    carol.send("chat", json!({ "content": "disk \x1b[31mfull\x07" })).await;
+    assert_eq!(carol.recv_type("broadcast").await["content"], "disk \\x1b[31mfull\\x07");
+}
+
+#[tokio::test]
+async fn interval_fsync_leaves_chat_in_memory_until_a_flush() {
+    let dir = std::env::temp_dir().join(format!("chat-test-interval-{}", std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    // The first flush runs at startup; the next is an hour away.
+    let (server, addr) = spawn_server(ServerConfig {
+        data_dir: dir.to_string_lossy().into_owned(),
+        fsync: FsyncPolicy::Interval,
+        flush_interval: Duration::from_secs(3600),
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    alice.send("chat", json!({ "content": "not yet on disk" })).await;
+    history_with(&mut alice, 1).await;
+    let written = || std::fs::read_to_string(dir.join("messages.json")).unwrap_or_default();
+    assert!(!written().contains("not yet on disk"));
+
+    server.flush().await.unwrap();
+    assert!(written().contains("not yet on disk"));
+    drop(server);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[tokio::test]
+async fn a_packet_cut_off_by_the_close_is_not_handled() {
+    let (mut alice, mut bob) = alice_and_bob(false).await;
+    alice.send_raw(br#"{"type":"chat","payload":{"content":"half a packet"}}"#).await;
+    alice.finish().await;
+    alice.expect_closed().await;
+
+    bob.send("chat", json!({ "content": "after" })).await;
+    nothing_from_before(&mut bob, "alice", "after").await;
+}
+
+#[tokio::test]
+async fn admins_set_room_topics_and_only_members_hear_of_it() {
+    let addr = spawn_test_server_with(ServerConfig {
+        ephemeral: true,
+        admins: vec!["root".to_string()],
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut root = TestClient::connect(addr).await;
+    root.register("root", PASSWORD).await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    let mut bob = TestClient::connect(addr).await;
+    bob.register("bob", PASSWORD).await;
+    alice.request("join", json!({ "room": "ops" })).await;
+
+    let response = alice.request("topic", json!({ "room": "ops", "topic": "mine now" })).await;
+    assert_eq!(response["message"], "error: only admins can set a room topic");
+    let long = "x".repeat(201);
+    let response = root.request("topic", json!({ "room": "ops", "topic": long })).await;
+    assert_eq!(response["message"], "error: topic is longer than 200 characters");
+
+    let topic = json!({ "room": "Ops", "topic": "deploys \x1b[1monly\x1b[0m" });
+    let response = root.request("topic", topic).await;
+    assert_eq!(response["message"], "root set the topic of #ops: deploys only");
+    let notice = alice.recv_type("system").await;
+    assert_eq!(notice["topic"]["room"], "ops");
+    assert_eq!(notice["topic"]["topic"], "deploys only");
+    assert_eq!(notice["topic"]["set_by"], "root");
+    // Bob isn't in #ops: the next thing he hears is the lobby chat.
+    root.send("chat", json!({ "content": "topic set" })).await;
+    loop {
+        let packet = bob.recv_packet().await;
+        assert!(packet["payload"]["topic"].is_null(), "bob heard {}", packet);
+        if packet["type"] == "broadcast" {
+            break;
+        }
+    }
+
+    let response = bob.request("join", json!({ "room": "ops" })).await;
+    assert_eq!(response["data"]["topic"]["topic"], "deploys only");
+    let response = root.request("topic", json!({ "room": "ops", "topic": "" })).await;
+    assert_eq!(response["message"], "root cleared the topic of #ops");
+    bob.request("leave", json!({ "room": "ops" })).await;
+    let response = bob.request("join", json!({ "room": "ops" })).await;
+    assert!(response["data"]["topic"].is_null(), "topic not cleared: {}", response);
+}
+
+/// Waits until `client` sees `username` online, or offline.
+async fn wait_online(client: &mut TestClient, username: &str, online: bool) {
+    for _ in 0..100 {
+        let response = client.request("users", json!({})).await;
+        let users = response["data"].as_array().cloned().unwrap_or_default();
+        if users.iter().any(|u| u["username"] == username) == online {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("{} never went {}", username, if online { "online" } else { "offline" });
+}
+
+#[tokio::test]
+async fn a_quick_reconnect_is_not_announced_but_a_real_leave_is() {
+    let addr = spawn_test_server_with(ServerConfig {
+        ephemeral: true,
+        presence_debounce: Some(Duration::from_millis(500)),
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    let mut bob = TestClient::connect(addr).await;
+    bob.register("bob", PASSWORD).await;
+    while alice.recv_type("system").await["message"] != "bob joined the chat" {}
+
+    // The online list changes at once; only the notice waits.
+    drop(bob);
+    wait_online(&mut alice, "bob", false).await;
+    let mut bob = TestClient::connect(addr).await;
+    bob.login("bob", PASSWORD).await;
+    wait_online(&mut alice, "bob", true).await;
+    tokio::time::sleep(Duration::from_millis(700)).await;
+    bob.send("chat", json!({ "content": "still here" })).await;
+    loop {
+        let packet = alice.recv_packet().await;
+        assert_ne!(packet["type"], "system", "the reconnect was announced: {}", packet);
+        if packet["type"] == "broadcast" {
+            break;
+        }
+    }
+
+    drop(bob);
+    let leave = alice.recv_type("system").await;
+    assert_eq!(leave["message"], "bob left the chat");
+    assert_eq!(leave["presence"]["event"], "leave");
+}
+
+/// Lets in anyone with the passphrase, and notes every attempt.
+#[derive(Default)]
+struct Gatekeeper {
+    attempts: std::sync::Mutex<Vec<String>>,
+}
+
+impl Gatekeeper {
+    fn check(&self, action: &str, username: &str, password: &str) -> anyhow::Result<User> {
+        self.attempts.lock().unwrap().push(format!("{} {}", action, username));
+        if password != "open sesame" {
+            anyhow::bail!("the gatekeeper says no");
+        }
+        Ok(User {
+            id: format!("gate-{}", username),
+            username: username.to_string(),
+            password_hash: String::new(),
+            created_at: chrono::Utc::now(),
+            blocked: Vec::new(),
+            last_seen: None,
+            display_name: None,
+            status_text: None,
+            avatar: None,
+            bot: false,
+            external: true,
+            prefs: Default::default(),
+        })
+    }
+}
+
+impl AuthProvider for Gatekeeper {
+    fn authenticate<'a>(&'a self, username: &'a str, password: &'a str) -> AuthFuture<'a> {
+        Box::pin(async move { self.check("login", username, password) })
+    }
+
+    fn register<'a>(&'a self, username: &'a str, password: &'a str) -> AuthFuture<'a> {
+        Box::pin(async move { self.check("register", username, password) })
+    }
+}
+
+#[tokio::test]
+async fn a_plugged_in_provider_decides_logins_and_registrations() {
+    let gatekeeper = std::sync::Arc::new(Gatekeeper::default());
+    let server = Server::new(ServerConfig {
+        ephemeral: true,
+        ..ServerConfig::default()
+    })
+    .unwrap()
+    .with_auth_provider(gatekeeper.clone());
+    let (_server, addr) = spawn_built(server).await;
+
+    let mut dana = TestClient::connect(addr).await;
+    let credentials = json!({ "username": "dana", "password": PASSWORD });
+    let response = dana.request("register", credentials).await;
+    assert_eq!(response["message"], "error: the gatekeeper says no");
+    let response = dana.login("dana", PASSWORD).await;
+    assert_eq!(response["message"], "error: the gatekeeper says no");
+    let response = dana.request("whoami", json!({})).await;
+    assert_eq!(response["success"], false, "logged in anyway: {}", response);
+
+    // No account in the store is needed; the provider's word is enough.
+    let response = dana.login("dana", "open sesame").await;
+    assert_eq!(response["success"], true, "login failed: {}", response);
+    assert_eq!(response["data"]["user_id"], "gate-dana");
+    let mut erin = TestClient::connect(addr).await;
+    erin.register("erin", "open sesame").await;
+    erin.send("chat", json!({ "content": "let in" })).await;
+    assert_eq!(dana.recv_type("broadcast").await["username"], "erin");
+
+    let attempts = gatekeeper.attempts.lock().unwrap().clone();
+    assert_eq!(attempts, ["register dana", "login dana", "login dana", "register erin"]);
+}
+
+#[tokio::test]
+async fn cached_history_keeps_up_with_changes_and_request_ids() {
+    let (mut alice, mut bob) = alice_and_bob(false).await;
+    alice.send("chat", json!({ "content": "first" })).await;
+    let first = history_with(&mut bob, 1).await;
+    // Served again from the cache, each under its own request's id.
+    for id in [7, 8] {
+        let request = json!({ "id": id, "type": "history", "payload": { "limit": 50 } });
+        bob.send_raw(format!("{}\n", request).as_bytes()).await;
+        let packet = bob.recv_packet().await;
+        assert_eq!(packet["id"], id);
+        assert_eq!(packet["payload"]["data"], json!(first));
+    }
+
+    alice.send("chat", json!({ "content": "fleeting", "ttl_secs": 1 })).await;
+    let contents = |history: &[Value]| -> Vec<String> {
+        history.iter().map(|m| m["content"].as_str().unwrap().to_string()).collect()
+    };
+    assert_eq!(contents(&history_with(&mut bob, 2).await), ["first", "fleeting"]);
+    assert_eq!(contents(&history_with(&mut alice, 2).await), ["first", "fleeting"]);
+    bob.recv_type("deleted").await;
+    let response = alice.request("history", json!({ "limit": 50 })).await;
+    assert_eq!(contents(response["data"].as_array().unwrap()), ["first"]);
+}
+
+/// The entries in the audit log at `path`, once there are at least `n`.
+async fn audit_entries(path: &std::path::Path, n: usize) -> Vec<Value> {
+    for _ in 0..250 {
+        let text = std::fs::read_to_string(path).unwrap_or_default();
+        let entries: Vec<Value> =
+            text.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        if entries.len() >= n {
+            return entries;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("the audit log never reached {} entries", n);
+}
+
+#[tokio::test]
+async fn logins_and_failures_are_audited() {
+    let path = std::env::temp_dir().join(format!("chat-test-audit-{}.log", std::process::id()));
+    std::fs::remove_file(&path).ok();
+    let addr = spawn_test_server_with(ServerConfig {
+        ephemeral: true,
+        audit_log: Some(path.clone()),
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    let user_id = alice.request("whoami", json!({})).await["data"]["user_id"].clone();
+    let mut again = TestClient::connect(addr).await;
+    again.login("Alice", "wrong").await;
+    again.login("alice", PASSWORD).await;
+
+    let entries = audit_entries(&path, 3).await;
+    let actions: Vec<&Value> = entries.iter().map(|e| &e["action"]).collect();
+    assert_eq!(actions, [&json!("register"), &json!("login_failed"), &json!("login")]);
+    assert_eq!(entries[1]["actor"], "Alice");
+    assert_eq!(entries[1]["detail"], "incorrect password");
+    assert_eq!(entries[2]["actor"], "alice");
+    assert_eq!(entries[2]["actor_id"], user_id);
+    assert_eq!(entries[2]["ip"], "127.0.0.1");
+    std::fs::remove_file(&path).ok();
+}
+
+#[tokio::test]
+async fn admin_actions_are_audited_with_what_they_did() {
+    let name = format!("chat-test-admin-audit-{}.log", std::process::id());
+    let path = std::env::temp_dir().join(name);
+    std::fs::remove_file(&path).ok();
+    let addr = spawn_test_server_with(ServerConfig {
+        ephemeral: true,
+        admins: vec!["root".to_string()],
+        audit_log: Some(path.clone()),
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut root = TestClient::connect(addr).await;
+    root.register("root", PASSWORD).await;
+    let root_id = root.request("whoami", json!({})).await["data"]["user_id"].clone();
+    for action in [
+        json!({ "action": "slow_mode", "seconds": 30 }),
+        json!({ "action": "read_only", "enabled": true }),
+    ] {
+        let response = root.request("admin", action).await;
+        assert_eq!(response["success"], true, "admin failed: {}", response);
+    }
+
+    let entries = audit_entries(&path, 3).await;
+    let actions: Vec<&Value> = entries.iter().map(|e| &e["action"]).collect();
+    assert_eq!(actions, [&json!("register"), &json!("slow_mode"), &json!("read_only")]);
+    for entry in &entries[1..] {
+        assert_eq!((&entry["actor"], &entry["actor_id"]), (&json!("root"), &root_id));
+    }
+    assert_eq!(entries[1]["detail"], "30s");
+    assert_eq!(entries[2]["detail"], "on");
+    std::fs::remove_file(&path).ok();
+}
+
+/// The away notice `client` has been sent since its last request, if any.
+/// A `whoami` round trip makes sure anything queued before it is read.
+async fn away_notice(client: &mut TestClient) -> Option<String> {
+    client.send("whoami", json!({})).await;
+    let mut notice = None;
+    loop {
+        let packet = client.recv_packet().await;
+        match packet["type"].as_str() {
+            Some("response") => return notice,
+            Some("system") => {
+                let message = packet["payload"]["message"].as_str().unwrap_or_default();
+                if message.contains(" is away: ") {
+                    notice = Some(message.to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[tokio::test]
+async fn direct_messages_to_an_away_user_get_one_reply_per_sender() {
+    let addr = spawn_test_server().await;
+    let mut alice = TestClient::connect(addr).await;
+    alice.register("alice", PASSWORD).await;
+    let mut senders = Vec::new();
+    for name in ["bob", "carol", "dave"] {
+        let mut client = TestClient::connect(addr).await;
+        client.register(name, PASSWORD).await;
+        senders.push(client);
+    }
+    let [bob, carol, dave] = &mut senders[..] else { unreachable!() };
+    alice.request("block", json!({ "username": "dave" })).await;
+    let response = alice.request("away", json!({ "message": "at lunch" })).await;
+    assert_eq!(response["message"], "you are marked away: at lunch");
+
+    let dm = json!({ "to": "alice", "content": "you there?" });
+    bob.request("direct", dm.clone()).await;
+    assert_eq!(away_notice(bob).await.as_deref(), Some("alice is away: at lunch"));
+    bob.request("direct", dm.clone()).await;
+    assert_eq!(away_notice(bob).await, None, "bob was told twice");
+    carol.request("direct", dm.clone()).await;
+    assert_eq!(away_notice(carol).await.as_deref(), Some("alice is away: at lunch"));
+    dave.request("direct", dm.clone()).await;
+    assert_eq!(away_notice(dave).await, None, "a blocked sender was told");
+
+    // Away outlasts a disconnect; messages queued meanwhile get the reply.
+    drop(alice);
+    wait_online(bob, "alice", false).await;
+    let mut erin = TestClient::connect(addr).await;
+    erin.register("erin", PASSWORD).await;
+    let response = erin.request("direct", dm.clone()).await;
+    assert!(response["message"].as_str().unwrap().contains("offline"), "{}", response);
+    assert_eq!(away_notice(&mut erin).await.as_deref(), Some("alice is away: at lunch"));
+
+    let mut alice = TestClient::connect(addr).await;
+    alice.login("alice", PASSWORD).await;
+    alice.send("chat", json!({ "content": "back" })).await;
+    loop {
+        let notice = alice.recv_type("system").await;
+        if notice["message"] == "welcome back; you are no longer marked away" {
+            break;
+        }
+    }
+    erin.request("direct", dm).await;
+    assert_eq!(away_notice(&mut erin).await, None, "told after alice came back");
+}